@@ -0,0 +1,16 @@
+# demo1.s
+#
+# Writes the value 0xcafebabe into address 0x42, like "rvp demo demo1".
+#
+# expect: $t0 == 0xcafebabe
+# expect: $s0 == 0x42
+# expect-mem: 0x42 word 0xcafebabe
+
+        .text
+main:
+        ori  $t0, $zero, 0xbabe
+        lui  $t1, 0xcafe
+        or   $t0, $t1, $t0
+        addi $s0, $zero, 0x42
+        swl  $t0, 0($s0)            # 0x42 is not word aligned, store in two parts
+        swr  $t0, 3($s0)
@@ -0,0 +1,23 @@
+# demo2.s
+#
+# Writes the value 0x42 into every address in data memory, like
+# "rvp demo demo2".
+#
+# expect: $s0 == 256
+# expect: $t0 == 0
+# expect-mem: 0x00 word 0x42424242
+# expect-mem: 0x80 word 0x42424242
+# expect-mem: 0xff byte 0x42
+
+        .text
+main:
+        add  $s0, $zero, $zero          # s0 = *mem
+        addi $s7, $zero, 256
+        addi $t1, $zero, 0x42
+loop:
+        slt  $t0, $s0, $s7
+        beq  $t0, $zero, end_loop
+        sb   $t1, 0($s0)
+        addi $s0, $s0, 1
+        j    loop
+end_loop:
@@ -0,0 +1,23 @@
+# hello.s
+#
+# Prints a string on the console uart and returns 0 from main.
+#
+# expect-stdout: Hello, World!
+# expect-exit: 0
+
+        .data
+msg:    .asciiz "Hello, World!\n"
+
+        .text
+main:
+        lui  $t0, 0xffff                # uart at 0xffff0000
+        la   $t1, msg
+loop:
+        lb   $t2, 0($t1)
+        beqz $t2, done
+        sw   $t2, 0xc($t0)              # transmitter data
+        addi $t1, $t1, 1
+        j    loop
+done:
+        add  $v0, $zero, $zero
+        jr   $ra
@@ -0,0 +1,504 @@
+/*
+ * cli.rs
+ *
+ * Author: Travis Banken
+ *
+ * Command line of rvp: rvp [OPTIONS] <command> [ARGS] [-- PROGRAM ARGS].
+ * Options may come before or after the command, everything after "--" is
+ * handed to the program. Options taking a value accept both --opt=value and
+ * --opt value, except --profile and --coverage whose value is optional.
+ */
+#![allow(dead_code)]
+
+use crate::gdb;
+use crate::hardware::cache::CacheConfig;
+use crate::hardware::hierarchy::{Inclusion, MemoryConfig};
+use crate::history::HistoryConfig;
+use crate::isa::Isa;
+use crate::loader::Format;
+use crate::single_cycle::Options;
+use crate::tools::test_runner::Report;
+use crate::tools::trace;
+use crate::watch;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    // run a program file
+    Run(String),
+    // run a program file in the debugger
+    Debug(String),
+    // assemble a source file
+    Asm(String),
+    // disassemble a program file
+    Disasm(String),
+    // print the memories of a program file
+    Dump(String),
+    // run a built in demo, demo1 or demo2
+    Demo(String),
+    // run the self checking programs in files and directories
+    Test(Vec<String>),
+    // run a program file on the datapath and the reference interpreter
+    Lockstep(String),
+    Help,
+}
+
+/*
+ * Processor models --model accepts, only the single cycle one exists so far.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Model {
+    Single,
+    Multi,
+    Pipeline,
+}
+
+impl Model {
+    pub fn parse(name: &str) -> Result<Model, String> {
+        match name {
+            "single" => Ok(Model::Single),
+            "multi" | "pipeline" => Err(format!("The {} cycle model is not implemented yet, only single is",
+                                                name)),
+            _ => Err(format!("Invalid model [{}], use single, multi or pipeline", name)),
+        }
+    }
+}
+
+pub struct Cli {
+    pub command: Command,
+    pub opts: Options,
+    // format of the program file, or of the output of asm
+    pub format: Option<Format>,
+    // output file of asm and test
+    pub output: Option<String>,
+    pub report: Report,
+    pub model: Model,
+    // --entry, otherwise the entry point of the program
+    pub entry: Option<u32>,
+    // dump the memories before and after the run
+    pub dump: bool,
+    // arguments after --
+    pub args: Vec<String>,
+}
+
+/*
+ * Parses the arguments (without the name of the executable).
+ */
+pub fn parse(argv: &[String]) -> Result<Cli, String> {
+    let mut cli = Cli {
+        command: Command::Help,
+        opts: Options::default(),
+        format: None,
+        output: None,
+        report: Report::Tap,
+        model: Model::Single,
+        entry: None,
+        dump: false,
+        args: Vec::new(),
+    };
+    let mut positional = Vec::new();
+    let mut help = false;
+    // parsed once --isa is known, it names the registers
+    let mut watches = Vec::new();
+
+    let mut i = 0;
+    while i < argv.len() {
+        let arg = &argv[i];
+        i += 1;
+        if arg == "--" {
+            cli.args = argv[i..].to_vec();
+            break;
+        }
+        if !arg.starts_with('-') || arg == "-" {
+            positional.push(arg.clone());
+            continue;
+        }
+
+        let (name, inline) = match arg.find('=') {
+            Some(at) => (&arg[..at], Some(&arg[at + 1..])),
+            None => (arg.as_str(), None),
+        };
+
+        // options without a value
+        let flag = match name {
+            "-h" | "--help" => {help = true; true},
+            "-g" | "--debug" => {cli.opts.debug = true; true},
+            "-i" | "--interactive" => {cli.opts.interactive = true; true},
+            "-d" | "--dump" => {cli.dump = true; true},
+            "-u" | "--uart" => {cli.opts.uart = true; true},
+            "--timer" => {cli.opts.timer = true; true},
+            "--mmu" => {cli.opts.mmu = true; true},
+            "-c" | "--cache" => {
+                cli.opts.hierarchy.l1i = Some(CacheConfig::default());
+                cli.opts.hierarchy.l1d = Some(CacheConfig::default());
+                true
+            },
+            _ => false,
+        };
+        if flag {
+            if inline.is_some() {
+                return Err(format!("Error: Option [{}] takes no value", name));
+            }
+            continue;
+        }
+
+        // options with an optional value
+        match name {
+            "--profile" => {
+                cli.opts.profile.report = Some(inline.unwrap_or("-").to_string());
+                continue;
+            },
+            "--coverage" => {
+                cli.opts.coverage.listing = Some(inline.unwrap_or("-").to_string());
+                continue;
+            },
+            _ => (),
+        }
+
+        // options with a value
+        if !VALUE_OPTIONS.contains(&name) {
+            return Err(format!("Error: Unknown option [{}]", arg));
+        }
+        let value = match inline {
+            Some(value) => value.to_string(),
+            None if i < argv.len() => {
+                i += 1;
+                argv[i - 1].clone()
+            },
+            None => return Err(format!("Error: Option [{}] needs a value", name)),
+        };
+        if name == "--watch" {
+            watches.push(value);
+            continue;
+        }
+        set_option(&mut cli, name, &value).map_err(|msg| format!("Error: {} {}: {}", name, value, msg))?;
+    }
+
+    for spec in watches.iter() {
+        let watch = watch::Watch::parse(spec, cli.opts.isa).map_err(|msg| format!("Error: --watch {}: {}", spec, msg))?;
+        cli.opts.watches.push(watch);
+    }
+
+    let command = match positional.first() {
+        Some(command) => command.clone(),
+        None if help => return Ok(cli),
+        None => return Err("Error: No command given".to_string()),
+    };
+    let operands = &positional[1..];
+    let operand = |what: &str| -> Result<String, String> {
+        match operands.first() {
+            Some(operand) => Ok(operand.clone()),
+            None => Err(format!("Error: [{}] needs {}", command, what)),
+        }
+    };
+    // number of operands the command takes
+    let mut used = 1;
+    cli.command = match command.as_str() {
+        "run" => Command::Run(operand("a program file")?),
+        "debug" => Command::Debug(operand("a program file")?),
+        "asm" => Command::Asm(operand("a source file")?),
+        "disasm" => Command::Disasm(operand("a program file")?),
+        "dump" => Command::Dump(operand("a program file")?),
+        "lockstep" => Command::Lockstep(operand("a program file")?),
+        "demo" => Command::Demo(demo_name(&operand("a demo name")?)?),
+        "test" => {
+            operand("program files or directories")?;
+            used = operands.len();
+            Command::Test(operands.to_vec())
+        },
+        // the demos used to be run by name
+        "demo1" | "demo2" => {
+            used = 0;
+            Command::Demo(command.clone())
+        },
+        "help" => {
+            used = 0;
+            Command::Help
+        },
+        _ => return Err(format!("Error: Unknown command [{}]", command)),
+    };
+    if let Some(extra) = operands.get(used) {
+        return Err(format!("Error: Unexpected argument [{}], program arguments go after --", extra));
+    }
+    if help {
+        cli.command = Command::Help;
+    }
+    if cli.opts.isa != Isa::Mips {
+        check_mips_only(&cli)?;
+    }
+    return Ok(cli);
+}
+
+pub fn usage() -> String {
+    return USAGE.to_string();
+}
+
+// *** PRIVATE FN ***
+
+const VALUE_OPTIONS: [&str; 26] = [
+    "--isa", "--format", "--entry", "--max-steps", "--random-regs", "--model", "-o", "--output", "--report",
+    "--l1i", "--l1d", "--l2", "--mem", "--inclusion",
+    "--gdb", "--history", "--watch",
+    "--trace", "--trace-format", "--trace-filter", "--vcd", "--datapath",
+    "--profile-folded", "--coverage-lcov", "--load-state", "--save-state",
+];
+
+fn set_option(cli: &mut Cli, name: &str, value: &str) -> Result<(), String> {
+    let opts = &mut cli.opts;
+    match name {
+        "--isa" => opts.isa = Isa::parse(value)?,
+        "--format" => cli.format = Some(Format::parse(value)?),
+        "--entry" => cli.entry = Some(parse_addr(value)?),
+        "--max-steps" => {
+            let steps = value.parse::<u64>().map_err(|_| "Expected a number of instructions".to_string())?;
+            opts.max_steps = Some(steps);
+        },
+        "--random-regs" => {
+            let seed = value.parse::<u32>().map_err(|_| "Expected a number to seed the registers with".to_string())?;
+            opts.random_regs = Some(seed);
+        },
+        "--model" => cli.model = Model::parse(value)?,
+        "-o" | "--output" => cli.output = Some(value.to_string()),
+        "--report" => cli.report = Report::parse(value)?,
+        "--l1i" => opts.hierarchy.l1i = Some(CacheConfig::parse(value)?),
+        "--l1d" => opts.hierarchy.l1d = Some(CacheConfig::parse(value)?),
+        "--l2" => opts.hierarchy.l2 = Some(CacheConfig::parse(value)?),
+        "--mem" => opts.hierarchy.memory = Some(MemoryConfig::parse(value)?),
+        "--inclusion" => opts.hierarchy.inclusion = Inclusion::parse(value)?,
+        "--gdb" => opts.gdb = Some(gdb::Transport::parse(value)?),
+        "--history" => opts.history = HistoryConfig::parse(value)?,
+        "--trace" => opts.trace.path = Some(value.to_string()),
+        "--trace-format" => opts.trace.format = trace::Format::parse(value)?,
+        "--trace-filter" => opts.trace.filter = trace::Filter::parse(value)?,
+        "--vcd" => opts.vcd = Some(value.to_string()),
+        "--datapath" => opts.datapath = Some(value.to_string()),
+        "--profile-folded" => opts.profile.folded = Some(value.to_string()),
+        "--coverage-lcov" => opts.coverage.lcov = Some(value.to_string()),
+        "--load-state" => opts.load_state = Some(value.to_string()),
+        "--save-state" => opts.save_state = Some(value.to_string()),
+        _ => return Err("Unknown option".to_string()),
+    }
+    return Ok(());
+}
+
+/*
+ * The assembler, the demos, the reference interpreter, gdb and the mmu only
+ * know MIPS.
+ */
+fn check_mips_only(cli: &Cli) -> Result<(), String> {
+    let command = match cli.command {
+        Command::Asm(_) => Some("asm"),
+        Command::Demo(_) => Some("demo"),
+        Command::Test(_) => Some("test"),
+        Command::Lockstep(_) => Some("lockstep"),
+        _ => None,
+    };
+    let option = if cli.opts.gdb.is_some() {
+        Some("--gdb")
+    } else if cli.opts.mmu {
+        Some("--mmu")
+    } else {
+        None
+    };
+    match command.or(option) {
+        Some(what) => Err(format!("Error: [{}] is only supported for mips, not {}", what, cli.opts.isa.name())),
+        None => Ok(()),
+    }
+}
+
+fn demo_name(name: &str) -> Result<String, String> {
+    match name {
+        "demo1" | "1" => Ok("demo1".to_string()),
+        "demo2" | "2" => Ok("demo2".to_string()),
+        _ => Err(format!("Error: Unknown demo [{}], use demo1 or demo2", name)),
+    }
+}
+
+fn parse_addr(addr: &str) -> Result<u32, String> {
+    let res = match addr.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(&hex.replace('_', ""), 16),
+        None => addr.parse::<u32>(),
+    };
+    match res {
+        Ok(val) => Ok(val),
+        Err(_) => Err(format!("Invalid address [{}]", addr)),
+    }
+}
+
+const USAGE: &str = "\
+Usage:
+      rvp [OPTIONS] <command> [ARGS] [-- PROGRAM ARGS]
+COMMANDS:
+      run <file>   Run a program, rvp exits with its exit code ($v0 when
+                   it returns from main with jr $ra, a0 for rv32i)
+      debug <file> Run a program in the interactive debugger
+      asm <file>   Assemble a source file, to -o <file> or as hex on
+                   stdout
+      disasm <file>
+                   Disassemble a program
+      dump <file>  Print the instruction and data memory of a program
+      demo <name>  Run a built in demo (demo1, demo2)
+      lockstep <file>
+                   Run a program on the datapath and on the reference
+                   interpreter side by side and report the first
+                   instruction where they disagree
+      test <files or directories>
+                   Run the programs with # expect annotations and
+                   report the results as TAP (or --report=junit),
+                   exits with 1 when any of them failed
+      help         Show this message
+PROGRAM OPTIONS:
+      --isa=<mips|rv32i>
+                   Instruction set of the program (default mips),
+                   rv32i runs RISC-V programs in little endian, asm,
+                   demo, test, lockstep, --gdb and --mmu are mips only
+      --format=<elf|bin|hex|asm>
+                   Format of the program file (default from the
+                   extension: .s/.asm, .bin, .hex, else elf), for asm
+                   the format written with -o (default elf, hex on stdout)
+      -o, --output=<file>
+                   Output file of asm and test
+      --report=<tap|junit>
+                   Format of the test results (default tap)
+      --entry=<addr>
+                   Address of the first instruction (default the entry
+                   point of the program, 0 for bin and hex)
+      --max-steps=<n>
+                   Stop after n instructions (exit code 124), test
+                   and lockstep stop at 1000000 by default
+      --model=<single|multi|pipeline>
+                   Processor model (only single is implemented)
+      Arguments after -- are passed to main(argc, argv) in $a0 and $a1
+OPTIONS:
+      -g, --debug  Prints out debug information while the processor
+                   runs and the registers at the end
+      --random-regs=<seed>
+                   Start with random values from seed in the registers
+                   ($zero aside) to find reads of registers the
+                   program never wrote
+      -i, --interactive
+                   Step through the program in the debugger, type
+                   help at the (rvp) prompt for the commands
+      --gdb=<port|host:port|stdio>
+                   Wait for gdb to connect over tcp, or speak the
+                   remote protocol on stdin/stdout
+      --history=<spec>
+                   Reverse execution history of the debugger, e.g.
+                   interval=1000,budget=16M (snapshot every interval
+                   instructions, at most budget bytes)
+      --watch=<spec>
+                   Stop when memory or a register is touched, e.g.
+                   write:0x40+4, read:0x40, access:0x40, reg:$t0,
+                   add ,log to log every hit instead of stopping
+      --trace=<file|->
+                   Record every retired instruction (registers,
+                   memory, branches) to a file or stdout
+      --trace-format=<text|jsonl|binary>
+                   Format of the trace (default text)
+      --trace-filter=<spec>
+                   Only trace some instructions, e.g.
+                   pc:0x10-0x40,class:load|store (classes: alu,
+                   imm, load, store, branch, jump, system)
+      --vcd=<file> Dump the datapath signals of every cycle as a
+                   value change dump (view it in GTKWave)
+      --datapath=<dir>
+                   Draw the datapath of every cycle as a graphviz
+                   file <dir>/cycle_<n>.dot
+      --profile[=<file>]
+                   Print the instruction mix, hot spots, branch and
+                   memory statistics and the call graph at the end
+      --profile-folded=<file>
+                   Write the call stacks in folded format for
+                   flamegraph tools
+      --coverage[=<file>]
+                   Print the disassembly with the times every
+                   instruction ran and the block and branch coverage
+      --coverage-lcov=<file>
+                   Write the coverage as an LCOV tracefile
+      --load-state=<file>
+                   Start from a machine state saved with
+                   --save-state (or save in the debugger)
+      --save-state=<file>
+                   Save the machine state when the run ends
+      -d, --dump   Dumps out the contents of instr mem and data mem
+                   to stdout
+      -u, --uart   Map a console uart at 0xffff0000 (stdin/stdout)
+      --timer      Map a count down timer at 0xffff0010
+      -c, --cache  Simulate L1 instruction and data caches with the
+                   default config and print their statistics
+      --l1i=<spec> Simulate an L1 instruction cache, e.g.
+                   size=64,block=8,assoc=2,repl=lru,write=back,
+                   alloc=yes,hit=1
+                   (repl: lru|fifo|random|plru, write: back|through)
+      --l1d=<spec> Simulate an L1 data cache, same spec as --l1i
+      --l2=<spec>  Simulate a unified L2 cache, same spec as --l1i
+      --mem=<spec> Main memory timing, e.g. latency=10,bandwidth=4
+                   (bandwidth in bytes/cycle, 0 for unlimited)
+      --inclusion=<inclusive|exclusive|nine>
+                   Inclusion policy of the L2 (default nine)
+      --mmu        Translate addresses through the R3000 style mmu
+                   (kuseg/kseg0/kseg1/kseg2 and a 64 entry tlb)
+Demo Names:
+      demo1        Writes the value 0xcafebabe into address 0x42
+      demo2        Writes the value 0x42 into every address in mem
+";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let cli = parse(&args("--uart run prog.s --format asm --max-steps=100 --entry 0x10 -- a -b")).unwrap();
+        assert_eq!(cli.command, Command::Run("prog.s".to_string()));
+        assert!(cli.opts.uart);
+        assert_eq!(cli.format, Some(Format::Asm));
+        assert_eq!(cli.opts.max_steps, Some(100));
+        assert_eq!(cli.entry, Some(0x10));
+        assert_eq!(cli.args, args("a -b"));
+
+        let cli = parse(&args("--profile --coverage=cov.txt demo 2")).unwrap();
+        assert_eq!(cli.command, Command::Demo("demo2".to_string()));
+        assert_eq!(cli.opts.profile.report, Some("-".to_string()));
+        assert_eq!(cli.opts.coverage.listing, Some("cov.txt".to_string()));
+        assert_eq!(parse(&args("--dump demo1")).unwrap().command, Command::Demo("demo1".to_string()));
+        assert_eq!(parse(&args("asm prog.s -o prog")).unwrap().output, Some("prog".to_string()));
+        assert_eq!(parse(&args("--help")).unwrap().command, Command::Help);
+        let cli = parse(&args("test programs a.s --report junit")).unwrap();
+        assert_eq!(cli.command, Command::Test(args("programs a.s")));
+        assert_eq!(cli.report, Report::Junit);
+        assert_eq!(parse(&args("run x --help")).unwrap().command, Command::Help);
+        assert_eq!(parse(&args("--isa rv32i disasm prog.hex")).unwrap().opts.isa, Isa::Rv32i);
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = |line: &str| parse(&args(line)).err().unwrap();
+        assert_eq!(err("run prog --bogus"), "Error: Unknown option [--bogus]");
+        assert_eq!(err("--uart=1 run prog"), "Error: Option [--uart] takes no value");
+        assert_eq!(err("run prog --max-steps"), "Error: Option [--max-steps] needs a value");
+        assert_eq!(err("run prog --max-steps=ten"), "Error: --max-steps ten: Expected a number of instructions");
+        assert_eq!(parse(&args("run prog --random-regs=7")).unwrap().opts.random_regs, Some(7));
+        assert!(err("run prog --random-regs=x").contains("Expected a number to seed the registers with"));
+        assert_eq!(err("run prog --entry zz"), "Error: --entry zz: Invalid address [zz]");
+        assert_eq!(err("run prog --format coff"),
+                   "Error: --format coff: Invalid format [coff], use elf, bin, hex or asm");
+        assert_eq!(err("run prog --model pipeline"),
+                   "Error: --model pipeline: The pipeline cycle model is not implemented yet, only single is");
+        assert_eq!(err("run"), "Error: [run] needs a program file");
+        assert_eq!(err("run a b"), "Error: Unexpected argument [b], program arguments go after --");
+        assert_eq!(err("test"), "Error: [test] needs program files or directories");
+        assert_eq!(err("test x --report=xml"), "Error: --report xml: Invalid report format [xml], use tap or junit");
+        assert_eq!(err("demo demo3"), "Error: Unknown demo [demo3], use demo1 or demo2");
+        assert_eq!(err("launch prog"), "Error: Unknown command [launch]");
+        assert_eq!(err("--uart"), "Error: No command given");
+        assert_eq!(err("run prog --isa=arm"), "Error: --isa arm: Invalid isa [arm], use mips or rv32i");
+        assert_eq!(err("--isa=rv32i asm prog.s"), "Error: [asm] is only supported for mips, not rv32i");
+        assert_eq!(err("--isa=rv32i run prog --mmu"), "Error: [--mmu] is only supported for mips, not rv32i");
+        assert_eq!(err("--isa=rv32i run prog --gdb=1234"),
+                   "Error: [--gdb] is only supported for mips, not rv32i");
+    }
+}
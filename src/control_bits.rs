@@ -1,51 +1,574 @@
 /*
  * control_bits.rs
- * 
+ *
  * Author: Travis Banken
- * 
- * Structure for the control bits of the cpu
+ *
+ * Structure for the control bits of the cpu, and the table the control unit
+ * decodes them from. Every instruction the datapath implements is one row
+ * of CONTROL_TABLE, any other instruction is a reserved instruction.
  */
-#[allow(dead_code)]
+#![allow(dead_code)]
 
-pub struct ControlBits {
-    pub alu_op: u8,
-    pub alu_bnegate: u8,
+use crate::instruction::{self, Instruction, Op};
+
+/*
+ * Operation of the alu. Sub, Slt and Sltu add the inverted second input
+ * with a carry in of 1, the shifts shift the first input by the low 5 bits
+ * of the second. Clz and Clo count the first input, Seb, Seh, Wsbh, Rotr and
+ * the MIPS shifts work on the second. Ext and Ins take the bit field from the second input
+ * (see AluSrc::Field).
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AluOp {
+    And,
+    Or,
+    Xor,
+    Nor,
+    Add,
+    Sub,
+    // set on less than, signed and unsigned
+    Slt,
+    Sltu,
+    Sll,
+    Srl,
+    Sra,
+    // shift the second input (rt) by the low 5 bits of the first, the
+    // operand order of the MIPS shifts
+    SllRt,
+    SrlRt,
+    SraRt,
+    // multiply, the low word or the high word of a signed, signed by
+    // unsigned or unsigned product
+    Mul,
+    Mulh,
+    Mulhsu,
+    Mulhu,
+    // divide and remainder, signed and unsigned
+    Div,
+    Divu,
+    Rem,
+    Remu,
+    // count leading zeros and ones
+    Clz,
+    Clo,
+    // sign extend a byte or half word, swap the bytes of each half word
+    Seb,
+    Seh,
+    Wsbh,
+    // rotate right by the low 5 bits of the first input
+    Rotr,
+    // the field of the first input at bit 0, the first input moved into the
+    // field with the other bits 0
+    Ext,
+    Ins,
+}
+
+/*
+ * Second input of the alu, the first one is rs (or the pc or shamt, see
+ * alu_pc and alu_shamt). The RISC-V decoder puts the immediate of the
+ * format in place, rt is rs2.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AluSrc {
+    Rt,
+    // imm16 sign extended, zero extended, or in the upper half (lui)
+    SignImm,
+    ZeroImm,
+    UpperImm,
+    // the bit field of ext and ins, the lowest bit (shamt) in bits 0-4 and
+    // the highest bit (rd, relative to the lowest for ext) in bits 5-9
+    Field,
+}
+
+/*
+ * Register written by the instruction, if any.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegDst {
+    None,
+    Rt,
+    Rd,
+    // $ra, which jal links
+    Ra,
+}
 
-    pub mem_read: u8,
-    pub mem_write: u8,
-    pub mem_to_reg: u8,
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Width {
+    Byte,
+    Half,
+    Word,
+}
 
-    pub reg_dst: u8,
-    pub reg_write: u8,
+/*
+ * Part of the aligned word holding an unaligned address: from the address
+ * to the end of the word (Left, the most significant bytes of a register)
+ * or from the start of the word to the address (Right, the least
+ * significant bytes).
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Part {
+    Left,
+    Right,
+}
 
-    pub branch: u8,
-    pub jump: u8,
+/*
+ * Access to data memory at the alu result. Loads write the value read back
+ * to the destination register, stores write rt. Half words and words must
+ * be aligned, the part accesses (lwl, lwr, swl, swr) take any address and
+ * merge rt with the aligned word.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MemAccess {
+    None,
+    // signed loads sign extend a byte or half word
+    Load { width: Width, signed: bool },
+    Store(Width),
+    LoadPart(Part),
+    StorePart(Part),
+}
 
-    // extra
-    pub not_res: u8,
-    pub mem_by_byte: u8,
-    pub imm_upper: u8,
+/*
+ * When the destination register is written: always, or for movz and movn
+ * only if rt is zero or not zero. The value written is rs.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CondWrite {
+    Always,
+    RtZero,
+    RtNotZero,
+}
+
+/*
+ * HI/LO as a 64 bit accumulator, mult and multu set it to the signed or
+ * unsigned product of rs and rt, madd and msub add the product to it or
+ * subtract it. mthi and mtlo move rs into one half, mfhi and mflo write one
+ * half back to rd.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HiLo {
+    None,
+    Mult,
+    Multu,
+    Madd,
+    Maddu,
+    Msub,
+    Msubu,
+    Mthi,
+    Mtlo,
+    Mfhi,
+    Mflo,
+}
+
+/*
+ * Condition of the trap instructions, on the alu result like the branches
+ * (see PcSource).
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trap {
+    None,
+    Eq,
+    Ne,
+    Lt,
+    Ge,
+}
+
+/*
+ * Where the next pc comes from. Branches compare rs with rt through the alu
+ * and go to their target if the zero output matches, or for Lt and Ge if
+ * the set on less than result (alu_op Slt or Sltu) does.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PcSource {
+    Next,
+    BranchEq,
+    BranchNe,
+    BranchLt,
+    BranchGe,
+    Jump,
+    JumpReg,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ControlBits {
+    pub alu_op: AluOp,
+    pub alu_src: AluSrc,
+    // the first alu input is the pc instead of rs (auipc)
+    pub alu_pc: bool,
+    // the first alu input is the shamt field instead of rs (rotr)
+    pub alu_shamt: bool,
+    // add, addi and sub raise an overflow exception instead of writing back
+    pub trap_overflow: bool,
+    // the trap instructions raise a trap exception when the condition holds
+    pub trap: Trap,
+    pub hilo: HiLo,
+
+    pub mem: MemAccess,
+    pub reg_dst: RegDst,
+    pub cond_write: CondWrite,
+    // ins keeps the bits of rt outside of the field, the alu result fills it
+    pub insert: bool,
+    pub pc_src: PcSource,
+    // the register written gets the address of the next instruction (jal,
+    // jalr) instead of the alu result
+    pub link: bool,
+
+    // coprocessor 0 instruction (mfc0, mtc0, tlb ops, rfe), runs instead of
+    // the alu
+    pub cop0: bool,
 }
 
 impl Default for ControlBits {
     fn default() -> ControlBits {
-        ControlBits {
-            alu_op: 0,
-            alu_bnegate: 0,
+        NOP
+    }
+}
 
-            mem_read: 0,
-            mem_write: 0,
-            mem_to_reg: 0,
+impl ControlBits {
+    pub fn reg_write(&self) -> bool {
+        return self.reg_dst != RegDst::None;
+    }
 
-            reg_dst: 0,
-            reg_write: 0,
+    /*
+     * Is the destination register written, given rt? Only movz and movn
+     * look at it.
+     */
+    pub fn write_taken(&self, rt: u32) -> bool {
+        return self.reg_write() && match self.cond_write {
+            CondWrite::Always => true,
+            CondWrite::RtZero => rt == 0,
+            CondWrite::RtNotZero => rt != 0,
+        };
+    }
 
-            branch: 0,
-            jump: 0,
+    pub fn mem_read(&self) -> bool {
+        return matches!(self.mem, MemAccess::Load {..} | MemAccess::LoadPart(_));
+    }
 
-            not_res: 0,
-            mem_by_byte: 0,
-            imm_upper: 0,
+    pub fn mem_write(&self) -> bool {
+        return matches!(self.mem, MemAccess::Store(_) | MemAccess::StorePart(_));
+    }
+
+    /*
+     * lwl, lwr, swl or swr, which access the aligned word holding their
+     * address.
+     */
+    pub fn mem_part(&self) -> bool {
+        return matches!(self.mem, MemAccess::LoadPart(_) | MemAccess::StorePart(_));
+    }
+
+    pub fn branch(&self) -> bool {
+        return matches!(self.pc_src, PcSource::BranchEq | PcSource::BranchNe | PcSource::BranchLt |
+                        PcSource::BranchGe);
+    }
+
+    /*
+     * Does the branch go to its target, given the result of the alu?
+     */
+    pub fn branch_taken(&self, alu_res: u32) -> bool {
+        return match self.pc_src {
+            PcSource::BranchEq | PcSource::BranchGe => alu_res == 0,
+            PcSource::BranchNe | PcSource::BranchLt => alu_res != 0,
+            _ => false,
+        };
+    }
+
+    /*
+     * Does the trap instruction trap, given the result of the alu?
+     */
+    pub fn trap_taken(&self, alu_res: u32) -> bool {
+        return match self.trap {
+            Trap::Eq | Trap::Ge => alu_res == 0,
+            Trap::Ne | Trap::Lt => alu_res != 0,
+            Trap::None => false,
+        };
+    }
+
+    /*
+     * mfhi or mflo, which write back HI or LO instead of the alu result.
+     */
+    pub fn hilo_read(&self) -> bool {
+        return matches!(self.hilo, HiLo::Mfhi | HiLo::Mflo);
+    }
+
+    pub fn jump(&self) -> bool {
+        return matches!(self.pc_src, PcSource::Jump | PcSource::JumpReg);
+    }
+
+    /*
+     * Bytes accessed in data memory, 0 without an access. The part accesses
+     * read (and write back) the whole aligned word.
+     */
+    pub fn mem_size(&self) -> u32 {
+        return match self.mem {
+            MemAccess::None => 0,
+            MemAccess::LoadPart(_) | MemAccess::StorePart(_) => 4,
+            MemAccess::Load { width, .. } | MemAccess::Store(width) => match width {
+                Width::Byte => 1,
+                Width::Half => 2,
+                Width::Word => 4,
+            },
+        };
+    }
+}
+
+pub struct Control {
+    pub op: Op,
+    pub ctrl: ControlBits,
+}
+
+/*
+ * Row of the table decoding the instruction, None for a reserved
+ * instruction.
+ */
+pub fn decode(instr: &Instruction) -> Option<&'static Control> {
+    let op = instruction::decode(instr)?.op;
+    return CONTROL_TABLE.iter().find(|row| row.op == op);
+}
+
+// does nothing, the pc moves on
+pub const NOP: ControlBits = ControlBits {
+    alu_op: AluOp::Add,
+    alu_src: AluSrc::Rt,
+    alu_pc: false,
+    alu_shamt: false,
+    trap_overflow: false,
+    trap: Trap::None,
+    hilo: HiLo::None,
+    mem: MemAccess::None,
+    reg_dst: RegDst::None,
+    cond_write: CondWrite::Always,
+    insert: false,
+    pc_src: PcSource::Next,
+    link: false,
+    cop0: false,
+};
+
+// rd = rs op rt
+const REG: ControlBits = ControlBits { reg_dst: RegDst::Rd, ..NOP };
+
+// rt = rs op imm
+const IMM: ControlBits = ControlBits { alu_src: AluSrc::SignImm, reg_dst: RegDst::Rt, ..NOP };
+
+// address rs + imm
+const LOAD: ControlBits = ControlBits { mem: MemAccess::Load { width: Width::Word, signed: true }, ..IMM };
+const STORE: ControlBits = ControlBits { mem: MemAccess::Store(Width::Word), reg_dst: RegDst::None, ..IMM };
+
+const COP0: ControlBits = ControlBits { cop0: true, ..NOP };
+
+// hi/lo (+-)= rs * rt, the alu puts out the low word of the product
+const ACC: ControlBits = ControlBits { alu_op: AluOp::Mul, ..NOP };
+
+// rd = rs if rt is (not) zero
+const MOVE: ControlBits = ControlBits { reg_dst: RegDst::Rd, ..NOP };
+
+// trap if rs compares with rt or imm
+const TRAP: ControlBits = ControlBits { alu_op: AluOp::Sub, ..NOP };
+const TRAP_IMM: ControlBits = ControlBits { alu_src: AluSrc::SignImm, ..TRAP };
+
+pub static CONTROL_TABLE: [Control; 81] = [
+    // rt shifted by shamt or rs, sll $0, $0, 0 is the nop
+    Control { op: Op::Sll, ctrl: ControlBits { alu_op: AluOp::SllRt, alu_shamt: true, ..REG } },
+    Control { op: Op::Srl, ctrl: ControlBits { alu_op: AluOp::SrlRt, alu_shamt: true, ..REG } },
+    Control { op: Op::Sra, ctrl: ControlBits { alu_op: AluOp::SraRt, alu_shamt: true, ..REG } },
+    Control { op: Op::Sllv, ctrl: ControlBits { alu_op: AluOp::SllRt, ..REG } },
+    Control { op: Op::Srlv, ctrl: ControlBits { alu_op: AluOp::SrlRt, ..REG } },
+    Control { op: Op::Srav, ctrl: ControlBits { alu_op: AluOp::SraRt, ..REG } },
+    Control { op: Op::Jr, ctrl: ControlBits { pc_src: PcSource::JumpReg, ..NOP } },
+    Control { op: Op::Jalr, ctrl: ControlBits { pc_src: PcSource::JumpReg, link: true, ..REG } },
+    Control { op: Op::Mfhi, ctrl: ControlBits { hilo: HiLo::Mfhi, ..REG } },
+    Control { op: Op::Mthi, ctrl: ControlBits { hilo: HiLo::Mthi, ..NOP } },
+    Control { op: Op::Mflo, ctrl: ControlBits { hilo: HiLo::Mflo, ..REG } },
+    Control { op: Op::Mtlo, ctrl: ControlBits { hilo: HiLo::Mtlo, ..NOP } },
+    Control { op: Op::Mult, ctrl: ControlBits { hilo: HiLo::Mult, ..ACC } },
+    Control { op: Op::Multu, ctrl: ControlBits { hilo: HiLo::Multu, ..ACC } },
+    Control { op: Op::Add, ctrl: ControlBits { trap_overflow: true, ..REG } },
+    Control { op: Op::Addu, ctrl: REG },
+    Control { op: Op::Sub, ctrl: ControlBits { alu_op: AluOp::Sub, trap_overflow: true, ..REG } },
+    Control { op: Op::Subu, ctrl: ControlBits { alu_op: AluOp::Sub, ..REG } },
+    Control { op: Op::And, ctrl: ControlBits { alu_op: AluOp::And, ..REG } },
+    Control { op: Op::Or, ctrl: ControlBits { alu_op: AluOp::Or, ..REG } },
+    Control { op: Op::Xor, ctrl: ControlBits { alu_op: AluOp::Xor, ..REG } },
+    Control { op: Op::Nor, ctrl: ControlBits { alu_op: AluOp::Nor, ..REG } },
+    Control { op: Op::Slt, ctrl: ControlBits { alu_op: AluOp::Slt, ..REG } },
+    Control { op: Op::Sltu, ctrl: ControlBits { alu_op: AluOp::Sltu, ..REG } },
+    Control { op: Op::Sync, ctrl: NOP },
+    Control { op: Op::Movz, ctrl: ControlBits { cond_write: CondWrite::RtZero, ..MOVE } },
+    Control { op: Op::Movn, ctrl: ControlBits { cond_write: CondWrite::RtNotZero, ..MOVE } },
+    Control { op: Op::Tge, ctrl: ControlBits { alu_op: AluOp::Slt, trap: Trap::Ge, ..TRAP } },
+    Control { op: Op::Tgeu, ctrl: ControlBits { alu_op: AluOp::Sltu, trap: Trap::Ge, ..TRAP } },
+    Control { op: Op::Tlt, ctrl: ControlBits { alu_op: AluOp::Slt, trap: Trap::Lt, ..TRAP } },
+    Control { op: Op::Tltu, ctrl: ControlBits { alu_op: AluOp::Sltu, trap: Trap::Lt, ..TRAP } },
+    Control { op: Op::Teq, ctrl: ControlBits { trap: Trap::Eq, ..TRAP } },
+    Control { op: Op::Tne, ctrl: ControlBits { trap: Trap::Ne, ..TRAP } },
+    // rt rotated by shamt or rs
+    Control { op: Op::Rotr, ctrl: ControlBits { alu_op: AluOp::Rotr, alu_shamt: true, ..REG } },
+    Control { op: Op::Rotrv, ctrl: ControlBits { alu_op: AluOp::Rotr, ..REG } },
+    Control { op: Op::Tgei, ctrl: ControlBits { alu_op: AluOp::Slt, trap: Trap::Ge, ..TRAP_IMM } },
+    Control { op: Op::Tgeiu, ctrl: ControlBits { alu_op: AluOp::Sltu, trap: Trap::Ge, ..TRAP_IMM } },
+    Control { op: Op::Tlti, ctrl: ControlBits { alu_op: AluOp::Slt, trap: Trap::Lt, ..TRAP_IMM } },
+    Control { op: Op::Tltiu, ctrl: ControlBits { alu_op: AluOp::Sltu, trap: Trap::Lt, ..TRAP_IMM } },
+    Control { op: Op::Teqi, ctrl: ControlBits { trap: Trap::Eq, ..TRAP_IMM } },
+    Control { op: Op::Tnei, ctrl: ControlBits { trap: Trap::Ne, ..TRAP_IMM } },
+    Control { op: Op::Madd, ctrl: ControlBits { hilo: HiLo::Madd, ..ACC } },
+    Control { op: Op::Maddu, ctrl: ControlBits { hilo: HiLo::Maddu, ..ACC } },
+    Control { op: Op::Msub, ctrl: ControlBits { hilo: HiLo::Msub, ..ACC } },
+    Control { op: Op::Msubu, ctrl: ControlBits { hilo: HiLo::Msubu, ..ACC } },
+    Control { op: Op::Mul, ctrl: ControlBits { alu_op: AluOp::Mul, ..REG } },
+    Control { op: Op::Clz, ctrl: ControlBits { alu_op: AluOp::Clz, ..REG } },
+    Control { op: Op::Clo, ctrl: ControlBits { alu_op: AluOp::Clo, ..REG } },
+    // rs is $zero in the encoding
+    Control { op: Op::Seb, ctrl: ControlBits { alu_op: AluOp::Seb, ..REG } },
+    Control { op: Op::Seh, ctrl: ControlBits { alu_op: AluOp::Seh, ..REG } },
+    Control { op: Op::Wsbh, ctrl: ControlBits { alu_op: AluOp::Wsbh, ..REG } },
+    Control { op: Op::Ext, ctrl: ControlBits { alu_op: AluOp::Ext, alu_src: AluSrc::Field, ..IMM } },
+    Control { op: Op::Ins, ctrl: ControlBits { alu_op: AluOp::Ins, alu_src: AluSrc::Field, insert: true, ..IMM } },
+
+    Control { op: Op::J, ctrl: ControlBits { pc_src: PcSource::Jump, ..NOP } },
+    Control { op: Op::Jal, ctrl: ControlBits { pc_src: PcSource::Jump, reg_dst: RegDst::Ra, link: true, ..NOP } },
+    Control { op: Op::Beq, ctrl: ControlBits { alu_op: AluOp::Sub, pc_src: PcSource::BranchEq, ..NOP } },
+    Control { op: Op::Bne, ctrl: ControlBits { alu_op: AluOp::Sub, pc_src: PcSource::BranchNe, ..NOP } },
+    Control { op: Op::Addi, ctrl: ControlBits { trap_overflow: true, ..IMM } },
+    Control { op: Op::Addiu, ctrl: IMM },
+    Control { op: Op::Slti, ctrl: ControlBits { alu_op: AluOp::Slt, ..IMM } },
+    Control { op: Op::Sltiu, ctrl: ControlBits { alu_op: AluOp::Sltu, ..IMM } },
+    Control { op: Op::Andi, ctrl: ControlBits { alu_op: AluOp::And, alu_src: AluSrc::ZeroImm, ..IMM } },
+    Control { op: Op::Ori, ctrl: ControlBits { alu_op: AluOp::Or, alu_src: AluSrc::ZeroImm, ..IMM } },
+    Control { op: Op::Xori, ctrl: ControlBits { alu_op: AluOp::Xor, alu_src: AluSrc::ZeroImm, ..IMM } },
+    // rs is $zero in the encoding
+    Control { op: Op::Lui, ctrl: ControlBits { alu_op: AluOp::Or, alu_src: AluSrc::UpperImm, ..IMM } },
+
+    Control { op: Op::Mfc0, ctrl: ControlBits { reg_dst: RegDst::Rt, ..COP0 } },
+    Control { op: Op::Mtc0, ctrl: COP0 },
+    Control { op: Op::Tlbr, ctrl: COP0 },
+    Control { op: Op::Tlbwi, ctrl: COP0 },
+    Control { op: Op::Tlbwr, ctrl: COP0 },
+    Control { op: Op::Tlbp, ctrl: COP0 },
+    Control { op: Op::Rfe, ctrl: COP0 },
+
+    Control { op: Op::Lb, ctrl: ControlBits { mem: MemAccess::Load { width: Width::Byte, signed: true }, ..LOAD } },
+    Control { op: Op::Lw, ctrl: LOAD },
+    Control { op: Op::Lbu, ctrl: ControlBits { mem: MemAccess::Load { width: Width::Byte, signed: false }, ..LOAD } },
+    Control { op: Op::Sb, ctrl: ControlBits { mem: MemAccess::Store(Width::Byte), ..STORE } },
+    Control { op: Op::Sw, ctrl: STORE },
+    Control { op: Op::Lwl, ctrl: ControlBits { mem: MemAccess::LoadPart(Part::Left), ..LOAD } },
+    Control { op: Op::Lwr, ctrl: ControlBits { mem: MemAccess::LoadPart(Part::Right), ..LOAD } },
+    Control { op: Op::Swl, ctrl: ControlBits { mem: MemAccess::StorePart(Part::Left), ..STORE } },
+    Control { op: Op::Swr, ctrl: ControlBits { mem: MemAccess::StorePart(Part::Right), ..STORE } },
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::phases::instr_decode;
+    use crate::demos::assembler::instr_macros::*;
+    use crate::demos::assembler::reg_macros::*;
+
+    fn control(word: u32) -> Option<&'static Control> {
+        let mut instr = Instruction::default();
+        instr_decode(word, &mut instr);
+        return decode(&instr);
+    }
+
+    #[test]
+    fn test_decode() {
+        let slti = control(SLTI(T0(), T1(), 5)).unwrap();
+        assert_eq!(slti.op, Op::Slti);
+        assert_eq!(slti.ctrl.reg_dst, RegDst::Rt);
+        assert_eq!(slti.ctrl.alu_src, AluSrc::SignImm);
+        assert_eq!(control(SLTIU(T0(), T1(), 5)).unwrap().ctrl.alu_op, AluOp::Sltu);
+        assert_eq!(control(SLTU(T0(), T1(), T2())).unwrap().ctrl.alu_op, AluOp::Sltu);
+
+        let lw = control(LW(T0(), 4, SP())).unwrap().ctrl;
+        assert!(lw.mem_read() && lw.reg_write() && !lw.mem_write());
+        assert_eq!(lw.mem_size(), 4);
+        let sb = control(SB(T0(), 4, SP())).unwrap().ctrl;
+        assert!(sb.mem_write() && !sb.reg_write());
+        assert_eq!(sb.mem_size(), 1);
+        let lwl = control(LWL(T0(), 1, SP())).unwrap().ctrl;
+        assert!(lwl.mem_read() && lwl.mem_part() && lwl.reg_write());
+        assert_eq!(lwl.mem_size(), 4);
+        assert!(control(SWR(T0(), 1, SP())).unwrap().ctrl.mem_write());
+
+        assert_eq!(control(MFC0(T0(), 12)).unwrap().ctrl.reg_dst, RegDst::Rt);
+        assert_eq!(control(MTC0(T0(), 12)).unwrap().op, Op::Mtc0);
+        assert_eq!(control(RFE()).unwrap().op, Op::Rfe);
+        assert!(control(J(0x40)).unwrap().ctrl.jump());
+        let jal = control(JAL(0x40)).unwrap().ctrl;
+        assert!(jal.jump() && jal.link && jal.reg_dst == RegDst::Ra);
+        let jalr = control(JALR(RA(), T0())).unwrap().ctrl;
+        assert_eq!((jalr.pc_src, jalr.reg_dst, jalr.link), (PcSource::JumpReg, RegDst::Rd, true));
+        assert!(control(BNE(T0(), T1(), 0x40)).unwrap().ctrl.branch());
+
+        assert_eq!(control(MUL(T0(), T1(), T2())).unwrap().ctrl.alu_op, AluOp::Mul);
+        assert_eq!(control(SEH(T0(), T1())).unwrap().ctrl.alu_op, AluOp::Seh);
+        assert_eq!(control(MSUBU(T0(), T1())).unwrap().ctrl.hilo, HiLo::Msubu);
+        let mult = control(MULT(T0(), T1())).unwrap().ctrl;
+        assert!(mult.hilo == HiLo::Mult && !mult.reg_write());
+        let mfhi = control(MFHI(T0())).unwrap().ctrl;
+        assert!(mfhi.hilo_read() && mfhi.reg_dst == RegDst::Rd);
+        let mtlo = control(MTLO(T0())).unwrap().ctrl;
+        assert!(mtlo.hilo == HiLo::Mtlo && !mtlo.reg_write() && !mtlo.hilo_read());
+        let movn = control(MOVN(T0(), T1(), T2())).unwrap().ctrl;
+        assert!(movn.write_taken(1) && !movn.write_taken(0));
+        assert_eq!(control(EXT(T0(), T1(), 0, 8)).unwrap().ctrl.alu_src, AluSrc::Field);
+        assert!(control(INS(T0(), T1(), 0, 8)).unwrap().ctrl.insert);
+        assert!(control(ROTR(T0(), T1(), 8)).unwrap().ctrl.alu_shamt);
+        assert!(!control(ROTRV(T0(), T1(), T2())).unwrap().ctrl.alu_shamt);
+        let srl = control(SRL(T0(), T0(), 1)).unwrap().ctrl;
+        assert!(srl.alu_op == AluOp::SrlRt && srl.alu_shamt && srl.reg_dst == RegDst::Rd);
+        let srav = control(SRAV(T0(), T1(), T2())).unwrap().ctrl;
+        assert!(srav.alu_op == AluOp::SraRt && !srav.alu_shamt);
+        // the nop is sll $0, $0, 0, the write to $0 is dropped
+        assert_eq!(control(0).unwrap().ctrl.alu_op, AluOp::SllRt);
+        let tltiu = control(TLTIU(T0(), 8)).unwrap().ctrl;
+        assert_eq!((tltiu.alu_op, tltiu.alu_src, tltiu.trap), (AluOp::Sltu, AluSrc::SignImm, Trap::Lt));
+        assert!(!tltiu.reg_write());
+
+        // div, an unknown cop0 function and opcode 0x3f are reserved
+        assert!(control(DIV(T0(), T1())).is_none());
+        assert!(control(0x4200_0020).is_none());
+        assert!(control(0xfc00_0000).is_none());
+    }
+
+    #[test]
+    fn test_branch_taken() {
+        let branch = |pc_src| ControlBits { pc_src, ..NOP };
+        assert!(branch(PcSource::BranchEq).branch_taken(0));
+        assert!(!branch(PcSource::BranchNe).branch_taken(0));
+        assert!(branch(PcSource::BranchLt).branch_taken(1));
+        assert!(!branch(PcSource::BranchGe).branch_taken(1));
+        assert!(!branch(PcSource::Next).branch_taken(0));
+    }
+
+    #[test]
+    fn test_trap_taken() {
+        let trap = |trap| ControlBits { trap, ..NOP };
+        assert!(trap(Trap::Eq).trap_taken(0));
+        assert!(!trap(Trap::Ne).trap_taken(0));
+        assert!(trap(Trap::Lt).trap_taken(1));
+        assert!(!trap(Trap::Ge).trap_taken(1));
+        assert!(!trap(Trap::None).trap_taken(0));
+    }
+
+    #[test]
+    fn test_table() {
+        // every instruction decodes to its own row
+        for (i, row) in CONTROL_TABLE.iter().enumerate() {
+            let def = instruction::def(row.op);
+            let word = instruction::encode(row.op, &vec![0; def.operands.len()]);
+            assert!(std::ptr::eq(control(word).unwrap(), &CONTROL_TABLE[i]), "{}", def.mnemonic);
+            // only the add instructions and sub trap on overflow, and memory is addressed by rs + imm
+            if row.ctrl.trap_overflow {
+                assert!(matches!(row.ctrl.alu_op, AluOp::Add | AluOp::Sub), "{}", def.mnemonic);
+            }
+            // the traps compare like the branches and write nothing
+            if row.ctrl.trap != Trap::None {
+                assert!(matches!(row.ctrl.alu_op, AluOp::Sub | AluOp::Slt | AluOp::Sltu), "{}", def.mnemonic);
+                assert!(!row.ctrl.reg_write(), "{}", def.mnemonic);
+            }
+            if row.ctrl.mem != MemAccess::None {
+                assert_eq!((row.ctrl.alu_op, row.ctrl.alu_src), (AluOp::Add, AluSrc::SignImm), "{}", def.mnemonic);
+            }
         }
     }
-}
\ No newline at end of file
+}
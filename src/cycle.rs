@@ -0,0 +1,168 @@
+/*
+ * cycle.rs
+ *
+ * Author: Travis Banken
+ *
+ * Record of everything that happened in one cycle of the processor (the
+ * signals of the datapath and the state they changed). Observers attached to
+ * the processor get one after every cycle, the trace, waveform and profiling
+ * tools are built on them.
+ */
+#![allow(dead_code)]
+
+use crate::control_bits::{AluSrc, ControlBits};
+use crate::hardware::cp0::ExcCode;
+use crate::instruction::Instruction;
+use crate::isa::Isa;
+
+/*
+ * Value written into the register file.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RegWrite {
+    pub reg: u8,
+    pub old: u32,
+    pub new: u32,
+}
+
+/*
+ * Load or store on the data bus.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemAccess {
+    // virtual address used by the program
+    pub addr: u32,
+    // address on the bus after translation
+    pub paddr: u32,
+    // 1, 2 or 4 bytes
+    pub size: u32,
+    pub is_write: bool,
+    // memory before a store, devices and loads leave it equal to value
+    pub old: u32,
+    // value loaded or stored
+    pub value: u32,
+}
+
+/*
+ * Instruction classes, used to filter and summarise.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Class {
+    // register-register alu
+    Alu,
+    // alu with an immediate
+    AluImm,
+    Load,
+    Store,
+    Branch,
+    Jump,
+    // coprocessor 0
+    System,
+}
+
+pub const CLASSES: [Class; 7] = [
+    Class::Alu, Class::AluImm, Class::Load, Class::Store, Class::Branch, Class::Jump, Class::System,
+];
+
+impl Class {
+    pub fn parse(name: &str) -> Result<Class, String> {
+        match name {
+            "alu" => Ok(Class::Alu),
+            "imm" => Ok(Class::AluImm),
+            "load" => Ok(Class::Load),
+            "store" => Ok(Class::Store),
+            "branch" => Ok(Class::Branch),
+            "jump" => Ok(Class::Jump),
+            "system" => Ok(Class::System),
+            _ => Err(format!("Invalid instruction class [{}], use alu, imm, load, store, branch, jump or system",
+                             name)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Class::Alu => "alu",
+            Class::AluImm => "imm",
+            Class::Load => "load",
+            Class::Store => "store",
+            Class::Branch => "branch",
+            Class::Jump => "jump",
+            Class::System => "system",
+        }
+    }
+
+    /*
+     * Class of an instruction from its control bits.
+     */
+    pub fn of(ctrl: &ControlBits) -> Class {
+        if ctrl.mem_read() {
+            Class::Load
+        } else if ctrl.mem_write() {
+            Class::Store
+        } else if ctrl.branch() {
+            Class::Branch
+        } else if ctrl.cop0 {
+            // mret jumps too
+            Class::System
+        } else if ctrl.jump() {
+            Class::Jump
+        } else if ctrl.alu_src == AluSrc::Rt {
+            Class::Alu
+        } else {
+            Class::AluImm
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Cycle {
+    // value of the cycle counter when the instruction started
+    pub cycle: u64,
+    pub pc: u32,
+    pub word: u32,
+    // the instruction set the word is from, instr holds RISC-V fields in
+    // the MIPS layout (rs1 as rs, rs2 as rt)
+    pub isa: Isa,
+    pub instr: Instruction,
+    pub ctrl: ControlBits,
+
+    pub alu_in1: u32,
+    pub alu_in2: u32,
+    pub alu_res: u32,
+    pub alu_zero: u32,
+
+    // registers the instruction read, with their values
+    pub reads: Vec<(u8, u32)>,
+    pub write: Option<RegWrite>,
+    pub mem: Option<MemAccess>,
+    // Some for branches, true if taken
+    pub branch: Option<bool>,
+
+    pub next_pc: u32,
+    // set when the cycle took an exception instead of retiring the
+    // instruction (the fields after the exception are left at 0)
+    pub exception: Option<ExcCode>,
+}
+
+impl Cycle {
+    /*
+     * Did the instruction complete?
+     */
+    pub fn retired(&self) -> bool {
+        self.exception.is_none()
+    }
+
+    pub fn class(&self) -> Class {
+        Class::of(&self.ctrl)
+    }
+}
+
+/*
+ * Watches the processor, called after every cycle.
+ */
+pub trait Observer {
+    fn cycle(&mut self, cycle: &Cycle);
+
+    // the run is over, flush output and print reports
+    fn finish(&mut self) {}
+}
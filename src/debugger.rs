@@ -0,0 +1,939 @@
+/*
+ * debugger.rs
+ *
+ * Author: Travis Banken
+ *
+ * Interactive debugger. Drives the processor one cycle (instr_fetch through
+ * write_back) at a time and lets the user look at and change its state in
+ * between.
+ *
+ * Commands:
+ *  s, step [n]                 run n instructions (default 1)
+ *  c, continue                 run until a breakpoint or the program ends
+ *  finish                      run until the current function returns (jr $ra)
+ *  rs, reverse-step [n]        undo n instructions (default 1)
+ *  rc, reverse-continue        run backwards to a breakpoint, a watch or the
+ *                              start of the history
+ *  goto <n>                    go back to where n instructions had run
+ *  b, break <loc>              break when the pc reaches loc
+ *  d, delete [n]               delete breakpoint n, or every breakpoint
+ *  i, info registers [reg]     print the registers (ABI names) and the pc
+ *  i, info breakpoints         list the breakpoints
+ *  watch <loc> [len] [log]     stop (or log) when memory is written
+ *  rwatch/awatch <loc> [len]   ... read, or read and written
+ *  watch $reg [log]            stop (or log) when the register changes
+ *  unwatch [n]                 delete watch n, or every watch
+ *  i, info watchpoints         list the watches
+ *  i, info history             how far back the history reaches
+ *  x[/<n><b|h|w|s>] <loc>      examine memory as bytes, halfs, words or a
+ *                              string
+ *  disas [n]                   disassemble n instructions around the pc
+ *  set <reg|pc> [=] <value>    change a register
+ *  set <b|h|w> <loc> [=] <val> change memory
+ *  save <file>                 save the machine state
+ *  load <file>                 restore a state saved from the same program
+ *  q, quit                     leave the debugger
+ *
+ * A location is a number (0x.. for hex), a label or a register ($t0).
+ * An empty line repeats the last command. Changing registers or memory by
+ * hand starts the history over.
+ */
+#![allow(dead_code)]
+
+use crate::hardware::*;
+use crate::history::{History, HistoryConfig};
+use crate::single_cycle::Processor;
+use crate::snapshot::Snapshot;
+use crate::tools::symbols::SymbolTable;
+use crate::watch;
+use std::io::{self, BufRead, Write};
+
+/*
+ * Why the processor stopped running.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stop {
+    // finished the requested number of steps
+    Step,
+    Breakpoint(u32),
+    // the function returned (finish)
+    Returned,
+    // a watch asked to stop
+    Watchpoint(watch::Hit),
+    // the program ran off the end of instruction memory
+    Exited,
+    // ran backwards into the start of the history
+    HistoryStart,
+}
+
+pub struct Debugger<'a, 'b> {
+    cpu: &'a mut Processor,
+    instr_mem: &'a instr_mem::Memory,
+    bus: &'a mut bus::Bus<'b>,
+    symbols: &'a SymbolTable,
+    breakpoints: Vec<u32>,
+    history: History,
+    last_cmd: String,
+    done: bool,
+}
+
+impl<'a, 'b> Debugger<'a, 'b> {
+    pub fn new(cpu: &'a mut Processor, instr_mem: &'a instr_mem::Memory,
+               bus: &'a mut bus::Bus<'b>, symbols: &'a SymbolTable) -> Debugger<'a, 'b> {
+        Debugger {
+            cpu,
+            instr_mem,
+            bus,
+            symbols,
+            breakpoints: Vec::new(),
+            history: History::new(HistoryConfig::default()),
+            last_cmd: String::new(),
+            done: false,
+        }
+    }
+
+    /*
+     * Reads commands from stdin until the user quits or stdin closes.
+     */
+    pub fn repl(&mut self) {
+        let stdin = io::stdin();
+        let mut lines = stdin.lock().lines();
+        println!("{}", self.location(self.cpu.ip));
+        while !self.done {
+            print!("(rvp) ");
+            let _ = io::stdout().flush();
+            let line = match lines.next() {
+                Some(Ok(line)) => line,
+                _ => break,
+            };
+            match self.command(&line) {
+                Ok(out) => print!("{}", out),
+                Err(msg) => println!("{}", msg),
+            }
+        }
+    }
+
+    /*
+     * Runs one command line, returns what it printed.
+     */
+    pub fn command(&mut self, line: &str) -> Result<String, String> {
+        let line = if line.trim().is_empty() {self.last_cmd.clone()} else {line.trim().to_string()};
+        self.last_cmd = line.clone();
+
+        let mut words = line.split_whitespace();
+        let cmd = match words.next() {
+            Some(cmd) => cmd,
+            None => return Ok(String::new()),
+        };
+        let args: Vec<&str> = words.collect();
+
+        match cmd {
+            "s" | "step" => {
+                let n = match args.first() {
+                    Some(n) => parse_num(n)?,
+                    None => 1,
+                };
+                let stop = self.step(n as u64);
+                return Ok(self.report(stop));
+            },
+            "c" | "continue" => {
+                let stop = self.cont();
+                return Ok(self.report(stop));
+            },
+            "finish" => {
+                let stop = self.finish();
+                return Ok(self.report(stop));
+            },
+            "rs" | "reverse-step" => {
+                let n = match args.first() {
+                    Some(n) => parse_num(n)?,
+                    None => 1,
+                };
+                let stop = self.reverse_step(n as u64);
+                return Ok(self.report(stop));
+            },
+            "rc" | "reverse-continue" => {
+                let stop = self.reverse_cont();
+                return Ok(self.report(stop));
+            },
+            "goto" => {
+                let n = args.first().ok_or("Error: goto needs an instruction number")?;
+                self.goto(parse_num(n)? as u64)?;
+                return Ok(self.report(Stop::Step));
+            },
+            "b" | "break" => {
+                let loc = args.first().ok_or("Error: break needs a location")?;
+                let addr = self.parse_loc(loc)?;
+                self.add_breakpoint(addr);
+                return Ok(format!("Breakpoint {} at {}\n", self.breakpoints.len(), self.describe(addr)));
+            },
+            "d" | "delete" => {
+                match args.first() {
+                    Some(n) => {
+                        let n = parse_num(n)? as usize;
+                        if n == 0 || n > self.breakpoints.len() {
+                            return Err(format!("Error: no breakpoint number {}", n));
+                        }
+                        self.breakpoints.remove(n - 1);
+                    },
+                    None => self.breakpoints.clear(),
+                }
+                return Ok(String::new());
+            },
+            "watch" | "rwatch" | "awatch" => return self.watch(cmd, &args),
+            "unwatch" => {
+                match args.first() {
+                    Some(n) => {
+                        let n = parse_num(n)? as usize;
+                        if n == 0 || !self.cpu.watches.remove(n - 1) {
+                            return Err(format!("Error: no watch number {}", n));
+                        }
+                    },
+                    None => self.cpu.watches.clear(),
+                }
+                return Ok(String::new());
+            },
+            "i" | "info" => match args.first() {
+                Some(&"r") | Some(&"registers") => return self.info_registers(args.get(1)),
+                Some(&"b") | Some(&"breakpoints") => return Ok(self.info_breakpoints()),
+                Some(&"w") | Some(&"watchpoints") => return Ok(self.info_watchpoints()),
+                Some(&"h") | Some(&"history") => return Ok(self.info_history()),
+                _ => return Err("Error: info registers|breakpoints|watchpoints|history".to_string()),
+            },
+            "disas" | "disassemble" => {
+                let n = match args.first() {
+                    Some(n) => parse_num(n)?,
+                    None => 4,
+                };
+                return Ok(self.disassemble_around(n));
+            },
+            "set" => return self.set(&args),
+            "save" => {
+                let path = args.first().ok_or("Error: save needs a file name")?;
+                Snapshot::capture(self.cpu, self.instr_mem, self.bus).save(path)?;
+                return Ok(format!("Saved state at instruction {} to {}\n", self.cpu.instr_count, path));
+            },
+            "load" => {
+                let path = args.first().ok_or("Error: load needs a file name")?;
+                let snap = Snapshot::load(path)?;
+                if snap.instr_mem != self.instr_mem.bytes() {
+                    return Err(format!("Error: {} was saved from a different program", path));
+                }
+                snap.restore(self.cpu, self.bus)?;
+                self.history.clear();
+                return Ok(format!("Loaded state at instruction {}\n{}\n", self.cpu.instr_count,
+                                  self.location(self.cpu.ip)));
+            },
+            "q" | "quit" => {
+                self.done = true;
+                return Ok(String::new());
+            },
+            "h" | "help" => return Ok(help()),
+            _ => {},
+        }
+
+        if cmd == "x" || cmd.starts_with("x/") {
+            let loc = args.first().ok_or("Error: x needs a location")?;
+            let addr = self.parse_loc(loc)?;
+            return self.examine(cmd.strip_prefix("x/").unwrap_or(""), addr);
+        }
+        return Err(format!("Error: unknown command [{}], try help", cmd));
+    }
+
+    /*
+     * True once the user has quit.
+     */
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u32) {
+        if !self.breakpoints.contains(&addr) {
+            self.breakpoints.push(addr);
+        }
+    }
+
+    pub fn breakpoints(&self) -> &[u32] {
+        &self.breakpoints
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u32) {
+        self.breakpoints.retain(|b| *b != addr);
+    }
+
+    pub fn cpu(&self) -> &Processor {
+        self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Processor {
+        self.cpu
+    }
+
+    /*
+     * Replaces the history (and forgets the one recorded so far).
+     */
+    pub fn set_history(&mut self, config: HistoryConfig) {
+        self.history = History::new(config);
+    }
+
+    /*
+     * Forgets the history, call after changing the machine by hand since
+     * replaying from an older snapshot would lose the change.
+     */
+    pub fn clear_history(&mut self) {
+        self.history.clear();
+    }
+
+    /*
+     * Reads a big endian value from data memory, size is 1, 2 or 4.
+     */
+    pub fn read_mem(&self, vaddr: u32, size: u32) -> Result<u32, String> {
+        let addr = self.data_addr(vaddr, size)?;
+        let mut val = 0;
+        for i in 0..size as usize {
+            val = (val << 8) | self.bus.mem().read(addr + i) as u32;
+        }
+        return Ok(val);
+    }
+
+    /*
+     * Writes a big endian value into data memory.
+     */
+    pub fn write_mem(&mut self, vaddr: u32, size: u32, value: u32) -> Result<(), String> {
+        let addr = self.data_addr(vaddr, size)?;
+        self.history.clear();
+        for i in 0..size as usize {
+            let shift = 8 * (size as usize - 1 - i);
+            self.bus.mem_mut().write((value >> shift) as u8, addr + i);
+        }
+        return Ok(());
+    }
+
+    /*
+     * Reads a big endian value from instruction memory.
+     */
+    pub fn read_code(&self, vaddr: u32, size: u32) -> Result<u32, String> {
+        let err = format!("Cannot access instruction at 0x{:08x}", vaddr);
+        let addr = self.cpu.mmu.translate(&self.cpu.cp0, vaddr, mmu::AccessKind::Fetch)
+            .map_err(|_| err.clone())?;
+        if addr as u64 + size as u64 > instr_mem::Memory::get_size() as u64 {
+            return Err(err);
+        }
+        let mut val = 0;
+        for i in 0..size as usize {
+            val = (val << 8) | self.instr_mem.read(addr as usize + i) as u32;
+        }
+        return Ok(val);
+    }
+
+    /*
+     * Instruction word at vaddr, in the byte order of the isa.
+     */
+    pub fn read_instr(&self, vaddr: u32) -> Result<u32, String> {
+        return self.read_code(vaddr, 4).map(|word| self.cpu.isa.byte_order(word));
+    }
+
+    /*
+     * Runs n instructions, stops early at a breakpoint.
+     */
+    pub fn step(&mut self, n: u64) -> Stop {
+        for _ in 0..n {
+            if !self.cpu.is_running() {
+                return Stop::Exited;
+            }
+            self.history.step(self.cpu, self.instr_mem, self.bus);
+            if let Some(stop) = self.check_stop() {
+                return stop;
+            }
+        }
+        return Stop::Step;
+    }
+
+    /*
+     * Runs until a breakpoint is reached or the program ends.
+     */
+    pub fn cont(&mut self) -> Stop {
+        loop {
+            if !self.cpu.is_running() {
+                return Stop::Exited;
+            }
+            self.history.step(self.cpu, self.instr_mem, self.bus);
+            if let Some(stop) = self.check_stop() {
+                return stop;
+            }
+        }
+    }
+
+    /*
+     * Runs until the current function returns with jr $ra (jalr zero, 0(ra)
+     * on RISC-V). Calls made on the way (jal/jalr) are run to their own
+     * return first.
+     */
+    pub fn finish(&mut self) -> Stop {
+        let mut depth = 0;
+        loop {
+            if !self.cpu.is_running() {
+                return Stop::Exited;
+            }
+            let pc = self.cpu.ip;
+            let word = self.read_instr(pc).unwrap_or(0);
+            self.history.step(self.cpu, self.instr_mem, self.bus);
+
+            let flow = self.cpu.isa.flow(word, pc);
+            if flow.call {
+                depth += 1;
+            } else if flow.ret {
+                if depth == 0 {
+                    return Stop::Returned;
+                }
+                depth -= 1;
+            }
+            if let Some(stop) = self.check_stop() {
+                return stop;
+            }
+        }
+    }
+
+    /*
+     * Undoes n instructions, stops early at a breakpoint or a watch the
+     * undone instruction triggers.
+     */
+    pub fn reverse_step(&mut self, n: u64) -> Stop {
+        for _ in 0..n {
+            if let Some(stop) = self.step_back() {
+                return stop;
+            }
+        }
+        return Stop::Step;
+    }
+
+    /*
+     * Runs backwards until a breakpoint, a watch or the start of the
+     * history.
+     */
+    pub fn reverse_cont(&mut self) -> Stop {
+        loop {
+            if let Some(stop) = self.step_back() {
+                return stop;
+            }
+        }
+    }
+
+    /*
+     * Goes back to the point where n instructions had run.
+     */
+    pub fn goto(&mut self, n: u64) -> Result<(), String> {
+        return self.history.goto(self.cpu, self.instr_mem, self.bus, n);
+    }
+
+    // *** PRIVATE FN ***
+
+    fn step_back(&mut self) -> Option<Stop> {
+        let undo = match self.history.back(self.cpu, self.instr_mem, self.bus) {
+            Some(undo) => undo,
+            None => return Some(Stop::HistoryStart),
+        };
+        let watches = &mut self.cpu.watches;
+        if let Some(mem) = undo.access {
+            if watches.watches_mem(mem.addr, mem.size) {
+                watches.check_mem(undo.pc, mem.addr, mem.size, mem.is_write, mem.old, mem.value);
+            }
+        }
+        if let Some(write) = undo.write {
+            watches.check_undo_reg(undo.pc, write.reg, write.old, write.new);
+        }
+        if let Some(hit) = watches.take_stop() {
+            return Some(Stop::Watchpoint(hit));
+        }
+        if self.breakpoints.contains(&self.cpu.ip) {
+            return Some(Stop::Breakpoint(self.cpu.ip));
+        }
+        return None;
+    }
+
+    fn check_stop(&mut self) -> Option<Stop> {
+        if let Some(hit) = self.cpu.watches.take_stop() {
+            return Some(Stop::Watchpoint(hit));
+        }
+        if !self.cpu.is_running() {
+            return Some(Stop::Exited);
+        }
+        if self.breakpoints.contains(&self.cpu.ip) {
+            return Some(Stop::Breakpoint(self.cpu.ip));
+        }
+        return None;
+    }
+
+    fn report(&mut self, stop: Stop) -> String {
+        let mut out = String::new();
+        for hit in self.cpu.watches.take_log() {
+            if hit.action == watch::Action::Log {
+                out += &format!("Watch {}: {}\n", hit.watch + 1, hit);
+            }
+        }
+        out += &match stop {
+            Stop::Exited => {
+                format!("Program exited after {} instructions\n", self.cpu.instr_count)
+            },
+            Stop::Watchpoint(hit) => {
+                format!("Watch {}: {}\n{}\n", hit.watch + 1, hit, self.location(self.cpu.ip))
+            },
+            Stop::Breakpoint(addr) => {
+                let num = self.breakpoints.iter().position(|b| *b == addr).unwrap_or(0) + 1;
+                format!("Breakpoint {}, {}\n", num, self.location(addr))
+            },
+            Stop::HistoryStart => {
+                format!("No more history, at instruction {}\n{}\n", self.cpu.instr_count,
+                        self.location(self.cpu.ip))
+            },
+            Stop::Step | Stop::Returned => format!("{}\n", self.location(self.cpu.ip)),
+        };
+        return out;
+    }
+
+    /*
+     * "0x0000000c <loop>: slt $t0, $s0, $s7"
+     */
+    fn location(&self, addr: u32) -> String {
+        let text = match self.read_instr(addr) {
+            Ok(word) => self.cpu.isa.disassemble(word, addr),
+            Err(msg) => msg,
+        };
+        return format!("{}: {}", self.describe(addr), text);
+    }
+
+    fn describe(&self, addr: u32) -> String {
+        match self.symbols.describe(addr) {
+            Some(name) => format!("0x{:08x} <{}>", addr, name),
+            None => format!("0x{:08x}", addr),
+        }
+    }
+
+    fn info_registers(&self, reg: Option<&&str>) -> Result<String, String> {
+        if let Some(reg) = reg {
+            if *reg == "pc" || *reg == "$pc" {
+                return Ok(format!("pc   0x{:08x}\n", self.cpu.ip));
+            }
+            let num = self.cpu.isa.reg_num(reg).ok_or(format!("Error: unknown register [{}]", reg))?;
+            let val = self.cpu.regfile.load(num as usize);
+            return Ok(format!("{:<4} 0x{:08x}  {}\n", self.cpu.isa.reg_name(num), val, val as i32));
+        }
+        let mut out = String::new();
+        for num in 0..32 {
+            let val = self.cpu.regfile.load(num);
+            out += &format!("{:<4} 0x{:08x}", self.cpu.isa.reg_name(num as u8), val);
+            out += if num % 4 == 3 {"\n"} else {"  "};
+        }
+        out += &format!("pc   0x{:08x}\n", self.cpu.ip);
+        return Ok(out);
+    }
+
+    fn info_history(&self) -> String {
+        let config = self.history.config();
+        let reach = match self.history.oldest() {
+            Some(oldest) => format!("instructions {} to {}", oldest, self.cpu.instr_count),
+            None => "nothing recorded yet".to_string(),
+        };
+        return format!("History: {}, {} undo records, {} of {} bytes, snapshot every {} instructions\n",
+                       reach, self.history.len(), self.history.size(), config.budget, config.interval);
+    }
+
+    fn info_breakpoints(&self) -> String {
+        if self.breakpoints.is_empty() {
+            return "No breakpoints\n".to_string();
+        }
+        let mut out = String::new();
+        for (i, addr) in self.breakpoints.iter().enumerate() {
+            out += &format!("{:<3} {}\n", i + 1, self.describe(*addr));
+        }
+        return out;
+    }
+
+    fn info_watchpoints(&self) -> String {
+        if self.cpu.watches.is_empty() {
+            return "No watchpoints\n".to_string();
+        }
+        let mut out = String::new();
+        for (num, watch) in self.cpu.watches.iter() {
+            out += &format!("{:<3} {}\n", num + 1, watch);
+        }
+        return out;
+    }
+
+    /*
+     * watch|rwatch|awatch <loc|$reg> [len] [log]
+     */
+    fn watch(&mut self, cmd: &str, args: &[&str]) -> Result<String, String> {
+        let action = if args.last() == Some(&"log") {watch::Action::Log} else {watch::Action::Stop};
+        let args: Vec<&str> = args.iter().copied().filter(|a| *a != "log").collect();
+        let loc = args.first().ok_or(format!("Error: {} needs a location", cmd))?;
+
+        let target = if loc.starts_with('$') && cmd == "watch" {
+            let reg = self.cpu.isa.reg_num(loc).ok_or(format!("Error: unknown register [{}]", loc))?;
+            watch::Target::Reg(reg)
+        } else {
+            let addr = self.parse_loc(loc)?;
+            let len = match args.get(1) {
+                Some(len) => parse_num(len)?,
+                None => 4,
+            };
+            let kind = match cmd {
+                "rwatch" => watch::WatchKind::Read,
+                "awatch" => watch::WatchKind::Access,
+                _ => watch::WatchKind::Write,
+            };
+            watch::Target::Mem { addr, len, kind }
+        };
+        let watch = watch::Watch { target, action, isa: self.cpu.isa };
+        let num = self.cpu.watches.add(watch, &self.cpu.regfile);
+        return Ok(format!("Watch {}: {}\n", num + 1, watch));
+    }
+
+    fn disassemble_around(&self, n: u32) -> String {
+        let pc = self.cpu.ip;
+        let start = pc.saturating_sub(4 * n);
+        let mut out = String::new();
+        for i in 0..(2 * n + 1) {
+            let addr = start.wrapping_add(4 * i);
+            if self.read_code(addr, 4).is_err() {
+                continue;
+            }
+            let mark = if addr == pc {"=>"} else {"  "};
+            out += &format!("{} {}\n", mark, self.location(addr));
+        }
+        return out;
+    }
+
+    /*
+     * fmt is "<count><b|h|w|s>", either part may be left out.
+     */
+    fn examine(&mut self, fmt: &str, addr: u32) -> Result<String, String> {
+        let digits = fmt.chars().take_while(|c| c.is_ascii_digit()).count();
+        let count = if digits == 0 {1} else {parse_num(&fmt[..digits])?};
+        let size = match &fmt[digits..] {
+            "" | "w" => 4,
+            "h" => 2,
+            "b" => 1,
+            "s" => return self.examine_string(addr, count),
+            unit => return Err(format!("Error: unknown unit [{}], use b, h, w or s", unit)),
+        };
+
+        let per_line = 16 / size;
+        let mut out = String::new();
+        for i in 0..count {
+            let at = addr.wrapping_add(i * size);
+            if i % per_line == 0 {
+                if i != 0 {
+                    out += "\n";
+                }
+                out += &format!("{}:", self.describe(at));
+            }
+            let val = self.read_mem(at, size)?;
+            out += &format!(" 0x{:0width$x}", val, width = 2 * size as usize);
+        }
+        out += "\n";
+        return Ok(out);
+    }
+
+    fn examine_string(&mut self, addr: u32, count: u32) -> Result<String, String> {
+        let mut out = String::new();
+        let mut at = addr;
+        for _ in 0..count {
+            let start = at;
+            let mut text = String::new();
+            loop {
+                let byte = self.read_mem(at, 1)? as u8;
+                at = at.wrapping_add(1);
+                if byte == 0 {
+                    break;
+                }
+                text += &(byte as char).escape_default().to_string();
+            }
+            out += &format!("{}: \"{}\"\n", self.describe(start), text);
+        }
+        return Ok(out);
+    }
+
+    fn set(&mut self, args: &[&str]) -> Result<String, String> {
+        let args: Vec<&str> = args.iter().copied().filter(|a| *a != "=").collect();
+        let usage = "Error: set <reg|pc> <value> or set <b|h|w> <loc> <value>";
+        match args.as_slice() {
+            [target, value] => {
+                let value = self.parse_loc(value)?;
+                if *target == "pc" || *target == "$pc" {
+                    self.cpu.ip = value;
+                } else {
+                    let num = self.cpu.isa.reg_num(target)
+                        .ok_or(format!("Error: unknown register [{}]", target))?;
+                    self.cpu.regfile.write(value, num as usize);
+                }
+                self.history.clear();
+                return Ok(String::new());
+            },
+            [unit, loc, value] => {
+                let size = match *unit {
+                    "b" => 1,
+                    "h" => 2,
+                    "w" => 4,
+                    _ => return Err(usage.to_string()),
+                };
+                let addr = self.parse_loc(loc)?;
+                let value = self.parse_loc(value)?;
+                self.write_mem(addr, size, value)?;
+                return Ok(String::new());
+            },
+            _ => return Err(usage.to_string()),
+        }
+    }
+
+    /*
+     * Number, label or register value.
+     */
+    fn parse_loc(&self, loc: &str) -> Result<u32, String> {
+        if loc == "$pc" || loc == "pc" {
+            return Ok(self.cpu.ip);
+        }
+        if loc.starts_with('$') {
+            let num = self.cpu.isa.reg_num(loc).ok_or(format!("Error: unknown register [{}]", loc))?;
+            return Ok(self.cpu.regfile.load(num as usize));
+        }
+        if let Some(addr) = self.symbols.lookup(loc) {
+            return Ok(addr);
+        }
+        return parse_num(loc).map_err(|_| format!("Error: no label or address [{}]", loc));
+    }
+
+    /*
+     * Physical address of a data access the debugger may make. Devices are
+     * off limits since reading them has side effects.
+     */
+    fn data_addr(&self, vaddr: u32, size: u32) -> Result<usize, String> {
+        let err = format!("Cannot access memory at 0x{:08x}", vaddr);
+        let addr = self.cpu.mmu.translate(&self.cpu.cp0, vaddr, mmu::AccessKind::Load)
+            .map_err(|_| err.clone())?;
+        if self.bus.is_device(addr) {
+            return Err(format!("0x{:08x} belongs to a device", vaddr));
+        }
+        if addr as u64 + size as u64 > data_mem::Memory::get_size() as u64 {
+            return Err(err);
+        }
+        return Ok(addr as usize);
+    }
+
+}
+
+/*
+ * Creates a debugger for the processor and hands control to the user.
+ */
+pub fn start(cpu: &mut Processor, instr_mem: &instr_mem::Memory, bus: &mut bus::Bus,
+             symbols: &SymbolTable, history: HistoryConfig) {
+    let mut dbg = Debugger::new(cpu, instr_mem, bus, symbols);
+    dbg.set_history(history);
+    dbg.repl();
+}
+
+fn parse_num(num: &str) -> Result<u32, String> {
+    let res = match num.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(&hex.replace('_', ""), 16),
+        None => num.parse::<u32>(),
+    };
+    return res.map_err(|_| format!("Error: invalid number [{}]", num));
+}
+
+fn help() -> String {
+    let lines = [
+        "s, step [n]                 run n instructions (default 1)",
+        "c, continue                 run until a breakpoint or the program ends",
+        "finish                      run until the current function returns",
+        "rs, reverse-step [n]        undo n instructions (default 1)",
+        "rc, reverse-continue        run backwards to a breakpoint or watch",
+        "goto <n>                    go back to where n instructions had run",
+        "b, break <loc>              break when the pc reaches loc",
+        "d, delete [n]               delete breakpoint n, or every breakpoint",
+        "i, info registers [reg]     print the registers",
+        "i, info breakpoints         list the breakpoints",
+        "watch <loc> [len] [log]     stop (or log) when memory is written",
+        "rwatch/awatch <loc> [len]   ... read, or read and written",
+        "watch $reg [log]            stop (or log) when the register changes",
+        "unwatch [n]                 delete watch n, or every watch",
+        "i, info watchpoints         list the watches",
+        "i, info history             how far back the history reaches",
+        "x[/<n><b|h|w|s>] <loc>      examine memory",
+        "disas [n]                   disassemble n instructions around the pc",
+        "set <reg|pc> [=] <value>    change a register",
+        "set <b|h|w> <loc> [=] <val> change memory",
+        "save <file>                 save the machine state",
+        "load <file>                 restore a state saved from the same program",
+        "q, quit                     leave the debugger",
+        "A location is a number, a label or a register ($t0).",
+    ];
+    return lines.join("\n") + "\n";
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::demos::assembler::reg_macros::*;
+    use crate::demos::assembler::instr_macros::*;
+    use crate::demos::assembler::mem_loader::MemLoader;
+    use crate::single_cycle::Options;
+
+    // demo 2, fills memory with 0x42
+    fn program() -> (instr_mem::Memory, SymbolTable) {
+        let mut loader = MemLoader::new(instr_mem::Memory::new());
+        loader.label("main");
+        loader.load_instr( ADD (S0(), ZERO(), ZERO()) );
+        loader.load_instr( ADDI(S7(), ZERO(), 0x10)   );
+        loader.load_instr( ADDI(T1(), ZERO(), 0x42)   );
+        let lp = loader.label("loop");
+        loader.load_instr( SLT (T0(), S0()  , S7())   );
+        loader.load_instr( BEQ (T0(), ZERO(), (lp + 5*4) as u16) );
+        loader.load_instr( SB  (T1(), 0     , S0())   );
+        loader.load_instr( ADDI(S0(), S0()  , 1)      );
+        loader.load_instr( J   (lp)                   );
+        loader.label("end");
+        let symbols = loader.symbols().clone();
+        return (loader.return_mem(), symbols);
+    }
+
+    #[test]
+    fn test_step_and_break() {
+        let (mem, symbols) = program();
+        let mut data_mem = data_mem::Memory::new();
+        let mut bus = bus::Bus::new(&mut data_mem);
+        let mut cpu = Processor::new(&Options::default());
+        let mut dbg = Debugger::new(&mut cpu, &mem, &mut bus, &symbols);
+
+        assert_eq!(dbg.command("step 2").unwrap(), "0x00000008 <main+0x8>: addi $t1, $zero, 0x42\n");
+        assert_eq!(dbg.command("b loop").unwrap(), "Breakpoint 1 at 0x0000000c <loop>\n");
+        assert_eq!(dbg.command("c").unwrap(), "Breakpoint 1, 0x0000000c <loop>: slt $t0, $s0, $s7\n");
+        assert_eq!(dbg.command("c"), Ok("Breakpoint 1, 0x0000000c <loop>: slt $t0, $s0, $s7\n".to_string()));
+        // empty line repeats the last command
+        dbg.command("").unwrap();
+        assert_eq!(dbg.command("info r $s0").unwrap(), "s0   0x00000002  2\n");
+        assert_eq!(dbg.command("x/2b 0").unwrap(), "0x00000000 <main>: 0x42 0x42\n");
+
+        // the rest of instruction memory is nops
+        dbg.command("delete").unwrap();
+        assert_eq!(dbg.command("c").unwrap(), "Program exited after 141 instructions\n");
+        assert_eq!(dbg.step(1), Stop::Exited);
+        assert_eq!(data_mem.read(0xf), 0x42);
+        assert_eq!(data_mem.read(0x10), 0);
+    }
+
+    #[test]
+    fn test_inspect_and_set() {
+        let (mem, symbols) = program();
+        let mut data_mem = data_mem::Memory::new();
+        let mut bus = bus::Bus::new(&mut data_mem);
+        let mut cpu = Processor::new(&Options::default());
+        let mut dbg = Debugger::new(&mut cpu, &mem, &mut bus, &symbols);
+
+        dbg.command("set $t2 = 0x10").unwrap();
+        dbg.command("set w $t2 0xcafebabe").unwrap();
+        dbg.command("set b 0x20 0x68").unwrap();
+        dbg.command("set b 0x21 0x69").unwrap();
+        assert_eq!(dbg.command("x/2h 0x10").unwrap(), "0x00000010 <loop+0x4>: 0xcafe 0xbabe\n");
+        assert_eq!(dbg.command("x/w $t2").unwrap(), "0x00000010 <loop+0x4>: 0xcafebabe\n");
+        assert_eq!(dbg.command("x/s 0x20").unwrap(), "0x00000020 <end>: \"hi\"\n");
+        assert!(dbg.command("x/w 0x100").is_err());
+        assert!(dbg.command("set $t10 1").is_err());
+        assert!(dbg.command("b nowhere").is_err());
+
+        dbg.command("set pc loop").unwrap();
+        let disas = dbg.command("disas 1").unwrap();
+        assert_eq!(disas, "   0x00000008 <main+0x8>: addi $t1, $zero, 0x42\n\
+                           => 0x0000000c <loop>: slt $t0, $s0, $s7\n   \
+                           0x00000010 <loop+0x4>: beq $t0, $zero, 0x00000020\n");
+
+        let regs = dbg.command("info registers").unwrap();
+        assert!(regs.starts_with("zero 0x00000000  at   0x00000000"));
+        assert!(regs.contains("t2   0x00000010"));
+        assert!(regs.ends_with("pc   0x0000000c\n"));
+
+        dbg.command("quit").unwrap();
+        assert!(dbg.is_done());
+    }
+
+    #[test]
+    fn test_finish() {
+        let mut loader = MemLoader::new(instr_mem::Memory::new());
+        loader.load_instr( ADDI(RA(), ZERO(), 0x10) );
+        loader.load_instr( ADDI(T0(), ZERO(), 1)    );
+        loader.load_instr( JR  (RA())               );
+        loader.load_instr( ADDI(T0(), ZERO(), 2)    ); // skipped
+        loader.load_instr( ADDI(T1(), ZERO(), 3)    );
+        let mem = loader.return_mem();
+        let symbols = SymbolTable::new();
+
+        let mut data_mem = data_mem::Memory::new();
+        let mut bus = bus::Bus::new(&mut data_mem);
+        let mut cpu = Processor::new(&Options::default());
+        let mut dbg = Debugger::new(&mut cpu, &mem, &mut bus, &symbols);
+        assert_eq!(dbg.finish(), Stop::Returned);
+        assert_eq!(dbg.command("info r pc").unwrap(), "pc   0x00000010\n");
+        assert_eq!(cpu.regfile.load(T0() as usize), 1);
+    }
+
+    #[test]
+    fn test_watch() {
+        let (mem, symbols) = program();
+        let mut data_mem = data_mem::Memory::new();
+        let mut bus = bus::Bus::new(&mut data_mem);
+        let mut cpu = Processor::new(&Options::default());
+        let mut dbg = Debugger::new(&mut cpu, &mem, &mut bus, &symbols);
+
+        assert_eq!(dbg.command("watch 0x2 1").unwrap(), "Watch 1: write 0x00000002+1\n");
+        assert_eq!(dbg.command("watch $s0 log").unwrap(), "Watch 2: reg $s0 (log)\n");
+        assert_eq!(dbg.command("c").unwrap(),
+                   "Watch 2: 0x00000018: $s0 0x0 -> 0x1\n\
+                    Watch 2: 0x00000018: $s0 0x1 -> 0x2\n\
+                    Watch 1: 0x00000014: write 0x00000002 (1 bytes) 0x0 -> 0x42\n\
+                    0x00000018 <loop+0xc>: addi $s0, $s0, 0x1\n");
+        assert_eq!(dbg.command("info watchpoints").unwrap(),
+                   "1   write 0x00000002+1\n2   reg $s0 (log)\n");
+        dbg.command("unwatch").unwrap();
+        assert_eq!(dbg.command("rwatch 0x2").unwrap(), "Watch 1: read 0x00000002+4\n");
+        assert!(dbg.command("unwatch 2").is_err());
+        assert_eq!(dbg.command("c").unwrap(), "Program exited after 141 instructions\n");
+    }
+
+    #[test]
+    fn test_reverse() {
+        let (mem, symbols) = program();
+        let mut data_mem = data_mem::Memory::new();
+        let mut bus = bus::Bus::new(&mut data_mem);
+        let mut cpu = Processor::new(&Options::default());
+        let mut dbg = Debugger::new(&mut cpu, &mem, &mut bus, &symbols);
+
+        dbg.command("watch 0x2 1").unwrap();
+        dbg.command("c").unwrap();
+        dbg.command("unwatch").unwrap();
+        assert_eq!(dbg.command("c").unwrap(), "Program exited after 141 instructions\n");
+
+        // back to the store that the watch saw
+        dbg.command("watch 0x2 1").unwrap();
+        assert_eq!(dbg.command("rc").unwrap(),
+                   "Watch 1: 0x00000014: write 0x00000002 (1 bytes) 0x0 -> 0x42\n\
+                    0x00000014 <loop+0x8>: sb $t1, 0x0($s0)\n");
+        assert_eq!(dbg.command("x/4b 0").unwrap(), "0x00000000 <main>: 0x42 0x42 0x00 0x00\n");
+        assert_eq!(dbg.command("info r $s0").unwrap(), "s0   0x00000002  2\n");
+        assert_eq!(dbg.command("rs 2").unwrap(), "0x0000000c <loop>: slt $t0, $s0, $s7\n");
+
+        dbg.command("goto 2").unwrap();
+        assert_eq!(dbg.command("info r pc").unwrap(), "pc   0x00000008\n");
+        assert_eq!(dbg.command("x/b 0").unwrap(), "0x00000000 <main>: 0x00\n");
+        assert!(dbg.command("goto 3").is_err());
+        assert!(dbg.command("info history").unwrap().starts_with("History: instructions 0 to 2, 2 undo records"));
+        assert_eq!(dbg.command("rs 5").unwrap(),
+                   "No more history, at instruction 0\n0x00000000 <main>: add $s0, $zero, $zero\n");
+
+        // changing the machine by hand starts the history over
+        dbg.command("unwatch").unwrap();
+        dbg.command("s 3").unwrap();
+        dbg.command("set $t1 0x43").unwrap();
+        assert!(dbg.command("rs").unwrap().starts_with("No more history, at instruction 3\n"));
+        assert_eq!(dbg.command("c").unwrap(), "Program exited after 141 instructions\n");
+        assert_eq!(data_mem.read(0x0), 0x43);
+    }
+}
@@ -3,255 +3,375 @@
  * 
  * Author: Travis Banken
  * 
- * Macros and functions for writing instructions
+ * Macros and functions for writing instructions, encoded from the
+ * instruction definitions in instruction.rs.
  */
 #![allow(dead_code)]
 #![allow(non_snake_case)]
 
+use crate::instruction::{encode, Op};
+
 // r-format
 
-pub fn ADD(RD: u8, RS: u8, RT: u8) -> u32 {
-    let opcode = 0x00 << 26;
-    let rs = (RS as u32) << 21;
-    let rt = (RT as u32) << 16;
-    let rd = (RD as u32) << 11;
-    let shamt = 0 << 6;
-    let funct = 0x20;
+pub fn SLL(RD: u8, RT: u8, shamt: u8) -> u32 {
+    return encode(Op::Sll, &[RD as u32, RT as u32, shamt as u32]);
+}
 
-    return opcode | rs | rt | rd | shamt | funct;
+pub fn SRL(RD: u8, RT: u8, shamt: u8) -> u32 {
+    return encode(Op::Srl, &[RD as u32, RT as u32, shamt as u32]);
 }
 
-pub fn ADDU(RD: u8, RS: u8, RT: u8) -> u32 {
-    let opcode = 0x00 << 26;
-    let rs = (RS as u32) << 21;
-    let rt = (RT as u32) << 16;
-    let rd = (RD as u32) << 11;
-    let shamt = 0 << 6;
-    let funct = 0x21;
+pub fn SRA(RD: u8, RT: u8, shamt: u8) -> u32 {
+    return encode(Op::Sra, &[RD as u32, RT as u32, shamt as u32]);
+}
 
-    return opcode | rs | rt | rd | shamt | funct;
+pub fn SLLV(RD: u8, RT: u8, RS: u8) -> u32 {
+    return encode(Op::Sllv, &[RD as u32, RT as u32, RS as u32]);
 }
 
-pub fn SUB(RD: u8, RS: u8, RT: u8) -> u32 {
-    let opcode = 0x00 << 26;
-    let rs = (RS as u32) << 21;
-    let rt = (RT as u32) << 16;
-    let rd = (RD as u32) << 11;
-    let shamt = 0 << 6;
-    let funct = 0x22;
+pub fn SRLV(RD: u8, RT: u8, RS: u8) -> u32 {
+    return encode(Op::Srlv, &[RD as u32, RT as u32, RS as u32]);
+}
 
-    return opcode | rs | rt | rd | shamt | funct;
+pub fn SRAV(RD: u8, RT: u8, RS: u8) -> u32 {
+    return encode(Op::Srav, &[RD as u32, RT as u32, RS as u32]);
 }
 
-pub fn SUBU(RD: u8, RS: u8, RT: u8) -> u32 {
-    let opcode = 0x00 << 26;
-    let rs = (RS as u32) << 21;
-    let rt = (RT as u32) << 16;
-    let rd = (RD as u32) << 11;
-    let shamt = 0 << 6;
-    let funct = 0x23;
+pub fn JR(RS: u8) -> u32 {
+    return encode(Op::Jr, &[RS as u32]);
+}
 
-    return opcode | rs | rt | rd | shamt | funct;
+pub fn JALR(RD: u8, RS: u8) -> u32 {
+    return encode(Op::Jalr, &[RD as u32, RS as u32]);
 }
 
-pub fn AND(RD: u8, RS: u8, RT: u8) -> u32 {
-    let opcode = 0x00 << 26;
-    let rs = (RS as u32) << 21;
-    let rt = (RT as u32) << 16;
-    let rd = (RD as u32) << 11;
-    let shamt = 0 << 6;
-    let funct = 0x24;
+pub fn SYSCALL() -> u32 {
+    return encode(Op::Syscall, &[]);
+}
 
-    return opcode | rs | rt | rd | shamt | funct;
+pub fn BREAK() -> u32 {
+    return encode(Op::Break, &[]);
 }
 
-pub fn OR(RD: u8, RS: u8, RT: u8) -> u32 {
-    let opcode = 0x00 << 26;
-    let rs = (RS as u32) << 21;
-    let rt = (RT as u32) << 16;
-    let rd = (RD as u32) << 11;
-    let shamt = 0 << 6;
-    let funct = 0x25;
+pub fn MFHI(RD: u8) -> u32 {
+    return encode(Op::Mfhi, &[RD as u32]);
+}
 
-    return opcode | rs | rt | rd | shamt | funct;
+pub fn MTHI(RS: u8) -> u32 {
+    return encode(Op::Mthi, &[RS as u32]);
 }
 
-pub fn XOR(RD: u8, RS: u8, RT: u8) -> u32 {
-    let opcode = 0x00 << 26;
-    let rs = (RS as u32) << 21;
-    let rt = (RT as u32) << 16;
-    let rd = (RD as u32) << 11;
-    let shamt = 0 << 6;
-    let funct = 0x26;
+pub fn MFLO(RD: u8) -> u32 {
+    return encode(Op::Mflo, &[RD as u32]);
+}
 
-    return opcode | rs | rt | rd | shamt | funct;
+pub fn MTLO(RS: u8) -> u32 {
+    return encode(Op::Mtlo, &[RS as u32]);
 }
 
-pub fn NOR(RD: u8, RS: u8, RT: u8) -> u32 {
-    let opcode = 0x00 << 26;
-    let rs = (RS as u32) << 21;
-    let rt = (RT as u32) << 16;
-    let rd = (RD as u32) << 11;
-    let shamt = 0 << 6;
-    let funct = 0x27;
+pub fn MULT(RS: u8, RT: u8) -> u32 {
+    return encode(Op::Mult, &[RS as u32, RT as u32]);
+}
 
-    return opcode | rs | rt | rd | shamt | funct;
+pub fn MULTU(RS: u8, RT: u8) -> u32 {
+    return encode(Op::Multu, &[RS as u32, RT as u32]);
 }
 
-pub fn SLT(RD: u8, RS: u8, RT: u8) -> u32 {
-    let opcode = 0x00 << 26;
-    let rs = (RS as u32) << 21;
-    let rt = (RT as u32) << 16;
-    let rd = (RD as u32) << 11;
-    let shamt = 0 << 6;
-    let funct = 0x2A;
+pub fn DIV(RS: u8, RT: u8) -> u32 {
+    return encode(Op::Div, &[RS as u32, RT as u32]);
+}
 
-    return opcode | rs | rt | rd | shamt | funct;
+pub fn DIVU(RS: u8, RT: u8) -> u32 {
+    return encode(Op::Divu, &[RS as u32, RT as u32]);
 }
 
-pub fn SLTU(RD: u8, RS: u8, RT: u8) -> u32 {
-    let opcode = 0x00 << 26;
-    let rs = (RS as u32) << 21;
-    let rt = (RT as u32) << 16;
-    let rd = (RD as u32) << 11;
-    let shamt = 0 << 6;
-    let funct = 0x2B;
+pub fn ADD(RD: u8, RS: u8, RT: u8) -> u32 {
+    return encode(Op::Add, &[RD as u32, RS as u32, RT as u32]);
+}
 
-    return opcode | rs | rt | rd | shamt | funct;
+pub fn ADDU(RD: u8, RS: u8, RT: u8) -> u32 {
+    return encode(Op::Addu, &[RD as u32, RS as u32, RT as u32]);
+}
+
+pub fn SUB(RD: u8, RS: u8, RT: u8) -> u32 {
+    return encode(Op::Sub, &[RD as u32, RS as u32, RT as u32]);
+}
+
+pub fn SUBU(RD: u8, RS: u8, RT: u8) -> u32 {
+    return encode(Op::Subu, &[RD as u32, RS as u32, RT as u32]);
+}
+
+pub fn AND(RD: u8, RS: u8, RT: u8) -> u32 {
+    return encode(Op::And, &[RD as u32, RS as u32, RT as u32]);
+}
+
+pub fn OR(RD: u8, RS: u8, RT: u8) -> u32 {
+    return encode(Op::Or, &[RD as u32, RS as u32, RT as u32]);
+}
+
+pub fn XOR(RD: u8, RS: u8, RT: u8) -> u32 {
+    return encode(Op::Xor, &[RD as u32, RS as u32, RT as u32]);
+}
+
+pub fn NOR(RD: u8, RS: u8, RT: u8) -> u32 {
+    return encode(Op::Nor, &[RD as u32, RS as u32, RT as u32]);
+}
+
+pub fn SLT(RD: u8, RS: u8, RT: u8) -> u32 {
+    return encode(Op::Slt, &[RD as u32, RS as u32, RT as u32]);
+}
+
+pub fn SLTU(RD: u8, RS: u8, RT: u8) -> u32 {
+    return encode(Op::Sltu, &[RD as u32, RS as u32, RT as u32]);
 }
 
 // j-format
 
 pub fn J(address: u32) -> u32 {
-    if address > 0x03ff_ffff {
-        panic!("Error [J macro]: Address [{:X}] too large!", address);
-    }
-    let opcode = 0x02 << 26;
-    let addr = address & 0x03ff_ffff;
+    return encode(Op::J, &[address]);
+}
 
-    return opcode | addr;
+pub fn JAL(address: u32) -> u32 {
+    return encode(Op::Jal, &[address]);
 }
 
 // i-format
 
 pub fn BEQ(RS: u8, RT: u8, address: u16) -> u32 {
-    let opcode = 0x04 << 26;
-    let rs = (RS as u32) << 21;
-    let rt = (RT as u32) << 16;
-    let addr = address as u32;
-
-    return opcode | rs | rt | addr;
+    return encode(Op::Beq, &[RS as u32, RT as u32, address as u32]);
 }
 
 pub fn BNE(RS: u8, RT: u8, address: u16) -> u32 {
-    let opcode = 0x05 << 26;
-    let rs = (RS as u32) << 21;
-    let rt = (RT as u32) << 16;
-    let addr = address as u32;
-
-    return opcode | rs | rt | addr;
+    return encode(Op::Bne, &[RS as u32, RT as u32, address as u32]);
 }
 
 pub fn ADDI(RT: u8, RS: u8, imm16: u16) -> u32 {
-    let opcode = 0x08 << 26;
-    let rs = (RS as u32) << 21;
-    let rt = (RT as u32) << 16;
-    let imm32 = imm16 as u32;
-
-    return opcode | rs | rt | imm32;
+    return encode(Op::Addi, &[RT as u32, RS as u32, imm16 as u32]);
 }
 
 pub fn ADDIU(RT: u8, RS: u8, imm16: u16) -> u32 {
-    let opcode = 0x09 << 26;
-    let rs = (RS as u32) << 21;
-    let rt = (RT as u32) << 16;
-    let imm32 = imm16 as u32;
-
-    return opcode | rs | rt | imm32;
+    return encode(Op::Addiu, &[RT as u32, RS as u32, imm16 as u32]);
 }
 
 pub fn SLTI(RT: u8, RS: u8, imm16: u16) -> u32 {
-    let opcode = 0x0A << 26;
-    let rs = (RS as u32) << 21;
-    let rt = (RT as u32) << 16;
-    let imm32 = imm16 as u32;
-
-    return opcode | rs | rt | imm32;
+    return encode(Op::Slti, &[RT as u32, RS as u32, imm16 as u32]);
 }
 
 pub fn SLTIU(RT: u8, RS: u8, imm16: u16) -> u32 {
-    let opcode = 0x0B << 26;
-    let rs = (RS as u32) << 21;
-    let rt = (RT as u32) << 16;
-    let imm32 = imm16 as u32;
-
-    return opcode | rs | rt | imm32;
+    return encode(Op::Sltiu, &[RT as u32, RS as u32, imm16 as u32]);
 }
 
 pub fn ANDI(RT: u8, RS: u8, imm16: u16) -> u32 {
-    let opcode = 0x0C << 26;
-    let rs = (RS as u32) << 21;
-    let rt = (RT as u32) << 16;
-    let imm32 = imm16 as u32;
-
-    return opcode | rs | rt | imm32;
+    return encode(Op::Andi, &[RT as u32, RS as u32, imm16 as u32]);
 }
 
 pub fn ORI(RT: u8, RS: u8, imm16: u16) -> u32 {
-    let opcode = 0x0D << 26;
-    let rs = (RS as u32) << 21;
-    let rt = (RT as u32) << 16;
-    let imm32 = imm16 as u32;
+    return encode(Op::Ori, &[RT as u32, RS as u32, imm16 as u32]);
+}
 
-    return opcode | rs | rt | imm32;
+pub fn XORI(RT: u8, RS: u8, imm16: u16) -> u32 {
+    return encode(Op::Xori, &[RT as u32, RS as u32, imm16 as u32]);
 }
 
 pub fn LUI(RT: u8, imm16: u16) -> u32 {
-    let opcode = 0x0F << 26;
-    let rs = 0 << 21;
-    let rt = (RT as u32) << 16;
-    let imm32 = imm16 as u32;
-
-    return opcode | rs | rt | imm32;
+    return encode(Op::Lui, &[RT as u32, imm16 as u32]);
 }
 
 pub fn LB(RT: u8, addr_offset: u16, RS: u8) -> u32 {
-    let opcode = 0x20 << 26;
-    let rs = (RS as u32) << 21;
-    let rt = (RT as u32) << 16;
-    let addr = addr_offset as u32;
+    return encode(Op::Lb, &[RT as u32, addr_offset as u32, RS as u32]);
+}
 
-    return opcode | rs | rt | addr;
+pub fn LH(RT: u8, addr_offset: u16, RS: u8) -> u32 {
+    return encode(Op::Lh, &[RT as u32, addr_offset as u32, RS as u32]);
 }
 
 pub fn LW(RT: u8, addr_offset: u16, RS: u8) -> u32 {
-    let opcode = 0x23 << 26;
-    let rs = (RS as u32) << 21;
-    let rt = (RT as u32) << 16;
-    let addr = addr_offset as u32;
+    return encode(Op::Lw, &[RT as u32, addr_offset as u32, RS as u32]);
+}
+
+pub fn LBU(RT: u8, addr_offset: u16, RS: u8) -> u32 {
+    return encode(Op::Lbu, &[RT as u32, addr_offset as u32, RS as u32]);
+}
 
-    return opcode | rs | rt | addr;
+pub fn LHU(RT: u8, addr_offset: u16, RS: u8) -> u32 {
+    return encode(Op::Lhu, &[RT as u32, addr_offset as u32, RS as u32]);
 }
 
 pub fn SB(RT: u8, addr_offset: u16, RS: u8) -> u32 {
-    let opcode = 0x28 << 26;
-    let rs = (RS as u32) << 21;
-    let rt = (RT as u32) << 16;
-    let addr = addr_offset as u32;
+    return encode(Op::Sb, &[RT as u32, addr_offset as u32, RS as u32]);
+}
 
-    return opcode | rs | rt | addr;
+pub fn SH(RT: u8, addr_offset: u16, RS: u8) -> u32 {
+    return encode(Op::Sh, &[RT as u32, addr_offset as u32, RS as u32]);
 }
 
 pub fn SW(RT: u8, addr_offset: u16, RS: u8) -> u32 {
-    let opcode = 0x2B << 26;
-    let rs = (RS as u32) << 21;
-    let rt = (RT as u32) << 16;
-    let addr = addr_offset as u32;
+    return encode(Op::Sw, &[RT as u32, addr_offset as u32, RS as u32]);
+}
 
-    return opcode | rs | rt | addr;
+pub fn LWL(RT: u8, addr_offset: u16, RS: u8) -> u32 {
+    return encode(Op::Lwl, &[RT as u32, addr_offset as u32, RS as u32]);
 }
 
+pub fn LWR(RT: u8, addr_offset: u16, RS: u8) -> u32 {
+    return encode(Op::Lwr, &[RT as u32, addr_offset as u32, RS as u32]);
+}
+
+pub fn SWL(RT: u8, addr_offset: u16, RS: u8) -> u32 {
+    return encode(Op::Swl, &[RT as u32, addr_offset as u32, RS as u32]);
+}
+
+pub fn SWR(RT: u8, addr_offset: u16, RS: u8) -> u32 {
+    return encode(Op::Swr, &[RT as u32, addr_offset as u32, RS as u32]);
+}
+
+// coprocessor 0
 
+pub fn MFC0(RT: u8, RD: u8) -> u32 {
+    return encode(Op::Mfc0, &[RT as u32, RD as u32]);
+}
+
+pub fn MTC0(RT: u8, RD: u8) -> u32 {
+    return encode(Op::Mtc0, &[RT as u32, RD as u32]);
+}
+
+pub fn TLBR() -> u32 {
+    return encode(Op::Tlbr, &[]);
+}
+
+pub fn TLBWI() -> u32 {
+    return encode(Op::Tlbwi, &[]);
+}
+
+pub fn TLBWR() -> u32 {
+    return encode(Op::Tlbwr, &[]);
+}
+
+pub fn TLBP() -> u32 {
+    return encode(Op::Tlbp, &[]);
+}
+
+pub fn RFE() -> u32 {
+    return encode(Op::Rfe, &[]);
+}
+
+// MIPS32 release 1 and 2
+
+pub fn SYNC() -> u32 {
+    return encode(Op::Sync, &[]);
+}
+
+pub fn MOVZ(RD: u8, RS: u8, RT: u8) -> u32 {
+    return encode(Op::Movz, &[RD as u32, RS as u32, RT as u32]);
+}
+
+pub fn MOVN(RD: u8, RS: u8, RT: u8) -> u32 {
+    return encode(Op::Movn, &[RD as u32, RS as u32, RT as u32]);
+}
+
+pub fn MUL(RD: u8, RS: u8, RT: u8) -> u32 {
+    return encode(Op::Mul, &[RD as u32, RS as u32, RT as u32]);
+}
+
+pub fn TGE(RS: u8, RT: u8) -> u32 {
+    return encode(Op::Tge, &[RS as u32, RT as u32]);
+}
+
+pub fn TGEU(RS: u8, RT: u8) -> u32 {
+    return encode(Op::Tgeu, &[RS as u32, RT as u32]);
+}
+
+pub fn TLT(RS: u8, RT: u8) -> u32 {
+    return encode(Op::Tlt, &[RS as u32, RT as u32]);
+}
+
+pub fn TLTU(RS: u8, RT: u8) -> u32 {
+    return encode(Op::Tltu, &[RS as u32, RT as u32]);
+}
+
+pub fn TEQ(RS: u8, RT: u8) -> u32 {
+    return encode(Op::Teq, &[RS as u32, RT as u32]);
+}
+
+pub fn TNE(RS: u8, RT: u8) -> u32 {
+    return encode(Op::Tne, &[RS as u32, RT as u32]);
+}
+
+pub fn MADD(RS: u8, RT: u8) -> u32 {
+    return encode(Op::Madd, &[RS as u32, RT as u32]);
+}
+
+pub fn MADDU(RS: u8, RT: u8) -> u32 {
+    return encode(Op::Maddu, &[RS as u32, RT as u32]);
+}
+
+pub fn MSUB(RS: u8, RT: u8) -> u32 {
+    return encode(Op::Msub, &[RS as u32, RT as u32]);
+}
+
+pub fn MSUBU(RS: u8, RT: u8) -> u32 {
+    return encode(Op::Msubu, &[RS as u32, RT as u32]);
+}
+
+pub fn TGEI(RS: u8, imm16: u16) -> u32 {
+    return encode(Op::Tgei, &[RS as u32, imm16 as u32]);
+}
+
+pub fn TGEIU(RS: u8, imm16: u16) -> u32 {
+    return encode(Op::Tgeiu, &[RS as u32, imm16 as u32]);
+}
+
+pub fn TLTI(RS: u8, imm16: u16) -> u32 {
+    return encode(Op::Tlti, &[RS as u32, imm16 as u32]);
+}
+
+pub fn TLTIU(RS: u8, imm16: u16) -> u32 {
+    return encode(Op::Tltiu, &[RS as u32, imm16 as u32]);
+}
+
+pub fn TEQI(RS: u8, imm16: u16) -> u32 {
+    return encode(Op::Teqi, &[RS as u32, imm16 as u32]);
+}
+
+pub fn TNEI(RS: u8, imm16: u16) -> u32 {
+    return encode(Op::Tnei, &[RS as u32, imm16 as u32]);
+}
+
+pub fn ROTR(RD: u8, RT: u8, shamt: u8) -> u32 {
+    return encode(Op::Rotr, &[RD as u32, RT as u32, shamt as u32]);
+}
+
+pub fn ROTRV(RD: u8, RT: u8, RS: u8) -> u32 {
+    return encode(Op::Rotrv, &[RD as u32, RT as u32, RS as u32]);
+}
+
+pub fn CLZ(RD: u8, RS: u8) -> u32 {
+    return encode(Op::Clz, &[RD as u32, RS as u32]);
+}
+
+pub fn CLO(RD: u8, RS: u8) -> u32 {
+    return encode(Op::Clo, &[RD as u32, RS as u32]);
+}
+
+pub fn EXT(RT: u8, RS: u8, pos: u8, size: u8) -> u32 {
+    return encode(Op::Ext, &[RT as u32, RS as u32, pos as u32, size as u32]);
+}
+
+pub fn INS(RT: u8, RS: u8, pos: u8, size: u8) -> u32 {
+    return encode(Op::Ins, &[RT as u32, RS as u32, pos as u32, size as u32]);
+}
+
+pub fn WSBH(RD: u8, RT: u8) -> u32 {
+    return encode(Op::Wsbh, &[RD as u32, RT as u32]);
+}
+
+pub fn SEB(RD: u8, RT: u8) -> u32 {
+    return encode(Op::Seb, &[RD as u32, RT as u32]);
+}
+
+pub fn SEH(RD: u8, RT: u8) -> u32 {
+    return encode(Op::Seh, &[RD as u32, RT as u32]);
+}
 
 #[cfg(test)]
 mod tests {
@@ -321,6 +441,12 @@ mod tests {
         assert_eq!(instr1, 0x014B482B);
     }
 
+    #[test]
+    fn test_JR() {
+        let instr1 = JR(RA());
+        assert_eq!(instr1, 0x03E00008);
+    }
+
     #[test]
     fn test_J() {
         let instr1 = J(0xDEAD);
@@ -404,4 +530,63 @@ mod tests {
         let instr1 = SW(T0(), 0x4, T1());
         assert_eq!(instr1, 0xAD280004);
     }
+
+    #[test]
+    fn test_UNALIGNED() {
+        assert_eq!(LWL(T0(), 0x1, T1()), 0x89280001);
+        assert_eq!(LWR(T0(), 0x4, T1()), 0x99280004);
+        assert_eq!(SWL(T0(), 0x1, T1()), 0xA9280001);
+        assert_eq!(SWR(T0(), 0x4, T1()), 0xB9280004);
+    }
+
+    #[test]
+    fn test_MFC0() {
+        let instr1 = MFC0(K0(), 14);
+        assert_eq!(instr1, 0x401A7000);
+    }
+
+    #[test]
+    fn test_MTC0() {
+        let instr1 = MTC0(T0(), 10);
+        assert_eq!(instr1, 0x40885000);
+    }
+
+    #[test]
+    fn test_TLB() {
+        assert_eq!(TLBR(), 0x42000001);
+        assert_eq!(TLBWI(), 0x42000002);
+        assert_eq!(TLBWR(), 0x42000006);
+        assert_eq!(TLBP(), 0x42000008);
+    }
+
+    #[test]
+    fn test_RFE() {
+        assert_eq!(RFE(), 0x42000010);
+    }
+
+    #[test]
+    fn test_SPECIAL2() {
+        assert_eq!(MUL(V0(), A0(), A1()), 0x70851002);
+        assert_eq!(MADDU(A0(), A1()), 0x70850001);
+        assert_eq!(MSUB(A0(), A1()), 0x70850004);
+        assert_eq!(CLO(V0(), A0()), 0x70821021);
+    }
+
+    #[test]
+    fn test_SPECIAL3() {
+        assert_eq!(EXT(V0(), A0(), 8, 4), 0x7C821A00);
+        assert_eq!(INS(V0(), A0(), 8, 4), 0x7C825A04);
+        assert_eq!(WSBH(V0(), A0()), 0x7C0410A0);
+        assert_eq!(SEH(V0(), A0()), 0x7C041620);
+    }
+
+    #[test]
+    fn test_R2_SPECIAL() {
+        assert_eq!(MOVZ(V0(), A0(), A1()), 0x0085100A);
+        assert_eq!(ROTR(V0(), A0(), 8), 0x00241202);
+        assert_eq!(ROTRV(V0(), A0(), A1()), 0x00A41046);
+        assert_eq!(TNE(A0(), A1()), 0x00850036);
+        assert_eq!(TGEI(A0(), 5), 0x04880005);
+        assert_eq!(SYNC(), 0x0000000F);
+    }
 }
\ No newline at end of file
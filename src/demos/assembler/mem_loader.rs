@@ -8,27 +8,39 @@
 #![allow(dead_code)]
 
 use crate::hardware::instr_mem::Memory;
+use crate::tools::symbols::SymbolTable;
+use std::panic::Location;
 
 pub struct MemLoader {
     mem: Memory,
-    addr: usize
+    addr: usize,
+    symbols: SymbolTable
 }
 
 impl MemLoader {
     pub fn new(memory: Memory) -> MemLoader {
         MemLoader {
             mem: memory,
-            addr: 0
+            addr: 0,
+            symbols: SymbolTable::new()
         }
     }
 
+    /*
+     * Writes the instruction at the current address, the line of the caller
+     * is kept as its source line.
+     */
+    #[track_caller]
     pub fn load_instr(&mut self, instr: u32) {
+        let caller = Location::caller();
+        self.symbols.add_line(self.addr as u32, caller.file(), caller.line());
+
         let write0 = (instr >> 24) as u8; 
         let write1 = (instr >> 16) as u8; 
         let write2 = (instr >> 8) as u8; 
-        let write3 = (instr >> 0) as u8; 
+        let write3 = instr as u8; 
 
-        self.mem.write(write0, self.addr + 0);
+        self.mem.write(write0, self.addr);
         self.mem.write(write1, self.addr + 1);
         self.mem.write(write2, self.addr + 2);
         self.mem.write(write3, self.addr + 3);
@@ -49,4 +61,20 @@ impl MemLoader {
     pub fn get_ip(&self) -> usize {
         return self.addr;
     }
+
+    pub fn set_ip(&mut self, addr: usize) {
+        self.addr = addr;
+    }
+
+    /*
+     * Names the address the next instruction is loaded at.
+     */
+    pub fn label(&mut self, name: &str) -> u32 {
+        self.symbols.add(name, self.addr as u32);
+        return self.addr as u32;
+    }
+
+    pub fn symbols(&self) -> &SymbolTable {
+        &self.symbols
+    }
 } 
\ No newline at end of file
@@ -13,34 +13,41 @@ use crate::demos::assembler::mem_loader::MemLoader;
 use crate::hardware::instr_mem;
 use crate::hardware::data_mem;
 use crate::single_cycle;
+use crate::single_cycle::Options;
 use crate::tools::dump_instr_mem;
 use crate::tools::dump_data_mem;
+use crate::tools::symbols::SymbolTable;
 
-pub fn start(debug: bool, mem_dump: bool) {
+pub fn start(opts: &Options, mem_dump: bool) -> i32 {
     println!("Running Demo 1...");
-    let mut instr_mem = instr_mem::Memory::new();
+    let instr_mem = instr_mem::Memory::new();
     let mut data_mem = data_mem::Memory::new();
 
-    instr_mem = load_instr(instr_mem);
+    let (instr_mem, symbols) = load_instr(instr_mem);
     if mem_dump {
         dump_instr_mem::dump_as_txt(&instr_mem);
     }
 
-    single_cycle::start(&instr_mem, &mut data_mem, debug);
+    let code = single_cycle::start(&instr_mem, &mut data_mem, &symbols, opts);
     if mem_dump {
         dump_data_mem::dump_as_txt(&data_mem);
     }
     println!("Done!");
+    return code;
 }
 
-fn load_instr(mem: instr_mem::Memory) -> instr_mem::Memory {
+fn load_instr(mem: instr_mem::Memory) -> (instr_mem::Memory, SymbolTable) {
     let mut loader = MemLoader::new(mem);
+    loader.label("main");
 
-    loader.load_instr( ADDI(T0(), ZERO(), 0xbabe) );
+    loader.load_instr( ORI (T0(), ZERO(), 0xbabe) );
     loader.load_instr( LUI (T1(),         0xcafe) );
     loader.load_instr( OR  (T0(), T1()  , T0()  ) );
     loader.load_instr( ADDI(S0(), ZERO(), 0x42  ) );
-    loader.load_instr( SW  (T0(), 0     , S0()  ) );
+    // 0x42 is not word aligned, the word is stored in two parts
+    loader.load_instr( SWL (T0(), 0     , S0()  ) );
+    loader.load_instr( SWR (T0(), 3     , S0()  ) );
 
-    return loader.return_mem();
+    let symbols = loader.symbols().clone();
+    return (loader.return_mem(), symbols);
 }
\ No newline at end of file
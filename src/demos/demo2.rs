@@ -14,35 +14,39 @@ use crate::demos::assembler::mem_loader::MemLoader;
 use crate::hardware::instr_mem;
 use crate::hardware::data_mem;
 use crate::single_cycle;
+use crate::single_cycle::Options;
 use crate::tools::dump_instr_mem;
 use crate::tools::dump_data_mem;
+use crate::tools::symbols::SymbolTable;
 
-pub fn start(debug: bool, mem_dump: bool) {
+pub fn start(opts: &Options, mem_dump: bool) -> i32 {
     println!("Runnning Demo 2...");
-    let mut instr_mem = instr_mem::Memory::new();
+    let instr_mem = instr_mem::Memory::new();
     let mut data_mem = data_mem::Memory::new();
 
-    instr_mem = load_instr(instr_mem);
+    let (instr_mem, symbols) = load_instr(instr_mem);
     if mem_dump {
         dump_instr_mem::dump_as_txt(&instr_mem);
     }
 
-    single_cycle::start(&instr_mem, &mut data_mem, debug);
+    let code = single_cycle::start(&instr_mem, &mut data_mem, &symbols, opts);
     if mem_dump {
         dump_data_mem::dump_as_txt(&data_mem);
     }
     println!("Done!");
+    return code;
 }
 
-fn load_instr(mem: instr_mem::Memory) -> instr_mem::Memory {
+fn load_instr(mem: instr_mem::Memory) -> (instr_mem::Memory, SymbolTable) {
     let mut loader = MemLoader::new(mem);
+    loader.label("main");
 
     // s0 = *mem
     loader.load_instr( ADD (S0(), ZERO(), ZERO()) );
     loader.load_instr( ADDI(S7(), ZERO(), 256)    );
     loader.load_instr( ADDI(T1(), ZERO(), 0x42)   );
 
-    let LOOP = loader.get_ip() as u32;
+    let LOOP = loader.label("loop");
 // LOOP:
     loader.load_instr( SLT (T0(), S0()  , S7())   );
     loader.load_instr( BEQ (T0(), ZERO(), (LOOP + 5*4) as u16)); // j END_LOOP
@@ -52,6 +56,8 @@ fn load_instr(mem: instr_mem::Memory) -> instr_mem::Memory {
 
     loader.load_instr( J   (LOOP)                  ); // j LOOP
 // END_LOOP:
+    loader.label("end_loop");
 
-    return loader.return_mem();
+    let symbols = loader.symbols().clone();
+    return (loader.return_mem(), symbols);
 }
\ No newline at end of file
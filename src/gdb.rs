@@ -0,0 +1,932 @@
+/*
+ * gdb.rs
+ *
+ * Author: Travis Banken
+ *
+ * GDB remote serial protocol stub, lets gdb (gdb-multiarch) debug the
+ * program running on the processor:
+ *
+ *   (gdb) set architecture mips
+ *   (gdb) target remote :1234               rvp --gdb=1234 demo2
+ *   (gdb) target remote | rvp --gdb=stdio demo2
+ *
+ * Registers use the MIPS o32 numbering gdb expects:
+ *  0-31 gprs, 32 status, 33 lo, 34 hi, 35 badvaddr, 36 cause, 37 pc,
+ *  38-69 f0-f31, 70 fcsr, 71 fir (there is no fpu, these read as 0)
+ *
+ * Watchpoints (Z2-Z4) work on data memory, hits of watches set to log are
+ * sent to the gdb console.
+ *
+ * Instruction and data memory both start at address 0. gdb sees data
+ * memory (what loads and stores see) unless "monitor code" switches the view
+ * to instruction memory, "monitor data" switches back.
+ */
+#![allow(dead_code)]
+
+use crate::debugger::{Debugger, Stop};
+use crate::hardware::*;
+use crate::history::HistoryConfig;
+use crate::single_cycle::Processor;
+use crate::tools::symbols::SymbolTable;
+use crate::watch;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+pub const NUM_REGS: usize = 72;
+
+const REG_STATUS: usize = 32;
+const REG_LO: usize = 33;
+const REG_HI: usize = 34;
+const REG_BAD_VADDR: usize = 35;
+const REG_CAUSE: usize = 36;
+const REG_PC: usize = 37;
+
+// how many instructions run between checks for ctrl-c
+const POLL_INTERVAL: u64 = 1000;
+
+/*
+ * Where the stub talks to gdb.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transport {
+    // address to listen on, "1234" is short for "127.0.0.1:1234"
+    Tcp(String),
+    Stdio,
+}
+
+impl Transport {
+    /*
+     * Parses "stdio", "<port>" or "<host>:<port>".
+     */
+    pub fn parse(spec: &str) -> Result<Transport, String> {
+        if spec == "stdio" || spec == "-" {
+            return Ok(Transport::Stdio);
+        }
+        if spec.parse::<u16>().is_ok() {
+            return Ok(Transport::Tcp(format!("127.0.0.1:{}", spec)));
+        }
+        match spec.rsplit_once(':') {
+            Some((_, port)) if port.parse::<u16>().is_ok() => Ok(Transport::Tcp(spec.to_string())),
+            _ => Err(format!("Gdb-Error: expected stdio, <port> or <host>:<port> not [{}]", spec)),
+        }
+    }
+}
+
+/*
+ * Byte stream to gdb.
+ */
+pub trait Connection: Read + Write {
+    // did gdb send an interrupt (ctrl-c) while the target was running?
+    fn interrupted(&mut self) -> bool {
+        false
+    }
+}
+
+impl Connection for TcpStream {
+    fn interrupted(&mut self) -> bool {
+        if self.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let mut byte = [0; 1];
+        let res = self.read(&mut byte);
+        let _ = self.set_nonblocking(false);
+        return matches!(res, Ok(1) if byte[0] == 0x03);
+    }
+}
+
+struct Stdio;
+
+impl Read for Stdio {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        io::stdin().read(buf)
+    }
+}
+
+impl Write for Stdio {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::stdout().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+impl Connection for Stdio {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum View {
+    Data,
+    Code,
+}
+
+pub struct GdbStub<'a, 'b> {
+    dbg: Debugger<'a, 'b>,
+    conn: Box<dyn Connection>,
+    sw_breaks: Vec<u32>,
+    hw_breaks: Vec<u32>,
+    view: View,
+    no_ack: bool,
+    // gdb understands the swbreak/hwbreak stop reasons
+    report_break_kind: bool,
+    done: bool,
+}
+
+impl<'a, 'b> GdbStub<'a, 'b> {
+    pub fn new(dbg: Debugger<'a, 'b>, conn: Box<dyn Connection>) -> GdbStub<'a, 'b> {
+        GdbStub {
+            dbg,
+            conn,
+            sw_breaks: Vec::new(),
+            hw_breaks: Vec::new(),
+            view: View::Data,
+            no_ack: false,
+            report_break_kind: false,
+            done: false,
+        }
+    }
+
+    /*
+     * Serves packets until gdb detaches, kills the target or hangs up.
+     */
+    pub fn serve(&mut self) -> io::Result<()> {
+        while !self.done {
+            let packet = match self.read_packet()? {
+                Some(packet) => packet,
+                None => break,
+            };
+            if let Some(reply) = self.handle(&packet) {
+                self.send_packet(&reply)?;
+            }
+            if packet == "QStartNoAckMode" {
+                self.no_ack = true;
+            }
+        }
+        return Ok(());
+    }
+
+    /*
+     * Answers one packet (without the framing), None when there is no
+     * reply.
+     */
+    pub fn handle(&mut self, packet: &str) -> Option<String> {
+        let reply = match packet.as_bytes().first() {
+            Some(b'?') => "S05".to_string(),
+            Some(b'g') => self.read_registers(),
+            Some(b'G') => self.write_registers(&packet[1..]),
+            Some(b'p') => self.read_register(&packet[1..]),
+            Some(b'P') => self.write_register(&packet[1..]),
+            Some(b'm') => self.read_memory(&packet[1..]),
+            Some(b'M') => self.write_memory(&packet[1..]),
+            Some(b'Z') => self.breakpoint(&packet[1..], true),
+            Some(b'z') => self.breakpoint(&packet[1..], false),
+            Some(b's') => self.resume(&packet[1..], true),
+            Some(b'c') => self.resume(&packet[1..], false),
+            Some(b'b') => self.reverse(packet),
+            Some(b'H') => "OK".to_string(),
+            Some(b'T') => "OK".to_string(),
+            Some(b'D') => {
+                self.done = true;
+                "OK".to_string()
+            },
+            Some(b'k') => {
+                self.done = true;
+                return None;
+            },
+            Some(b'q') | Some(b'Q') => self.query(packet),
+            Some(b'v') => self.v_packet(packet),
+            _ => String::new(),
+        };
+        return Some(reply);
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    // *** PRIVATE FN ***
+
+    fn query(&mut self, packet: &str) -> String {
+        if let Some(features) = packet.strip_prefix("qSupported") {
+            self.report_break_kind = features.contains("swbreak+");
+            return "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+;swbreak+;hwbreak+;vContSupported+;\
+              ReverseStep+;ReverseContinue+"
+                .to_string();
+        }
+        if let Some(args) = packet.strip_prefix("qXfer:features:read:") {
+            return read_target_xml(args);
+        }
+        if let Some(cmd) = packet.strip_prefix("qRcmd,") {
+            return self.monitor(cmd);
+        }
+        match packet {
+            "QStartNoAckMode" => "OK".to_string(),
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    fn v_packet(&mut self, packet: &str) -> String {
+        if packet == "vCont?" {
+            return "vCont;c;C;s;S".to_string();
+        }
+        if let Some(actions) = packet.strip_prefix("vCont;") {
+            // one thread, the first action is the one that counts
+            let action = actions.split(';').next().unwrap_or("");
+            return match action.as_bytes().first() {
+                Some(b's') | Some(b'S') => self.resume("", true),
+                Some(b'c') | Some(b'C') => self.resume("", false),
+                _ => "E01".to_string(),
+            };
+        }
+        return String::new();
+    }
+
+    fn monitor(&mut self, hex_cmd: &str) -> String {
+        let cmd = match decode_hex(hex_cmd) {
+            Some(bytes) => String::from_utf8_lossy(&bytes).trim().to_string(),
+            None => return "E01".to_string(),
+        };
+        let out = match cmd.as_str() {
+            "code" => {
+                self.view = View::Code;
+                "memory reads now see instruction memory\n"
+            },
+            "data" => {
+                self.view = View::Data;
+                "memory reads now see data memory\n"
+            },
+            _ => "monitor commands: code, data\n",
+        };
+        return encode_hex(out.as_bytes());
+    }
+
+    fn read_registers(&self) -> String {
+        let mut out = String::new();
+        for reg in 0..NUM_REGS {
+            out += &format!("{:08x}", self.register(reg).unwrap_or(0));
+        }
+        return out;
+    }
+
+    fn write_registers(&mut self, hex: &str) -> String {
+        for reg in 0..NUM_REGS {
+            let field = match hex.get(8 * reg..8 * reg + 8) {
+                Some(field) => field,
+                None => break,
+            };
+            match u32::from_str_radix(field, 16) {
+                Ok(val) => self.set_register(reg, val),
+                Err(_) => return "E01".to_string(),
+            }
+        }
+        return "OK".to_string();
+    }
+
+    fn read_register(&self, args: &str) -> String {
+        let reg = match usize::from_str_radix(args, 16) {
+            Ok(reg) => reg,
+            Err(_) => return "E01".to_string(),
+        };
+        match self.register(reg) {
+            Some(val) => format!("{:08x}", val),
+            None => "E01".to_string(),
+        }
+    }
+
+    fn write_register(&mut self, args: &str) -> String {
+        let (reg, val) = match args.split_once('=') {
+            Some(pair) => pair,
+            None => return "E01".to_string(),
+        };
+        match (usize::from_str_radix(reg, 16), u32::from_str_radix(val, 16)) {
+            (Ok(reg), Ok(val)) if reg < NUM_REGS => {
+                self.set_register(reg, val);
+                "OK".to_string()
+            },
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn register(&self, reg: usize) -> Option<u32> {
+        let cpu = self.dbg.cpu();
+        let val = match reg {
+            0..=31 => cpu.regfile.load(reg),
+            REG_STATUS => cpu.cp0.load(cp0::STATUS),
+            REG_LO => cpu.lo,
+            REG_HI => cpu.hi,
+            REG_BAD_VADDR => cpu.cp0.load(cp0::BAD_VADDR),
+            REG_CAUSE => cpu.cp0.load(cp0::CAUSE),
+            REG_PC => cpu.ip,
+            38..=71 => 0,
+            _ => return None,
+        };
+        return Some(val);
+    }
+
+    fn set_register(&mut self, reg: usize, val: u32) {
+        let cpu = self.dbg.cpu_mut();
+        match reg {
+            // $zero stays zero
+            1..=31 => cpu.regfile.write(val, reg),
+            REG_STATUS => cpu.cp0.set(val, cp0::STATUS),
+            REG_LO => cpu.lo = val,
+            REG_HI => cpu.hi = val,
+            REG_BAD_VADDR => cpu.cp0.set(val, cp0::BAD_VADDR),
+            REG_CAUSE => cpu.cp0.set(val, cp0::CAUSE),
+            REG_PC => cpu.ip = val,
+            _ => {},
+        }
+        self.dbg.clear_history();
+    }
+
+    fn read_memory(&self, args: &str) -> String {
+        let (addr, len) = match parse_addr_len(args) {
+            Some(pair) => pair,
+            None => return "E01".to_string(),
+        };
+        let mut out = String::new();
+        for i in 0..len {
+            let at = addr.wrapping_add(i);
+            let byte = match self.view {
+                View::Data => self.dbg.read_mem(at, 1),
+                View::Code => self.dbg.read_code(at, 1),
+            };
+            match byte {
+                Ok(byte) => out += &format!("{:02x}", byte),
+                // a short read is fine, as long as something was read
+                Err(_) => break,
+            }
+        }
+        if out.is_empty() && len != 0 {
+            return "E14".to_string();
+        }
+        return out;
+    }
+
+    fn write_memory(&mut self, args: &str) -> String {
+        let (head, data) = match args.split_once(':') {
+            Some(pair) => pair,
+            None => return "E01".to_string(),
+        };
+        let (addr, len) = match parse_addr_len(head) {
+            Some(pair) => pair,
+            None => return "E01".to_string(),
+        };
+        let bytes = match decode_hex(data) {
+            Some(bytes) if bytes.len() == len as usize => bytes,
+            _ => return "E01".to_string(),
+        };
+        if self.view == View::Code {
+            // instruction memory is read only while running
+            return "E14".to_string();
+        }
+        for (i, byte) in bytes.iter().enumerate() {
+            if self.dbg.write_mem(addr.wrapping_add(i as u32), 1, *byte as u32).is_err() {
+                return "E14".to_string();
+            }
+        }
+        return "OK".to_string();
+    }
+
+    /*
+     * Z0/z0 software and Z1/z1 hardware breakpoints, both are checked by the
+     * debugger before every instruction so they behave the same. Z2-Z4 are
+     * write, read and access watchpoints.
+     */
+    fn breakpoint(&mut self, args: &str, insert: bool) -> String {
+        let mut parts = args.split(',');
+        let kind = parts.next();
+        let addr = match parts.next().map(|a| u32::from_str_radix(a, 16)) {
+            Some(Ok(addr)) => addr,
+            _ => return "E01".to_string(),
+        };
+        let watch_kind = match kind {
+            Some("2") => Some(watch::WatchKind::Write),
+            Some("3") => Some(watch::WatchKind::Read),
+            Some("4") => Some(watch::WatchKind::Access),
+            _ => None,
+        };
+        if let Some(kind) = watch_kind {
+            let len = match parts.next().map(|l| u32::from_str_radix(l, 16)) {
+                Some(Ok(len)) if len > 0 => len,
+                _ => return "E01".to_string(),
+            };
+            let cpu = self.dbg.cpu_mut();
+            if insert {
+                let target = watch::Target::Mem { addr, len, kind };
+                cpu.watches.add(watch::Watch { target, action: watch::Action::Stop, isa: cpu.isa }, &cpu.regfile);
+            } else {
+                cpu.watches.remove_mem(addr, len, kind);
+            }
+            return "OK".to_string();
+        }
+        let list = match kind {
+            Some("0") => &mut self.sw_breaks,
+            Some("1") => &mut self.hw_breaks,
+            _ => return String::new(),
+        };
+        if insert {
+            if !list.contains(&addr) {
+                list.push(addr);
+            }
+            self.dbg.add_breakpoint(addr);
+        } else {
+            list.retain(|b| *b != addr);
+            if !self.sw_breaks.contains(&addr) && !self.hw_breaks.contains(&addr) {
+                self.dbg.remove_breakpoint(addr);
+            }
+        }
+        return "OK".to_string();
+    }
+
+    /*
+     * s/c with an optional address to resume at, returns the stop reply.
+     */
+    fn resume(&mut self, args: &str, single: bool) -> String {
+        if !args.is_empty() {
+            match u32::from_str_radix(args, 16) {
+                Ok(addr) => {
+                    self.dbg.cpu_mut().ip = addr;
+                    self.dbg.clear_history();
+                },
+                Err(_) => return "E01".to_string(),
+            }
+        }
+        let stop = if single {
+            self.dbg.step(1)
+        } else {
+            loop {
+                match self.dbg.step(POLL_INTERVAL) {
+                    Stop::Step => {
+                        if self.conn.interrupted() {
+                            return "S02".to_string();
+                        }
+                    },
+                    stop => break stop,
+                }
+            }
+        };
+        return self.stop_reply(stop, single);
+    }
+
+    /*
+     * bs/bc, step or continue backwards through the history.
+     */
+    fn reverse(&mut self, packet: &str) -> String {
+        let stop = match packet {
+            "bs" => self.dbg.reverse_step(1),
+            "bc" => self.dbg.reverse_cont(),
+            _ => return String::new(),
+        };
+        return self.stop_reply(stop, packet == "bs");
+    }
+
+    fn stop_reply(&mut self, stop: Stop, single: bool) -> String {
+        // logging watches show up on the gdb console
+        for hit in self.dbg.cpu_mut().watches.take_log() {
+            if hit.action == watch::Action::Log {
+                let text = format!("Watch {}: {}\n", hit.watch + 1, hit);
+                let _ = self.send_packet(&format!("O{}", encode_hex(text.as_bytes())));
+            }
+        }
+
+        match stop {
+            Stop::Exited => return "W00".to_string(),
+            Stop::HistoryStart => return "T05replaylog:begin;".to_string(),
+            Stop::Breakpoint(addr) if !single => {
+                if !self.report_break_kind {
+                    return "S05".to_string();
+                }
+                if self.hw_breaks.contains(&addr) && !self.sw_breaks.contains(&addr) {
+                    return "T05hwbreak:;".to_string();
+                }
+                return "T05swbreak:;".to_string();
+            },
+            Stop::Watchpoint(hit) => {
+                let (addr, is_write) = match hit.access {
+                    watch::Access::Mem { addr, is_write, .. } => (addr, is_write),
+                    // register watches are not something gdb knows about
+                    watch::Access::Reg(_) => return "S05".to_string(),
+                };
+                let kind = match self.dbg.cpu().watches.iter().find(|(num, _)| *num == hit.watch) {
+                    Some((_, watch::Watch { target: watch::Target::Mem { kind, .. }, .. })) => kind,
+                    _ => if is_write {watch::WatchKind::Write} else {watch::WatchKind::Read},
+                };
+                let name = match kind {
+                    watch::WatchKind::Write => "watch",
+                    watch::WatchKind::Read => "rwatch",
+                    watch::WatchKind::Access => "awatch",
+                };
+                return format!("T05{}:{:08x};", name, addr);
+            },
+            _ => return "S05".to_string(),
+        }
+    }
+
+    /*
+     * Reads the next "$data#cs" packet, acking it unless no-ack mode is on.
+     * Returns None once the connection closes.
+     */
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            // skip acks, stray interrupts and junk up to the start of a packet
+            loop {
+                match self.read_byte()? {
+                    Some(b'$') => break,
+                    Some(_) => continue,
+                    None => return Ok(None),
+                }
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                    None => return Ok(None),
+                }
+            }
+            let mut cs = [0; 2];
+            for c in cs.iter_mut() {
+                *c = match self.read_byte()? {
+                    Some(byte) => byte,
+                    None => return Ok(None),
+                };
+            }
+            let expected = u8::from_str_radix(&String::from_utf8_lossy(&cs), 16).ok();
+            let sum = data.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+            if expected != Some(sum) && !self.no_ack {
+                self.conn.write_all(b"-")?;
+                self.conn.flush()?;
+                continue;
+            }
+            if !self.no_ack {
+                self.conn.write_all(b"+")?;
+            }
+            return Ok(Some(String::from_utf8_lossy(&unescape(&data)).to_string()));
+        }
+    }
+
+    fn send_packet(&mut self, data: &str) -> io::Result<()> {
+        let body = escape(data.as_bytes());
+        let sum = body.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+        let mut out = vec![b'$'];
+        out.extend_from_slice(&body);
+        out.extend_from_slice(format!("#{:02x}", sum).as_bytes());
+        self.conn.write_all(&out)?;
+        self.conn.flush()
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0; 1];
+        loop {
+            match self.conn.read(&mut byte) {
+                Ok(0) => return Ok(None),
+                Ok(_) => return Ok(Some(byte[0])),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/*
+ * Waits for gdb to connect then serves it until it goes away.
+ */
+pub fn start(cpu: &mut Processor, instr_mem: &instr_mem::Memory, bus: &mut bus::Bus,
+             symbols: &SymbolTable, transport: &Transport, history: HistoryConfig) {
+    let conn: Box<dyn Connection> = match transport {
+        Transport::Stdio => Box::new(Stdio),
+        Transport::Tcp(addr) => {
+            let listener = match TcpListener::bind(addr) {
+                Ok(listener) => listener,
+                Err(e) => panic!("Gdb-Error: cannot listen on {}: {}", addr, e),
+            };
+            eprintln!("Waiting for gdb on {}...", addr);
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    let _ = stream.set_nodelay(true);
+                    Box::new(stream)
+                },
+                Err(e) => panic!("Gdb-Error: accept failed: {}", e),
+            }
+        },
+    };
+    let mut dbg = Debugger::new(cpu, instr_mem, bus, symbols);
+    dbg.set_history(history);
+    let mut stub = GdbStub::new(dbg, conn);
+    if let Err(e) = stub.serve() {
+        eprintln!("Gdb-Error: {}", e);
+    }
+}
+
+/*
+ * Target description, gdb needs the cpu, cp0 and fpu features to accept a
+ * mips target.
+ */
+pub fn target_xml() -> String {
+    let mut xml = String::new();
+    xml += "<?xml version=\"1.0\"?>\n";
+    xml += "<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n";
+    xml += "<target version=\"1.0\">\n";
+    xml += "  <architecture>mips</architecture>\n";
+    xml += "  <feature name=\"org.gnu.gdb.mips.cpu\">\n";
+    // gdb finds the gprs by their numeric names
+    for i in 0..32 {
+        xml += &format!("    <reg name=\"r{}\" bitsize=\"32\" regnum=\"{}\"/>\n", i, i);
+    }
+    xml += "    <reg name=\"lo\" bitsize=\"32\" regnum=\"33\"/>\n";
+    xml += "    <reg name=\"hi\" bitsize=\"32\" regnum=\"34\"/>\n";
+    xml += "    <reg name=\"pc\" bitsize=\"32\" regnum=\"37\"/>\n";
+    xml += "  </feature>\n";
+    xml += "  <feature name=\"org.gnu.gdb.mips.cp0\">\n";
+    xml += "    <reg name=\"status\" bitsize=\"32\" regnum=\"32\"/>\n";
+    xml += "    <reg name=\"badvaddr\" bitsize=\"32\" regnum=\"35\"/>\n";
+    xml += "    <reg name=\"cause\" bitsize=\"32\" regnum=\"36\"/>\n";
+    xml += "  </feature>\n";
+    xml += "  <feature name=\"org.gnu.gdb.mips.fpu\">\n";
+    for i in 0..32 {
+        xml += &format!("    <reg name=\"f{}\" bitsize=\"32\" type=\"ieee_single\" regnum=\"{}\"/>\n", i, 38 + i);
+    }
+    xml += "    <reg name=\"fcsr\" bitsize=\"32\" group=\"float\" regnum=\"70\"/>\n";
+    xml += "    <reg name=\"fir\" bitsize=\"32\" group=\"float\" regnum=\"71\"/>\n";
+    xml += "  </feature>\n";
+    xml += "</target>\n";
+    return xml;
+}
+
+fn read_target_xml(args: &str) -> String {
+    // target.xml:<offset>,<length>
+    let (annex, range) = match args.split_once(':') {
+        Some(pair) => pair,
+        None => return "E01".to_string(),
+    };
+    if annex != "target.xml" {
+        return "E00".to_string();
+    }
+    let (offset, len) = match parse_addr_len(range) {
+        Some((offset, len)) => (offset as usize, len as usize),
+        None => return "E01".to_string(),
+    };
+    let xml = target_xml();
+    if offset >= xml.len() {
+        return "l".to_string();
+    }
+    let end = (offset + len).min(xml.len());
+    let marker = if end == xml.len() {"l"} else {"m"};
+    return format!("{}{}", marker, &xml[offset..end]);
+}
+
+fn parse_addr_len(args: &str) -> Option<(u32, u32)> {
+    let (addr, len) = args.split_once(',')?;
+    let addr = u32::from_str_radix(addr, 16).ok()?;
+    let len = u32::from_str_radix(len, 16).ok()?;
+    return Some((addr, len));
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/*
+ * '#', '$', '}' and '*' are sent as '}' followed by the byte xor 0x20.
+ */
+fn escape(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for byte in data {
+        match byte {
+            b'#' | b'$' | b'}' | b'*' => {
+                out.push(b'}');
+                out.push(byte ^ 0x20);
+            },
+            _ => out.push(*byte),
+        }
+    }
+    return out;
+}
+
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut bytes = data.iter();
+    while let Some(byte) = bytes.next() {
+        if *byte == b'}' {
+            if let Some(next) = bytes.next() {
+                out.push(next ^ 0x20);
+            }
+        } else {
+            out.push(*byte);
+        }
+    }
+    return out;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::demos::assembler::reg_macros::*;
+    use crate::demos::assembler::instr_macros::*;
+    use crate::demos::assembler::mem_loader::MemLoader;
+    use crate::single_cycle::Options;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+
+    // gdb's side of the connection
+    #[derive(Clone)]
+    struct Pipe {
+        input: Rc<RefCell<VecDeque<u8>>>,
+        output: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut input = self.input.borrow_mut();
+            let n = buf.len().min(input.len());
+            for b in buf.iter_mut().take(n) {
+                *b = input.pop_front().unwrap();
+            }
+            Ok(n)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for Pipe {}
+
+    fn program() -> instr_mem::Memory {
+        let mut loader = MemLoader::new(instr_mem::Memory::new());
+        loader.load_instr( ORI (T0(), ZERO(), 0xbabe) );
+        loader.load_instr( LUI (T1(),         0xcafe) );
+        loader.load_instr( OR  (T0(), T1()  , T0()  ) );
+        loader.load_instr( ADDI(S0(), ZERO(), 0x42  ) );
+        loader.load_instr( SWL (T0(), 0     , S0()  ) );
+        loader.load_instr( SWR (T0(), 3     , S0()  ) );
+        return loader.return_mem();
+    }
+
+    fn pipe() -> Pipe {
+        Pipe {
+            input: Rc::new(RefCell::new(VecDeque::new())),
+            output: Rc::new(RefCell::new(Vec::new())),
+        }
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+        let mem = program();
+        let symbols = SymbolTable::new();
+        let mut data_mem = data_mem::Memory::new();
+        let mut bus = bus::Bus::new(&mut data_mem);
+        let mut cpu = Processor::new(&Options::default());
+        let dbg = Debugger::new(&mut cpu, &mem, &mut bus, &symbols);
+        let mut stub = GdbStub::new(dbg, Box::new(pipe()));
+
+        assert_eq!(stub.handle("?").unwrap(), "S05");
+        assert_eq!(stub.handle("g").unwrap().len(), NUM_REGS * 8);
+        assert_eq!(stub.handle("P8=00000010").unwrap(), "OK");
+        assert_eq!(stub.handle("p8").unwrap(), "00000010");
+        assert_eq!(stub.handle("P21=12345678").unwrap(), "OK"); // lo
+        assert_eq!(stub.handle("p21").unwrap(), "12345678");
+        assert_eq!(stub.handle("p25").unwrap(), "00000000"); // pc
+        assert_eq!(stub.handle("p48").unwrap(), "E01");
+
+        assert_eq!(stub.handle("M10,4:deadbeef").unwrap(), "OK");
+        assert_eq!(stub.handle("m10,4").unwrap(), "deadbeef");
+        assert_eq!(stub.handle("mfe,4").unwrap(), "0000");
+        assert_eq!(stub.handle("m100,4").unwrap(), "E14");
+
+        // instruction memory view
+        stub.handle(&format!("qRcmd,{}", encode_hex(b"code")));
+        assert_eq!(stub.handle("m4,4").unwrap(), "3c09cafe");
+        assert_eq!(stub.handle("M4,1:00").unwrap(), "E14");
+        stub.handle(&format!("qRcmd,{}", encode_hex(b"data")));
+        assert_eq!(stub.handle("m10,2").unwrap(), "dead");
+
+        let xml = stub.handle("qXfer:features:read:target.xml:0,2000").unwrap();
+        assert!(xml.starts_with("l<?xml"));
+        assert!(xml.contains("org.gnu.gdb.mips.cpu"));
+        assert_eq!(stub.handle("qXfer:features:read:target.xml:0,5").unwrap(), "m<?xml");
+    }
+
+    #[test]
+    fn test_step_and_breakpoints() {
+        let mem = program();
+        let symbols = SymbolTable::new();
+        let mut data_mem = data_mem::Memory::new();
+        let mut bus = bus::Bus::new(&mut data_mem);
+        let mut cpu = Processor::new(&Options::default());
+        let dbg = Debugger::new(&mut cpu, &mem, &mut bus, &symbols);
+        let mut stub = GdbStub::new(dbg, Box::new(pipe()));
+
+        stub.handle("qSupported:multiprocess+;swbreak+;hwbreak+");
+        assert_eq!(stub.handle("s").unwrap(), "S05");
+        assert_eq!(stub.handle("p25").unwrap(), "00000004");
+        assert_eq!(stub.handle("p8").unwrap(), "0000babe");
+
+        assert_eq!(stub.handle("Z1,c,4").unwrap(), "OK");
+        assert_eq!(stub.handle("Z0,10,4").unwrap(), "OK");
+        assert_eq!(stub.handle("vCont;c").unwrap(), "T05hwbreak:;");
+        assert_eq!(stub.handle("p25").unwrap(), "0000000c");
+        assert_eq!(stub.handle("c").unwrap(), "T05swbreak:;");
+        assert_eq!(stub.handle("z0,10,4").unwrap(), "OK");
+        assert_eq!(stub.handle("z1,c,4").unwrap(), "OK");
+
+        // resume at an address
+        assert_eq!(stub.handle("s0").unwrap(), "S05");
+        assert_eq!(stub.handle("p25").unwrap(), "00000004");
+        assert_eq!(stub.handle("c").unwrap(), "W00");
+        assert_eq!(stub.handle("m42,4").unwrap(), "cafebabe");
+        assert_eq!(stub.handle("k"), None);
+        assert!(stub.is_done());
+    }
+
+    #[test]
+    fn test_framing() {
+        let mem = program();
+        let symbols = SymbolTable::new();
+        let mut data_mem = data_mem::Memory::new();
+        let mut bus = bus::Bus::new(&mut data_mem);
+        let mut cpu = Processor::new(&Options::default());
+        let dbg = Debugger::new(&mut cpu, &mem, &mut bus, &symbols);
+        let conn = pipe();
+        let mut stub = GdbStub::new(dbg, Box::new(conn.clone()));
+
+        // bad checksum is nacked, then the retry is answered
+        conn.input.borrow_mut().extend(b"+$?#00$?#3f$QStartNoAckMode#b0$p25#d7$D#44".iter());
+        stub.serve().unwrap();
+        assert_eq!(String::from_utf8_lossy(&conn.output.borrow()),
+                   "-+$S05#b8+$OK#9a$00000000#80$OK#9a");
+        assert_eq!(escape(b"a}b#"), b"a}]b}\x03".to_vec());
+        assert_eq!(unescape(b"a}]b}\x03"), b"a}b#".to_vec());
+    }
+
+    #[test]
+    fn test_transport() {
+        assert_eq!(Transport::parse("stdio"), Ok(Transport::Stdio));
+        assert_eq!(Transport::parse("1234"), Ok(Transport::Tcp("127.0.0.1:1234".to_string())));
+        assert_eq!(Transport::parse("0.0.0.0:2000"), Ok(Transport::Tcp("0.0.0.0:2000".to_string())));
+        assert!(Transport::parse("nope").is_err());
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mem = program();
+        let symbols = SymbolTable::new();
+        let mut data_mem = data_mem::Memory::new();
+        let mut bus = bus::Bus::new(&mut data_mem);
+        let mut cpu = Processor::new(&Options::default());
+        let dbg = Debugger::new(&mut cpu, &mem, &mut bus, &symbols);
+        let mut stub = GdbStub::new(dbg, Box::new(pipe()));
+
+        assert_eq!(stub.handle("Z2,42,2").unwrap(), "OK");
+        // swl writes the aligned word holding 0x42
+        assert_eq!(stub.handle("c").unwrap(), "T05watch:00000040;");
+        assert_eq!(stub.handle("p25").unwrap(), "00000014");
+        assert_eq!(stub.handle("z2,42,2").unwrap(), "OK");
+        assert_eq!(stub.handle("Z4,40,1").unwrap(), "OK");
+        assert_eq!(stub.handle("Z2,40,0").unwrap(), "E01");
+        assert_eq!(stub.handle("c").unwrap(), "W00");
+    }
+
+    #[test]
+    fn test_reverse() {
+        let mem = program();
+        let symbols = SymbolTable::new();
+        let mut data_mem = data_mem::Memory::new();
+        let mut bus = bus::Bus::new(&mut data_mem);
+        let mut cpu = Processor::new(&Options::default());
+        let dbg = Debugger::new(&mut cpu, &mem, &mut bus, &symbols);
+        let mut stub = GdbStub::new(dbg, Box::new(pipe()));
+
+        assert!(stub.handle("qSupported:swbreak+").unwrap().contains("ReverseStep+;ReverseContinue+"));
+        assert_eq!(stub.handle("s").unwrap(), "S05");
+        assert_eq!(stub.handle("s").unwrap(), "S05");
+        assert_eq!(stub.handle("p25").unwrap(), "00000008");
+        assert_eq!(stub.handle("bs").unwrap(), "S05");
+        assert_eq!(stub.handle("p25").unwrap(), "00000004");
+        assert_eq!(stub.handle("bc").unwrap(), "T05replaylog:begin;");
+        assert_eq!(stub.handle("p25").unwrap(), "00000000");
+    }
+}
@@ -9,15 +9,43 @@
  *  0) AND
  *  1) OR
  *  2) ADD
- *  3) XOR
+ *  3) LESS (set on less than, signed)
+ *  4) XOR
+ *  5) LESS UNSIGNED (set on less than, unsigned)
+ *  6) SHIFT LEFT
+ *  7) SHIFT RIGHT (logical)
+ *  8) SHIFT RIGHT ARITHMETIC
+ *  9) MULTIPLY (low word)
+ * 10) MULTIPLY HIGH (high word, each input signed or unsigned)
+ * 11) DIVIDE (signed or unsigned)
+ * 12) REMAINDER (signed or unsigned)
+ * 13) COUNT LEADING ZEROS or ONES (of the first input)
+ * 14) SIGN EXTEND a byte or half word (of the second input)
+ * 15) SWAP BYTES in each half word (of the second input)
+ * 16) ROTATE RIGHT (the second input by the low 5 bits of the first)
+ * 17) EXTRACT a bit field of the first input
+ * 18) INSERT the first input into a bit field
+ *
+ * The bit field of extract and insert is the second input, its lowest bit
+ * in bits 0-4 and its size - 1 (extract) or its highest bit (insert) in
+ * bits 5-9.
+ * Subtraction adds the inverted second input with a carry in of 1. The
+ * shifts shift the first input by the low 5 bits of the second. Dividing
+ * never traps: by zero the quotient is all ones and the remainder the
+ * first input, the overflowing -2^31 / -1 gives -2^31 remainder 0 (the
+ * RISC-V M results).
  */
 #![allow(dead_code)]
 
-pub struct Alu(u32, u32);
+pub struct Alu(u32, u32, u32);
 
 impl Alu {
     pub fn new(in1: u32, in2: u32) -> Alu {
-        Alu(in1, in2)
+        Alu(in1, in2, 0)
+    }
+
+    pub fn with_carry(in1: u32, in2: u32, carry_in: u32) -> Alu {
+        Alu(in1, in2, carry_in & 0x1)
     }
 
     // op = 0
@@ -31,22 +59,123 @@ impl Alu {
     }
 
     // op = 2
-    pub fn add(self) -> u32 {
-        self.0.overflowing_add(self.1).0
+    pub fn add(&self) -> u32 {
+        self.0.wrapping_add(self.1).wrapping_add(self.2)
     }
 
-    // op = 3
+    // op = 3, the sign of the sum unless the sum overflowed
     pub fn less(self) -> u32 {
-        self.add() >> 31 
+        let overflow = if self.overflow() {1} else {0};
+        (self.add() >> 31) ^ overflow
     }
 
     // op = 4
     pub fn xor(self) -> u32 {
         self.0 ^ self.1
     }
+
+    // op = 5, subtracting borrows when there is no carry out of the sum
+    pub fn less_unsigned(self) -> u32 {
+        if self.carry_out() {0} else {1}
+    }
+
+    // op = 6
+    pub fn shift_left(self) -> u32 {
+        self.0 << (self.1 & 0x1f)
+    }
+
+    // op = 7
+    pub fn shift_right(self) -> u32 {
+        self.0 >> (self.1 & 0x1f)
+    }
+
+    // op = 8, copies of the sign bit shift in
+    pub fn shift_right_arith(self) -> u32 {
+        ((self.0 as i32) >> (self.1 & 0x1f)) as u32
+    }
+
+    // op = 9
+    pub fn multiply(self) -> u32 {
+        self.0.wrapping_mul(self.1)
+    }
+
+    // op = 10, the upper 32 bits of the 64 bit product
+    pub fn multiply_high(self, signed1: bool, signed2: bool) -> u32 {
+        let extend = |val: u32, signed: bool| if signed {val as i32 as i64} else {val as i64};
+        (extend(self.0, signed1).wrapping_mul(extend(self.1, signed2)) >> 32) as u32
+    }
+
+    // op = 11
+    pub fn divide(self, signed: bool) -> u32 {
+        if self.1 == 0 {
+            return 0xffff_ffff;
+        }
+        if signed {(self.0 as i32).wrapping_div(self.1 as i32) as u32} else {self.0 / self.1}
+    }
+
+    // op = 12
+    pub fn remainder(self, signed: bool) -> u32 {
+        if self.1 == 0 {
+            return self.0;
+        }
+        if signed {(self.0 as i32).wrapping_rem(self.1 as i32) as u32} else {self.0 % self.1}
+    }
+
+    // op = 13
+    pub fn count_leading(self, ones: bool) -> u32 {
+        if ones {self.0.leading_ones()} else {self.0.leading_zeros()}
+    }
+
+    // op = 14, from the low 8 or 16 bits
+    pub fn sign_extend(self, bits: u32) -> u32 {
+        let shift = 32 - bits;
+        (((self.1 << shift) as i32) >> shift) as u32
+    }
+
+    // op = 15
+    pub fn swap_bytes(self) -> u32 {
+        ((self.1 & 0x00ff_00ff) << 8) | ((self.1 >> 8) & 0x00ff_00ff)
+    }
+
+    // op = 16
+    pub fn rotate_right(self) -> u32 {
+        self.1.rotate_right(self.0 & 0x1f)
+    }
+
+    // op = 17, the field moved down to bit 0
+    pub fn extract(self) -> u32 {
+        let (lsb, size) = (self.1 & 0x1f, ((self.1 >> 5) & 0x1f) + 1);
+        (((self.0 as u64) >> lsb) & ((1 << size) - 1)) as u32
+    }
+
+    // op = 18, the low bits of the first input moved up into the field, the
+    // bits outside of it are 0 (empty when the highest bit is below the lowest)
+    pub fn insert(self) -> u32 {
+        let (lsb, msb) = (self.1 & 0x1f, (self.1 >> 5) & 0x1f);
+        if msb < lsb {
+            return 0;
+        }
+        let mask = (((1u64 << (msb + 1 - lsb)) - 1) << lsb) as u32;
+        (self.0 << lsb) & mask
+    }
+
+    pub fn carry_out(&self) -> bool {
+        (self.0 as u64 + self.1 as u64 + self.2 as u64) >> 32 == 1
+    }
+
+    /*
+     * Signed overflow of the sum: both inputs have the same sign and the
+     * sum has the other one.
+     */
+    pub fn overflow(&self) -> bool {
+        let sum = self.add();
+        ((self.0 ^ sum) & (self.1 ^ sum)) >> 31 == 1
+    }
 }
 
+// the tests spell out the operation they expect, 0b0 & 0b1 included
 #[cfg(test)]
+#[allow(clippy::identity_op, clippy::erasing_op, clippy::unnecessary_cast, clippy::legacy_numeric_constants)]
 mod tests {
     use super::*;
     use std::u32;
@@ -100,6 +229,53 @@ mod tests {
         assert_eq!(alu_ovrflw.add(), u32::MAX.overflowing_add(u32::MAX).0)
     }
 
+    #[test]
+    fn test_shifts() {
+        assert_eq!(Alu::new(0x8000_0001, 4).shift_left(), 0x10);
+        assert_eq!(Alu::new(0x8000_0010, 4).shift_right(), 0x0800_0001);
+        assert_eq!(Alu::new(0x8000_0010, 4).shift_right_arith(), 0xf800_0001);
+        // only the low 5 bits of the amount count
+        assert_eq!(Alu::new(1, 33).shift_left(), 2);
+        assert_eq!(Alu::new(0x8000_0000, 0xffff_ffff).shift_right_arith(), 0xffff_ffff);
+    }
+
+    #[test]
+    fn test_bit_fields() {
+        assert_eq!(Alu::new(4, 0x1234_5678).rotate_right(), 0x8123_4567);
+        assert_eq!(Alu::new(36, 0x1234_5678).rotate_right(), 0x8123_4567);
+        // 8 bits from bit 4
+        assert_eq!(Alu::new(0x1234_5678, (7 << 5) | 4).extract(), 0x67);
+        assert_eq!(Alu::new(0x1234_5678, 31 << 5).extract(), 0x1234_5678);
+        // bits 4 to 11
+        assert_eq!(Alu::new(0xffff_ffab, (11 << 5) | 4).insert(), 0xab0);
+        assert_eq!(Alu::new(0xffff_ffff, 31 << 5).insert(), 0xffff_ffff);
+        assert_eq!(Alu::new(0xffff_ffff, (3 << 5) | 4).insert(), 0);
+    }
+
+    #[test]
+    fn test_multiply() {
+        assert_eq!(Alu::new(6, 7).multiply(), 42);
+        assert_eq!(Alu::new(0xffff_ffff, 2).multiply(), 0xffff_fffe);
+        // -1 * 2 as signed, unsigned and mixed
+        assert_eq!(Alu::new(0xffff_ffff, 2).multiply_high(true, true), 0xffff_ffff);
+        assert_eq!(Alu::new(0xffff_ffff, 2).multiply_high(false, false), 1);
+        assert_eq!(Alu::new(0xffff_ffff, 2).multiply_high(true, false), 0xffff_ffff);
+        assert_eq!(Alu::new(0x8000_0000, 0x8000_0000).multiply_high(true, true), 0x4000_0000);
+    }
+
+    #[test]
+    fn test_divide() {
+        assert_eq!(Alu::new((-7i32) as u32, 2).divide(true), (-3i32) as u32);
+        assert_eq!(Alu::new((-7i32) as u32, 2).remainder(true), (-1i32) as u32);
+        assert_eq!(Alu::new(7, 2).divide(false), 3);
+        assert_eq!(Alu::new(0xffff_fff9, 2).remainder(false), 1);
+        // by zero and overflow
+        assert_eq!(Alu::new(5, 0).divide(true), 0xffff_ffff);
+        assert_eq!(Alu::new(5, 0).remainder(false), 5);
+        assert_eq!(Alu::new(0x8000_0000, 0xffff_ffff).divide(true), 0x8000_0000);
+        assert_eq!(Alu::new(0x8000_0000, 0xffff_ffff).remainder(true), 0);
+    }
+
     #[test]
     fn test_less() {
         let alu1 = Alu::new(0b1, !0b1 + 1);
@@ -119,6 +295,35 @@ mod tests {
 
         let alu_big2 = Alu::new(1234, !12223 + 1);
         assert_eq!(alu_big2.less(), 1);
+
+        // the sign of the difference is wrong when it overflows
+        let alu_ovrflw1 = Alu::with_carry(0x7fff_ffff, !0xffff_ffff, 1);
+        assert_eq!(alu_ovrflw1.less(), 0);
+
+        let alu_ovrflw2 = Alu::with_carry(0, !0x8000_0000, 1);
+        assert_eq!(alu_ovrflw2.less(), 0);
+
+        let alu_ovrflw3 = Alu::with_carry(0x8000_0000, !1, 1);
+        assert_eq!(alu_ovrflw3.less(), 1);
+    }
+
+    #[test]
+    fn test_overflow() {
+        assert!(Alu::new(0x7fff_ffff, 1).overflow());
+        assert!(Alu::new(0x8000_0000, 0x8000_0000).overflow());
+        assert!(!Alu::new(0x7fff_ffff, 0x8000_0000).overflow());
+        assert!(Alu::with_carry(0x8000_0000, !1, 1).overflow());
+        assert!(!Alu::with_carry(5, !7, 1).overflow());
+    }
+
+    #[test]
+    fn test_less_unsigned() {
+        assert_eq!(Alu::with_carry(1, !2, 1).less_unsigned(), 1);
+        assert_eq!(Alu::with_carry(2, !2, 1).less_unsigned(), 0);
+        assert_eq!(Alu::with_carry(0xffff_ffff, !1, 1).less_unsigned(), 0);
+        assert_eq!(Alu::with_carry(1, !0xffff_ffff, 1).less_unsigned(), 1);
+        // slt sees -1 < 1 in the same inputs
+        assert_eq!(Alu::with_carry(0xffff_ffff, !1, 1).less(), 1);
     }
 
     #[test]
@@ -135,4 +340,14 @@ mod tests {
         let alu_big = Alu::new(3452, 23555);
         assert_eq!(alu_big.xor(), 3452 ^ 23555);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_bit_ops() {
+        assert_eq!(Alu::new(0x0000_8000, 0).count_leading(false), 16);
+        assert_eq!(Alu::new(0, 0).count_leading(false), 32);
+        assert_eq!(Alu::new(0xfff0_0000, 0).count_leading(true), 12);
+        assert_eq!(Alu::new(0, 0x0000_0080).sign_extend(8), 0xffff_ff80);
+        assert_eq!(Alu::new(0, 0x1234_7fff).sign_extend(16), 0x0000_7fff);
+        assert_eq!(Alu::new(0, 0x1122_3344).swap_bytes(), 0x2211_4433);
+    }
+}
@@ -0,0 +1,316 @@
+/*
+ * bus.rs
+ *
+ * Author: Travis Banken
+ *
+ * Data bus between the cpu and data memory. Devices can claim a range of
+ * (physical) addresses, loads and stores inside that range go to the device
+ * instead of memory (memory mapped I/O).
+ */
+#![allow(dead_code)]
+
+use super::data_mem;
+
+/*
+ * A memory mapped device. Offsets are relative to the base address the
+ * device was attached at, size is 1 (byte) or 4 (word).
+ */
+pub trait Device {
+    fn name(&self) -> &str;
+
+    // number of bytes claimed
+    fn size(&self) -> u32;
+
+    fn read(&mut self, offset: u32, size: u32) -> u32;
+
+    fn write(&mut self, offset: u32, size: u32, value: u32);
+
+    // called once per instruction, for devices that keep time
+    fn tick(&mut self) {}
+
+    // is the device asking for an interrupt?
+    fn irq(&self) -> bool {
+        false
+    }
+
+    // internal state for snapshots, devices without state save nothing
+    fn save(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn restore(&mut self, _state: &[u8]) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+struct Mapping {
+    base: u32,
+    device: Box<dyn Device>,
+    // interrupt controller line, if the device is wired to one
+    irq_line: Option<usize>,
+}
+
+pub struct Bus<'a> {
+    mem: &'a mut data_mem::Memory,
+    devices: Vec<Mapping>,
+}
+
+impl<'a> Bus<'a> {
+    pub fn new(mem: &'a mut data_mem::Memory) -> Bus<'a> {
+        Bus {
+            mem,
+            devices: Vec::new(),
+        }
+    }
+
+    /*
+     * Claim the address range [base, base + size) for the device.
+     *
+     * Will panic if the range overlaps another device.
+     */
+    pub fn attach(&mut self, base: u32, device: Box<dyn Device>) {
+        self.map(base, device, None);
+    }
+
+    /*
+     * Same as attach but also wires the device to an interrupt line.
+     */
+    pub fn attach_irq(&mut self, base: u32, device: Box<dyn Device>, line: usize) {
+        self.map(base, device, Some(line));
+    }
+
+    /*
+     * Advance every device by one instruction. Returns the raised interrupt
+     * lines as a bit mask (bit n = line n).
+     */
+    pub fn tick(&mut self) -> u8 {
+        let mut lines = 0;
+        for m in self.devices.iter_mut() {
+            m.device.tick();
+            if let Some(line) = m.irq_line {
+                if m.device.irq() {
+                    lines |= 1 << line;
+                }
+            }
+        }
+        return lines;
+    }
+
+    /*
+     * (base, name, state) of every device, in the order they were attached.
+     */
+    pub fn save_devices(&self) -> Vec<(u32, String, Vec<u8>)> {
+        self.devices.iter()
+            .map(|m| (m.base, m.device.name().to_string(), m.device.save()))
+            .collect()
+    }
+
+    /*
+     * Restores the state saved by save_devices, the same devices must be
+     * attached at the same addresses.
+     */
+    pub fn restore_devices(&mut self, states: &[(u32, String, Vec<u8>)]) -> Result<(), String> {
+        for (base, name, state) in states.iter() {
+            let m = self.devices.iter_mut().find(|m| m.base == *base && m.device.name() == name);
+            match m {
+                Some(m) => m.device.restore(state)?,
+                None => return Err(format!("Bus-Error: no {} attached at 0x{:08x}", name, base)),
+            }
+        }
+        return Ok(());
+    }
+
+    /*
+     * Returns true if addr belongs to a device rather than memory.
+     */
+    pub fn is_device(&self, addr: u32) -> bool {
+        self.find(addr).is_some()
+    }
+
+    pub fn mem(&self) -> &data_mem::Memory {
+        self.mem
+    }
+
+    pub fn mem_mut(&mut self) -> &mut data_mem::Memory {
+        self.mem
+    }
+
+    pub fn read_byte(&mut self, addr: u32) -> u8 {
+        match self.find(addr) {
+            Some(i) => {
+                let offset = addr - self.devices[i].base;
+                self.devices[i].device.read(offset, 1) as u8
+            },
+            None => self.mem.read(addr as usize),
+        }
+    }
+
+    pub fn write_byte(&mut self, value: u8, addr: u32) {
+        match self.find(addr) {
+            Some(i) => {
+                let offset = addr - self.devices[i].base;
+                self.devices[i].device.write(offset, 1, value as u32);
+            },
+            None => self.mem.write(value, addr as usize),
+        }
+    }
+
+    /*
+     * Reads a big endian word.
+     */
+    pub fn read_word(&mut self, addr: u32) -> u32 {
+        if let Some(i) = self.find(addr) {
+            let offset = addr - self.devices[i].base;
+            return self.devices[i].device.read(offset, 4);
+        }
+        let read0 = self.mem.read(addr as usize) as u32;
+        let read1 = self.mem.read(addr as usize + 1) as u32;
+        let read2 = self.mem.read(addr as usize + 2) as u32;
+        let read3 = self.mem.read(addr as usize + 3) as u32;
+
+        return (read0 << 24) | (read1 << 16) | (read2 << 8) | read3;
+    }
+
+    /*
+     * Writes a big endian word.
+     */
+    pub fn write_word(&mut self, val: u32, addr: u32) {
+        if let Some(i) = self.find(addr) {
+            let offset = addr - self.devices[i].base;
+            self.devices[i].device.write(offset, 4, val);
+            return;
+        }
+        self.mem.write((val >> 24) as u8, addr as usize);
+        self.mem.write((val >> 16) as u8, addr as usize + 1);
+        self.mem.write((val >> 8) as u8, addr as usize + 2);
+        self.mem.write(val as u8, addr as usize + 3);
+    }
+
+    // *** PRIVATE FN ***
+
+    fn map(&mut self, base: u32, device: Box<dyn Device>, irq_line: Option<usize>) {
+        let end = base as u64 + device.size() as u64;
+        for other in self.devices.iter() {
+            let other_end = other.base as u64 + other.device.size() as u64;
+            if (base as u64) < other_end && (other.base as u64) < end {
+                panic!("Bus-Error: {} at 0x{:x} overlaps {} at 0x{:x}",
+                       device.name(), base, other.device.name(), other.base);
+            }
+        }
+        self.devices.push(Mapping { base, device, irq_line });
+    }
+
+    fn find(&self, addr: u32) -> Option<usize> {
+        self.devices.iter().position(|m| {
+            addr >= m.base && (addr as u64) < m.base as u64 + m.device.size() as u64
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // remembers the last write, reads return offset + size
+    struct Scratch {
+        last: Option<(u32, u32, u32)>,
+    }
+
+    impl Device for Scratch {
+        fn name(&self) -> &str {
+            "scratch"
+        }
+
+        fn size(&self) -> u32 {
+            0x10
+        }
+
+        fn read(&mut self, offset: u32, size: u32) -> u32 {
+            offset + size
+        }
+
+        fn write(&mut self, offset: u32, size: u32, value: u32) {
+            self.last = Some((offset, size, value));
+        }
+    }
+
+    #[test]
+    fn test_memory() {
+        let mut mem = data_mem::Memory::new();
+        let mut bus = Bus::new(&mut mem);
+        bus.write_word(0xdeadbeef, 0x10);
+        bus.write_byte(0x42, 0x20);
+        assert_eq!(bus.read_word(0x10), 0xdeadbeef);
+        assert_eq!(bus.read_byte(0x11), 0xad);
+        assert_eq!(bus.read_byte(0x20), 0x42);
+        assert_eq!(mem.read(0x13), 0xef);
+    }
+
+    #[test]
+    fn test_device() {
+        let mut mem = data_mem::Memory::new();
+        let mut bus = Bus::new(&mut mem);
+        bus.attach(0xffff_0000, Box::new(Scratch { last: None }));
+
+        assert!(bus.is_device(0xffff_000f));
+        assert!(!bus.is_device(0xffff_0010));
+        assert_eq!(bus.read_word(0xffff_0004), 8);
+        assert_eq!(bus.read_byte(0xffff_0004), 5);
+
+        // device writes never reach memory
+        bus.write_word(0x1234, 0xffff_0008);
+        assert_eq!(bus.read_word(0x8), 0);
+    }
+
+    // raises its irq every other tick
+    struct Ticker {
+        ticks: u32,
+    }
+
+    impl Device for Ticker {
+        fn name(&self) -> &str {
+            "ticker"
+        }
+
+        fn size(&self) -> u32 {
+            0x4
+        }
+
+        fn read(&mut self, _offset: u32, _size: u32) -> u32 {
+            self.ticks
+        }
+
+        fn write(&mut self, _offset: u32, _size: u32, _value: u32) {}
+
+        fn tick(&mut self) {
+            self.ticks += 1;
+        }
+
+        fn irq(&self) -> bool {
+            self.ticks.is_multiple_of(2)
+        }
+    }
+
+    #[test]
+    fn test_tick() {
+        let mut mem = data_mem::Memory::new();
+        let mut bus = Bus::new(&mut mem);
+        bus.attach_irq(0x1000, Box::new(Ticker { ticks: 0 }), 3);
+        // not wired to a line, never shows up in the mask
+        bus.attach(0x2000, Box::new(Ticker { ticks: 0 }));
+
+        assert_eq!(bus.tick(), 0);
+        assert_eq!(bus.tick(), 1 << 3);
+        assert_eq!(bus.read_word(0x1000), 2);
+        assert_eq!(bus.read_word(0x2000), 2);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_overlap() {
+        let mut mem = data_mem::Memory::new();
+        let mut bus = Bus::new(&mut mem);
+        bus.attach(0x1000, Box::new(Scratch { last: None }));
+        bus.attach(0x1008, Box::new(Scratch { last: None }));
+    }
+}
@@ -0,0 +1,712 @@
+/*
+ * cache.rs
+ *
+ * Author: Travis Banken
+ *
+ * Configurable cache model that sits between the cpu and a memory unit.
+ *
+ * The cache only keeps track of tags, the data itself always lives in the
+ * backing memory. Every access reports whether it hit, what kind of miss it
+ * was (compulsory, capacity or conflict) and which block it evicted. The
+ * time an access takes depends on the levels below the cache and is worked
+ * out by the hierarchy (see hierarchy.rs).
+ *
+ * Spec format (used by --l1i / --l1d / --l2):
+ *  size=64,block=8,assoc=2,repl=lru,write=back,alloc=yes,hit=1
+ *
+ *  size    total size in bytes
+ *  block   block size in bytes
+ *  assoc   number of ways per set
+ *  repl    lru | fifo | random | plru
+ *  write   back | through
+ *  alloc   yes | no (write allocate)
+ *  hit     hit latency in cycles
+ */
+#![allow(dead_code)]
+
+use std::collections::HashSet;
+use std::collections::VecDeque;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Replacement {
+    Lru,
+    Fifo,
+    Random,
+    Plru,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WritePolicy {
+    WriteBack,
+    WriteThrough,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MissKind {
+    Compulsory,
+    Capacity,
+    Conflict,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheConfig {
+    pub size: usize,
+    pub block_size: usize,
+    pub assoc: usize,
+    pub replacement: Replacement,
+    pub write_policy: WritePolicy,
+    pub write_allocate: bool,
+    pub hit_latency: u32,
+}
+
+impl Default for CacheConfig {
+    fn default() -> CacheConfig {
+        CacheConfig {
+            size: 64,
+            block_size: 8,
+            assoc: 2,
+            replacement: Replacement::Lru,
+            write_policy: WritePolicy::WriteBack,
+            write_allocate: true,
+            hit_latency: 1,
+        }
+    }
+}
+
+impl CacheConfig {
+    /*
+     * Builds a config from a spec string, see the header of this file for
+     * the format. Keys that are left out keep their default value.
+     */
+    pub fn parse(spec: &str) -> Result<CacheConfig, String> {
+        let mut config = CacheConfig::default();
+        for field in spec.split(',').filter(|f| !f.is_empty()) {
+            let mut kv = field.splitn(2, '=');
+            let key = kv.next().unwrap_or("").trim();
+            let val = match kv.next() {
+                Some(val) => val.trim(),
+                None => return Err(format!("Cache-Error: missing value for [{}]", key)),
+            };
+            match key {
+                "size" => config.size = parse_num(key, val)? as usize,
+                "block" => config.block_size = parse_num(key, val)? as usize,
+                "assoc" => config.assoc = parse_num(key, val)? as usize,
+                "hit" => config.hit_latency = parse_num(key, val)?,
+                "repl" => {
+                    config.replacement = match val {
+                        "lru" => Replacement::Lru,
+                        "fifo" => Replacement::Fifo,
+                        "random" => Replacement::Random,
+                        "plru" => Replacement::Plru,
+                        _ => return Err(format!("Cache-Error: unknown replacement policy [{}]", val)),
+                    }
+                },
+                "write" => {
+                    config.write_policy = match val {
+                        "back" => WritePolicy::WriteBack,
+                        "through" => WritePolicy::WriteThrough,
+                        _ => return Err(format!("Cache-Error: unknown write policy [{}]", val)),
+                    }
+                },
+                "alloc" => {
+                    config.write_allocate = match val {
+                        "yes" | "true" => true,
+                        "no" | "false" => false,
+                        _ => return Err(format!("Cache-Error: bad write allocate value [{}]", val)),
+                    }
+                },
+                _ => return Err(format!("Cache-Error: unknown cache option [{}]", key)),
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /*
+     * Checks that the geometry of the cache makes sense.
+     */
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.block_size.is_power_of_two() {
+            return Err(format!("Cache-Error: block size [{}] must be a power of two", self.block_size));
+        }
+        if self.assoc == 0 || !self.assoc.is_power_of_two() {
+            return Err(format!("Cache-Error: associativity [{}] must be a power of two", self.assoc));
+        }
+        if self.size == 0 || !self.size.is_multiple_of(self.block_size * self.assoc) {
+            return Err(format!("Cache-Error: size [{}] must be a multiple of block * assoc", self.size));
+        }
+        if !self.num_sets().is_power_of_two() {
+            return Err(format!("Cache-Error: number of sets [{}] must be a power of two", self.num_sets()));
+        }
+        // the plru tree of a set is held in 64 bits
+        if self.replacement == Replacement::Plru && self.assoc > 64 {
+            return Err(format!("Cache-Error: plru associativity [{}] must be at most 64", self.assoc));
+        }
+        Ok(())
+    }
+
+    pub fn num_lines(&self) -> usize {
+        self.size / self.block_size
+    }
+
+    pub fn num_sets(&self) -> usize {
+        self.num_lines() / self.assoc
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CacheStats {
+    pub reads: u64,
+    pub writes: u64,
+    pub hits: u64,
+    pub compulsory: u64,
+    pub capacity: u64,
+    pub conflict: u64,
+    pub evictions: u64,
+    pub writebacks: u64,
+    // total latency of the accesses that started at this cache
+    pub cycles: u64,
+}
+
+impl CacheStats {
+    pub fn accesses(&self) -> u64 {
+        self.reads + self.writes
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.compulsory + self.capacity + self.conflict
+    }
+
+    pub fn miss_rate(&self) -> f64 {
+        if self.accesses() == 0 {
+            return 0.0;
+        }
+        self.misses() as f64 / self.accesses() as f64
+    }
+}
+
+/*
+ * A block that was thrown out of the cache to make room for a new one.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Eviction {
+    // address of the first byte of the block
+    pub addr: u32,
+    pub dirty: bool,
+}
+
+/*
+ * Result of a single access.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Access {
+    pub hit: bool,
+    pub miss: Option<MissKind>,
+    pub evicted: Option<Eviction>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Line {
+    valid: bool,
+    dirty: bool,
+    tag: u32,
+    // last use for lru, fill time for fifo
+    stamp: u64,
+}
+
+#[derive(Clone)]
+pub struct Cache {
+    config: CacheConfig,
+    sets: Vec<Vec<Line>>,
+    // one tree of assoc - 1 bits per set for plru
+    plru: Vec<u64>,
+    // every block that has ever been brought in (compulsory misses)
+    seen: HashSet<u32>,
+    // fully associative lru cache of the same size (capacity vs conflict)
+    shadow: VecDeque<u32>,
+    clock: u64,
+    rng: u32,
+    stats: CacheStats,
+}
+
+impl Cache {
+    /*
+     * Creates an empty (cold) cache.
+     *
+     * Will panic if the config describes an impossible geometry.
+     */
+    pub fn new(config: CacheConfig) -> Cache {
+        if let Err(msg) = config.validate() {
+            panic!("{}", msg);
+        }
+        let sets = vec![vec![Line::default(); config.assoc]; config.num_sets()];
+        let plru = vec![0; config.num_sets()];
+        Cache {
+            config,
+            sets,
+            plru,
+            seen: HashSet::new(),
+            shadow: VecDeque::new(),
+            clock: 0,
+            rng: 0x2545_f491,
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub fn config(&self) -> &CacheConfig {
+        &self.config
+    }
+
+    pub fn stats(&self) -> &CacheStats {
+        &self.stats
+    }
+
+    /*
+     * Read the block holding addr.
+     */
+    pub fn read(&mut self, addr: u32) -> Access {
+        self.access(addr, false)
+    }
+
+    /*
+     * Write into the block holding addr.
+     */
+    pub fn write(&mut self, addr: u32) -> Access {
+        self.access(addr, true)
+    }
+
+    /*
+     * Looks up addr and updates the replacement state and the statistics.
+     * Misses bring the block in, unless it is a write miss and the cache
+     * does not write allocate.
+     */
+    pub fn access(&mut self, addr: u32, is_write: bool) -> Access {
+        let allocate = !is_write || self.config.write_allocate;
+        self.lookup_or_fill(addr, is_write, allocate)
+    }
+
+    /*
+     * Like access, but a miss never brings the block in.
+     */
+    pub fn probe(&mut self, addr: u32, is_write: bool) -> Access {
+        self.lookup_or_fill(addr, is_write, false)
+    }
+
+    /*
+     * Places the block holding addr into the cache without counting it as
+     * an access, e.g. a victim handed down from a higher level. Marks the
+     * block dirty if it is already present.
+     */
+    pub fn insert(&mut self, addr: u32, dirty: bool) -> Option<Eviction> {
+        let block_addr = self.block_addr(addr);
+        if let Some(way) = self.lookup(block_addr) {
+            let set = self.set_index(block_addr);
+            self.sets[set][way].dirty |= dirty;
+            return None;
+        }
+        self.clock += 1;
+        self.fill(block_addr, dirty)
+    }
+
+    /*
+     * Removes the block holding addr. Returns the removed block, if there
+     * was one.
+     */
+    pub fn invalidate(&mut self, addr: u32) -> Option<Eviction> {
+        let block_addr = self.block_addr(addr);
+        let way = self.lookup(block_addr)?;
+        let set = self.set_index(block_addr);
+        let line = self.sets[set][way];
+        self.sets[set][way] = Line::default();
+        Some(Eviction { addr: block_addr * self.config.block_size as u32, dirty: line.dirty })
+    }
+
+    /*
+     * Returns true if the block holding addr is currently in the cache. Does
+     * not count as an access.
+     */
+    pub fn contains(&self, addr: u32) -> bool {
+        self.lookup(self.block_addr(addr)).is_some()
+    }
+
+    /*
+     * Add to the time spent servicing accesses made to this cache.
+     */
+    pub fn add_cycles(&mut self, cycles: u32) {
+        self.stats.cycles += cycles as u64;
+    }
+
+    // *** PRIVATE FN ***
+
+    fn lookup_or_fill(&mut self, addr: u32, is_write: bool, allocate: bool) -> Access {
+        self.clock += 1;
+        if is_write {
+            self.stats.writes += 1;
+        } else {
+            self.stats.reads += 1;
+        }
+
+        let block_addr = self.block_addr(addr);
+        let shadow_hit = self.shadow.contains(&block_addr);
+        let write_back = self.config.write_policy == WritePolicy::WriteBack;
+
+        if let Some(way) = self.lookup(block_addr) {
+            self.stats.hits += 1;
+            let set = self.set_index(block_addr);
+            self.touch(set, way);
+            self.touch_shadow(block_addr);
+            if is_write && write_back {
+                self.sets[set][way].dirty = true;
+            }
+            return Access { hit: true, miss: None, evicted: None };
+        }
+
+        // a miss that does not allocate leaves seen and the shadow cache
+        // alone, they only follow the blocks actually brought in
+        let kind = if !self.seen.contains(&block_addr) {
+            MissKind::Compulsory
+        } else if shadow_hit {
+            MissKind::Conflict
+        } else {
+            MissKind::Capacity
+        };
+        match kind {
+            MissKind::Compulsory => self.stats.compulsory += 1,
+            MissKind::Capacity => self.stats.capacity += 1,
+            MissKind::Conflict => self.stats.conflict += 1,
+        }
+
+        let evicted = if allocate {
+            self.fill(block_addr, is_write && write_back)
+        } else {
+            None
+        };
+        Access { hit: false, miss: Some(kind), evicted }
+    }
+
+    fn block_addr(&self, addr: u32) -> u32 {
+        addr / self.config.block_size as u32
+    }
+
+    fn set_index(&self, block_addr: u32) -> usize {
+        (block_addr as usize) % self.config.num_sets()
+    }
+
+    fn tag(&self, block_addr: u32) -> u32 {
+        block_addr / self.config.num_sets() as u32
+    }
+
+    fn lookup(&self, block_addr: u32) -> Option<usize> {
+        let set = self.set_index(block_addr);
+        let tag = self.tag(block_addr);
+        self.sets[set].iter().position(|line| line.valid && line.tag == tag)
+    }
+
+    /*
+     * Bring the block into the cache, evicting a victim if the set is full.
+     */
+    fn fill(&mut self, block_addr: u32, dirty: bool) -> Option<Eviction> {
+        self.seen.insert(block_addr);
+        self.touch_shadow(block_addr);
+        let set = self.set_index(block_addr);
+        let way = self.victim(set);
+        let old = self.sets[set][way];
+        let evicted = if old.valid {
+            self.stats.evictions += 1;
+            if old.dirty {
+                self.stats.writebacks += 1;
+            }
+            let old_block = old.tag * self.config.num_sets() as u32 + set as u32;
+            Some(Eviction { addr: old_block * self.config.block_size as u32, dirty: old.dirty })
+        } else {
+            None
+        };
+
+        self.sets[set][way] = Line {
+            valid: true,
+            dirty,
+            tag: self.tag(block_addr),
+            stamp: self.clock,
+        };
+        self.touch_plru(set, way);
+        return evicted;
+    }
+
+    /*
+     * Picks the way to replace, free ways are always used first.
+     */
+    fn victim(&mut self, set: usize) -> usize {
+        if let Some(way) = self.sets[set].iter().position(|line| !line.valid) {
+            return way;
+        }
+        match self.config.replacement {
+            Replacement::Lru | Replacement::Fifo => {
+                let mut oldest = 0;
+                for (way, line) in self.sets[set].iter().enumerate() {
+                    if line.stamp < self.sets[set][oldest].stamp {
+                        oldest = way;
+                    }
+                }
+                oldest
+            },
+            Replacement::Random => {
+                // xorshift32
+                self.rng ^= self.rng << 13;
+                self.rng ^= self.rng >> 17;
+                self.rng ^= self.rng << 5;
+                (self.rng as usize) % self.config.assoc
+            },
+            Replacement::Plru => {
+                // follow the tree bits away from recently used halves
+                let bits = self.plru[set];
+                let mut node = 0;
+                let mut way = 0;
+                let mut span = self.config.assoc;
+                while span > 1 {
+                    span /= 2;
+                    let right = (bits >> node) & 1 == 1;
+                    if right {
+                        way += span;
+                        node = 2 * node + 2;
+                    } else {
+                        node = 2 * node + 1;
+                    }
+                }
+                way
+            },
+        }
+    }
+
+    /*
+     * Update replacement state after a hit.
+     */
+    fn touch(&mut self, set: usize, way: usize) {
+        if self.config.replacement == Replacement::Lru {
+            self.sets[set][way].stamp = self.clock;
+        }
+        self.touch_plru(set, way);
+    }
+
+    /*
+     * Point every tree node on the path to way towards it (bit set = left
+     * half was used last).
+     */
+    fn touch_plru(&mut self, set: usize, way: usize) {
+        let mut node = 0;
+        let mut lo = 0;
+        let mut span = self.config.assoc;
+        while span > 1 {
+            span /= 2;
+            if way < lo + span {
+                self.plru[set] |= 1 << node;
+                node = 2 * node + 1;
+            } else {
+                self.plru[set] &= !(1 << node);
+                lo += span;
+                node = 2 * node + 2;
+            }
+        }
+    }
+
+    /*
+     * Make the block the most recently used one of the fully associative
+     * shadow cache, bringing it in if it is not there.
+     */
+    fn touch_shadow(&mut self, block_addr: u32) {
+        if let Some(pos) = self.shadow.iter().position(|&b| b == block_addr) {
+            self.shadow.remove(pos);
+        }
+        self.shadow.push_front(block_addr);
+        if self.shadow.len() > self.config.num_lines() {
+            self.shadow.pop_back();
+        }
+    }
+}
+
+fn parse_num(key: &str, val: &str) -> Result<u32, String> {
+    match val.parse::<u32>() {
+        Ok(num) => Ok(num),
+        Err(_) => Err(format!("Cache-Error: bad number [{}] for [{}]", val, key)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(assoc: usize, replacement: Replacement) -> CacheConfig {
+        CacheConfig {
+            size: 32,
+            block_size: 8,
+            assoc,
+            replacement,
+            ..CacheConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let config = CacheConfig::parse("size=128,block=16,assoc=4,repl=plru,write=through,alloc=no,hit=2").unwrap();
+        assert_eq!(config.size, 128);
+        assert_eq!(config.block_size, 16);
+        assert_eq!(config.assoc, 4);
+        assert_eq!(config.replacement, Replacement::Plru);
+        assert_eq!(config.write_policy, WritePolicy::WriteThrough);
+        assert!(!config.write_allocate);
+        assert_eq!(config.hit_latency, 2);
+
+        assert_eq!(CacheConfig::parse("").unwrap(), CacheConfig::default());
+        assert!(CacheConfig::parse("size=100").is_err());
+        assert!(CacheConfig::parse("repl=mru").is_err());
+        assert!(CacheConfig::parse("colour=blue").is_err());
+    }
+
+    #[test]
+    fn test_hit_miss() {
+        let mut cache = Cache::new(CacheConfig::default());
+        let first = cache.read(0x10);
+        assert!(!first.hit);
+        assert_eq!(first.miss, Some(MissKind::Compulsory));
+
+        // same block
+        let second = cache.read(0x14);
+        assert!(second.hit);
+        assert_eq!(second.miss, None);
+
+        assert_eq!(cache.stats().hits, 1);
+        assert_eq!(cache.stats().compulsory, 1);
+    }
+
+    #[test]
+    fn test_conflict_miss() {
+        // direct mapped, 4 sets of 8 bytes
+        let mut cache = Cache::new(config(1, Replacement::Lru));
+        cache.read(0x00);
+        cache.read(0x20); // same set, evicts 0x00
+        let access = cache.read(0x00);
+        assert_eq!(access.miss, Some(MissKind::Conflict));
+        assert_eq!(cache.stats().evictions, 2);
+    }
+
+    #[test]
+    fn test_capacity_miss() {
+        // fully associative, 4 lines
+        let mut cache = Cache::new(config(4, Replacement::Lru));
+        for addr in (0..0x28).step_by(8) {
+            cache.read(addr);
+        }
+        let access = cache.read(0x00);
+        assert_eq!(access.miss, Some(MissKind::Capacity));
+    }
+
+    #[test]
+    fn test_lru_vs_fifo() {
+        // 0x00, 0x20 fill a 2 way set, reuse 0x00, then bring in 0x40
+        let mut lru = Cache::new(config(2, Replacement::Lru));
+        let mut fifo = Cache::new(config(2, Replacement::Fifo));
+        for cache in [&mut lru, &mut fifo].iter_mut() {
+            cache.read(0x00);
+            cache.read(0x20);
+            cache.read(0x00);
+            cache.read(0x40);
+        }
+        assert!(lru.contains(0x00));
+        assert!(!lru.contains(0x20));
+        assert!(!fifo.contains(0x00));
+        assert!(fifo.contains(0x20));
+    }
+
+    #[test]
+    fn test_plru() {
+        let mut cache = Cache::new(CacheConfig {
+            size: 32,
+            block_size: 8,
+            assoc: 4,
+            replacement: Replacement::Plru,
+            ..CacheConfig::default()
+        });
+        for addr in [0x00, 0x08, 0x10, 0x18].iter() {
+            cache.read(*addr);
+        }
+        cache.read(0x00);
+        cache.read(0x20);
+        // the tree points away from 0x00 and 0x18 was used after 0x10
+        assert!(cache.contains(0x00));
+        assert!(!cache.contains(0x10));
+    }
+
+    #[test]
+    fn test_random() {
+        let mut cache = Cache::new(config(2, Replacement::Random));
+        for addr in (0..0x100).step_by(8) {
+            cache.read(addr);
+        }
+        assert_eq!(cache.stats().compulsory, 32);
+        assert_eq!(cache.stats().evictions, 28);
+    }
+
+    #[test]
+    fn test_write_back() {
+        let mut cache = Cache::new(config(1, Replacement::Lru));
+        cache.write(0x04);
+        // dirty line written back on eviction
+        let access = cache.read(0x20);
+        assert_eq!(access.evicted, Some(Eviction { addr: 0, dirty: true }));
+        assert_eq!(cache.stats().writebacks, 1);
+    }
+
+    #[test]
+    fn test_write_through_no_allocate() {
+        let mut cache = Cache::new(CacheConfig {
+            write_policy: WritePolicy::WriteThrough,
+            write_allocate: false,
+            ..CacheConfig::default()
+        });
+        let access = cache.write(0x00);
+        assert!(!access.hit);
+        assert!(!cache.contains(0x00));
+
+        // the write did not bring the block in, so it is still a first reference
+        assert_eq!(cache.read(0x00).miss, Some(MissKind::Compulsory));
+        let access = cache.write(0x00);
+        assert!(access.hit);
+        // write through never leaves dirty lines behind
+        let evicted = cache.invalidate(0x00);
+        assert_eq!(evicted, Some(Eviction { addr: 0, dirty: false }));
+        assert_eq!(cache.stats().writebacks, 0);
+    }
+
+    #[test]
+    fn test_validate() {
+        let config = |assoc, replacement| CacheConfig { size: 16 * 128, assoc, replacement, ..CacheConfig::default() };
+        assert_eq!(config(128, Replacement::Plru).validate(),
+                   Err("Cache-Error: plru associativity [128] must be at most 64".to_string()));
+        assert_eq!(config(64, Replacement::Plru).validate(), Ok(()));
+        assert_eq!(config(128, Replacement::Lru).validate(), Ok(()));
+        assert!(CacheConfig::parse("size=2048,assoc=128,repl=plru").is_err());
+    }
+
+    #[test]
+    fn test_probe_insert_invalidate() {
+        let mut cache = Cache::new(config(1, Replacement::Lru));
+        let access = cache.probe(0x08, false);
+        assert!(!access.hit);
+        assert!(!cache.contains(0x08));
+
+        assert_eq!(cache.insert(0x08, false), None);
+        assert!(cache.probe(0x08, false).hit);
+        assert_eq!(cache.stats().accesses(), 2);
+
+        // insert into an occupied set evicts the old block
+        let evicted = cache.insert(0x28, true);
+        assert_eq!(evicted, Some(Eviction { addr: 0x08, dirty: false }));
+        assert_eq!(cache.invalidate(0x28), Some(Eviction { addr: 0x28, dirty: true }));
+        assert_eq!(cache.invalidate(0x28), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_bad_geometry() {
+        Cache::new(CacheConfig { assoc: 3, ..CacheConfig::default() });
+    }
+}
@@ -0,0 +1,384 @@
+/*
+ * cp0.rs
+ *
+ * Author: Travis Banken
+ *
+ * Coprocessor 0 (system control) registers and exception entry/return,
+ * modelled after the MIPS R3000.
+ *
+ * Supported Registers:
+ *  $0  - Index     $8  - BadVAddr  $13 - Cause
+ *  $1  - Random    $9  - Count     $14 - EPC
+ *  $2  - EntryLo   $10 - EntryHi   $15 - PRId
+ *  $4  - Context   $11 - Compare
+ *                  $12 - Status
+ *
+ * Count goes up by one every instruction. When it reaches Compare the timer
+ * interrupt (hardware line 5, Cause.IP7) is raised until Compare is written.
+ *
+ * Exception Vectors:
+ *                  Status.BEV = 0  Status.BEV = 1
+ *  TLB refill      0x8000_0000     0xbfc0_0100
+ *  Everything else 0x8000_0080     0xbfc0_0180
+ */
+#![allow(dead_code)]
+
+// register numbers
+pub const INDEX: usize = 0;
+pub const RANDOM: usize = 1;
+pub const ENTRY_LO: usize = 2;
+pub const CONTEXT: usize = 4;
+pub const BAD_VADDR: usize = 8;
+pub const COUNT: usize = 9;
+pub const ENTRY_HI: usize = 10;
+pub const COMPARE: usize = 11;
+pub const STATUS: usize = 12;
+pub const CAUSE: usize = 13;
+pub const EPC: usize = 14;
+pub const PRID: usize = 15;
+
+// status bits
+pub const STATUS_CU0: u32 = 1 << 28;
+pub const STATUS_BEV: u32 = 1 << 22;
+pub const STATUS_KUC: u32 = 1 << 1;
+pub const STATUS_IEC: u32 = 1 << 0;
+pub const STATUS_IM: u32 = 0xff << 8;
+
+// cause bits
+pub const CAUSE_IP: u32 = 0xff << 8;
+
+// index bits
+pub const INDEX_P: u32 = 1 << 31;
+
+// R3000 implementation/revision
+const PRID_R3000: u32 = 0x0000_0220;
+
+// Random counts down from 63 to 8, entries below 8 are never replaced by tlbwr
+pub const RANDOM_UPPER: u32 = 63;
+pub const RANDOM_LOWER: u32 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExcCode {
+    Int = 0,
+    Mod = 1,
+    TlbL = 2,
+    TlbS = 3,
+    AdEL = 4,
+    AdES = 5,
+    Sys = 8,
+    Bp = 9,
+    RI = 10,
+    CpU = 11,
+    Ov = 12,
+    Tr = 13,
+}
+
+/*
+ * An exception raised while running an instruction.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Exception {
+    pub code: ExcCode,
+    // faulting address for address errors and tlb exceptions
+    pub bad_vaddr: Option<u32>,
+    // tlb refill of a kuseg address, uses its own vector
+    pub utlb_miss: bool,
+}
+
+impl Exception {
+    pub fn new(code: ExcCode) -> Exception {
+        Exception {
+            code,
+            bad_vaddr: None,
+            utlb_miss: false,
+        }
+    }
+
+    pub fn with_addr(code: ExcCode, vaddr: u32) -> Exception {
+        Exception {
+            code,
+            bad_vaddr: Some(vaddr),
+            utlb_miss: false,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Cp0 {
+    registers: [u32; 32],
+    timer_irq: bool,
+}
+
+impl Cp0 {
+    pub fn new() -> Cp0 {
+        let mut cp0 = Cp0 {
+            registers: [0; 32],
+            timer_irq: false,
+        };
+        cp0.registers[RANDOM] = RANDOM_UPPER << 8;
+        cp0.registers[PRID] = PRID_R3000;
+        return cp0;
+    }
+
+    /*
+     * Loads value from register (mfc0).
+     */
+    pub fn load(&self, reg_num: usize) -> u32 {
+        self.registers[reg_num]
+    }
+
+    /*
+     * Writes value into register (mtc0). Read only registers and fields
+     * keep their value.
+     */
+    pub fn write(&mut self, value: u32, reg_num: usize) {
+        match reg_num {
+            INDEX => self.registers[INDEX] = (self.registers[INDEX] & INDEX_P) | (value & 0x3f00),
+            RANDOM | BAD_VADDR | PRID => {},
+            ENTRY_LO => self.registers[ENTRY_LO] = value & 0xffff_ff00,
+            ENTRY_HI => self.registers[ENTRY_HI] = value & 0xffff_ffc0,
+            CONTEXT => self.registers[CONTEXT] = (self.registers[CONTEXT] & 0x001f_fffc) | (value & 0xffe0_0000),
+            // only the two software interrupt bits can be written
+            CAUSE => self.registers[CAUSE] = (self.registers[CAUSE] & !0x300) | (value & 0x300),
+            COMPARE => {
+                self.registers[COMPARE] = value;
+                self.timer_irq = false;
+            },
+            _ => self.registers[reg_num] = value,
+        }
+    }
+
+    /*
+     * Writes a register, including read only ones. Used by the hardware
+     * itself (tlbp, exceptions, ...).
+     */
+    pub fn set(&mut self, value: u32, reg_num: usize) {
+        self.registers[reg_num] = value;
+    }
+
+    pub fn is_user_mode(&self) -> bool {
+        self.registers[STATUS] & STATUS_KUC != 0
+    }
+
+    /*
+     * Current ASID from EntryHi.
+     */
+    pub fn asid(&self) -> u32 {
+        (self.registers[ENTRY_HI] >> 6) & 0x3f
+    }
+
+    /*
+     * Index field of the Random register.
+     */
+    pub fn random_index(&self) -> usize {
+        ((self.registers[RANDOM] >> 8) & 0x3f) as usize
+    }
+
+    /*
+     * Index field of the Index register.
+     */
+    pub fn index(&self) -> usize {
+        ((self.registers[INDEX] >> 8) & 0x3f) as usize
+    }
+
+    /*
+     * Random counts down once every instruction.
+     */
+    pub fn tick_random(&mut self) {
+        let random = self.random_index() as u32;
+        let next = if random <= RANDOM_LOWER {RANDOM_UPPER} else {random - 1};
+        self.registers[RANDOM] = next << 8;
+    }
+
+    /*
+     * Count goes up once every instruction.
+     */
+    pub fn tick_count(&mut self) {
+        let count = self.registers[COUNT].wrapping_add(1);
+        self.registers[COUNT] = count;
+        if count == self.registers[COMPARE] {
+            self.timer_irq = true;
+        }
+    }
+
+    /*
+     * True while the Count/Compare interrupt is raised.
+     */
+    pub fn timer_irq(&self) -> bool {
+        self.timer_irq
+    }
+
+    /*
+     * Every register as stored, for snapshots.
+     */
+    pub fn dump(&self) -> [u32; 32] {
+        self.registers
+    }
+
+    /*
+     * Replaces every register and the timer interrupt as they were dumped.
+     */
+    pub fn restore(&mut self, registers: &[u32; 32], timer_irq: bool) {
+        self.registers = *registers;
+        self.timer_irq = timer_irq;
+    }
+
+    /*
+     * Interrupts are taken when they are enabled (Status.IEc) and one of the
+     * pending lines (Cause.IP) is not masked (Status.IM).
+     */
+    pub fn interrupt_pending(&self) -> bool {
+        let status = self.registers[STATUS];
+        let cause = self.registers[CAUSE];
+        return status & STATUS_IEC != 0 && cause & status & CAUSE_IP != 0;
+    }
+
+    /*
+     * Enter the exception, returns the address of the handler.
+     */
+    pub fn enter_exception(&mut self, exc: &Exception, pc: u32) -> u32 {
+        self.registers[EPC] = pc;
+
+        let cause = self.registers[CAUSE] & !0x7c;
+        self.registers[CAUSE] = cause | ((exc.code as u32) << 2);
+
+        // push the KU/IE stack, the handler runs in kernel mode with
+        // interrupts off
+        let status = self.registers[STATUS];
+        self.registers[STATUS] = (status & !0x3f) | ((status << 2) & 0x3c);
+
+        if let Some(vaddr) = exc.bad_vaddr {
+            self.registers[BAD_VADDR] = vaddr;
+            if is_tlb_exception(exc.code) {
+                let vpn = vaddr & 0xffff_f000;
+                let asid = self.registers[ENTRY_HI] & 0xfc0;
+                self.registers[ENTRY_HI] = vpn | asid;
+                let context = self.registers[CONTEXT] & 0xffe0_0000;
+                self.registers[CONTEXT] = context | ((vaddr >> 10) & 0x001f_fffc);
+            }
+        }
+
+        let base = if self.registers[STATUS] & STATUS_BEV != 0 {0xbfc0_0100} else {0x8000_0000};
+        if exc.utlb_miss {
+            return base;
+        }
+        return base + 0x80;
+    }
+
+    /*
+     * Restore from exception (rfe), pops the KU/IE stack.
+     */
+    pub fn restore_from_exception(&mut self) {
+        let status = self.registers[STATUS];
+        self.registers[STATUS] = (status & !0x0f) | ((status >> 2) & 0x0f);
+    }
+}
+
+fn is_tlb_exception(code: ExcCode) -> bool {
+    code == ExcCode::Mod || code == ExcCode::TlbL || code == ExcCode::TlbS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_and_load() {
+        let mut cp0 = Cp0::new();
+        cp0.write(0xdead_beef, EPC);
+        assert_eq!(cp0.load(EPC), 0xdead_beef);
+
+        // read only
+        cp0.write(0x1234, PRID);
+        assert_eq!(cp0.load(PRID), PRID_R3000);
+        cp0.write(0, RANDOM);
+        assert_eq!(cp0.random_index(), 63);
+
+        cp0.write(0xffff_ffff, ENTRY_HI);
+        assert_eq!(cp0.load(ENTRY_HI), 0xffff_ffc0);
+        assert_eq!(cp0.asid(), 0x3f);
+    }
+
+    #[test]
+    fn test_tick_random() {
+        let mut cp0 = Cp0::new();
+        for _ in 0..(RANDOM_UPPER - RANDOM_LOWER) {
+            cp0.tick_random();
+        }
+        assert_eq!(cp0.random_index(), 8);
+        cp0.tick_random();
+        assert_eq!(cp0.random_index(), 63);
+    }
+
+    #[test]
+    fn test_exception_entry() {
+        let mut cp0 = Cp0::new();
+        // user mode with interrupts on
+        cp0.write(STATUS_KUC | STATUS_IEC, STATUS);
+        cp0.write(0x0000_0080, ENTRY_HI);
+
+        let exc = Exception {
+            code: ExcCode::TlbL,
+            bad_vaddr: Some(0x0040_1234),
+            utlb_miss: true,
+        };
+        let vector = cp0.enter_exception(&exc, 0x40);
+        assert_eq!(vector, 0x8000_0000);
+        assert_eq!(cp0.load(EPC), 0x40);
+        assert_eq!(cp0.load(CAUSE), 2 << 2);
+        assert_eq!(cp0.load(BAD_VADDR), 0x0040_1234);
+        assert_eq!(cp0.load(ENTRY_HI), 0x0040_1080);
+        assert_eq!(cp0.load(CONTEXT), 0x401 << 2);
+        assert_eq!(cp0.load(STATUS) & 0x3f, 0b00_1100);
+        assert!(!cp0.is_user_mode());
+
+        cp0.restore_from_exception();
+        assert!(cp0.is_user_mode());
+        assert_eq!(cp0.load(STATUS) & 0x3f, 0b00_0011);
+    }
+
+    #[test]
+    fn test_count_compare() {
+        let mut cp0 = Cp0::new();
+        cp0.write(3, COMPARE);
+        cp0.tick_count();
+        cp0.tick_count();
+        assert!(!cp0.timer_irq());
+        cp0.tick_count();
+        assert!(cp0.timer_irq());
+        cp0.tick_count();
+        assert!(cp0.timer_irq());
+        // writing compare acknowledges the interrupt
+        cp0.write(10, COMPARE);
+        assert!(!cp0.timer_irq());
+    }
+
+    #[test]
+    fn test_interrupt_pending() {
+        let mut cp0 = Cp0::new();
+        cp0.set(1 << 10, CAUSE);
+        assert!(!cp0.interrupt_pending());
+        cp0.write(STATUS_IEC | (1 << 10), STATUS);
+        assert!(cp0.interrupt_pending());
+        // masked
+        cp0.write(STATUS_IEC | (1 << 11), STATUS);
+        assert!(!cp0.interrupt_pending());
+        // software interrupts can be raised through cause
+        cp0.write(1 << 8, CAUSE);
+        cp0.write(STATUS_IEC | (1 << 8), STATUS);
+        assert!(cp0.interrupt_pending());
+    }
+
+    #[test]
+    fn test_exception_vectors() {
+        let mut cp0 = Cp0::new();
+        assert_eq!(cp0.enter_exception(&Exception::new(ExcCode::Sys), 0), 0x8000_0080);
+        cp0.write(STATUS_BEV, STATUS);
+        let exc = Exception {
+            utlb_miss: true,
+            ..Exception::with_addr(ExcCode::TlbS, 0)
+        };
+        assert_eq!(cp0.enter_exception(&exc, 0), 0xbfc0_0100);
+        assert_eq!(cp0.enter_exception(&Exception::new(ExcCode::Sys), 0), 0xbfc0_0180);
+    }
+}
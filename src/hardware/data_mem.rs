@@ -9,6 +9,7 @@
 
 const MEM_SIZE: usize = 256;
 
+#[derive(Clone)]
 pub struct Memory {
     mem: [u8; MEM_SIZE]
 }
@@ -48,6 +49,25 @@ impl Memory {
         self.mem[addr] = value;
     }
 
+    /*
+     * The whole memory, address 0 first.
+     */
+    pub fn bytes(&self) -> &[u8] {
+        &self.mem
+    }
+
+    /*
+     * Replaces the whole memory.
+     *
+     * Will panic if bytes is not the size of memory.
+     */
+    pub fn load_bytes(&mut self, bytes: &[u8]) {
+        if bytes.len() != MEM_SIZE {
+            panic!("Mem-Error (Data): Expected 256 bytes, got {}", bytes.len());
+        }
+        self.mem.copy_from_slice(bytes);
+    }
+
     /*
      * Return size of memory
      */
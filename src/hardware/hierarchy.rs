@@ -0,0 +1,602 @@
+/*
+ * hierarchy.rs
+ *
+ * Author: Travis Banken
+ *
+ * Memory hierarchy timing model: split L1 instruction and data caches, an
+ * optional unified L2 and main memory.
+ *
+ * Every level is optional, an access simply falls through to the next level
+ * that exists. The latency of an access is the sum of the hit latencies of
+ * every level it touches plus the main memory time, which is a fixed latency
+ * plus the time it takes to move the bytes over the memory bus.
+ *
+ * Inclusion policy of the L2 relative to the L1s:
+ *  inclusive  every block in an L1 is also in the L2, L2 evictions remove
+ *             the block from the L1s too
+ *  exclusive  a block lives in either an L1 or the L2, the L2 only holds
+ *             blocks evicted from the L1s
+ *  nine       non-inclusive non-exclusive, the L2 is filled on misses but
+ *             never forces evictions in the L1s
+ *
+ * Memory spec format (used by --mem):
+ *  latency=10,bandwidth=4
+ *
+ *  latency    cycles before the first byte arrives
+ *  bandwidth  bytes moved per cycle, 0 for unlimited
+ */
+#![allow(dead_code)]
+
+use super::cache::{Cache, CacheConfig, Eviction, WritePolicy};
+
+const WORD_SIZE: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum L1 {
+    Instr,
+    Data,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Inclusion {
+    Inclusive,
+    Exclusive,
+    Nine,
+}
+
+impl Inclusion {
+    pub fn parse(name: &str) -> Result<Inclusion, String> {
+        match name {
+            "inclusive" => Ok(Inclusion::Inclusive),
+            "exclusive" => Ok(Inclusion::Exclusive),
+            "nine" => Ok(Inclusion::Nine),
+            _ => Err(format!("Cache-Error: unknown inclusion policy [{}]", name)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryConfig {
+    pub latency: u32,
+    pub bandwidth: u32,
+}
+
+impl Default for MemoryConfig {
+    fn default() -> MemoryConfig {
+        MemoryConfig {
+            latency: 10,
+            bandwidth: 0,
+        }
+    }
+}
+
+impl MemoryConfig {
+    /*
+     * Builds a config from a spec string, see the header of this file for
+     * the format.
+     */
+    pub fn parse(spec: &str) -> Result<MemoryConfig, String> {
+        let mut config = MemoryConfig::default();
+        for field in spec.split(',').filter(|f| !f.is_empty()) {
+            let mut kv = field.splitn(2, '=');
+            let key = kv.next().unwrap_or("").trim();
+            let val = kv.next().unwrap_or("").trim();
+            let num = match val.parse::<u32>() {
+                Ok(num) => num,
+                Err(_) => return Err(format!("Cache-Error: bad number [{}] for [{}]", val, key)),
+            };
+            match key {
+                "latency" => config.latency = num,
+                "bandwidth" => config.bandwidth = num,
+                _ => return Err(format!("Cache-Error: unknown memory option [{}]", key)),
+            }
+        }
+        Ok(config)
+    }
+
+    /*
+     * Cycles needed to move the given number of bytes to or from memory.
+     */
+    pub fn access_time(&self, bytes: u32) -> u32 {
+        if self.bandwidth == 0 {
+            return self.latency;
+        }
+        self.latency + bytes.div_ceil(self.bandwidth)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HierarchyConfig {
+    pub l1i: Option<CacheConfig>,
+    pub l1d: Option<CacheConfig>,
+    pub l2: Option<CacheConfig>,
+    pub memory: Option<MemoryConfig>,
+    pub inclusion: Inclusion,
+}
+
+impl Default for HierarchyConfig {
+    fn default() -> HierarchyConfig {
+        HierarchyConfig {
+            l1i: None,
+            l1d: None,
+            l2: None,
+            memory: None,
+            inclusion: Inclusion::Nine,
+        }
+    }
+}
+
+impl HierarchyConfig {
+    /*
+     * Memory timing is only modelled when some part of the hierarchy was
+     * asked for.
+     */
+    pub fn is_enabled(&self) -> bool {
+        self.l1i.is_some() || self.l1d.is_some() || self.l2.is_some() || self.memory.is_some()
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MemoryStats {
+    pub reads: u64,
+    pub writes: u64,
+    pub bytes: u64,
+    pub cycles: u64,
+}
+
+#[derive(Clone)]
+pub struct Hierarchy {
+    pub l1i: Option<Cache>,
+    pub l1d: Option<Cache>,
+    pub l2: Option<Cache>,
+    memory: MemoryConfig,
+    inclusion: Inclusion,
+    mem_stats: MemoryStats,
+}
+
+impl Hierarchy {
+    pub fn new(config: &HierarchyConfig) -> Hierarchy {
+        Hierarchy {
+            l1i: config.l1i.clone().map(Cache::new),
+            l1d: config.l1d.clone().map(Cache::new),
+            l2: config.l2.clone().map(Cache::new),
+            memory: config.memory.clone().unwrap_or_default(),
+            inclusion: config.inclusion,
+            mem_stats: MemoryStats::default(),
+        }
+    }
+
+    pub fn memory_config(&self) -> &MemoryConfig {
+        &self.memory
+    }
+
+    pub fn memory_stats(&self) -> &MemoryStats {
+        &self.mem_stats
+    }
+
+    pub fn inclusion(&self) -> Inclusion {
+        self.inclusion
+    }
+
+    /*
+     * Instruction fetch, returns the latency in cycles.
+     */
+    pub fn fetch(&mut self, addr: u32) -> u32 {
+        self.cpu_access(L1::Instr, addr, false)
+    }
+
+    /*
+     * Data load, returns the latency in cycles.
+     */
+    pub fn load(&mut self, addr: u32) -> u32 {
+        self.cpu_access(L1::Data, addr, false)
+    }
+
+    /*
+     * Data store, returns the latency in cycles.
+     */
+    pub fn store(&mut self, addr: u32) -> u32 {
+        self.cpu_access(L1::Data, addr, true)
+    }
+
+    /*
+     * Access that bypasses the caches (kseg1, uncached pages) and goes to
+     * main memory for a word, returns the latency in cycles.
+     */
+    pub fn uncached(&mut self, is_write: bool) -> u32 {
+        return if is_write {self.mem_write(WORD_SIZE)} else {self.mem_read(WORD_SIZE)};
+    }
+
+    /*
+     * Latency of an access that hits in the first level, so the cpu only
+     * stalls for the time above it.
+     */
+    pub fn fetch_hit_latency(&self) -> u32 {
+        self.first_level_hit(&self.l1i)
+    }
+
+    pub fn data_hit_latency(&self) -> u32 {
+        self.first_level_hit(&self.l1d)
+    }
+
+    /*
+     * Average memory access time of an L1 using the textbook formula
+     *  AMAT = hit time + miss rate * miss penalty
+     * with the miss penalty being the AMAT of the level below.
+     */
+    pub fn amat(&self, l1: &Cache) -> f64 {
+        let penalty = match self.l2.as_ref() {
+            Some(l2) => {
+                let l2_penalty = self.memory.access_time(l2.config().block_size as u32) as f64;
+                l2.config().hit_latency as f64 + l2.stats().miss_rate() * l2_penalty
+            },
+            None => self.memory.access_time(l1.config().block_size as u32) as f64,
+        };
+        l1.config().hit_latency as f64 + l1.stats().miss_rate() * penalty
+    }
+
+    /*
+     * Average latency the accesses to the cache actually took, including
+     * write backs and write throughs the formula ignores.
+     */
+    pub fn measured_amat(&self, cache: &Cache) -> f64 {
+        let accesses = cache.stats().accesses();
+        if accesses == 0 {
+            return 0.0;
+        }
+        cache.stats().cycles as f64 / accesses as f64
+    }
+
+    // *** PRIVATE FN ***
+
+    fn first_level_hit(&self, l1: &Option<Cache>) -> u32 {
+        if let Some(cache) = l1.as_ref() {
+            return cache.config().hit_latency;
+        }
+        if let Some(cache) = self.l2.as_ref() {
+            return cache.config().hit_latency;
+        }
+        return 0;
+    }
+
+    fn l1_mut(&mut self, which: L1) -> Option<&mut Cache> {
+        match which {
+            L1::Instr => self.l1i.as_mut(),
+            L1::Data => self.l1d.as_mut(),
+        }
+    }
+
+    fn cpu_access(&mut self, which: L1, addr: u32, is_write: bool) -> u32 {
+        let (config, access) = match self.l1_mut(which) {
+            Some(cache) => (cache.config().clone(), cache.access(addr, is_write)),
+            None => return self.l2_access(addr, is_write),
+        };
+        let block = config.block_size as u32;
+        let write_through = config.write_policy == WritePolicy::WriteThrough;
+        let mut latency = config.hit_latency;
+
+        if !access.hit {
+            if is_write && !config.write_allocate {
+                latency += self.next_write(addr, WORD_SIZE);
+            } else {
+                let block_addr = addr & !(block - 1);
+                let (fill_latency, dirty) = self.next_fill(block_addr, block);
+                latency += fill_latency;
+                if dirty {
+                    // the dirty copy moved up from an exclusive L2
+                    if let Some(cache) = self.l1_mut(which) {
+                        cache.insert(block_addr, true);
+                    }
+                }
+                if let Some(ev) = access.evicted {
+                    latency += self.l1_victim(ev, block);
+                }
+            }
+        }
+        if is_write && write_through && (access.hit || config.write_allocate) {
+            latency += self.next_write(addr, WORD_SIZE);
+        }
+        if let Some(cache) = self.l1_mut(which) {
+            cache.add_cycles(latency);
+        }
+        return latency;
+    }
+
+    /*
+     * Access made by the cpu straight to the L2 (no L1 in front of it).
+     */
+    fn l2_access(&mut self, addr: u32, is_write: bool) -> u32 {
+        let mut l2 = match self.l2.take() {
+            Some(l2) => l2,
+            None => {
+                return if is_write {self.mem_write(WORD_SIZE)} else {self.mem_read(WORD_SIZE)};
+            },
+        };
+        let latency = self.l2_lookup(&mut l2, addr, is_write, WORD_SIZE);
+        self.l2 = Some(l2);
+        return latency;
+    }
+
+    /*
+     * Bring a block into an L1 from the level below. Also returns whether
+     * the block arrives dirty.
+     */
+    fn next_fill(&mut self, addr: u32, bytes: u32) -> (u32, bool) {
+        let mut l2 = match self.l2.take() {
+            Some(l2) => l2,
+            None => return (self.mem_read(bytes), false),
+        };
+
+        let mut dirty = false;
+        let latency = if self.inclusion == Inclusion::Exclusive {
+            // move the block up, or get it from memory without keeping a copy
+            let mut latency = l2.config().hit_latency;
+            if l2.probe(addr, false).hit {
+                if let Some(ev) = l2.invalidate(addr) {
+                    dirty = ev.dirty;
+                }
+            } else {
+                latency += self.mem_read(bytes);
+            }
+            l2.add_cycles(latency);
+            latency
+        } else {
+            self.l2_lookup(&mut l2, addr, false, bytes)
+        };
+        self.l2 = Some(l2);
+        return (latency, dirty);
+    }
+
+    /*
+     * Write bytes (a dirty block or a written through word) to the level
+     * below the L1s.
+     */
+    fn next_write(&mut self, addr: u32, bytes: u32) -> u32 {
+        let mut l2 = match self.l2.take() {
+            Some(l2) => l2,
+            None => return self.mem_write(bytes),
+        };
+        let latency = if self.inclusion == Inclusion::Exclusive {
+            // update the copy if there is one, never allocate
+            let mut latency = l2.config().hit_latency;
+            let write_through = l2.config().write_policy == WritePolicy::WriteThrough;
+            if !l2.probe(addr, true).hit || write_through {
+                latency += self.mem_write(bytes);
+            }
+            l2.add_cycles(latency);
+            latency
+        } else {
+            self.l2_lookup(&mut l2, addr, true, bytes)
+        };
+        self.l2 = Some(l2);
+        return latency;
+    }
+
+    /*
+     * Normal (allocating) access to the L2.
+     */
+    fn l2_lookup(&mut self, l2: &mut Cache, addr: u32, is_write: bool, bytes: u32) -> u32 {
+        let config = l2.config().clone();
+        let access = l2.access(addr, is_write);
+        let mut latency = config.hit_latency;
+        if !access.hit {
+            if is_write && !config.write_allocate {
+                latency += self.mem_write(bytes);
+            } else {
+                latency += self.mem_read(config.block_size as u32);
+            }
+        }
+        if is_write && config.write_policy == WritePolicy::WriteThrough {
+            latency += self.mem_write(bytes);
+        }
+        if let Some(ev) = access.evicted {
+            latency += self.l2_victim(ev, config.block_size as u32);
+        }
+        l2.add_cycles(latency);
+        return latency;
+    }
+
+    /*
+     * Deal with a block thrown out of an L1.
+     */
+    fn l1_victim(&mut self, ev: Eviction, bytes: u32) -> u32 {
+        if self.inclusion != Inclusion::Exclusive {
+            if ev.dirty {
+                return self.next_write(ev.addr, bytes);
+            }
+            return 0;
+        }
+
+        // exclusive: every victim moves down into the L2
+        let mut l2 = match self.l2.take() {
+            Some(l2) => l2,
+            None => return if ev.dirty {self.mem_write(bytes)} else {0},
+        };
+        let mut latency = 0;
+        if let Some(l2_ev) = l2.insert(ev.addr, ev.dirty) {
+            if l2_ev.dirty {
+                latency += self.mem_write(l2.config().block_size as u32);
+            }
+        }
+        self.l2 = Some(l2);
+        return latency;
+    }
+
+    /*
+     * Deal with a block thrown out of the L2.
+     */
+    fn l2_victim(&mut self, ev: Eviction, bytes: u32) -> u32 {
+        let mut latency = 0;
+        if ev.dirty {
+            latency += self.mem_write(bytes);
+        }
+        if self.inclusion == Inclusion::Inclusive {
+            // back invalidate every L1 block inside the evicted L2 block
+            for l1 in [&mut self.l1i, &mut self.l1d].iter_mut() {
+                if let Some(cache) = l1.as_mut() {
+                    let step = cache.config().block_size as u32;
+                    let mut dirty_bytes = 0;
+                    let mut addr = ev.addr;
+                    while addr < ev.addr + bytes {
+                        if let Some(l1_ev) = cache.invalidate(addr) {
+                            if l1_ev.dirty {
+                                dirty_bytes += step;
+                            }
+                        }
+                        addr += step;
+                    }
+                    if dirty_bytes > 0 {
+                        self.mem_stats.writes += 1;
+                        self.mem_stats.bytes += dirty_bytes as u64;
+                        let time = self.memory.access_time(dirty_bytes);
+                        self.mem_stats.cycles += time as u64;
+                        latency += time;
+                    }
+                }
+            }
+        }
+        return latency;
+    }
+
+    fn mem_read(&mut self, bytes: u32) -> u32 {
+        let time = self.memory.access_time(bytes);
+        self.mem_stats.reads += 1;
+        self.mem_stats.bytes += bytes as u64;
+        self.mem_stats.cycles += time as u64;
+        return time;
+    }
+
+    fn mem_write(&mut self, bytes: u32) -> u32 {
+        let time = self.memory.access_time(bytes);
+        self.mem_stats.writes += 1;
+        self.mem_stats.bytes += bytes as u64;
+        self.mem_stats.cycles += time as u64;
+        return time;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn l1() -> CacheConfig {
+        CacheConfig {
+            size: 16,
+            block_size: 8,
+            assoc: 1,
+            ..CacheConfig::default()
+        }
+    }
+
+    fn l2() -> CacheConfig {
+        CacheConfig {
+            size: 64,
+            block_size: 8,
+            assoc: 2,
+            hit_latency: 4,
+            ..CacheConfig::default()
+        }
+    }
+
+    fn hierarchy(inclusion: Inclusion) -> Hierarchy {
+        Hierarchy::new(&HierarchyConfig {
+            l1i: Some(l1()),
+            l1d: Some(l1()),
+            l2: Some(l2()),
+            memory: Some(MemoryConfig { latency: 20, bandwidth: 4 }),
+            inclusion,
+        })
+    }
+
+    #[test]
+    fn test_memory_parse() {
+        let config = MemoryConfig::parse("latency=50,bandwidth=8").unwrap();
+        assert_eq!(config, MemoryConfig { latency: 50, bandwidth: 8 });
+        assert_eq!(config.access_time(16), 52);
+        assert_eq!(config.access_time(4), 51);
+        assert!(MemoryConfig::parse("speed=3").is_err());
+        assert_eq!(Inclusion::parse("nine"), Ok(Inclusion::Nine));
+        assert!(Inclusion::parse("mostly").is_err());
+    }
+
+    #[test]
+    fn test_levels() {
+        let mut hier = hierarchy(Inclusion::Nine);
+        // L1 miss, L2 miss, memory: 1 + 4 + 20 + 8/4
+        assert_eq!(hier.load(0x00), 27);
+        // L1 hit
+        assert_eq!(hier.load(0x04), 1);
+        // evict from the direct mapped L1, then L2 hit
+        hier.load(0x10);
+        assert_eq!(hier.load(0x00), 5);
+        assert_eq!(hier.memory_stats().reads, 2);
+    }
+
+    #[test]
+    fn test_no_caches() {
+        let mut hier = Hierarchy::new(&HierarchyConfig {
+            memory: Some(MemoryConfig { latency: 7, bandwidth: 0 }),
+            ..HierarchyConfig::default()
+        });
+        assert_eq!(hier.fetch(0x00), 7);
+        assert_eq!(hier.store(0x00), 7);
+        assert_eq!(hier.fetch_hit_latency(), 0);
+    }
+
+    #[test]
+    fn test_uncached() {
+        let mut hier = Hierarchy::new(&HierarchyConfig {
+            l1d: Some(l1()),
+            memory: Some(MemoryConfig { latency: 7, bandwidth: 0 }),
+            ..HierarchyConfig::default()
+        });
+        assert_eq!(hier.uncached(false), 7);
+        assert_eq!(hier.uncached(true), 7);
+        assert_eq!(hier.l1d.as_ref().unwrap().stats().accesses(), 0);
+    }
+
+    #[test]
+    fn test_inclusive_back_invalidate() {
+        let mut hier = Hierarchy::new(&HierarchyConfig {
+            l1d: Some(CacheConfig { size: 32, block_size: 8, assoc: 4, ..CacheConfig::default() }),
+            l2: Some(CacheConfig { size: 16, block_size: 8, assoc: 1, ..CacheConfig::default() }),
+            inclusion: Inclusion::Inclusive,
+            ..HierarchyConfig::default()
+        });
+        hier.load(0x00);
+        // conflicts with 0x00 in the L2 but not in the L1
+        hier.load(0x10);
+        assert!(!hier.l1d.as_ref().unwrap().contains(0x00));
+        assert!(!hier.l2.as_ref().unwrap().contains(0x00));
+    }
+
+    #[test]
+    fn test_exclusive() {
+        let mut hier = hierarchy(Inclusion::Exclusive);
+        hier.store(0x00);
+        assert!(hier.l1d.as_ref().unwrap().contains(0x00));
+        assert!(!hier.l2.as_ref().unwrap().contains(0x00));
+
+        // dirty victim moves down into the L2
+        hier.load(0x10);
+        assert!(hier.l2.as_ref().unwrap().contains(0x00));
+
+        // and back up again, still dirty
+        assert_eq!(hier.load(0x00), 5);
+        assert!(!hier.l2.as_ref().unwrap().contains(0x00));
+        assert_eq!(hier.memory_stats().writes, 0);
+    }
+
+    #[test]
+    fn test_amat() {
+        let mut hier = hierarchy(Inclusion::Nine);
+        for _ in 0..3 {
+            hier.fetch(0x00);
+        }
+        hier.fetch(0x20);
+        let l1i = hier.l1i.as_ref().unwrap();
+        // l1: 2 misses in 4, l2: 2 misses in 2
+        assert_eq!(hier.amat(l1i), 1.0 + 0.5 * (4.0 + 22.0));
+        assert_eq!(hier.measured_amat(l1i), (27.0 + 1.0 + 1.0 + 27.0) / 4.0);
+    }
+}
@@ -0,0 +1,103 @@
+/*
+ * intc.rs
+ *
+ * Author: Travis Banken
+ *
+ * Interrupt controller. Routes the six hardware interrupt lines into
+ * Cause.IP2 - Cause.IP7 of coprocessor 0. Whether an interrupt is actually
+ * taken is decided by the masks in the Status register (IM and IEc).
+ *
+ * Default Lines:
+ *  0 (IP2) - uart
+ *  1 (IP3) - mmio timer
+ *  5 (IP7) - cp0 Count/Compare timer
+ */
+#![allow(dead_code)]
+
+use super::cp0;
+
+pub const NUM_LINES: usize = 6;
+
+pub const LINE_UART: usize = 0;
+pub const LINE_TIMER: usize = 1;
+pub const LINE_CP0_TIMER: usize = 5;
+
+#[derive(Clone)]
+pub struct InterruptController {
+    lines: u8
+}
+
+impl InterruptController {
+    pub fn new() -> InterruptController {
+        InterruptController {
+            lines: 0
+        }
+    }
+
+    /*
+     * Raise or lower a hardware line.
+     *
+     * Will panic if the line does not exist.
+     */
+    pub fn set_line(&mut self, line: usize, raised: bool) {
+        if line >= NUM_LINES {
+            panic!("Intc-Error: interrupt line [{}] does not exist", line);
+        }
+        if raised {
+            self.lines |= 1 << line;
+        } else {
+            self.lines &= !(1 << line);
+        }
+    }
+
+    /*
+     * Set every line at once from a bit mask (bit n = line n).
+     */
+    pub fn set_lines(&mut self, lines: u8) {
+        self.lines = lines & ((1 << NUM_LINES) - 1);
+    }
+
+    pub fn lines(&self) -> u8 {
+        self.lines
+    }
+
+    /*
+     * Copy the lines into Cause.IP7-2, the software bits (IP1-0) are left
+     * alone.
+     */
+    pub fn update_cause(&self, cp0: &mut cp0::Cp0) {
+        let cause = cp0.load(cp0::CAUSE) & !(0x3f << 10);
+        cp0.set(cause | ((self.lines as u32) << 10), cp0::CAUSE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_update_cause() {
+        let mut intc = InterruptController::new();
+        let mut cp0 = cp0::Cp0::new();
+        cp0.write(1 << 8, cp0::CAUSE);
+
+        intc.set_line(LINE_TIMER, true);
+        intc.set_line(LINE_CP0_TIMER, true);
+        intc.update_cause(&mut cp0);
+        assert_eq!(cp0.load(cp0::CAUSE), (1 << 15) | (1 << 11) | (1 << 8));
+
+        intc.set_line(LINE_CP0_TIMER, false);
+        intc.update_cause(&mut cp0);
+        assert_eq!(cp0.load(cp0::CAUSE), (1 << 11) | (1 << 8));
+
+        intc.set_lines(0xff);
+        assert_eq!(intc.lines(), 0x3f);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_bad_line() {
+        let mut intc = InterruptController::new();
+        intc.set_line(6, true);
+    }
+}
@@ -0,0 +1,173 @@
+/*
+ * mmu.rs
+ *
+ * Author: Travis Banken
+ *
+ * Memory management unit, turns the virtual addresses used by the cpu into
+ * physical addresses (MIPS R3000 segmentation + TLB).
+ *
+ * Segments:
+ *  kuseg  0x0000_0000 - 0x7fff_ffff  mapped through the tlb, user + kernel
+ *  kseg0  0x8000_0000 - 0x9fff_ffff  unmapped (minus 0x8000_0000), kernel
+ *  kseg1  0xa000_0000 - 0xbfff_ffff  unmapped (minus 0xa000_0000), uncached,
+ *                                    kernel
+ *  kseg2  0xc000_0000 - 0xffff_ffff  mapped through the tlb, kernel
+ *
+ * When the mmu is disabled every address is used as a physical address.
+ * kseg1 and the tlb pages with the N bit set are not cached, the caches
+ * are only used for the other accesses.
+ */
+#![allow(dead_code)]
+
+use super::cp0::{Cp0, ExcCode, Exception};
+use super::tlb::{Tlb, LO_D, LO_N, LO_V};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessKind {
+    Fetch,
+    Load,
+    Store,
+}
+
+/*
+ * Physical address of an access, and whether it goes through the caches.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Translation {
+    pub paddr: u32,
+    pub cached: bool,
+}
+
+#[derive(Clone)]
+pub struct Mmu {
+    pub enabled: bool,
+    pub tlb: Tlb,
+}
+
+impl Mmu {
+    pub fn new(enabled: bool) -> Mmu {
+        Mmu {
+            enabled,
+            tlb: Tlb::new(),
+        }
+    }
+
+    /*
+     * Translate a virtual address. Returns the exception to raise if the
+     * access is not allowed or the page is not mapped.
+     */
+    pub fn translate(&self, cp0: &Cp0, vaddr: u32, kind: AccessKind) -> Result<u32, Exception> {
+        return self.map(cp0, vaddr, kind).map(|translation| translation.paddr);
+    }
+
+    /*
+     * Translate a virtual address, with the cacheability of its segment or
+     * page.
+     */
+    pub fn map(&self, cp0: &Cp0, vaddr: u32, kind: AccessKind) -> Result<Translation, Exception> {
+        if !self.enabled {
+            return Ok(Translation { paddr: vaddr, cached: true });
+        }
+
+        let is_store = kind == AccessKind::Store;
+        let kernel_segment = vaddr >= 0x8000_0000;
+        if kernel_segment && cp0.is_user_mode() {
+            let code = if is_store {ExcCode::AdES} else {ExcCode::AdEL};
+            return Err(Exception::with_addr(code, vaddr));
+        }
+
+        match vaddr {
+            0x8000_0000..=0x9fff_ffff => return Ok(Translation { paddr: vaddr - 0x8000_0000, cached: true }),
+            0xa000_0000..=0xbfff_ffff => return Ok(Translation { paddr: vaddr - 0xa000_0000, cached: false }),
+            _ => {},
+        }
+
+        let code = if is_store {ExcCode::TlbS} else {ExcCode::TlbL};
+        let entry_hi = (vaddr & 0xffff_f000) | (cp0.asid() << 6);
+        let entry = match self.tlb.probe(entry_hi) {
+            Some(index) => self.tlb.read(index),
+            None => {
+                return Err(Exception {
+                    // kseg2 refills go through the general vector
+                    utlb_miss: !kernel_segment,
+                    ..Exception::with_addr(code, vaddr)
+                });
+            },
+        };
+        if entry.lo & LO_V == 0 {
+            return Err(Exception::with_addr(code, vaddr));
+        }
+        if is_store && entry.lo & LO_D == 0 {
+            return Err(Exception::with_addr(ExcCode::Mod, vaddr));
+        }
+        return Ok(Translation {
+            paddr: (entry.lo & 0xffff_f000) | (vaddr & 0x0000_0fff),
+            cached: entry.lo & LO_N == 0,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::cp0;
+    use crate::hardware::tlb::{TlbEntry, LO_G};
+
+    #[test]
+    fn test_disabled() {
+        let mmu = Mmu::new(false);
+        let cp0 = Cp0::new();
+        assert_eq!(mmu.translate(&cp0, 0x42, AccessKind::Load), Ok(0x42));
+        assert_eq!(mmu.translate(&cp0, 0xffff_0000, AccessKind::Store), Ok(0xffff_0000));
+    }
+
+    #[test]
+    fn test_unmapped_segments() {
+        let mmu = Mmu::new(true);
+        let mut cp0 = Cp0::new();
+        assert_eq!(mmu.translate(&cp0, 0x8000_0042, AccessKind::Fetch), Ok(0x42));
+        assert_eq!(mmu.translate(&cp0, 0xa000_0042, AccessKind::Store), Ok(0x42));
+        // kseg1 is not cached
+        assert!(mmu.map(&cp0, 0x8000_0042, AccessKind::Load).unwrap().cached);
+        assert!(!mmu.map(&cp0, 0xa000_0042, AccessKind::Load).unwrap().cached);
+
+        cp0.write(cp0::STATUS_KUC, cp0::STATUS);
+        let err = mmu.translate(&cp0, 0x8000_0042, AccessKind::Store).unwrap_err();
+        assert_eq!(err.code, ExcCode::AdES);
+        assert_eq!(err.bad_vaddr, Some(0x8000_0042));
+    }
+
+    #[test]
+    fn test_mapped() {
+        let mut mmu = Mmu::new(true);
+        let mut cp0 = Cp0::new();
+        cp0.write(1 << 6, cp0::ENTRY_HI);
+        mmu.tlb.write(8, TlbEntry { hi: 0x0040_0000 | (1 << 6), lo: LO_V | LO_D });
+        mmu.tlb.write(9, TlbEntry { hi: 0x0040_1000 | (1 << 6), lo: LO_V | LO_N });
+        mmu.tlb.write(10, TlbEntry { hi: 0x0040_2000 | (1 << 6), lo: 0x0000_1000 });
+        mmu.tlb.write(11, TlbEntry { hi: 0xc000_0000, lo: LO_V | LO_G });
+
+        assert_eq!(mmu.translate(&cp0, 0x0040_0042, AccessKind::Store), Ok(0x42));
+        assert_eq!(mmu.translate(&cp0, 0x0040_1042, AccessKind::Load), Ok(0x42));
+        assert_eq!(mmu.translate(&cp0, 0xc000_0010, AccessKind::Load), Ok(0x10));
+        // pages with the N bit are not cached
+        assert_eq!(mmu.map(&cp0, 0x0040_1042, AccessKind::Load), Ok(Translation { paddr: 0x42, cached: false }));
+        assert!(mmu.map(&cp0, 0x0040_0042, AccessKind::Load).unwrap().cached);
+
+        // read only page
+        let err = mmu.translate(&cp0, 0x0040_1042, AccessKind::Store).unwrap_err();
+        assert_eq!(err.code, ExcCode::Mod);
+
+        // invalid page
+        let err = mmu.translate(&cp0, 0x0040_2000, AccessKind::Fetch).unwrap_err();
+        assert_eq!(err.code, ExcCode::TlbL);
+        assert!(!err.utlb_miss);
+
+        // refill
+        let err = mmu.translate(&cp0, 0x0050_0000, AccessKind::Store).unwrap_err();
+        assert_eq!(err.code, ExcCode::TlbS);
+        assert!(err.utlb_miss);
+        let err = mmu.translate(&cp0, 0xd000_0000, AccessKind::Load).unwrap_err();
+        assert!(!err.utlb_miss);
+    }
+}
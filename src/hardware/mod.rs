@@ -2,4 +2,13 @@
 pub mod alu;
 pub mod reg_file;
 pub mod data_mem;
-pub mod instr_mem;
\ No newline at end of file
+pub mod instr_mem;
+pub mod cache;
+pub mod hierarchy;
+pub mod cp0;
+pub mod tlb;
+pub mod mmu;
+pub mod bus;
+pub mod uart;
+pub mod timer;
+pub mod intc;
\ No newline at end of file
@@ -4,7 +4,8 @@
  * Author: Travis Banken
  * 
  * Simulated Register file, which allows for loading and storing of data into
- * registers.
+ * registers. Register 0 is hardwired to zero: writes to it are dropped, on
+ * MIPS ($zero) and RISC-V (x0) alike.
  * 
  * Supported Registers:
  *  $0 - $zero      $10 - $t2       $20 - $s4       $30 - $fp
@@ -21,15 +22,68 @@
 
 #![allow(dead_code)]
 
+use std::fmt;
+
+// o32 ABI register names
+pub const REG_NAMES: [&str; 32] = [
+    "zero", "at", "v0", "v1", "a0", "a1", "a2", "a3",
+    "t0", "t1", "t2", "t3", "t4", "t5", "t6", "t7",
+    "s0", "s1", "s2", "s3", "s4", "s5", "s6", "s7",
+    "t8", "t9", "k0", "k1", "gp", "sp", "fp", "ra",
+];
+
+/*
+ * ABI name of a register number, e.g. 8 -> "t0".
+ */
+pub fn reg_name(reg_num: u8) -> &'static str {
+    REG_NAMES[(reg_num & 0x1f) as usize]
+}
+
+/*
+ * Register number of a name with or without the '$', by ABI name ("t0") or
+ * number ("8").
+ */
+pub fn reg_num(name: &str) -> Option<u8> {
+    let name = name.strip_prefix('$').unwrap_or(name);
+    if let Some(num) = REG_NAMES.iter().position(|n| *n == name) {
+        return Some(num as u8);
+    }
+    if name == "s8" {
+        return Some(30);
+    }
+    match name.parse::<u8>() {
+        Ok(num) if num < 32 => Some(num),
+        _ => None,
+    }
+}
+
+#[derive(Clone)]
 pub struct Registers {
-    registers: [u32; 32]
+    registers: [u32; 32],
 }
 
 impl Registers {
     pub fn new() -> Registers {
         Registers {
-            registers: [0; 32]
+            registers: [0; 32],
+        }
+    }
+
+    /*
+     * Register file holding garbage instead of zeros, every register but
+     * $zero gets a value from the xorshift32 sequence of the seed. A program
+     * that reads a register it never wrote shows it.
+     */
+    pub fn garbage(seed: u32) -> Registers {
+        let mut regfile = Registers::new();
+        let mut rng = seed.max(1);
+        for reg in regfile.registers.iter_mut().skip(1) {
+            rng ^= rng << 13;
+            rng ^= rng >> 17;
+            rng ^= rng << 5;
+            *reg = rng;
         }
+        return regfile;
     }
 
     /*
@@ -40,11 +94,70 @@ impl Registers {
     }
 
     /*
-     * Writes value into register.
+     * Writes value into register, writes to register 0 are dropped.
      */
     pub fn write(&mut self, value: u32, reg_num: usize) {
+        if reg_num == 0 {
+            return;
+        }
         self.registers[reg_num] = value;
     }
+
+    /*
+     * Loads the register with a MIPS ABI name or number, e.g. "$sp" or "29".
+     */
+    pub fn load_named(&self, name: &str) -> Result<u32, String> {
+        let num = reg_num(name).ok_or(format!("Reg-Error: unknown register [{}]", name))?;
+        return Ok(self.load(num as usize));
+    }
+
+    /*
+     * Writes the register with a MIPS ABI name or number.
+     */
+    pub fn write_named(&mut self, value: u32, name: &str) -> Result<(), String> {
+        let num = reg_num(name).ok_or(format!("Reg-Error: unknown register [{}]", name))?;
+        self.write(value, num as usize);
+        return Ok(());
+    }
+
+    /*
+     * Every register, $zero first.
+     */
+    pub fn dump(&self) -> [u32; 32] {
+        self.registers
+    }
+
+    /*
+     * Replaces every register.
+     */
+    pub fn restore(&mut self, values: &[u32; 32]) {
+        self.registers = *values;
+        self.registers[0] = 0;
+    }
+
+    /*
+     * Every register with its value, laid out like the table at the top of
+     * this file with the names of reg_name (the names of another isa can be
+     * passed in).
+     */
+    pub fn format_with(&self, reg_name: impl Fn(u8) -> &'static str) -> String {
+        let mut out = String::new();
+        for row in 0..10 {
+            let line = (row..32).step_by(10)
+                .map(|reg| format!("${:<2} {:<4} 0x{:08x}", reg, reg_name(reg as u8), self.registers[reg]))
+                .collect::<Vec<String>>()
+                .join("    ");
+            out += &line;
+            out.push('\n');
+        }
+        return out;
+    }
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.format_with(reg_name))
+    }
 }
 
 #[cfg(test)]
@@ -61,4 +174,61 @@ mod tests {
         r3 = regfile.load(3);
         assert_ne!(r3, 2);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_hardwired_zero() {
+        let mut regfile = Registers::new();
+        regfile.write(5, 0);
+        assert_eq!(regfile.load(0), 0);
+        regfile.restore(&[7; 32]);
+        assert_eq!(regfile.load(0), 0);
+        assert_eq!(regfile.load(31), 7);
+        assert_eq!(Registers::garbage(42).load(0), 0);
+    }
+
+    #[test]
+    fn test_named() {
+        let mut regfile = Registers::new();
+        regfile.write_named(0x7fff_effc, "$sp").unwrap();
+        assert_eq!(regfile.load(29), 0x7fff_effc);
+        assert_eq!(regfile.load_named("sp"), Ok(0x7fff_effc));
+        assert_eq!(regfile.load_named("$29"), Ok(0x7fff_effc));
+        regfile.write_named(1, "zero").unwrap();
+        assert_eq!(regfile.load_named("$zero"), Ok(0));
+        assert!(regfile.load_named("$pc").is_err());
+        assert!(regfile.write_named(1, "$32").is_err());
+    }
+
+    #[test]
+    fn test_reg_num() {
+        assert_eq!(reg_num("$t0"), Some(8));
+        assert_eq!(reg_num("ra"), Some(31));
+        assert_eq!(reg_num("$s8"), Some(30));
+        assert_eq!(reg_num("$17"), Some(17));
+        assert_eq!(reg_num("$32"), None);
+        assert_eq!(reg_num("pc"), None);
+        assert_eq!(reg_name(8), "t0");
+    }
+
+    #[test]
+    fn test_garbage() {
+        let regfile = Registers::garbage(7);
+        // the same seed gives the same garbage
+        assert_eq!(regfile.dump(), Registers::garbage(7).dump());
+        assert_ne!(regfile.dump(), Registers::garbage(8).dump());
+        assert!(regfile.dump()[1..].iter().all(|val| *val != 0));
+    }
+
+    #[test]
+    fn test_format() {
+        let mut regfile = Registers::new();
+        regfile.write(0xcafe_babe, 31);
+        let text = regfile.to_string();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 10);
+        assert_eq!(lines[0], "$0  zero 0x00000000    $10 t2   0x00000000    $20 s4   0x00000000    $30 fp   0x00000000");
+        assert_eq!(lines[1], "$1  at   0x00000000    $11 t3   0x00000000    $21 s5   0x00000000    $31 ra   0xcafebabe");
+        assert_eq!(lines[9], "$9  t1   0x00000000    $19 s3   0x00000000    $29 sp   0x00000000");
+        assert!(regfile.format_with(|_| "x").starts_with("$0  x    0x00000000"));
+    }
+}
@@ -0,0 +1,173 @@
+/*
+ * timer.rs
+ *
+ * Author: Travis Banken
+ *
+ * Memory mapped count down timer with automatic reload.
+ *
+ * Registers (offsets from the base address, default 0xffff_0010):
+ *  0x0  control  bit 0 = enable, bit 1 = irq enable, bit 2 = auto reload
+ *  0x4  reload   value loaded into the counter when it is written and when
+ *                the counter expires (auto reload)
+ *  0x8  counter  current value, counts down once every instruction
+ *  0xc  status   bit 0 = expired, write 1 to clear
+ */
+#![allow(dead_code)]
+
+use super::bus::Device;
+
+pub const TIMER_BASE: u32 = 0xffff_0010;
+
+const CONTROL: u32 = 0x0;
+const RELOAD: u32 = 0x4;
+const COUNTER: u32 = 0x8;
+const STATUS: u32 = 0xc;
+
+const CTRL_ENABLE: u32 = 1 << 0;
+const CTRL_IE: u32 = 1 << 1;
+const CTRL_RELOAD: u32 = 1 << 2;
+
+const STATUS_EXPIRED: u32 = 1 << 0;
+
+pub struct Timer {
+    control: u32,
+    reload: u32,
+    counter: u32,
+    status: u32,
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer {
+            control: 0,
+            reload: 0,
+            counter: 0,
+            status: 0,
+        }
+    }
+
+    fn read_reg(&self, reg: u32) -> u32 {
+        match reg {
+            CONTROL => self.control,
+            RELOAD => self.reload,
+            COUNTER => self.counter,
+            STATUS => self.status,
+            _ => 0,
+        }
+    }
+
+    fn write_reg(&mut self, reg: u32, value: u32) {
+        match reg {
+            CONTROL => self.control = value & (CTRL_ENABLE | CTRL_IE | CTRL_RELOAD),
+            RELOAD => {
+                self.reload = value;
+                self.counter = value;
+            },
+            COUNTER => self.counter = value,
+            STATUS => self.status &= !(value & STATUS_EXPIRED),
+            _ => {},
+        }
+    }
+}
+
+impl Device for Timer {
+    fn name(&self) -> &str {
+        "timer"
+    }
+
+    fn size(&self) -> u32 {
+        0x10
+    }
+
+    fn read(&mut self, offset: u32, size: u32) -> u32 {
+        let val = self.read_reg(offset & !0x3);
+        if size == 4 {
+            return val;
+        }
+        return (val >> (8 * (3 - (offset & 0x3)))) & 0xff;
+    }
+
+    /*
+     * Byte writes only touch the low byte of a register.
+     */
+    fn write(&mut self, offset: u32, size: u32, value: u32) {
+        if size == 4 || offset & 0x3 == 3 {
+            self.write_reg(offset & !0x3, value);
+        }
+    }
+
+    fn tick(&mut self) {
+        if self.control & CTRL_ENABLE == 0 || self.counter == 0 {
+            return;
+        }
+        self.counter -= 1;
+        if self.counter == 0 {
+            self.status |= STATUS_EXPIRED;
+            if self.control & CTRL_RELOAD != 0 {
+                self.counter = self.reload;
+            }
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.control & CTRL_IE != 0 && self.status & STATUS_EXPIRED != 0
+    }
+
+    fn save(&self) -> Vec<u8> {
+        let mut state = Vec::new();
+        for reg in [self.control, self.reload, self.counter, self.status].iter() {
+            state.extend_from_slice(&reg.to_be_bytes());
+        }
+        return state;
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<(), String> {
+        if state.len() != 16 {
+            return Err(format!("Timer-Error: state is {} bytes, expected 16", state.len()));
+        }
+        let reg = |i: usize| u32::from_be_bytes([state[i], state[i + 1], state[i + 2], state[i + 3]]);
+        self.control = reg(0);
+        self.reload = reg(4);
+        self.counter = reg(8);
+        self.status = reg(12);
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_one_shot() {
+        let mut timer = Timer::new();
+        timer.write(RELOAD, 4, 2);
+        timer.write(CONTROL, 4, CTRL_ENABLE | CTRL_IE);
+        timer.tick();
+        assert!(!timer.irq());
+        timer.tick();
+        assert!(timer.irq());
+        assert_eq!(timer.read(COUNTER, 4), 0);
+        timer.tick();
+        assert_eq!(timer.read(COUNTER, 4), 0);
+
+        timer.write(STATUS, 4, STATUS_EXPIRED);
+        assert!(!timer.irq());
+    }
+
+    #[test]
+    fn test_reload() {
+        let mut timer = Timer::new();
+        timer.write(RELOAD, 4, 3);
+        timer.write(CONTROL + 3, 1, CTRL_ENABLE | CTRL_RELOAD);
+        for _ in 0..3 {
+            timer.tick();
+        }
+        assert_eq!(timer.read(STATUS, 4), STATUS_EXPIRED);
+        assert_eq!(timer.read(COUNTER, 4), 3);
+        // irq disabled
+        assert!(!timer.irq());
+        timer.tick();
+        assert_eq!(timer.read(COUNTER + 3, 1), 2);
+    }
+}
@@ -0,0 +1,109 @@
+/*
+ * tlb.rs
+ *
+ * Author: Travis Banken
+ *
+ * 64 entry fully associative, software managed TLB (MIPS R3000).
+ *
+ * Entry Layout:
+ *  EntryHi  | VPN (31-12) | ASID (11-6) | 0 (5-0) |
+ *  EntryLo  | PFN (31-12) | N (11) | D (10) | V (9) | G (8) | 0 (7-0) |
+ *
+ *  N  non-cacheable
+ *  D  dirty, the page may be written
+ *  V  valid
+ *  G  global, ignore the ASID
+ */
+#![allow(dead_code)]
+
+pub const TLB_SIZE: usize = 64;
+
+pub const LO_N: u32 = 1 << 11;
+pub const LO_D: u32 = 1 << 10;
+pub const LO_V: u32 = 1 << 9;
+pub const LO_G: u32 = 1 << 8;
+
+const VPN_MASK: u32 = 0xffff_f000;
+const ASID_MASK: u32 = 0x0000_0fc0;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TlbEntry {
+    pub hi: u32,
+    pub lo: u32,
+}
+
+impl TlbEntry {
+    /*
+     * Does this entry map the page given by an EntryHi value?
+     */
+    pub fn matches(&self, entry_hi: u32) -> bool {
+        if self.hi & VPN_MASK != entry_hi & VPN_MASK {
+            return false;
+        }
+        return self.lo & LO_G != 0 || self.hi & ASID_MASK == entry_hi & ASID_MASK;
+    }
+}
+
+#[derive(Clone)]
+pub struct Tlb {
+    entries: [TlbEntry; TLB_SIZE]
+}
+
+impl Tlb {
+    pub fn new() -> Tlb {
+        Tlb {
+            entries: [TlbEntry::default(); TLB_SIZE]
+        }
+    }
+
+    /*
+     * Reads an entry (tlbr).
+     */
+    pub fn read(&self, index: usize) -> TlbEntry {
+        self.entries[index]
+    }
+
+    /*
+     * Writes an entry (tlbwi, tlbwr).
+     */
+    pub fn write(&mut self, index: usize, entry: TlbEntry) {
+        self.entries[index] = TlbEntry {
+            hi: entry.hi & (VPN_MASK | ASID_MASK),
+            lo: entry.lo & 0xffff_ff00,
+        };
+    }
+
+    /*
+     * Searches for the entry mapping the page given by an EntryHi value
+     * (tlbp).
+     */
+    pub fn probe(&self, entry_hi: u32) -> Option<usize> {
+        self.entries.iter().position(|entry| entry.matches(entry_hi))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_and_read() {
+        let mut tlb = Tlb::new();
+        tlb.write(5, TlbEntry { hi: 0x0040_1fff, lo: 0x0000_2fff });
+        assert_eq!(tlb.read(5), TlbEntry { hi: 0x0040_1fc0, lo: 0x0000_2f00 });
+    }
+
+    #[test]
+    fn test_probe() {
+        let mut tlb = Tlb::new();
+        tlb.write(10, TlbEntry { hi: 0x0040_1000 | (3 << 6), lo: 0x0000_2000 | LO_V });
+        tlb.write(11, TlbEntry { hi: 0x0050_0000, lo: 0x0000_3000 | LO_V | LO_G });
+
+        assert_eq!(tlb.probe(0x0040_1000 | (3 << 6)), Some(10));
+        // wrong asid
+        assert_eq!(tlb.probe(0x0040_1000 | (4 << 6)), None);
+        // global pages match any asid
+        assert_eq!(tlb.probe(0x0050_0000 | (9 << 6)), Some(11));
+        assert_eq!(tlb.probe(0x0060_0000), None);
+    }
+}
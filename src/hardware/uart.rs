@@ -0,0 +1,252 @@
+/*
+ * uart.rs
+ *
+ * Author: Travis Banken
+ *
+ * Console UART in the style of the SPIM/MARS memory mapped keyboard and
+ * display.
+ *
+ * Registers (offsets from the base address, default 0xffff_0000):
+ *  0x0  receiver control     bit 0 = a byte is ready, bit 1 = irq enable
+ *  0x4  receiver data        low byte, reading it consumes the byte
+ *  0x8  transmitter control  bit 0 = ready (always set), bit 1 = irq enable
+ *  0xc  transmitter data     writing the low byte prints it
+ */
+#![allow(dead_code)]
+
+use super::bus::Device;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+pub const UART_BASE: u32 = 0xffff_0000;
+
+const RX_CTRL: u32 = 0x0;
+const RX_DATA: u32 = 0x4;
+const TX_CTRL: u32 = 0x8;
+const TX_DATA: u32 = 0xc;
+
+const CTRL_READY: u32 = 1 << 0;
+const CTRL_IE: u32 = 1 << 1;
+
+pub struct Uart {
+    // bytes typed on the host, read from another thread so polling the
+    // status register never blocks
+    input: Option<Receiver<u8>>,
+    rx_buf: VecDeque<u8>,
+    output: Box<dyn Write>,
+    rx_ctrl: u32,
+    tx_ctrl: u32,
+}
+
+impl Uart {
+    /*
+     * Uart connected to the host stdin and stdout.
+     */
+    pub fn stdio() -> Uart {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let stdin = io::stdin();
+            for byte in stdin.lock().bytes() {
+                match byte {
+                    Ok(byte) => {
+                        if tx.send(byte).is_err() {
+                            break;
+                        }
+                    },
+                    Err(_) => break,
+                }
+            }
+        });
+        Uart::new(Some(rx), Box::new(io::stdout()))
+    }
+
+    pub fn new(input: Option<Receiver<u8>>, output: Box<dyn Write>) -> Uart {
+        Uart {
+            input,
+            rx_buf: VecDeque::new(),
+            output,
+            rx_ctrl: 0,
+            tx_ctrl: CTRL_READY,
+        }
+    }
+
+    /*
+     * Queue bytes as if they had been typed.
+     */
+    pub fn push_input(&mut self, bytes: &[u8]) {
+        self.rx_buf.extend(bytes.iter());
+    }
+
+    // *** PRIVATE FN ***
+
+    fn poll(&mut self) {
+        if let Some(input) = self.input.as_ref() {
+            while let Ok(byte) = input.try_recv() {
+                self.rx_buf.push_back(byte);
+            }
+        }
+    }
+
+    fn read_reg(&mut self, reg: u32) -> u32 {
+        match reg {
+            RX_CTRL => {
+                self.poll();
+                let ready = if self.rx_buf.is_empty() {0} else {CTRL_READY};
+                (self.rx_ctrl & CTRL_IE) | ready
+            },
+            RX_DATA => {
+                self.poll();
+                self.rx_buf.pop_front().unwrap_or(0) as u32
+            },
+            TX_CTRL => self.tx_ctrl,
+            _ => 0,
+        }
+    }
+
+    fn write_reg(&mut self, reg: u32, value: u32) {
+        match reg {
+            RX_CTRL => self.rx_ctrl = value & CTRL_IE,
+            TX_CTRL => self.tx_ctrl = (value & CTRL_IE) | CTRL_READY,
+            TX_DATA => {
+                // the console is best effort, a closed stdout is not the
+                // program's problem
+                let _ = self.output.write_all(&[value as u8]);
+                let _ = self.output.flush();
+            },
+            _ => {},
+        }
+    }
+}
+
+impl Device for Uart {
+    fn name(&self) -> &str {
+        "uart"
+    }
+
+    fn size(&self) -> u32 {
+        0x10
+    }
+
+    /*
+     * Registers are words, a byte access sees the matching big endian byte
+     * of the register.
+     */
+    fn read(&mut self, offset: u32, size: u32) -> u32 {
+        let reg = offset & !0x3;
+        if size == 4 {
+            return self.read_reg(reg);
+        }
+        // only reading the low byte consumes received data
+        let byte = offset & 0x3;
+        if reg == RX_DATA && byte != 3 {
+            return 0;
+        }
+        let val = self.read_reg(reg);
+        return (val >> (8 * (3 - byte))) & 0xff;
+    }
+
+    fn write(&mut self, offset: u32, size: u32, value: u32) {
+        let reg = offset & !0x3;
+        if size == 4 || offset & 0x3 == 3 {
+            self.write_reg(reg, value);
+        }
+    }
+
+    fn tick(&mut self) {
+        self.poll();
+    }
+
+    /*
+     * Receiver interrupts while a byte is waiting, the transmitter is always
+     * ready so it interrupts whenever enabled.
+     */
+    fn irq(&self) -> bool {
+        let rx = self.rx_ctrl & CTRL_IE != 0 && !self.rx_buf.is_empty();
+        let tx = self.tx_ctrl & CTRL_IE != 0;
+        return rx || tx;
+    }
+
+    /*
+     * Control registers followed by the bytes typed but not read yet.
+     */
+    fn save(&self) -> Vec<u8> {
+        let mut state = Vec::new();
+        state.extend_from_slice(&self.rx_ctrl.to_be_bytes());
+        state.extend_from_slice(&self.tx_ctrl.to_be_bytes());
+        state.extend(self.rx_buf.iter());
+        return state;
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<(), String> {
+        if state.len() < 8 {
+            return Err(format!("Uart-Error: state is {} bytes, expected at least 8", state.len()));
+        }
+        self.rx_ctrl = u32::from_be_bytes([state[0], state[1], state[2], state[3]]);
+        self.tx_ctrl = u32::from_be_bytes([state[4], state[5], state[6], state[7]]);
+        self.rx_buf = state[8..].iter().copied().collect();
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Clone)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_transmit() {
+        let out = Shared(Rc::new(RefCell::new(Vec::new())));
+        let mut uart = Uart::new(None, Box::new(out.clone()));
+        assert_eq!(uart.read(TX_CTRL, 4), CTRL_READY);
+        uart.write(TX_DATA, 4, 'h' as u32);
+        uart.write(TX_DATA + 3, 1, 'i' as u32);
+        // high bytes of the data register are ignored
+        uart.write(TX_DATA, 1, 'x' as u32);
+        assert_eq!(*out.0.borrow(), b"hi".to_vec());
+    }
+
+    #[test]
+    fn test_receive() {
+        let (tx, rx) = mpsc::channel();
+        let mut uart = Uart::new(Some(rx), Box::new(io::sink()));
+        assert_eq!(uart.read(RX_CTRL, 4), 0);
+
+        tx.send(b'a').unwrap();
+        uart.push_input(b"b");
+        assert_eq!(uart.read(RX_CTRL, 4), CTRL_READY);
+        assert_eq!(uart.read(RX_DATA, 4), 'b' as u32);
+        assert_eq!(uart.read(RX_DATA, 1), 0);
+        assert_eq!(uart.read(RX_DATA + 3, 1), 'a' as u32);
+        assert_eq!(uart.read(RX_CTRL + 3, 1), 0);
+    }
+
+    #[test]
+    fn test_irq() {
+        let mut uart = Uart::new(None, Box::new(io::sink()));
+        uart.push_input(b"a");
+        assert!(!uart.irq());
+        uart.write(RX_CTRL, 4, CTRL_IE);
+        assert!(uart.irq());
+        uart.read(RX_DATA, 4);
+        assert!(!uart.irq());
+        uart.write(TX_CTRL, 4, CTRL_IE);
+        assert!(uart.irq());
+    }
+}
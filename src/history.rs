@@ -0,0 +1,452 @@
+/*
+ * history.rs
+ *
+ * Author: Travis Banken
+ *
+ * Execution history for reverse debugging. Every instruction run through the
+ * history leaves an undo record (pc, hi/lo, cp0, the RISC-V csrs, the
+ * register and memory it wrote) and every `interval` instructions a full
+ * snapshot of the machine is taken. Stepping back applies the undo record
+ * when it covers everything the instruction changed, otherwise the machine
+ * is restored from the closest snapshot before it and replayed. The oldest
+ * snapshots (and the records before them) are dropped to keep the history
+ * under its memory budget.
+ *
+ * Devices on the bus are not rewound, a replay reads and writes them again.
+ *
+ * Config spec: "interval=1000,budget=16M"
+ *  interval    instructions between two snapshots
+ *  budget      bytes the history may use, with an optional K or M suffix
+ */
+#![allow(dead_code)]
+
+use crate::cycle::{Cycle, MemAccess, RegWrite};
+use crate::hardware::*;
+use crate::hardware::hierarchy::Hierarchy;
+use crate::riscv::csr;
+use crate::single_cycle::Processor;
+use std::collections::VecDeque;
+use std::mem::size_of;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HistoryConfig {
+    pub interval: u64,
+    pub budget: usize,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> HistoryConfig {
+        HistoryConfig {
+            interval: 1000,
+            budget: 16 << 20,
+        }
+    }
+}
+
+impl HistoryConfig {
+    /*
+     * Builds a config from a spec string, see the header of this file for
+     * the format.
+     */
+    pub fn parse(spec: &str) -> Result<HistoryConfig, String> {
+        let mut config = HistoryConfig::default();
+        for field in spec.split(',').filter(|f| !f.is_empty()) {
+            let mut kv = field.splitn(2, '=');
+            let key = kv.next().unwrap_or("").trim();
+            let val = kv.next().unwrap_or("").trim();
+            let (digits, scale) = if let Some(num) = val.strip_suffix('K') {
+                (num, 1 << 10)
+            } else if let Some(num) = val.strip_suffix('M') {
+                (num, 1 << 20)
+            } else {
+                (val, 1)
+            };
+            let num = match digits.parse::<u64>() {
+                Ok(num) if num > 0 => num * scale,
+                _ => return Err(format!("History-Error: bad number [{}] for [{}]", val, key)),
+            };
+            match key {
+                "interval" => config.interval = num,
+                "budget" => config.budget = num as usize,
+                _ => return Err(format!("History-Error: unknown history option [{}]", key)),
+            }
+        }
+        return Ok(config);
+    }
+}
+
+/*
+ * What one instruction changed, enough to run it backwards.
+ */
+#[derive(Clone)]
+pub struct Undo {
+    // instructions retired before this one, its place in the history
+    pub when: u64,
+    pub pc: u32,
+    pub write: Option<RegWrite>,
+    // stores to memory (not devices)
+    pub mem: Option<MemAccess>,
+    // the load or store as the program saw it, for watches
+    pub access: Option<MemAccess>,
+
+    cycles: u64,
+    hi: u32,
+    lo: u32,
+    cp0: cp0::Cp0,
+    csrs: csr::Csrs,
+    intc: u8,
+    // false when the instruction changed state the record does not cover
+    // (tlb, caches), undoing it needs a snapshot
+    exact: bool,
+}
+
+/*
+ * Full copy of the machine, devices aside.
+ */
+#[derive(Clone)]
+struct Snapshot {
+    when: u64,
+    cycles: u64,
+    ip: u32,
+    hi: u32,
+    lo: u32,
+    regfile: reg_file::Registers,
+    cp0: cp0::Cp0,
+    csrs: csr::Csrs,
+    mmu: mmu::Mmu,
+    intc: intc::InterruptController,
+    hierarchy: Option<Hierarchy>,
+    mem: data_mem::Memory,
+}
+
+impl Snapshot {
+    fn take(cpu: &Processor, mem: &data_mem::Memory) -> Snapshot {
+        Snapshot {
+            when: cpu.instr_count,
+            cycles: cpu.cycles,
+            ip: cpu.ip,
+            hi: cpu.hi,
+            lo: cpu.lo,
+            regfile: cpu.regfile.clone(),
+            cp0: cpu.cp0.clone(),
+            csrs: cpu.csrs,
+            mmu: cpu.mmu.clone(),
+            intc: cpu.intc.clone(),
+            hierarchy: cpu.hierarchy.clone(),
+            mem: mem.clone(),
+        }
+    }
+
+    fn restore(&self, cpu: &mut Processor, mem: &mut data_mem::Memory) {
+        cpu.instr_count = self.when;
+        cpu.cycles = self.cycles;
+        cpu.ip = self.ip;
+        cpu.hi = self.hi;
+        cpu.lo = self.lo;
+        cpu.regfile = self.regfile.clone();
+        cpu.cp0 = self.cp0.clone();
+        cpu.csrs = self.csrs;
+        cpu.mmu = self.mmu.clone();
+        cpu.intc = self.intc.clone();
+        cpu.hierarchy = self.hierarchy.clone();
+        *mem = self.mem.clone();
+    }
+}
+
+pub struct History {
+    config: HistoryConfig,
+    snapshots: VecDeque<Snapshot>,
+    log: VecDeque<Undo>,
+}
+
+impl History {
+    pub fn new(config: HistoryConfig) -> History {
+        History {
+            config,
+            snapshots: VecDeque::new(),
+            log: VecDeque::new(),
+        }
+    }
+
+    pub fn config(&self) -> HistoryConfig {
+        self.config
+    }
+
+    /*
+     * Forgets everything, used when the machine was changed by hand.
+     */
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+        self.log.clear();
+    }
+
+    /*
+     * Earliest instruction count the machine can go back to.
+     */
+    pub fn oldest(&self) -> Option<u64> {
+        self.snapshots.front().map(|snap| snap.when)
+    }
+
+    /*
+     * Number of instructions that can be undone.
+     */
+    pub fn len(&self) -> usize {
+        self.log.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.log.is_empty()
+    }
+
+    /*
+     * Bytes used by the history. Cache contents in snapshots are not
+     * counted.
+     */
+    pub fn size(&self) -> usize {
+        return self.snapshots.len() * size_of::<Snapshot>() + self.log.len() * size_of::<Undo>();
+    }
+
+    /*
+     * Runs one instruction and records how to undo it.
+     */
+    pub fn step(&mut self, cpu: &mut Processor, instr_mem: &instr_mem::Memory, bus: &mut bus::Bus) -> Cycle {
+        let when = cpu.instr_count;
+        let due = match self.snapshots.back() {
+            Some(snap) => when >= snap.when + self.config.interval,
+            None => true,
+        };
+        if due {
+            self.snapshots.push_back(Snapshot::take(cpu, bus.mem()));
+        }
+
+        let mut undo = Undo {
+            when,
+            pc: cpu.ip,
+            write: None,
+            mem: None,
+            access: None,
+            cycles: cpu.cycles,
+            hi: cpu.hi,
+            lo: cpu.lo,
+            cp0: cpu.cp0.clone(),
+            csrs: cpu.csrs,
+            intc: cpu.intc.lines(),
+            exact: true,
+        };
+        let cycle = cpu.step(instr_mem, bus);
+        undo.write = cycle.write;
+        undo.access = cycle.mem;
+        undo.mem = cycle.mem.filter(|m| m.is_write && !bus.is_device(m.paddr));
+        // tlb writes and cache state are only kept by the snapshots
+        undo.exact = !cycle.ctrl.cop0 && cpu.hierarchy.is_none();
+        self.log.push_back(undo);
+
+        self.trim();
+        return cycle;
+    }
+
+    /*
+     * Undoes the last instruction, returns its record or None at the start
+     * of the history.
+     */
+    pub fn back(&mut self, cpu: &mut Processor, instr_mem: &instr_mem::Memory, bus: &mut bus::Bus)
+                -> Option<Undo> {
+        let undo = self.log.pop_back()?;
+        if undo.exact {
+            if let Some(write) = undo.write {
+                cpu.regfile.write(write.old, write.reg as usize);
+            }
+            if let Some(mem) = undo.mem {
+                let addr = mem.paddr as usize;
+                for i in 0..mem.size as usize {
+                    let shift = 8 * (mem.size as usize - 1 - i);
+                    bus.mem_mut().write((mem.old >> shift) as u8, addr + i);
+                }
+            }
+            cpu.ip = undo.pc;
+            cpu.hi = undo.hi;
+            cpu.lo = undo.lo;
+            cpu.cp0 = undo.cp0.clone();
+            cpu.csrs = undo.csrs;
+            cpu.intc.set_lines(undo.intc);
+            cpu.instr_count = undo.when;
+            cpu.cycles = undo.cycles;
+        } else {
+            // the record is back in the log once the replay passes it
+            self.replay(cpu, instr_mem, bus, undo.when);
+        }
+        while self.snapshots.back().map(|snap| snap.when > undo.when).unwrap_or(false) {
+            self.snapshots.pop_back();
+        }
+        return Some(undo);
+    }
+
+    /*
+     * Takes the machine back to the point where `when` instructions had
+     * run.
+     */
+    pub fn goto(&mut self, cpu: &mut Processor, instr_mem: &instr_mem::Memory, bus: &mut bus::Bus,
+                when: u64) -> Result<(), String> {
+        if when > cpu.instr_count {
+            return Err(format!("History-Error: instruction {} has not run yet", when));
+        }
+        match self.oldest() {
+            Some(oldest) if when >= oldest => {},
+            Some(oldest) => return Err(format!("History-Error: instruction {} is no longer in the \
+                                                history, the oldest is {}", when, oldest)),
+            None if when == cpu.instr_count => return Ok(()),
+            None => return Err("History-Error: no history recorded".to_string()),
+        }
+        if cpu.instr_count - when <= self.config.interval {
+            while cpu.instr_count > when {
+                self.back(cpu, instr_mem, bus);
+            }
+        } else {
+            self.replay(cpu, instr_mem, bus, when);
+            while self.snapshots.back().map(|snap| snap.when > when).unwrap_or(false) {
+                self.snapshots.pop_back();
+            }
+        }
+        return Ok(());
+    }
+
+    // *** PRIVATE FN ***
+
+    /*
+     * Restores the last snapshot at or before `when` and runs forward to it.
+     * Observers and watches are kept out of the replay.
+     */
+    fn replay(&mut self, cpu: &mut Processor, instr_mem: &instr_mem::Memory, bus: &mut bus::Bus, when: u64) {
+        let snap = match self.snapshots.iter().rev().find(|snap| snap.when <= when) {
+            Some(snap) => snap.clone(),
+            None => return,
+        };
+        snap.restore(cpu, bus.mem_mut());
+        while self.log.back().map(|undo| undo.when >= snap.when).unwrap_or(false) {
+            self.log.pop_back();
+        }
+        while self.snapshots.back().map(|s| s.when > snap.when).unwrap_or(false) {
+            self.snapshots.pop_back();
+        }
+
+        let observers = std::mem::take(&mut cpu.observers);
+        while cpu.instr_count < when && cpu.is_running() {
+            self.step(cpu, instr_mem, bus);
+        }
+        cpu.observers = observers;
+        cpu.watches.take_log();
+        cpu.watches.take_stop();
+        cpu.watches.sync_regs(&cpu.regfile);
+    }
+
+    /*
+     * Drops the oldest snapshots and the records before them until the
+     * history fits its budget again. The newest snapshot always stays.
+     */
+    fn trim(&mut self) {
+        while self.size() > self.config.budget && self.snapshots.len() > 1 {
+            self.snapshots.pop_front();
+            let oldest = self.snapshots.front().map(|snap| snap.when).unwrap_or(0);
+            while self.log.front().map(|undo| undo.when < oldest).unwrap_or(false) {
+                self.log.pop_front();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::demos::assembler::instr_macros::*;
+    use crate::demos::assembler::mem_loader::MemLoader;
+    use crate::demos::assembler::reg_macros::*;
+    use crate::single_cycle::Options;
+
+    fn program() -> instr_mem::Memory {
+        let mut loader = MemLoader::new(instr_mem::Memory::new());
+        loader.load_instr( ADDI(S0(), ZERO(), 0x40) );
+        loader.load_instr( ADDI(T0(), ZERO(), 0) );
+        // loop: store t0 at s0 + t0, 8 times
+        loader.load_instr( ADD (T1(), S0(),   T0()) );
+        loader.load_instr( SB  (T0(), 0     , T1()) );
+        loader.load_instr( ADDI(T0(), T0(),   1) );
+        loader.load_instr( ADDI(T2(), ZERO(), 8) );
+        loader.load_instr( BEQ (T0(), T2(),   0x20) );
+        loader.load_instr( J   (0x8) );
+        return loader.return_mem();
+    }
+
+    fn state(cpu: &Processor, mem: &data_mem::Memory) -> (u32, Vec<u32>, Vec<u8>, u64, u32) {
+        let regs = (0..32).map(|r| cpu.regfile.load(r)).collect();
+        let bytes = (0..data_mem::Memory::get_size()).map(|a| mem.read(a)).collect();
+        return (cpu.ip, regs, bytes, cpu.instr_count, cpu.cp0.load(cp0::COUNT));
+    }
+
+    #[test]
+    fn test_parse() {
+        let config = HistoryConfig::parse("interval=10,budget=2M").unwrap();
+        assert_eq!(config, HistoryConfig { interval: 10, budget: 2 << 20 });
+        assert_eq!(HistoryConfig::parse("budget=64K").unwrap().budget, 64 << 10);
+        assert!(HistoryConfig::parse("interval=0").is_err());
+        assert!(HistoryConfig::parse("depth=3").is_err());
+    }
+
+    #[test]
+    fn test_back_and_goto() {
+        let mem = program();
+        let mut data_mem = data_mem::Memory::new();
+        let mut bus = bus::Bus::new(&mut data_mem);
+        let mut cpu = Processor::new(&Options::default());
+        let mut history = History::new(HistoryConfig { interval: 7, budget: 1 << 20 });
+
+        let mut states = Vec::new();
+        for _ in 0..30 {
+            states.push(state(&cpu, bus.mem()));
+            history.step(&mut cpu, &mem, &mut bus);
+        }
+        states.push(state(&cpu, bus.mem()));
+
+        // undo records
+        for when in (25..30).rev() {
+            let undo = history.back(&mut cpu, &mem, &mut bus).unwrap();
+            assert_eq!(undo.when, when);
+            assert_eq!(state(&cpu, bus.mem()), states[when as usize]);
+        }
+        // snapshot + replay
+        history.goto(&mut cpu, &mem, &mut bus, 3).unwrap();
+        assert_eq!(state(&cpu, bus.mem()), states[3]);
+        // forward again leaves the same trail
+        for _ in 3..20 {
+            history.step(&mut cpu, &mem, &mut bus);
+        }
+        assert_eq!(state(&cpu, bus.mem()), states[20]);
+        history.goto(&mut cpu, &mem, &mut bus, 12).unwrap();
+        assert_eq!(state(&cpu, bus.mem()), states[12]);
+
+        assert!(history.goto(&mut cpu, &mem, &mut bus, 13).is_err());
+        history.goto(&mut cpu, &mem, &mut bus, 0).unwrap();
+        assert_eq!(state(&cpu, bus.mem()), states[0]);
+        assert!(history.back(&mut cpu, &mem, &mut bus).is_none());
+    }
+
+    #[test]
+    fn test_budget() {
+        let mem = program();
+        let mut data_mem = data_mem::Memory::new();
+        let mut bus = bus::Bus::new(&mut data_mem);
+        let mut cpu = Processor::new(&Options::default());
+        let budget = 3 * size_of::<Snapshot>() + 20 * size_of::<Undo>();
+        let mut history = History::new(HistoryConfig { interval: 10, budget });
+
+        for _ in 0..60 {
+            history.step(&mut cpu, &mem, &mut bus);
+        }
+        assert!(history.size() <= budget);
+        let oldest = history.oldest().unwrap();
+        assert!(oldest > 0);
+        assert_eq!(history.len() as u64, 60 - oldest);
+        assert!(history.goto(&mut cpu, &mem, &mut bus, oldest - 1).is_err());
+        history.goto(&mut cpu, &mem, &mut bus, oldest).unwrap();
+        assert_eq!(cpu.instr_count, oldest);
+    }
+}
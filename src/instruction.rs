@@ -1,13 +1,20 @@
 /*
  * instruction.rs
- * 
+ *
  * Author: Travis Banken
- * 
- * Structure for holding decoded instruction
+ *
+ * Structure for holding decoded instruction, and the definition of every
+ * instruction the tools know: its mnemonic, format and operands. The
+ * decoder, the encoders in instr_macros.rs, the disassembler, the assembler
+ * and the control table of the datapath are all built on DEFS.
  */
 #![allow(dead_code)]
 
-#[derive(Debug)]
+use crate::isa::Flow;
+use crate::phases::instr_decode;
+use Operand::*;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
 pub struct Instruction {
     pub opcode: u8,
     pub rs: u8,
@@ -25,17 +32,407 @@ pub struct Instruction {
     pub addr: u32
 }
 
-impl Default for Instruction {
-    fn default() -> Instruction {
-        Instruction {
-            opcode: 0,
-            rs: 0,
-            rt: 0,
-            rd: 0,
-            shamt: 0,
-            funct: 0,
-            imm16: 0,
-            addr: 0 
+/*
+ * Every instruction, in the order of DEFS.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Op {
+    Sll, Srl, Sra, Sllv, Srlv, Srav, Jr, Jalr, Syscall, Break,
+    Mfhi, Mthi, Mflo, Mtlo, Mult, Multu, Div, Divu,
+    Add, Addu, Sub, Subu, And, Or, Xor, Nor, Slt, Sltu,
+    J, Jal, Beq, Bne,
+    Addi, Addiu, Slti, Sltiu, Andi, Ori, Xori, Lui,
+    Lb, Lh, Lw, Lbu, Lhu, Sb, Sh, Sw,
+    Lwl, Lwr, Swl, Swr,
+    Mfc0, Mtc0, Tlbr, Tlbwi, Tlbwr, Tlbp, Rfe,
+    // MIPS32 release 1 and 2
+    Sync, Movz, Movn, Tge, Tgeu, Tlt, Tltu, Teq, Tne, Rotr, Rotrv,
+    Tgei, Tgeiu, Tlti, Tltiu, Teqi, Tnei,
+    Madd, Maddu, Mul, Msub, Msubu, Clz, Clo,
+    Ext, Ins, Wsbh, Seb, Seh,
+}
+
+/*
+ * Format of an instruction with the fields that select it.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    // opcode 0, by funct
+    R(u8),
+    // by opcode
+    I(u8),
+    J(u8),
+    // coprocessor 0 moves by rs, and the co instructions (rs 0x10) by funct
+    Cop0(u8),
+    Co(u8),
+    // opcode 0 rotates by funct, srl and srlv with the R bit (rs for rotr,
+    // shamt for rotrv) set
+    Rot(u8),
+    // opcode 0x01 by rt, opcode 0x1c by funct
+    Regimm(u8),
+    Special2(u8),
+    // opcode 0x1f by funct, and the bshfl instructions (funct 0x20) by shamt
+    Special3(u8),
+    Bshfl(u8),
+}
+
+/*
+ * Operand of an instruction in assembly order, with the field it is
+ * encoded in.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+    Rd,
+    Rs,
+    Rt,
+    Shamt,
+    // imm16
+    Imm,
+    // imm16 and rs, written "offset($base)"
+    Offset,
+    Base,
+    // absolute branch target in imm16
+    Branch,
+    // 26 bit jump target
+    Target,
+    // coprocessor 0 register number in rd
+    Cop0Reg,
+    // bit field size of ext in rd as size - 1, of ins as pos + size - 1
+    // (pos is the shamt operand before it)
+    ExtSize,
+    InsSize,
+}
+
+pub struct Def {
+    pub op: Op,
+    pub mnemonic: &'static str,
+    pub format: Format,
+    pub operands: &'static [Operand],
+}
+
+/*
+ * Definition of an instruction.
+ */
+pub fn def(op: Op) -> &'static Def {
+    return &DEFS[op as usize];
+}
+
+/*
+ * Definition of the instruction with the given mnemonic.
+ */
+pub fn lookup(mnemonic: &str) -> Option<&'static Def> {
+    return DEFS.iter().find(|def| def.mnemonic == mnemonic);
+}
+
+/*
+ * Definition of a decoded instruction, None if no instruction has its
+ * opcode and function fields.
+ */
+pub fn decode(instr: &Instruction) -> Option<&'static Def> {
+    let format = match instr.opcode {
+        0x00 if instr.funct == 0x02 && instr.rs == 1 => Format::Rot(0x02),
+        0x00 if instr.funct == 0x06 && instr.shamt == 1 => Format::Rot(0x06),
+        0x00 => Format::R(instr.funct),
+        0x01 => Format::Regimm(instr.rt),
+        0x02 | 0x03 => Format::J(instr.opcode),
+        0x10 if instr.rs == 0x10 => Format::Co(instr.funct),
+        0x10 => Format::Cop0(instr.rs),
+        0x1c => Format::Special2(instr.funct),
+        0x1f if instr.funct == 0x20 => Format::Bshfl(instr.shamt),
+        0x1f => Format::Special3(instr.funct),
+        opcode => Format::I(opcode),
+    };
+    return DEFS.iter().find(|def| def.format == format);
+}
+
+/*
+ * Word of an instruction, with the values of its operands in assembly
+ * order. Values are truncated to their field.
+ */
+pub fn encode(op: Op, values: &[u32]) -> u32 {
+    let def = def(op);
+    assert_eq!(values.len(), def.operands.len(), "Error: [{}] takes {} operands", def.mnemonic, def.operands.len());
+    let mut word = match def.format {
+        Format::R(funct) => funct as u32,
+        Format::I(opcode) | Format::J(opcode) => (opcode as u32) << 26,
+        Format::Cop0(rs) => 0x10 << 26 | (rs as u32) << 21,
+        Format::Co(funct) => 0x10 << 26 | 0x10 << 21 | funct as u32,
+        Format::Rot(funct) => funct as u32 | if funct == 0x02 {1 << 21} else {1 << 6},
+        Format::Regimm(rt) => 0x01 << 26 | (rt as u32) << 16,
+        Format::Special2(funct) => 0x1c << 26 | funct as u32,
+        Format::Special3(funct) => 0x1f << 26 | funct as u32,
+        Format::Bshfl(shamt) => 0x1f << 26 | (shamt as u32) << 6 | 0x20,
+    };
+    for (operand, val) in def.operands.iter().zip(values.iter()) {
+        word |= match operand {
+            Operand::Rs | Operand::Base => (val & 0x1f) << 21,
+            Operand::Rt => (val & 0x1f) << 16,
+            Operand::Rd | Operand::Cop0Reg => (val & 0x1f) << 11,
+            Operand::Shamt => (val & 0x1f) << 6,
+            Operand::Imm | Operand::Offset | Operand::Branch => val & 0xffff,
+            Operand::Target => val & 0x03ff_ffff,
+            Operand::ExtSize => (val.wrapping_sub(1) & 0x1f) << 11,
+            Operand::InsSize => ((((word >> 6) & 0x1f) + val).wrapping_sub(1) & 0x1f) << 11,
+        };
+    }
+    // clz and clo name their destination twice, in rd and rt
+    if matches!(op, Op::Clz | Op::Clo) {
+        word |= ((word >> 11) & 0x1f) << 16;
+    }
+    return word;
+}
+
+/*
+ * Value of an operand in a decoded instruction.
+ */
+pub fn operand_value(operand: Operand, instr: &Instruction) -> u32 {
+    return match operand {
+        Operand::Rs | Operand::Base => instr.rs as u32,
+        Operand::Rt => instr.rt as u32,
+        Operand::Rd | Operand::Cop0Reg => instr.rd as u32,
+        Operand::Shamt => instr.shamt as u32,
+        Operand::Imm | Operand::Offset | Operand::Branch => instr.imm16 as u32,
+        Operand::Target => instr.addr,
+        Operand::ExtSize => instr.rd as u32 + 1,
+        Operand::InsSize => (instr.rd as u32 + 1).wrapping_sub(instr.shamt as u32),
+    };
+}
+
+/*
+ * How the instruction word at pc moves the pc. jal and jalr are calls, jr
+ * $ra is a return and rfe returns from the exception handler.
+ */
+pub fn flow(word: u32, pc: u32) -> Flow {
+    let mut instr = Instruction::default();
+    instr_decode(word, &mut instr);
+    let def = match decode(&instr) {
+        Some(def) => def,
+        None => return Flow::default(),
+    };
+    let target = if def.operands.contains(&Target) {
+        Some((pc & 0xff00_0000) | instr.addr)
+    } else if def.operands.contains(&Branch) {
+        Some((pc & 0xffff_0000) | instr.imm16 as u32)
+    } else {
+        None
+    };
+    return Flow {
+        branch: def.operands.contains(&Branch),
+        target,
+        ends_block: matches!(def.op, Op::Jr | Op::Jalr | Op::Rfe) || target.is_some(),
+        call: matches!(def.op, Op::Jal | Op::Jalr),
+        ret: def.op == Op::Jr && instr.rs == 31,
+    };
+}
+
+// *** PRIVATE FN ***
+
+const ALU: &[Operand] = &[Rd, Rs, Rt];
+const SHIFT: &[Operand] = &[Rd, Rt, Shamt];
+const ALU_IMM: &[Operand] = &[Rt, Rs, Imm];
+const MEM: &[Operand] = &[Rt, Offset, Base];
+const BRANCH: &[Operand] = &[Rs, Rt, Branch];
+const MUL_DIV: &[Operand] = &[Rs, Rt];
+const COP0_MOVE: &[Operand] = &[Rt, Cop0Reg];
+const TRAP: &[Operand] = &[Rs, Rt];
+const TRAP_IMM: &[Operand] = &[Rs, Imm];
+// rd = f(rt)
+const BIT_OP: &[Operand] = &[Rd, Rt];
+const NONE: &[Operand] = &[];
+
+const fn r(op: Op, mnemonic: &'static str, funct: u8, operands: &'static [Operand]) -> Def {
+    Def { op, mnemonic, format: Format::R(funct), operands }
+}
+
+const fn special2(op: Op, mnemonic: &'static str, funct: u8, operands: &'static [Operand]) -> Def {
+    Def { op, mnemonic, format: Format::Special2(funct), operands }
+}
+
+const fn regimm(op: Op, mnemonic: &'static str, rt: u8) -> Def {
+    Def { op, mnemonic, format: Format::Regimm(rt), operands: TRAP_IMM }
+}
+
+const fn bshfl(op: Op, mnemonic: &'static str, shamt: u8) -> Def {
+    Def { op, mnemonic, format: Format::Bshfl(shamt), operands: BIT_OP }
+}
+
+const fn i(op: Op, mnemonic: &'static str, opcode: u8, operands: &'static [Operand]) -> Def {
+    Def { op, mnemonic, format: Format::I(opcode), operands }
+}
+
+pub static DEFS: [Def; 88] = [
+    r(Op::Sll, "sll", 0x00, SHIFT),
+    r(Op::Srl, "srl", 0x02, SHIFT),
+    r(Op::Sra, "sra", 0x03, SHIFT),
+    r(Op::Sllv, "sllv", 0x04, &[Rd, Rt, Rs]),
+    r(Op::Srlv, "srlv", 0x06, &[Rd, Rt, Rs]),
+    r(Op::Srav, "srav", 0x07, &[Rd, Rt, Rs]),
+    r(Op::Jr, "jr", 0x08, &[Rs]),
+    r(Op::Jalr, "jalr", 0x09, &[Rd, Rs]),
+    r(Op::Syscall, "syscall", 0x0C, NONE),
+    r(Op::Break, "break", 0x0D, NONE),
+    r(Op::Mfhi, "mfhi", 0x10, &[Rd]),
+    r(Op::Mthi, "mthi", 0x11, &[Rs]),
+    r(Op::Mflo, "mflo", 0x12, &[Rd]),
+    r(Op::Mtlo, "mtlo", 0x13, &[Rs]),
+    r(Op::Mult, "mult", 0x18, MUL_DIV),
+    r(Op::Multu, "multu", 0x19, MUL_DIV),
+    r(Op::Div, "div", 0x1A, MUL_DIV),
+    r(Op::Divu, "divu", 0x1B, MUL_DIV),
+    r(Op::Add, "add", 0x20, ALU),
+    r(Op::Addu, "addu", 0x21, ALU),
+    r(Op::Sub, "sub", 0x22, ALU),
+    r(Op::Subu, "subu", 0x23, ALU),
+    r(Op::And, "and", 0x24, ALU),
+    r(Op::Or, "or", 0x25, ALU),
+    r(Op::Xor, "xor", 0x26, ALU),
+    r(Op::Nor, "nor", 0x27, ALU),
+    r(Op::Slt, "slt", 0x2A, ALU),
+    r(Op::Sltu, "sltu", 0x2B, ALU),
+
+    Def { op: Op::J, mnemonic: "j", format: Format::J(0x02), operands: &[Target] },
+    Def { op: Op::Jal, mnemonic: "jal", format: Format::J(0x03), operands: &[Target] },
+    i(Op::Beq, "beq", 0x04, BRANCH),
+    i(Op::Bne, "bne", 0x05, BRANCH),
+
+    i(Op::Addi, "addi", 0x08, ALU_IMM),
+    i(Op::Addiu, "addiu", 0x09, ALU_IMM),
+    i(Op::Slti, "slti", 0x0A, ALU_IMM),
+    i(Op::Sltiu, "sltiu", 0x0B, ALU_IMM),
+    i(Op::Andi, "andi", 0x0C, ALU_IMM),
+    i(Op::Ori, "ori", 0x0D, ALU_IMM),
+    i(Op::Xori, "xori", 0x0E, ALU_IMM),
+    i(Op::Lui, "lui", 0x0F, &[Rt, Imm]),
+
+    i(Op::Lb, "lb", 0x20, MEM),
+    i(Op::Lh, "lh", 0x21, MEM),
+    i(Op::Lw, "lw", 0x23, MEM),
+    i(Op::Lbu, "lbu", 0x24, MEM),
+    i(Op::Lhu, "lhu", 0x25, MEM),
+    i(Op::Sb, "sb", 0x28, MEM),
+    i(Op::Sh, "sh", 0x29, MEM),
+    i(Op::Sw, "sw", 0x2B, MEM),
+    // unaligned words, in parts
+    i(Op::Lwl, "lwl", 0x22, MEM),
+    i(Op::Lwr, "lwr", 0x26, MEM),
+    i(Op::Swl, "swl", 0x2A, MEM),
+    i(Op::Swr, "swr", 0x2E, MEM),
+
+    Def { op: Op::Mfc0, mnemonic: "mfc0", format: Format::Cop0(0x00), operands: COP0_MOVE },
+    Def { op: Op::Mtc0, mnemonic: "mtc0", format: Format::Cop0(0x04), operands: COP0_MOVE },
+    Def { op: Op::Tlbr, mnemonic: "tlbr", format: Format::Co(0x01), operands: NONE },
+    Def { op: Op::Tlbwi, mnemonic: "tlbwi", format: Format::Co(0x02), operands: NONE },
+    Def { op: Op::Tlbwr, mnemonic: "tlbwr", format: Format::Co(0x06), operands: NONE },
+    Def { op: Op::Tlbp, mnemonic: "tlbp", format: Format::Co(0x08), operands: NONE },
+    Def { op: Op::Rfe, mnemonic: "rfe", format: Format::Co(0x10), operands: NONE },
+
+    // sync orders memory accesses, they are already in order here
+    r(Op::Sync, "sync", 0x0F, NONE),
+    r(Op::Movz, "movz", 0x0A, ALU),
+    r(Op::Movn, "movn", 0x0B, ALU),
+    r(Op::Tge, "tge", 0x30, TRAP),
+    r(Op::Tgeu, "tgeu", 0x31, TRAP),
+    r(Op::Tlt, "tlt", 0x32, TRAP),
+    r(Op::Tltu, "tltu", 0x33, TRAP),
+    r(Op::Teq, "teq", 0x34, TRAP),
+    r(Op::Tne, "tne", 0x36, TRAP),
+    Def { op: Op::Rotr, mnemonic: "rotr", format: Format::Rot(0x02), operands: SHIFT },
+    Def { op: Op::Rotrv, mnemonic: "rotrv", format: Format::Rot(0x06), operands: &[Rd, Rt, Rs] },
+    regimm(Op::Tgei, "tgei", 0x08),
+    regimm(Op::Tgeiu, "tgeiu", 0x09),
+    regimm(Op::Tlti, "tlti", 0x0A),
+    regimm(Op::Tltiu, "tltiu", 0x0B),
+    regimm(Op::Teqi, "teqi", 0x0C),
+    regimm(Op::Tnei, "tnei", 0x0E),
+    special2(Op::Madd, "madd", 0x00, MUL_DIV),
+    special2(Op::Maddu, "maddu", 0x01, MUL_DIV),
+    special2(Op::Mul, "mul", 0x02, ALU),
+    special2(Op::Msub, "msub", 0x04, MUL_DIV),
+    special2(Op::Msubu, "msubu", 0x05, MUL_DIV),
+    special2(Op::Clz, "clz", 0x20, &[Rd, Rs]),
+    special2(Op::Clo, "clo", 0x21, &[Rd, Rs]),
+    Def { op: Op::Ext, mnemonic: "ext", format: Format::Special3(0x00), operands: &[Rt, Rs, Shamt, ExtSize] },
+    Def { op: Op::Ins, mnemonic: "ins", format: Format::Special3(0x04), operands: &[Rt, Rs, Shamt, InsSize] },
+    bshfl(Op::Wsbh, "wsbh", 0x02),
+    bshfl(Op::Seb, "seb", 0x10),
+    bshfl(Op::Seh, "seh", 0x18),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_defs() {
+        for (i, def) in DEFS.iter().enumerate() {
+            assert_eq!(def.op as usize, i, "{}", def.mnemonic);
+            assert_eq!(lookup(def.mnemonic).map(|d| d.op), Some(def.op));
+            assert_eq!(DEFS.iter().filter(|d| d.format == def.format).count(), 1, "{}", def.mnemonic);
+            // offset($base) is a single operand in assembly
+            assert_eq!(def.operands.contains(&Offset), def.operands.contains(&Base), "{}", def.mnemonic);
         }
+        assert!(lookup("nop").is_none());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_round_trip() {
+        // every instruction encodes then decodes to itself, with each
+        // operand in its own field
+        for def in DEFS.iter() {
+            let values: Vec<u32> = def.operands.iter().enumerate()
+                .map(|(i, operand)| match operand {
+                    Imm | Offset | Branch => 0x8000 | i as u32,
+                    Target => 0x0200_0000 | i as u32,
+                    // pos + size stays within the word
+                    ExtSize | InsSize => 1 + i as u32,
+                    _ => 1 + i as u32 * 7,
+                })
+                .collect();
+            let word = encode(def.op, &values);
+            let mut instr = Instruction::default();
+            instr_decode(word, &mut instr);
+            assert_eq!(decode(&instr).map(|d| d.op), Some(def.op), "{}", def.mnemonic);
+            let decoded: Vec<u32> = def.operands.iter().map(|operand| operand_value(*operand, &instr)).collect();
+            assert_eq!(decoded, values, "{}", def.mnemonic);
+        }
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(encode(Op::Add, &[9, 10, 11]), 0x014B4820);
+        assert_eq!(encode(Op::Lw, &[8, 4, 9]), 0x8D280004);
+        assert_eq!(encode(Op::Swr, &[8, 3, 9]), 0xB9280003);
+        assert_eq!(encode(Op::J, &[0xDEAD]), 0x0800DEAD);
+        assert_eq!(encode(Op::Srlv, &[2, 4, 5]), 0x00a41006);
+        assert_eq!(encode(Op::Mtc0, &[8, 10]), 0x40885000);
+        assert_eq!(encode(Op::Rfe, &[]), 0x42000010);
+        // fields are masked
+        assert_eq!(encode(Op::Addiu, &[8, 0, 0xffff_ffff]), 0x2408ffff);
+        // MIPS32 release 1 and 2
+        assert_eq!(encode(Op::Mul, &[2, 4, 5]), 0x70851002);
+        assert_eq!(encode(Op::Madd, &[4, 5]), 0x70850000);
+        assert_eq!(encode(Op::Clz, &[2, 4]), 0x70821020);
+        assert_eq!(encode(Op::Movn, &[2, 4, 5]), 0x0085100b);
+        assert_eq!(encode(Op::Rotr, &[2, 4, 8]), 0x00241202);
+        assert_eq!(encode(Op::Rotrv, &[2, 4, 5]), 0x00a41046);
+        assert_eq!(encode(Op::Teq, &[4, 5]), 0x00850034);
+        assert_eq!(encode(Op::Tnei, &[4, 0xffff]), 0x048effff);
+        assert_eq!(encode(Op::Ext, &[2, 4, 8, 4]), 0x7c821a00);
+        assert_eq!(encode(Op::Ins, &[2, 4, 8, 4]), 0x7c825a04);
+        assert_eq!(encode(Op::Seb, &[2, 4]), 0x7c041420);
+        assert_eq!(encode(Op::Wsbh, &[2, 4]), 0x7c0410a0);
+        assert_eq!(encode(Op::Sync, &[]), 0x0000000f);
+    }
+
+    #[test]
+    fn test_flow() {
+        let jal = flow(encode(Op::Jal, &[0x40]), 0x8000_0010);
+        assert_eq!((jal.call, jal.target, jal.ends_block), (true, Some(0x8000_0040), true));
+        assert!(flow(encode(Op::Jr, &[31]), 0).ret);
+        assert!(!flow(encode(Op::Jr, &[8]), 0).ret);
+        assert!(flow(encode(Op::Rfe, &[]), 0).ends_block);
+        let beq = flow(encode(Op::Beq, &[8, 0, 0x1c]), 0x10);
+        assert_eq!((beq.branch, beq.target), (true, Some(0x1c)));
+        assert_eq!(flow(encode(Op::Add, &[8, 9, 10]), 0), Flow::default());
+    }
+}
@@ -0,0 +1,161 @@
+/*
+ * isa.rs
+ *
+ * Author: Travis Banken
+ *
+ * Instruction sets the processor runs. Both share the datapath (alu,
+ * register file, memories, caches, bus) and every tool built on the cycle
+ * records, what differs between them goes through Isa: the byte order of
+ * instruction words, their decoding and disassembly, the register names
+ * and how instructions move the pc.
+ *  mips   MIPS I subset, big endian (the default)
+ *  rv32i  RISC-V RV32I base integer instructions, little endian (riscv/)
+ */
+#![allow(dead_code)]
+
+use crate::hardware::{instr_mem, reg_file};
+use crate::instruction;
+use crate::phases::instr_fetch;
+use crate::riscv;
+use crate::tools::disasm;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Isa {
+    #[default]
+    Mips,
+    Rv32i,
+}
+
+/*
+ * How an instruction moves the pc, for the tools following the control
+ * flow of a program.
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Flow {
+    // conditional branch
+    pub branch: bool,
+    // target of a branch or jump known without running it
+    pub target: Option<u32>,
+    // control leaves the basic block after the instruction
+    pub ends_block: bool,
+    // calls a function, or returns from one
+    pub call: bool,
+    pub ret: bool,
+}
+
+impl Isa {
+    pub fn parse(name: &str) -> Result<Isa, String> {
+        match name {
+            "mips" => Ok(Isa::Mips),
+            "rv32i" | "riscv" => Ok(Isa::Rv32i),
+            _ => Err(format!("Invalid isa [{}], use mips or rv32i", name)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Isa::Mips => "mips",
+            Isa::Rv32i => "rv32i",
+        }
+    }
+
+    /*
+     * Instruction word at addr of instruction memory.
+     */
+    pub fn fetch(&self, mem: &instr_mem::Memory, addr: usize) -> u32 {
+        let word = instr_fetch(mem, addr);
+        return self.byte_order(word);
+    }
+
+    /*
+     * A word read from memory as big endian in the byte order of the isa,
+     * and the other way around.
+     */
+    pub fn byte_order(&self, word: u32) -> u32 {
+        match self {
+            Isa::Mips => word,
+            Isa::Rv32i => word.swap_bytes(),
+        }
+    }
+
+    /*
+     * Bytes of a word in memory order.
+     */
+    pub fn word_bytes(&self, word: u32) -> [u8; 4] {
+        match self {
+            Isa::Mips => word.to_be_bytes(),
+            Isa::Rv32i => word.to_le_bytes(),
+        }
+    }
+
+    pub fn disassemble(&self, word: u32, pc: u32) -> String {
+        match self {
+            Isa::Mips => disasm::disassemble(word, pc),
+            Isa::Rv32i => riscv::disasm::disassemble(word, pc),
+        }
+    }
+
+    /*
+     * ABI name of a register number, without the '$'.
+     */
+    pub fn reg_name(&self, reg_num: u8) -> &'static str {
+        match self {
+            Isa::Mips => reg_file::reg_name(reg_num),
+            Isa::Rv32i => riscv::disasm::reg_name(reg_num),
+        }
+    }
+
+    /*
+     * Register number of an ABI name or a number, with or without '$'.
+     */
+    pub fn reg_num(&self, name: &str) -> Option<u8> {
+        match self {
+            Isa::Mips => reg_file::reg_num(name),
+            Isa::Rv32i => riscv::disasm::reg_num(name),
+        }
+    }
+
+    pub fn flow(&self, word: u32, pc: u32) -> Flow {
+        match self {
+            Isa::Mips => instruction::flow(word, pc),
+            Isa::Rv32i => riscv::instruction::flow(word, pc),
+        }
+    }
+
+    /*
+     * Register holding the exit code of a program returning from main, $v0
+     * or a0.
+     */
+    pub fn result_reg(&self) -> usize {
+        match self {
+            Isa::Mips => 2,
+            Isa::Rv32i => 10,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Isa::parse("mips"), Ok(Isa::Mips));
+        assert_eq!(Isa::parse("rv32i").map(|isa| isa.name()), Ok("rv32i"));
+        assert_eq!(Isa::parse("arm"), Err("Invalid isa [arm], use mips or rv32i".to_string()));
+    }
+
+    #[test]
+    fn test_fetch() {
+        let mut mem = instr_mem::Memory::new();
+        for (i, byte) in [0x13, 0x05, 0x50, 0x00].iter().enumerate() {
+            mem.write(*byte, i);
+        }
+        assert_eq!(Isa::Mips.fetch(&mem, 0), 0x1305_5000);
+        assert_eq!(Isa::Rv32i.fetch(&mem, 0), 0x0050_0513);
+        assert_eq!(Isa::Rv32i.disassemble(0x0050_0513, 0), "addi a0, zero, 5");
+        assert_eq!(Isa::Rv32i.word_bytes(0x0050_0513), [0x13, 0x05, 0x50, 0x00]);
+        assert_eq!(Isa::Rv32i.reg_num("a0"), Some(10));
+        assert_eq!(Isa::Mips.reg_num("a0"), Some(4));
+    }
+}
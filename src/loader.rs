@@ -0,0 +1,411 @@
+/*
+ * loader.rs
+ *
+ * Author: Travis Banken
+ *
+ * Loads programs from files into the instruction and data memories:
+ *  elf  32 bit big endian MIPS or little endian RISC-V executables (see
+ *       tools/elf.rs)
+ *  bin  raw instruction memory image, starting at address 0
+ *  hex  one hex instruction word per line ('#' and '//' comments), the
+ *       format of Verilog's $readmemh without @ addresses
+ *  asm  MIPS source text (see tools/asm.rs)
+ * and sets up the registers to call the program like main(argc, argv).
+ * Words are laid out in the byte order of the isa.
+ */
+#![allow(dead_code)]
+
+use crate::hardware::{data_mem, instr_mem};
+use crate::isa::Isa;
+use crate::single_cycle::Processor;
+use crate::tools::asm::{self, Assembly};
+use crate::tools::elf::{Elf, Segment, Symbol, EM_MIPS, EM_RISCV};
+use crate::tools::symbols::SymbolTable;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Elf,
+    Bin,
+    Hex,
+    Asm,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Result<Format, String> {
+        match name {
+            "elf" => Ok(Format::Elf),
+            "bin" => Ok(Format::Bin),
+            "hex" => Ok(Format::Hex),
+            "asm" => Ok(Format::Asm),
+            _ => Err(format!("Invalid format [{}], use elf, bin, hex or asm", name)),
+        }
+    }
+
+    /*
+     * Format from the extension of a file name, elf when it has none of
+     * .bin, .hex, .s or .asm.
+     */
+    pub fn guess(path: &str) -> Format {
+        let ext = Path::new(path).extension().and_then(|ext| ext.to_str()).unwrap_or("");
+        match ext.to_lowercase().as_str() {
+            "bin" => Format::Bin,
+            "hex" => Format::Hex,
+            "s" | "asm" => Format::Asm,
+            _ => Format::Elf,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Format::Elf => "elf",
+            Format::Bin => "bin",
+            Format::Hex => "hex",
+            Format::Asm => "asm",
+        }
+    }
+}
+
+/*
+ * Program ready to run.
+ */
+#[derive(Clone)]
+pub struct Program {
+    pub instr_mem: instr_mem::Memory,
+    pub data_mem: data_mem::Memory,
+    pub symbols: SymbolTable,
+    pub entry: u32,
+}
+
+impl Program {
+    /*
+     * Reads the file of a program for isa, format None guesses it from the
+     * file name.
+     */
+    pub fn load(path: &str, format: Option<Format>, isa: Isa) -> Result<Program, String> {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) => return Err(format!("Load-Error: Failed to read [{}]: {}", path, e)),
+        };
+        return Program::from_bytes(&bytes, format.unwrap_or_else(|| Format::guess(path)), path, isa);
+    }
+
+    pub fn from_bytes(bytes: &[u8], format: Format, path: &str, isa: Isa) -> Result<Program, String> {
+        match format {
+            Format::Elf => {
+                let elf = Elf::parse(bytes)?;
+                let machine = match isa {
+                    Isa::Mips => EM_MIPS,
+                    Isa::Rv32i => EM_RISCV,
+                };
+                if elf.machine != machine {
+                    return Err(format!("Load-Error: [{}] is not a {} executable", path, isa.name()));
+                }
+                return Program::from_elf(&elf);
+            },
+            Format::Bin => Program::from_image(bytes),
+            Format::Hex => Program::from_image(&parse_hex(&String::from_utf8_lossy(bytes), path, isa)?),
+            Format::Asm if isa != Isa::Mips => {
+                Err(format!("Load-Error: The assembler only knows MIPS, assemble [{}] with a {} toolchain",
+                            path, isa.name()))
+            },
+            Format::Asm => Program::from_assembly(&asm::assemble(&String::from_utf8_lossy(bytes), path)?),
+        }
+    }
+
+    pub fn from_assembly(asm: &Assembly) -> Result<Program, String> {
+        let mut program = Program::from_image(&asm.text)?;
+        program.data_mem.load_bytes(&fill("data", &[(0, &asm.data)], data_mem::Memory::get_size())?);
+        program.symbols = asm.symbols.clone();
+        program.entry = asm.entry();
+        return Ok(program);
+    }
+
+    pub fn from_elf(elf: &Elf) -> Result<Program, String> {
+        let text: Vec<(u32, &[u8])> = elf.segments.iter()
+            .filter(|seg| seg.exec)
+            .map(|seg| (seg.addr, seg.bytes.as_slice()))
+            .collect();
+        let data: Vec<(u32, &[u8])> = elf.segments.iter()
+            .filter(|seg| !seg.exec)
+            .map(|seg| (seg.addr, seg.bytes.as_slice()))
+            .collect();
+        let mut program = Program {
+            instr_mem: instr_mem::Memory::new(),
+            data_mem: data_mem::Memory::new(),
+            symbols: SymbolTable::new(),
+            entry: elf.entry,
+        };
+        program.instr_mem.load_bytes(&fill("instruction", &text, instr_mem::Memory::get_size())?);
+        program.data_mem.load_bytes(&fill("data", &data, data_mem::Memory::get_size())?);
+        for sym in elf.symbols.iter().filter(|sym| sym.exec) {
+            program.symbols.add(&sym.name, sym.addr);
+        }
+        return Ok(program);
+    }
+
+    /*
+     * Instruction memory image starting at address 0.
+     */
+    pub fn from_image(bytes: &[u8]) -> Result<Program, String> {
+        let mut program = Program {
+            instr_mem: instr_mem::Memory::new(),
+            data_mem: data_mem::Memory::new(),
+            symbols: SymbolTable::new(),
+            entry: 0,
+        };
+        program.instr_mem.load_bytes(&fill("instruction", &[(0, bytes)], instr_mem::Memory::get_size())?);
+        return Ok(program);
+    }
+}
+
+/*
+ * ELF executable of assembled code, the labels become its symbols.
+ */
+pub fn to_elf(asm: &Assembly) -> Elf {
+    let mut elf = Elf {
+        machine: EM_MIPS,
+        entry: asm.entry(),
+        segments: vec![Segment { addr: 0, bytes: asm.text.clone(), exec: true }],
+        symbols: Vec::new(),
+    };
+    if !asm.data.is_empty() {
+        elf.segments.push(Segment { addr: 0, bytes: asm.data.clone(), exec: false });
+    }
+    for (addr, name) in asm.symbols.iter() {
+        elf.symbols.push(Symbol { name: name.to_string(), addr, exec: true });
+    }
+    for (name, addr) in asm.data_labels.iter() {
+        elf.symbols.push(Symbol { name: name.clone(), addr: *addr, exec: false });
+    }
+    return elf;
+}
+
+/*
+ * Words in the hex format, one per line.
+ */
+pub fn to_hex(bytes: &[u8]) -> String {
+    let mut text = String::new();
+    for word in bytes.chunks(4) {
+        let mut padded = [0; 4];
+        padded[..word.len()].copy_from_slice(word);
+        text.push_str(&format!("{:08x}\n", u32::from_be_bytes(padded)));
+    }
+    return text;
+}
+
+/*
+ * Address -> word of every instruction of the program: the instructions
+ * with a source line, or without line info every word up to the last
+ * non-zero one.
+ */
+pub fn instructions(mem: &instr_mem::Memory, symbols: &SymbolTable, isa: Isa) -> BTreeMap<u32, u32> {
+    let mut words = BTreeMap::new();
+    if symbols.has_lines() {
+        for (addr, _, _) in symbols.lines() {
+            words.insert(addr, isa.byte_order(word_at(mem, addr)));
+        }
+        return words;
+    }
+    let count = instr_mem::Memory::get_size() as u32 / 4;
+    let end = (0..count).rev().find(|i| word_at(mem, i * 4) != 0).map(|i| i + 1).unwrap_or(0);
+    for i in 0..end {
+        words.insert(i * 4, isa.byte_order(word_at(mem, i * 4)));
+    }
+    return words;
+}
+
+/*
+ * Sets up a call of main(argc, argv): the argument strings and the argv
+ * array go to the top of data memory, $a0 = argc, $a1 = argv, $sp below
+ * them and $ra the end of instruction memory, so returning from main with
+ * "jr $ra" ends the program. RISC-V uses a0, a1, sp and ra the same way.
+ */
+pub fn call_main(cpu: &mut Processor, mem: &mut data_mem::Memory, args: &[String]) -> Result<(), String> {
+    let size = data_mem::Memory::get_size();
+    let strings: usize = args.iter().map(|arg| arg.len() + 1).sum();
+    // argv is word aligned and ends with a NULL pointer, sp 8 byte aligned
+    let argv = size.checked_sub(strings)
+        .and_then(|free| (free & !0x3).checked_sub((args.len() + 1) * 4))
+        .map(|addr| addr & !0x7);
+    let argv = match argv {
+        Some(addr) => addr,
+        None => return Err(format!("Load-Error: The program arguments need more than the {} bytes of data memory",
+                                   size)),
+    };
+
+    let mut at = size - strings;
+    for (i, arg) in args.iter().enumerate() {
+        for (j, byte) in arg.bytes().chain(std::iter::once(0)).enumerate() {
+            mem.write(byte, at + j);
+        }
+        for (j, byte) in cpu.isa.word_bytes(at as u32).iter().enumerate() {
+            mem.write(*byte, argv + i * 4 + j);
+        }
+        at += arg.len() + 1;
+    }
+    for j in 0..4 {
+        mem.write(0, argv + args.len() * 4 + j);
+    }
+
+    let (a0, a1, sp, ra) = match cpu.isa {
+        Isa::Mips => (4, 5, 29, 31),
+        Isa::Rv32i => (10, 11, 2, 1),
+    };
+    cpu.regfile.write(args.len() as u32, a0);
+    cpu.regfile.write(argv as u32, a1);
+    cpu.regfile.write(argv as u32, sp);
+    cpu.regfile.write(instr_mem::Memory::get_size() as u32, ra);
+    return Ok(());
+}
+
+// *** PRIVATE FN ***
+
+/*
+ * Memory image with every (address, bytes) piece in place.
+ */
+fn fill(name: &str, pieces: &[(u32, &[u8])], size: usize) -> Result<Vec<u8>, String> {
+    let mut image = vec![0; size];
+    for (addr, bytes) in pieces.iter() {
+        let start = *addr as usize;
+        if start + bytes.len() > size {
+            return Err(format!("Load-Error: {} bytes at 0x{:x} do not fit in the {} byte {} memory",
+                               bytes.len(), addr, size, name));
+        }
+        image[start..start + bytes.len()].copy_from_slice(bytes);
+    }
+    return Ok(image);
+}
+
+fn parse_hex(text: &str, path: &str, isa: Isa) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("");
+        let line = line.split("//").next().unwrap_or("").trim();
+        for word in line.split_whitespace() {
+            let digits = word.strip_prefix("0x").unwrap_or(word).replace('_', "");
+            match u32::from_str_radix(&digits, 16) {
+                Ok(val) => bytes.extend_from_slice(&isa.word_bytes(val)),
+                Err(_) => return Err(format!("Load-Error: {}:{}: Invalid hex word [{}]", path, i + 1, word)),
+            }
+        }
+    }
+    return Ok(bytes);
+}
+
+fn word_at(mem: &instr_mem::Memory, addr: u32) -> u32 {
+    let mut word = 0;
+    for i in 0..4 {
+        let at = (addr + i) as usize;
+        let byte = if at < instr_mem::Memory::get_size() {mem.read(at)} else {0};
+        word = (word << 8) | byte as u32;
+    }
+    return word;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::demos::assembler::instr_macros::*;
+    use crate::demos::assembler::reg_macros::*;
+    use crate::single_cycle::Options;
+
+    const SOURCE: &str = "
+        .data
+        msg: .asciiz \"hi\"
+        .text
+        helper: nop
+        main: lb $v0, msg($zero)
+              jr $ra
+    ";
+
+    #[test]
+    fn test_formats() {
+        assert_eq!(Format::guess("prog.S"), Format::Asm);
+        assert_eq!(Format::guess("prog.hex"), Format::Hex);
+        assert_eq!(Format::guess("prog"), Format::Elf);
+        assert_eq!(Format::parse("coff"), Err("Invalid format [coff], use elf, bin, hex or asm".to_string()));
+
+        let asm = asm::assemble(SOURCE, "prog.s").unwrap();
+        let from_asm = Program::from_assembly(&asm).unwrap();
+        assert_eq!(from_asm.entry, 4);
+        assert_eq!(&from_asm.data_mem.bytes()[..3], b"hi\0");
+        assert_eq!(instructions(&from_asm.instr_mem, &from_asm.symbols, Isa::Mips).len(), 3);
+
+        let elf = to_elf(&asm).write();
+        let from_elf = Program::from_bytes(&elf, Format::Elf, "prog", Isa::Mips).unwrap();
+        assert_eq!(from_elf.instr_mem.bytes(), from_asm.instr_mem.bytes());
+        assert_eq!(from_elf.data_mem.bytes(), from_asm.data_mem.bytes());
+        assert_eq!(from_elf.symbols.lookup("main"), Some(4));
+        assert_eq!(from_elf.symbols.lookup("msg"), None);
+        assert_eq!(from_elf.entry, 4);
+
+        let hex = to_hex(&asm.text);
+        assert_eq!(hex, format!("00000000\n{:08x}\n{:08x}\n", LB(V0(), 0, ZERO()), JR(RA())));
+        let commented = format!("# comment\n{}", hex);
+        let from_hex = Program::from_bytes(commented.as_bytes(), Format::Hex, "p.hex", Isa::Mips).unwrap();
+        assert_eq!(from_hex.instr_mem.bytes(), from_asm.instr_mem.bytes());
+        assert_eq!(instructions(&from_hex.instr_mem, &from_hex.symbols, Isa::Mips).len(), 3);
+        let from_bin = Program::from_bytes(&asm.text, Format::Bin, "p.bin", Isa::Mips).unwrap();
+        assert_eq!(from_bin.instr_mem.bytes(), from_asm.instr_mem.bytes());
+
+        assert_eq!(Program::from_bytes(b"0x1\nzz", Format::Hex, "p.hex", Isa::Mips).err().unwrap(),
+                   "Load-Error: p.hex:2: Invalid hex word [zz]");
+        assert_eq!(Program::from_bytes(&[0; 300], Format::Bin, "p.bin", Isa::Mips).err().unwrap(),
+                   "Load-Error: 300 bytes at 0x0 do not fit in the 256 byte instruction memory");
+    }
+
+    #[test]
+    fn test_riscv() {
+        // addi a0, zero, 5 is stored little endian
+        let from_hex = Program::from_bytes(b"00500513", Format::Hex, "p.hex", Isa::Rv32i).unwrap();
+        assert_eq!(&from_hex.instr_mem.bytes()[..4], &[0x13, 0x05, 0x50, 0x00]);
+        let words = instructions(&from_hex.instr_mem, &from_hex.symbols, Isa::Rv32i);
+        assert_eq!(words.into_iter().collect::<Vec<_>>(), vec![(0, 0x0050_0513)]);
+
+        let mut elf = Elf {
+            machine: EM_RISCV,
+            entry: 0,
+            segments: vec![Segment { addr: 0, bytes: vec![0x13, 0x05, 0x50, 0x00], exec: true }],
+            symbols: Vec::new(),
+        };
+        let from_elf = Program::from_bytes(&elf.write(), Format::Elf, "prog", Isa::Rv32i).unwrap();
+        assert_eq!(from_elf.instr_mem.bytes(), from_hex.instr_mem.bytes());
+        assert_eq!(Program::from_bytes(&elf.write(), Format::Elf, "prog", Isa::Mips).err().unwrap(),
+                   "Load-Error: [prog] is not a mips executable");
+        elf.machine = EM_MIPS;
+        assert_eq!(Program::from_bytes(&elf.write(), Format::Elf, "prog", Isa::Rv32i).err().unwrap(),
+                   "Load-Error: [prog] is not a rv32i executable");
+        assert_eq!(Program::from_bytes(SOURCE.as_bytes(), Format::Asm, "p.s", Isa::Rv32i).err().unwrap(),
+                   "Load-Error: The assembler only knows MIPS, assemble [p.s] with a rv32i toolchain");
+
+        let mut cpu = Processor::new(&Options { isa: Isa::Rv32i, ..Options::default() });
+        let mut mem = data_mem::Memory::new();
+        call_main(&mut cpu, &mut mem, &["prog".to_string()]).unwrap();
+        // argv at 0xf0 points to the string at 0xfb
+        assert_eq!(&mem.bytes()[0xf0..0xf8], &[0xfb, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!((cpu.regfile.load(10), cpu.regfile.load(11)), (1, 0xf0));
+        assert_eq!((cpu.regfile.load(2), cpu.regfile.load(1)), (0xf0, 0x100));
+    }
+
+    #[test]
+    fn test_call_main() {
+        let mut cpu = Processor::new(&Options::default());
+        let mut mem = data_mem::Memory::new();
+        let args = vec!["prog".to_string(), "ab".to_string()];
+        call_main(&mut cpu, &mut mem, &args).unwrap();
+
+        // "prog\0ab\0" at 0xf8, argv at 0xe8 (8 byte aligned)
+        assert_eq!(&mem.bytes()[0xf8..], b"prog\0ab\0");
+        assert_eq!(&mem.bytes()[0xe8..0xf4], &[0, 0, 0, 0xf8, 0, 0, 0, 0xfd, 0, 0, 0, 0]);
+        assert_eq!(cpu.regfile.load(4), 2);
+        assert_eq!(cpu.regfile.load(5), 0xe8);
+        assert_eq!(cpu.regfile.load(29), 0xe8);
+        assert_eq!(cpu.regfile.load(31), 0x100);
+
+        let long = vec!["x".repeat(300)];
+        assert!(call_main(&mut cpu, &mut mem, &long).is_err());
+    }
+}
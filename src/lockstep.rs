@@ -0,0 +1,438 @@
+/*
+ * lockstep.rs
+ *
+ * Author: Travis Banken
+ *
+ * Differential testing of the datapath against the reference interpreter.
+ * Both start from the same state and run the program one instruction at a
+ * time. After every instruction the pc, the registers, HI/LO, data memory
+ * and the exception taken are compared, and the first instruction where they
+ * disagree is reported with both states.
+ *
+ * random_program() builds instruction sequences for the fuzz test out of
+ * the instructions the datapath implements.
+ */
+#![allow(dead_code)]
+
+use crate::hardware::{bus, reg_file};
+use crate::hardware::cp0::ExcCode;
+use crate::loader::{self, Program};
+use crate::reference::Reference;
+use crate::single_cycle::{Options, Processor};
+use crate::tools::disasm;
+use std::fmt;
+
+/*
+ * State after an instruction.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct State {
+    pub pc: u32,
+    pub regs: [u32; 32],
+    pub hi: u32,
+    pub lo: u32,
+    pub exception: Option<ExcCode>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    // instructions that agreed before this one
+    pub step: u64,
+    pub pc: u32,
+    pub word: u32,
+    // what differs, "$t0: datapath 0x1, reference 0x2"
+    pub diffs: Vec<String>,
+    pub datapath: State,
+    pub reference: State,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "First divergence at instruction {}, 0x{:08x}: 0x{:08x}  {}",
+                 self.step + 1, self.pc, self.word, disasm::disassemble(self.word, self.pc))?;
+        for diff in self.diffs.iter() {
+            writeln!(f, "  {}", diff)?;
+        }
+        writeln!(f, "Datapath:")?;
+        write_state(f, &self.datapath)?;
+        writeln!(f, "Reference:")?;
+        write_state(f, &self.reference)?;
+        return Ok(());
+    }
+}
+
+/*
+ * Runs the program on both until it ends or max_steps instructions ran,
+ * returns the number of instructions they agreed on. With args the program
+ * is called like main(argc, argv) (see loader::call_main).
+ */
+pub fn run(program: &Program, args: &[String], max_steps: u64) -> Result<u64, Box<Divergence>> {
+    let opts = Options {
+        entry: program.entry,
+        ..Options::default()
+    };
+    let mut mem = program.data_mem.clone();
+    let mut bus = bus::Bus::new(&mut mem);
+    let mut cpu = Processor::new(&opts);
+    if !args.is_empty() {
+        // the arguments always fit when the program loaded with them
+        let _ = loader::call_main(&mut cpu, bus.mem_mut(), args);
+    }
+    let mut reference = Reference::new(program.instr_mem.bytes(), bus.mem().bytes(), cpu.ip);
+    reference.regs = cpu.regfile.dump();
+    return lockstep(program, &mut cpu, &mut bus, &mut reference, max_steps);
+}
+
+/*
+ * Random program of len instructions (at most 56) that always ends: a
+ * prologue loads random values into the registers, then come alu,
+ * immediate, load/store, branch and jump (j, jal) instructions, now and
+ * then a multiply or a move to or from HI/LO, or one of the MIPS32
+ * madd/msub, ext/ins, shift, rotate and trap instructions.
+ * Branches and jumps only go forward. Loads and stores address data memory through
+ * $s7 = 0x80: lw and sw are misaligned one time in eight (an address
+ * error), lwl, lwr, swl and swr take any address.
+ */
+pub fn random_program(seed: u32, len: usize) -> Vec<u32> {
+    let mut rng = Rng(seed.max(1));
+    let len = len.min(56);
+    let mut words = Vec::new();
+
+    words.push(i_type(0x0D, 0, BASE, 0x80)); // ori $s7, $zero, 0x80
+    for _ in 0..3 {
+        let reg = rng.dest();
+        words.push(i_type(0x0F, 0, reg, rng.next() & 0xffff)); // lui
+        words.push(i_type(0x0D, reg, reg, rng.next() & 0xffff)); // ori
+    }
+    let start = words.len();
+    let end = start + len;
+
+    for i in start..end {
+        let word = match rng.below(19) {
+            0..=5 => {
+                let funct = ALU_FUNCTS[rng.below(ALU_FUNCTS.len() as u32) as usize];
+                (rng.src() << 21) | (rng.src() << 16) | (rng.dest() << 11) | funct
+            },
+            6..=9 => {
+                let op = IMM_OPS[rng.below(IMM_OPS.len() as u32) as usize];
+                let imm = match rng.below(3) {
+                    // small values, to run into fewer overflows
+                    0 => rng.below(16),
+                    1 => 0x10000 - rng.below(16),
+                    _ => rng.next() & 0xffff,
+                };
+                i_type(op, rng.src(), rng.dest(), imm)
+            },
+            10 => i_type(0x0F, 0, rng.dest(), rng.next() & 0xffff), // lui
+            11 => { // lw, sw, lwl, lwr, swl, swr
+                let op = [0x23, 0x2B, 0x22, 0x26, 0x2A, 0x2E][rng.below(6) as usize];
+                // lw and sw are aligned but one time in eight, an address error
+                let aligned = matches!(op, 0x23 | 0x2B) && rng.below(8) != 0;
+                let offset = if aligned {rng.below(64) * 4} else {rng.below(256)};
+                let rt = if op & 0x08 == 0 {rng.dest()} else {rng.src()};
+                i_type(op, BASE, rt, offset.wrapping_sub(0x80) & 0xffff)
+            },
+            12 => { // lb, lbu, sb
+                let op = [0x20, 0x24, 0x28][rng.below(3) as usize];
+                let offset = rng.below(256).wrapping_sub(0x80) & 0xffff;
+                let rt = if op == 0x28 {rng.src()} else {rng.dest()};
+                i_type(op, BASE, rt, offset)
+            },
+            13 | 14 => { // beq, bne
+                let op = if rng.below(2) == 0 {0x04} else {0x05};
+                let target = forward(&mut rng, i, end);
+                i_type(op, rng.src(), rng.src(), target)
+            },
+            15 => ((0x02 | rng.below(2)) << 26) | forward(&mut rng, i, end), // j, jal
+            16 => match rng.below(4) {
+                0 => { // madd, maddu, msub, msubu
+                    let funct = [0x00, 0x01, 0x04, 0x05][rng.below(4) as usize];
+                    (0x1C << 26) | (rng.src() << 21) | (rng.src() << 16) | funct
+                },
+                1 => (rng.src() << 21) | (rng.src() << 16) | 0x18 | rng.below(2), // mult, multu
+                2 => (rng.dest() << 11) | 0x10 | (rng.below(2) << 1), // mfhi, mflo
+                _ => (rng.src() << 21) | 0x11 | (rng.below(2) << 1), // mthi, mtlo
+            },
+            17 => { // ext, ins, the field may end past bit 31 or (ins) start after it ends
+                let funct = if rng.below(2) == 0 {0x00} else {0x04};
+                (0x1F << 26) | (rng.src() << 21) | (rng.dest() << 16) | (rng.below(32) << 11) | (rng.below(32) << 6) |
+                    funct
+            },
+            _ => match rng.below(8) {
+                0 => (0x01 << 21) | (rng.src() << 16) | (rng.dest() << 11) | (rng.below(32) << 6) | 0x02, // rotr
+                1 => (rng.src() << 21) | (rng.src() << 16) | (rng.dest() << 11) | (0x01 << 6) | 0x06, // rotrv
+                4 => { // sll, srl, sra
+                    let funct = [0x00, 0x02, 0x03][rng.below(3) as usize];
+                    (rng.src() << 16) | (rng.dest() << 11) | (rng.below(32) << 6) | funct
+                },
+                // the traps end the program, they are rare
+                2 => {
+                    let funct = [0x30, 0x31, 0x32, 0x33, 0x34, 0x36][rng.below(6) as usize];
+                    (rng.src() << 21) | (rng.src() << 16) | funct
+                },
+                3 => {
+                    let cond = [0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0E][rng.below(6) as usize];
+                    (0x01 << 26) | (rng.src() << 21) | (cond << 16) | (rng.next() & 0xffff)
+                },
+                _ => (rng.src() << 21) | (rng.src() << 16) | (rng.dest() << 11) | 0x0A | rng.below(2), // movz, movn
+            },
+        };
+        words.push(word);
+    }
+    return words;
+}
+
+// *** PRIVATE FN ***
+
+/*
+ * Steps the datapath through the program and the reference through its own
+ * copy, from the state they are in.
+ */
+fn lockstep(program: &Program, cpu: &mut Processor, bus: &mut bus::Bus, reference: &mut Reference,
+            max_steps: u64) -> Result<u64, Box<Divergence>> {
+    let mut steps = 0;
+    while steps < max_steps && (cpu.is_running() || reference.is_running()) {
+        let pc = cpu.ip;
+        let word = fetch(program, pc);
+        let cycle = cpu.step(&program.instr_mem, bus);
+        let ref_exc = reference.step().err();
+        let ref_state = State {
+            pc: reference.pc,
+            regs: reference.regs,
+            hi: reference.hi,
+            lo: reference.lo,
+            exception: ref_exc,
+        };
+
+        let cpu_exc = cycle.exception;
+        let cpu_state = State {
+            pc: cpu.ip,
+            regs: cpu.regfile.dump(),
+            hi: cpu.hi,
+            lo: cpu.lo,
+            exception: cpu_exc,
+        };
+        let mut diffs = compare(&cpu_state, &ref_state);
+        diffs.extend(compare_mem(bus.mem().bytes(), &reference.mem));
+        if !diffs.is_empty() {
+            return Err(Box::new(Divergence {
+                step: steps,
+                pc,
+                word,
+                diffs,
+                datapath: cpu_state,
+                reference: ref_state,
+            }));
+        }
+        steps += 1;
+        // both took the same exception, which ends the program
+        if cpu_exc.is_some() {
+            break;
+        }
+    }
+    return Ok(steps);
+}
+
+// base register of the loads and stores, never written
+const BASE: u32 = 23;
+
+// sllv, srlv, srav, add, addu, sub, subu, and, or, xor, nor, slt, sltu
+const ALU_FUNCTS: [u32; 13] = [0x04, 0x06, 0x07, 0x20, 0x21, 0x22, 0x23, 0x24, 0x25, 0x26, 0x27, 0x2A, 0x2B];
+
+// addi, addiu, slti, sltiu, andi, ori, xori
+const IMM_OPS: [u32; 7] = [0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E];
+
+// xorshift32, like the random replacement of the caches
+struct Rng(u32);
+
+impl Rng {
+    fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        return self.0;
+    }
+
+    fn below(&mut self, n: u32) -> u32 {
+        return self.next() % n;
+    }
+
+    // $t0-$t7 and $s0-$s3, now and then $zero which has to stay 0
+    fn dest(&mut self) -> u32 {
+        return self.src();
+    }
+
+    fn src(&mut self) -> u32 {
+        match self.below(13) {
+            12 => 0,
+            n => 8 + n,
+        }
+    }
+}
+
+fn i_type(op: u32, rs: u32, rt: u32, imm: u32) -> u32 {
+    return (op << 26) | (rs << 21) | (rt << 16) | (imm & 0xffff);
+}
+
+/*
+ * Address of an instruction after i, end is the address past the program.
+ */
+fn forward(rng: &mut Rng, i: usize, end: usize) -> u32 {
+    let target = i + 1 + rng.below((end - i) as u32) as usize;
+    return (target * 4) as u32;
+}
+
+fn fetch(program: &Program, pc: u32) -> u32 {
+    let bytes = program.instr_mem.bytes();
+    let at = pc as usize;
+    if at + 4 > bytes.len() {
+        return 0;
+    }
+    return u32::from_be_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]]);
+}
+
+fn compare(datapath: &State, reference: &State) -> Vec<String> {
+    let mut diffs = Vec::new();
+    if datapath.exception != reference.exception {
+        diffs.push(format!("exception: datapath {:?}, reference {:?}", datapath.exception, reference.exception));
+    }
+    if datapath.pc != reference.pc {
+        diffs.push(format!("pc: datapath 0x{:08x}, reference 0x{:08x}", datapath.pc, reference.pc));
+    }
+    for reg in 0..32 {
+        if datapath.regs[reg] != reference.regs[reg] {
+            diffs.push(format!("${}: datapath 0x{:08x}, reference 0x{:08x}", reg_file::reg_name(reg as u8),
+                               datapath.regs[reg], reference.regs[reg]));
+        }
+    }
+    if datapath.hi != reference.hi {
+        diffs.push(format!("hi: datapath 0x{:08x}, reference 0x{:08x}", datapath.hi, reference.hi));
+    }
+    if datapath.lo != reference.lo {
+        diffs.push(format!("lo: datapath 0x{:08x}, reference 0x{:08x}", datapath.lo, reference.lo));
+    }
+    return diffs;
+}
+
+fn compare_mem(datapath: &[u8], reference: &[u8]) -> Vec<String> {
+    let mut diffs = Vec::new();
+    for (addr, (d, r)) in datapath.iter().zip(reference.iter()).enumerate() {
+        if d != r {
+            diffs.push(format!("mem 0x{:02x}: datapath 0x{:02x}, reference 0x{:02x}", addr, d, r));
+        }
+    }
+    return diffs;
+}
+
+fn write_state(f: &mut fmt::Formatter, state: &State) -> fmt::Result {
+    let exception = match state.exception {
+        Some(code) => format!(" exception {:?}", code),
+        None => String::new(),
+    };
+    writeln!(f, "  pc 0x{:08x} hi 0x{:08x} lo 0x{:08x}{}", state.pc, state.hi, state.lo, exception)?;
+    for row in 0..8 {
+        let cols: Vec<String> = (0..4).map(|col| {
+            let reg = row * 4 + col;
+            format!("${:<4} 0x{:08x}", reg_file::reg_name(reg as u8), state.regs[reg])
+        }).collect();
+        writeln!(f, "  {}", cols.join("  "))?;
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::demos::assembler::instr_macros::*;
+    use crate::demos::assembler::reg_macros::*;
+
+    fn program(words: &[u32]) -> Program {
+        let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_be_bytes().to_vec()).collect();
+        return Program::from_image(&bytes).unwrap();
+    }
+
+    #[test]
+    fn test_agree() {
+        let prog = program(&[
+            ORI (T0(), ZERO(), 0xbabe),
+            LUI (T1(),         0xcafe),
+            OR  (T0(), T1(),   T0()),
+            ADDI(S0(), ZERO(), 0x42),
+            SW  (T0(), 0x2,    S0()),
+            LB  (T2(), 0x47,   ZERO()),
+            SUB (T3(), T2(),   T1()),
+        ]);
+        // 7 instructions and the nops up to the end of memory
+        assert_eq!(run(&prog, &[], 1000), Ok(64));
+        assert_eq!(run(&prog, &[], 5), Ok(5));
+    }
+
+    #[test]
+    fn test_divergence() {
+        // the reference runs sll where the datapath runs srl
+        let prog = program(&[
+            ADDI(T0(), ZERO(), 8),
+            SRL(T1(), T0(), 1),
+        ]);
+        let other = program(&[
+            ADDI(T0(), ZERO(), 8),
+            SLL(T1(), T0(), 1),
+        ]);
+        let mut mem = prog.data_mem.clone();
+        let mut bus = bus::Bus::new(&mut mem);
+        let mut cpu = Processor::new(&Options { entry: prog.entry, ..Options::default() });
+        let mut reference = Reference::new(other.instr_mem.bytes(), bus.mem().bytes(), cpu.ip);
+        reference.regs = cpu.regfile.dump();
+
+        let div = lockstep(&prog, &mut cpu, &mut bus, &mut reference, 100).unwrap_err();
+        assert_eq!(div.step, 1);
+        assert_eq!(div.pc, 0x4);
+        assert_eq!(div.diffs, vec!["$t1: datapath 0x00000004, reference 0x00000010"]);
+        let report = div.to_string();
+        assert!(report.starts_with("First divergence at instruction 2, 0x00000004: 0x00084842  srl $t1, $t0, 1\n"));
+        assert!(report.contains("Datapath:\n  pc 0x00000008 hi 0x00000000 lo 0x00000000\n"));
+        assert!(report.contains("$t1   0x00000004"));
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        // data memory is 256 bytes, both raise an address error past its end
+        let prog = program(&[
+            ADDI(T0(), ZERO(), 0xfc),
+            LW  (T1(), 0,      T0()),
+            SW  (T1(), 4,      T0()),
+        ]);
+        assert_eq!(run(&prog, &[], 100), Ok(3));
+    }
+
+    #[test]
+    fn test_set_less_than() {
+        // signed and unsigned compares and the sign extended immediates
+        let prog = program(&[
+            ADDI (T0(), ZERO(), 0xffff),
+            ADDI (T1(), ZERO(), 1),
+            SLT  (T2(), T0(),   T1()),
+            SLTU (T3(), T0(),   T1()),
+            SLTU (T4(), T1(),   T0()),
+            SLTI (T5(), T0(),   0),
+            SLTIU(T6(), T1(),   0xffff),
+            SLTIU(T7(), T0(),   0xffff),
+            SLTIU(S0(), ZERO(), 1),
+        ]);
+        assert_eq!(run(&prog, &[], 9), Ok(9));
+    }
+
+    #[test]
+    fn test_fuzz() {
+        for seed in 1..=500 {
+            let words = random_program(seed, 48);
+            let prog = program(&words);
+            if let Err(div) = run(&prog, &[], 1000) {
+                let listing: Vec<String> = words.iter().enumerate()
+                    .map(|(i, word)| format!("  0x{:08x}: {}", i * 4, disasm::disassemble(*word, i as u32 * 4)))
+                    .collect();
+                panic!("seed {}:\n{}\n{}", seed, div, listing.join("\n"));
+            }
+        }
+    }
+}
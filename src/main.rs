@@ -1,61 +1,213 @@
+// The codebase favours explicit `return`s, so the matching style lint is
+// silenced crate wide.
+#![allow(clippy::needless_return)]
+
 mod hardware;
 mod instruction;
+mod isa;
+mod riscv;
 mod phases;
 mod single_cycle;
 mod control_bits;
 mod demos;
 mod tools;
+mod debugger;
+mod gdb;
+mod watch;
+mod cycle;
+mod history;
+mod snapshot;
+mod loader;
+mod reference;
+mod lockstep;
+mod cli;
 
+use cli::Command;
 use demos::demo1;
 use demos::demo2;
+use loader::{Format, Program};
 use std::env;
+use std::fs;
 use std::process::exit;
+use tools::asm;
+use tools::dump_data_mem;
+use tools::dump_instr_mem;
+use tools::test_runner::{self, Report};
+
+// exit code of bad command lines
+const EXIT_USAGE: i32 = 2;
 
 fn main() {
-    let argv: Vec<String> = env::args().collect();
-
-    let mut debug: bool = false;
-    let mut dump: bool = false;
-    let mut demo: i32 = -1;
-    for arg in argv.iter() {
-        if arg == "--debug" || arg == "-g" {
-            debug = true;
-        } else if arg == "--dump" || arg == "-d" {
-            dump = true;
-        } else if arg == "--help" || arg == "-h" {
-            show_help(0);
-        } else if arg == "demo1" {
-            demo = 1;
-        } else if arg == "demo2" {
-            demo = 2;
+    let argv: Vec<String> = env::args().skip(1).collect();
+    let mut cli = match cli::parse(&argv) {
+        Ok(cli) => cli,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            eprintln!("Try 'rvp --help' for more information.");
+            exit(EXIT_USAGE);
+        }
+    };
+
+    let code = match cli.command.clone() {
+        Command::Help => {
+            print!("{}", cli::usage());
+            0
+        },
+        Command::Demo(name) => {
+            match name.as_str() {
+                "demo1" => demo1::start(&cli.opts, cli.dump),
+                _ => demo2::start(&cli.opts, cli.dump),
+            }
+        },
+        Command::Run(path) => or_exit(run(&mut cli, &path)),
+        Command::Debug(path) => {
+            cli.opts.interactive = true;
+            or_exit(run(&mut cli, &path))
+        },
+        Command::Asm(path) => or_exit(assemble(&cli, &path)),
+        Command::Disasm(path) => or_exit(disassemble(&cli, &path)),
+        Command::Dump(path) => or_exit(dump(&cli, &path)),
+        Command::Test(paths) => or_exit(test(&cli, &paths)),
+        Command::Lockstep(path) => or_exit(lockstep(&cli, &path)),
+    };
+    exit(code);
+}
+
+fn or_exit(res: Result<i32, String>) -> i32 {
+    match res {
+        Ok(code) => code,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            exit(single_cycle::EXIT_ERROR);
         }
     }
+}
+
+/*
+ * Runs the program like main(argc, argv) with the file name as argv[0] and
+ * returns its exit code.
+ */
+fn run(cli: &mut cli::Cli, path: &str) -> Result<i32, String> {
+    let mut program = Program::load(path, cli.format, cli.opts.isa)?;
+    cli.opts.entry = cli.entry.unwrap_or(program.entry);
+    let mut args = vec![path.to_string()];
+    args.extend(cli.args.iter().cloned());
+    cli.opts.args = Some(args);
 
-    match demo {
-        1 => {
-            demo1::start(debug, dump);
+    if cli.dump {
+        dump_instr_mem::dump_as_txt(&program.instr_mem);
+    }
+    let code = single_cycle::start(&program.instr_mem, &mut program.data_mem, &program.symbols, &cli.opts);
+    if cli.dump {
+        dump_data_mem::dump_as_txt(&program.data_mem);
+    }
+    return Ok(code);
+}
+
+/*
+ * Assembles to the -o file in the --format (guessed from the name of the
+ * file, elf if none fits), or as hex to stdout without -o.
+ */
+fn assemble(cli: &cli::Cli, path: &str) -> Result<i32, String> {
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) => return Err(format!("Load-Error: Failed to read [{}]: {}", path, e)),
+    };
+    let assembly = asm::assemble(&source, path)?;
+    let format = match (cli.format, &cli.output) {
+        (Some(format), _) => format,
+        (None, Some(output)) => Format::guess(output),
+        (None, None) => Format::Hex,
+    };
+    let bytes = match format {
+        Format::Elf => loader::to_elf(&assembly).write(),
+        Format::Bin | Format::Hex if !assembly.data.is_empty() => {
+            return Err(format!("Error: The {} format has no data memory, use elf for programs with .data",
+                               format.name()));
         },
-        2 => {
-            demo2::start(debug, dump);
+        Format::Bin => assembly.text.clone(),
+        Format::Hex => loader::to_hex(&assembly.text).into_bytes(),
+        Format::Asm => return Err("Error: asm can write elf, bin or hex, not asm".to_string()),
+    };
+    match &cli.output {
+        Some(output) => {
+            if let Err(e) = fs::write(output, &bytes) {
+                return Err(format!("Error: Failed to write [{}]: {}", output, e));
+            }
+        },
+        None if format == Format::Elf || format == Format::Bin => {
+            return Err(format!("Error: Refusing to write {} to the terminal, give an output file with -o",
+                               format.name()));
         },
-        _ => {
-            eprintln!("Invalid demo name!");
-            show_help(1);
+        None => print!("{}", String::from_utf8_lossy(&bytes)),
+    }
+    return Ok(0);
+}
+
+fn disassemble(cli: &cli::Cli, path: &str) -> Result<i32, String> {
+    let program = Program::load(path, cli.format, cli.opts.isa)?;
+    for (addr, word) in loader::instructions(&program.instr_mem, &program.symbols, cli.opts.isa) {
+        if let Some(label) = program.symbols.name_at(addr) {
+            println!("{}:", label);
         }
+        println!("  0x{:08x}: 0x{:08x}  {}", addr, word, cli.opts.isa.disassemble(word, addr));
+    }
+    return Ok(0);
+}
+
+fn dump(cli: &cli::Cli, path: &str) -> Result<i32, String> {
+    let program = Program::load(path, cli.format, cli.opts.isa)?;
+    dump_instr_mem::dump_as_txt(&program.instr_mem);
+    dump_data_mem::dump_as_txt(&program.data_mem);
+    return Ok(0);
+}
+
+/*
+ * Runs every program found with expectations, the report goes to -o or
+ * stdout and a summary to stderr. Fails unless all of them passed.
+ */
+fn test(cli: &cli::Cli, paths: &[String]) -> Result<i32, String> {
+    let files = test_runner::discover(paths)?;
+    if files.is_empty() {
+        return Err(format!("Test-Error: No programs with expectations in [{}]", paths.join(", ")));
+    }
+    let max_steps = cli.opts.max_steps.unwrap_or(test_runner::DEFAULT_MAX_STEPS);
+    let results: Vec<_> = files.iter().map(|path| test_runner::run_file(path, max_steps)).collect();
+    let report = match cli.report {
+        Report::Tap => test_runner::tap(&results),
+        Report::Junit => test_runner::junit(&results),
     };
-    exit(0);
-}
-
-fn show_help(retval: i32) {
-    println!("Usage: ");
-    println!("      rvp [OPTIONS] <demo-name>");
-    println!("OPTIONS:");
-    println!("      -g, --debug  Prints out debug information while the processor");
-    println!("                   runs");
-    println!("      -d, --dump   Dumps out the contents of instr mem and data mem");
-    println!("                   to stdout");
-    println!("Demo Names:");
-    println!("      demo1        Writes the value 0xcafebabe into address 0x42");
-    println!("      demo2        Writes the value 0x42 into every address in mem");
-    exit(retval);
+    match cli.output.as_ref() {
+        Some(output) => {
+            if let Err(e) = fs::write(output, report) {
+                return Err(format!("Error: Failed to write [{}]: {}", output, e));
+            }
+        },
+        None => print!("{}", report),
+    }
+    let failed = results.iter().filter(|result| !result.passed()).count();
+    eprintln!("{} programs, {} passed, {} failed", results.len(), results.len() - failed, failed);
+    return Ok(if failed == 0 {0} else {1});
+}
+
+/*
+ * Runs the program on the datapath and the reference interpreter, fails
+ * at the first instruction they disagree on.
+ */
+fn lockstep(cli: &cli::Cli, path: &str) -> Result<i32, String> {
+    let mut program = Program::load(path, cli.format, cli.opts.isa)?;
+    program.entry = cli.entry.unwrap_or(program.entry);
+    let mut args = vec![path.to_string()];
+    args.extend(cli.args.iter().cloned());
+    let max_steps = cli.opts.max_steps.unwrap_or(test_runner::DEFAULT_MAX_STEPS);
+    match lockstep::run(&program, &args, max_steps) {
+        Ok(steps) => {
+            println!("Datapath and reference agree on {} instructions", steps);
+            return Ok(0);
+        },
+        Err(divergence) => {
+            print!("{}", divergence);
+            return Ok(1);
+        },
+    }
 }
@@ -9,18 +9,18 @@
 
 use super::hardware::*;
 use crate::instruction::Instruction;
-use crate::control_bits::ControlBits;
+use crate::control_bits::{AluOp, ControlBits, HiLo, MemAccess, Part, Width};
 
 /*
  * Fetch the next instruction from memory
  */
 pub fn instr_fetch(mem: &instr_mem::Memory, addr: usize) -> u32 {
-    let read0 = mem.read(addr + 0) as u32;
+    let read0 = mem.read(addr) as u32;
     let read1 = mem.read(addr + 1) as u32;
     let read2 = mem.read(addr + 2) as u32;
     let read3 = mem.read(addr + 3) as u32;
 
-    return read0 << 24 | read1 << 16 | read2 << 8 | read3 << 0;
+    return read0 << 24 | read1 << 16 | read2 << 8 | read3;
 }
 
 /*
@@ -35,52 +35,116 @@ pub fn instr_decode(instr_raw: u32, instr_struct: &mut Instruction) {
     // r-format
     instr_struct.rd = ((instr_raw >> 11) & 0x1f) as u8;
     instr_struct.shamt = ((instr_raw >> 6) & 0x1f) as u8;
-    instr_struct.funct = ((instr_raw >> 0) & 0x3f) as u8;
+    instr_struct.funct = (instr_raw & 0x3f) as u8;
 
     // i-format
-    instr_struct.imm16 = ((instr_raw >> 0) & 0xffff) as u16;
+    instr_struct.imm16 = (instr_raw & 0xffff) as u16;
 
     // j-format
-    instr_struct.addr = (instr_raw >> 0) & 0x03ff_ffff;
+    instr_struct.addr = instr_raw & 0x03ff_ffff;
 }
 
 /*
  * Executes the given operation on the given inputs.
  */
-pub fn execute_alu(alu_op: u8, alu_in1: u32, alu_in2: u32, bnegate: u8) -> u32 {
-    let in2 = if bnegate == 1 {(!alu_in2).overflowing_add(1).0} else {alu_in2};
-    let alu = alu::Alu::new(alu_in1, in2);
+pub fn execute_alu(alu_op: AluOp, alu_in1: u32, alu_in2: u32) -> u32 {
+    let alu = make_alu(alu_op, alu_in1, alu_in2);
     match alu_op {
-        0 => alu.and(),
-        1 => alu.or(),
-        2 => alu.add(),
-        3 => alu.less(),
-        4 => alu.xor(),
-        op => panic!("Error: Alu op [{}] not supported!", op)
+        AluOp::And => alu.and(),
+        AluOp::Or => alu.or(),
+        AluOp::Xor => alu.xor(),
+        AluOp::Nor => !alu.or(),
+        AluOp::Add | AluOp::Sub => alu.add(),
+        AluOp::Slt => alu.less(),
+        AluOp::Sltu => alu.less_unsigned(),
+        AluOp::Sll | AluOp::SllRt => alu.shift_left(),
+        AluOp::Srl | AluOp::SrlRt => alu.shift_right(),
+        AluOp::Sra | AluOp::SraRt => alu.shift_right_arith(),
+        AluOp::Mul => alu.multiply(),
+        AluOp::Mulh => alu.multiply_high(true, true),
+        AluOp::Mulhsu => alu.multiply_high(true, false),
+        AluOp::Mulhu => alu.multiply_high(false, false),
+        AluOp::Div => alu.divide(true),
+        AluOp::Divu => alu.divide(false),
+        AluOp::Rem => alu.remainder(true),
+        AluOp::Remu => alu.remainder(false),
+        AluOp::Clz => alu.count_leading(false),
+        AluOp::Clo => alu.count_leading(true),
+        AluOp::Seb => alu.sign_extend(8),
+        AluOp::Seh => alu.sign_extend(16),
+        AluOp::Wsbh => alu.swap_bytes(),
+        AluOp::Rotr => alu.rotate_right(),
+        AluOp::Ext => alu.extract(),
+        AluOp::Ins => alu.insert(),
     }
 }
 
 /*
- * Based on the given control bits, the mem phase will read/write mem or do 
- * nothing.
+ * HI/LO after mult, multu, madd, maddu, msub, msubu, mthi or mtlo of rs and
+ * rt, the 64 bit product is the high and the low word out of the multiplier.
  */
-pub fn mem_phase(ctrl: &ControlBits, mem: &mut data_mem::Memory, addr: usize, write_val: u32) -> Option<u32> {
-    if ctrl.mem_read == 1 {
-        // read by byte or word
-        if ctrl.mem_by_byte == 1 {
-            return Some(mem.read(addr) as u32);
-        } else {
-            return Some(read_word(&mem, addr));
-        }
-    } else if ctrl.mem_write == 1 {
-        // write by byte or word
-        if ctrl.mem_by_byte == 1 {
-            mem.write(write_val as u8, addr);
-        } else {
-            write_word(mem, write_val, addr);
-        }
-        return None;
-    }
+pub fn accumulate(hilo: HiLo, acc: u64, rs: u32, rt: u32) -> u64 {
+    let signed = matches!(hilo, HiLo::Mult | HiLo::Madd | HiLo::Msub);
+    let high = alu::Alu::new(rs, rt).multiply_high(signed, signed);
+    let low = alu::Alu::new(rs, rt).multiply();
+    let product = (high as u64) << 32 | low as u64;
+    return match hilo {
+        HiLo::Mult | HiLo::Multu => product,
+        HiLo::Madd | HiLo::Maddu => acc.wrapping_add(product),
+        HiLo::Msub | HiLo::Msubu => acc.wrapping_sub(product),
+        HiLo::Mthi => (rs as u64) << 32 | (acc & 0xffff_ffff),
+        HiLo::Mtlo => (acc & !0xffff_ffff) | rs as u64,
+        HiLo::Mfhi | HiLo::Mflo | HiLo::None => acc,
+    };
+}
+
+/*
+ * True if the sum (or difference) of the inputs overflows as signed
+ * numbers.
+ */
+pub fn alu_overflow(alu_op: AluOp, alu_in1: u32, alu_in2: u32) -> bool {
+    return make_alu(alu_op, alu_in1, alu_in2).overflow();
+}
+
+/*
+ * True if the access is a half word or word one at an address that is not
+ * a multiple of its size, an address error. lwl, lwr, swl and swr take any
+ * address.
+ */
+pub fn misaligned(ctrl: &ControlBits, addr: u32) -> bool {
+    return !ctrl.mem_part() && ctrl.mem_size() > 1 && !addr.is_multiple_of(ctrl.mem_size());
+}
+
+/*
+ * Based on the given control bits, the mem phase will read/write mem (or a
+ * device on the bus) or do nothing. Half words and words are big endian.
+ * The part accesses merge rt (write_val) with the aligned word holding addr,
+ * loads return the merged register.
+ */
+pub fn mem_phase(ctrl: &ControlBits, bus: &mut bus::Bus, addr: u32, write_val: u32) -> Option<u32> {
+    match ctrl.mem {
+        MemAccess::Load { width: Width::Byte, signed } => {
+            let byte = bus.read_byte(addr);
+            return Some(if signed {byte as i8 as u32} else {byte as u32});
+        },
+        MemAccess::Load { width: Width::Half, signed } => {
+            let half = (bus.read_byte(addr) as u16) << 8 | bus.read_byte(addr + 1) as u16;
+            return Some(if signed {half as i16 as u32} else {half as u32});
+        },
+        MemAccess::Load { width: Width::Word, .. } => return Some(bus.read_word(addr)),
+        MemAccess::Store(Width::Byte) => bus.write_byte(write_val as u8, addr),
+        MemAccess::Store(Width::Half) => {
+            bus.write_byte((write_val >> 8) as u8, addr);
+            bus.write_byte(write_val as u8, addr + 1);
+        },
+        MemAccess::Store(Width::Word) => bus.write_word(write_val, addr),
+        MemAccess::LoadPart(part) => return Some(merge_load(part, addr & 0x3, bus.read_word(addr & !0x3), write_val)),
+        MemAccess::StorePart(part) => {
+            let word = bus.read_word(addr & !0x3);
+            bus.write_word(merge_store(part, addr & 0x3, word, write_val), addr & !0x3);
+        },
+        MemAccess::None => (),
+    };
     return None;
 }
 
@@ -88,37 +152,53 @@ pub fn mem_phase(ctrl: &ControlBits, mem: &mut data_mem::Memory, addr: usize, wr
  * Write the given data into the given register
  */
 pub fn write_back(regfile: &mut reg_file::Registers, reg_num: usize, ctrl: &ControlBits, wbval: u32) {
-    if ctrl.reg_write == 1 {
+    if ctrl.reg_write() {
         regfile.write(wbval, reg_num);
     }
 }
 
 // *** PRIVATE FN ***
 
-fn write_word(mem: &mut data_mem::Memory, val: u32, addr: usize) {
-    let write0 = (val >> 24) as u8;
-    let write1 = (val >> 16) as u8;
-    let write2 = (val >> 8) as u8;
-    let write3 = (val >> 0) as u8;
-
-    mem.write(write0, addr + 0);
-    mem.write(write1, addr + 1);
-    mem.write(write2, addr + 2);
-    mem.write(write3, addr + 3);
+/*
+ * Register of lwl/lwr at byte offset k of the word: lwl puts the bytes from
+ * k on in the top of rt, lwr the bytes up to k in the bottom, the other
+ * bytes of rt stay.
+ */
+fn merge_load(part: Part, k: u32, word: u32, rt: u32) -> u32 {
+    return match part {
+        Part::Left => (word << (8 * k)) | (rt & ((1 << (8 * k)) - 1)),
+        Part::Right => (word >> (8 * (3 - k))) | (rt & !(0xffff_ffff >> (8 * (3 - k)))),
+    };
 }
 
-fn read_word(mem: &data_mem::Memory, addr: usize) -> u32 {
-    let read0 = mem.read(addr + 0) as u32;
-    let read1 = mem.read(addr + 1) as u32;
-    let read2 = mem.read(addr + 2) as u32;
-    let read3 = mem.read(addr + 3) as u32;
+/*
+ * Word written by swl/swr at byte offset k: swl writes the top of rt to the
+ * bytes from k on, swr the bottom of rt to the bytes up to k.
+ */
+fn merge_store(part: Part, k: u32, word: u32, rt: u32) -> u32 {
+    return match part {
+        Part::Left => (word & !(0xffff_ffff >> (8 * k))) | (rt >> (8 * k)),
+        Part::Right => (rt << (8 * (3 - k))) | (word & ((1 << (8 * (3 - k))) - 1)),
+    };
+}
 
-    return (read0 << 24) | (read1 << 16) | (read2 << 8) | (read3 << 0);
+fn make_alu(alu_op: AluOp, alu_in1: u32, alu_in2: u32) -> alu::Alu {
+    if matches!(alu_op, AluOp::Sub | AluOp::Slt | AluOp::Sltu) {
+        // a - b = a + !b + 1
+        return alu::Alu::with_carry(alu_in1, !alu_in2, 1);
+    }
+    if matches!(alu_op, AluOp::SllRt | AluOp::SrlRt | AluOp::SraRt) {
+        return alu::Alu::new(alu_in2, alu_in1);
+    }
+    return alu::Alu::new(alu_in1, alu_in2);
 }
 
+// the tests spell out the reads and the missing values they expect
 #[cfg(test)]
+#[allow(clippy::identity_op, clippy::manual_unwrap_or, clippy::manual_unwrap_or_default)]
 mod tests {
     use super::*;
+    use crate::control_bits::RegDst;
 
     fn test_instr_fetch() {
         let mut mem = instr_mem::Memory::new();
@@ -148,29 +228,42 @@ mod tests {
 
     #[test]
     fn test_execute_alu() {
-        // and
-        let mut res = execute_alu(0, 1, 0, 0);
-        assert_eq!(res, 0);
-
-        // or
-        res = execute_alu(1, 1, 0, 0);
-        assert_eq!(res, 1);
-
-        // add
-        res = execute_alu(2, 1, 1, 0);
-        assert_eq!(res, 2);
-
-        // sub
-        res = execute_alu(2, 3, 2, 1);
-        assert_eq!(res, 1);
-
-        // less
-        res = execute_alu(3, 3, 1, 1);
-        assert_eq!(res, 0);
+        assert_eq!(execute_alu(AluOp::And, 1, 0), 0);
+        assert_eq!(execute_alu(AluOp::Or, 1, 0), 1);
+        assert_eq!(execute_alu(AluOp::Add, 1, 1), 2);
+        assert_eq!(execute_alu(AluOp::Sub, 3, 2), 1);
+        assert_eq!(execute_alu(AluOp::Slt, 3, 1), 0);
+        assert_eq!(execute_alu(AluOp::Slt, 0xffff_ffff, 1), 1);
+        assert_eq!(execute_alu(AluOp::Sltu, 0xffff_ffff, 1), 0);
+        assert_eq!(execute_alu(AluOp::Xor, 1, 1), 0);
+        assert_eq!(execute_alu(AluOp::Nor, 0xf0, 0x0f), 0xffff_ff00);
+        assert_eq!(execute_alu(AluOp::Sra, 0xffff_fff0, 2), 0xffff_fffc);
+        assert_eq!(execute_alu(AluOp::SraRt, 2, 0xffff_fff0), 0xffff_fffc);
+        assert_eq!(execute_alu(AluOp::SllRt, 0x24, 1), 0x10);
+        assert_eq!(execute_alu(AluOp::Mulhu, 0xffff_ffff, 0xffff_ffff), 0xffff_fffe);
+        assert_eq!(execute_alu(AluOp::Rem, (-7i32) as u32, 3), (-1i32) as u32);
+        assert_eq!(execute_alu(AluOp::Clo, 0xffff_0000, 0), 16);
+        assert_eq!(execute_alu(AluOp::Seb, 0, 0x0000_00ff), 0xffff_ffff);
+        assert_eq!(execute_alu(AluOp::Rotr, 8, 0x1234_5678), 0x7812_3456);
+        assert_eq!(execute_alu(AluOp::Ext, 0x1234_5678, (3 << 5) | 8), 0x6);
+
+        assert!(alu_overflow(AluOp::Add, 0x7fff_ffff, 1));
+        assert!(alu_overflow(AluOp::Sub, 0x8000_0000, 1));
+        assert!(!alu_overflow(AluOp::Sub, 0x7fff_ffff, 1));
+    }
 
-        // xor
-        res = execute_alu(4, 1, 1, 0);
-        assert_eq!(res, 0);
+    #[test]
+    fn test_accumulate() {
+        // -1 * 2 signed and unsigned
+        assert_eq!(accumulate(HiLo::Madd, 5, 0xffff_ffff, 2), 3);
+        assert_eq!(accumulate(HiLo::Maddu, 0, 0xffff_ffff, 2), 0x1_ffff_fffe);
+        assert_eq!(accumulate(HiLo::Msub, 0, 0xffff_ffff, 2), 2);
+        assert_eq!(accumulate(HiLo::Msubu, 0, 1, 1), 0xffff_ffff_ffff_ffff);
+        assert_eq!(accumulate(HiLo::Mult, 5, 0xffff_ffff, 2), 0xffff_ffff_ffff_fffe);
+        assert_eq!(accumulate(HiLo::Multu, 5, 0xffff_ffff, 2), 0x1_ffff_fffe);
+        assert_eq!(accumulate(HiLo::Mthi, 0x1_0000_0002, 7, 0), 0x7_0000_0002);
+        assert_eq!(accumulate(HiLo::Mtlo, 0x1_0000_0002, 7, 0), 0x1_0000_0007);
+        assert_eq!(accumulate(HiLo::Mflo, 0x1_0000_0002, 7, 0), 0x1_0000_0002);
     }
 
     #[test]
@@ -179,65 +272,90 @@ mod tests {
 
         // test reads
         let ctrl_read_word = ControlBits {
-            alu_op: 0,
-            alu_bnegate: 0,
-
-            mem_read: 1,
-            mem_write: 0,
-            mem_to_reg: 1,
-
-            reg_dst: 0,
-            reg_write: 1,
-
-            branch: 0,
-            jump: 0,
-
-            not_res: 0,
-            mem_by_byte: 0,
-            imm_upper: 0,
+            mem: MemAccess::Load { width: Width::Word, signed: true },
+            ..ControlBits::default()
         };
         mem.write(0x12, 0x0);
         mem.write(0x34, 0x1);
         mem.write(0x56, 0x2);
         mem.write(0x78, 0x3);
-        let readval_word = match mem_phase(&ctrl_read_word, &mut mem, 0x0, 0) {
+        mem.write(0x9a, 0x4);
+        let mut bus = bus::Bus::new(&mut mem);
+        let readval_word = match mem_phase(&ctrl_read_word, &mut bus, 0x0, 0) {
             Some(val) => val,
             None => 0
         };
         assert_eq!(readval_word, 0x12345678);
 
         let ctrl_read_byte = ControlBits {
-            mem_by_byte: 1,
+            mem: MemAccess::Load { width: Width::Byte, signed: true },
             ..ctrl_read_word
         };
-        let readval_byte = match mem_phase(&ctrl_read_byte, &mut mem, 0x0, 0) {
-            Some(val) => val,
-            None => 0
+        assert_eq!(mem_phase(&ctrl_read_byte, &mut bus, 0x0, 0), Some(0x12));
+        assert_eq!(mem_phase(&ctrl_read_byte, &mut bus, 0x4, 0), Some(0xffff_ff9a));
+        let ctrl_read_ubyte = ControlBits {
+            mem: MemAccess::Load { width: Width::Byte, signed: false },
+            ..ctrl_read_word
         };
-        assert_eq!(readval_byte, 0x12);
+        assert_eq!(mem_phase(&ctrl_read_ubyte, &mut bus, 0x4, 0), Some(0x9a));
 
         // test writes
         let ctrl_write_word = ControlBits {
-            mem_read: 0,
-            mem_write: 1,
-            mem_to_reg: 0,
+            mem: MemAccess::Store(Width::Word),
             ..ctrl_read_word
         };
-        mem_phase(&ctrl_write_word, &mut mem, 0x4, 0xdeadbeef);
-        let read0 = mem.read(0x4) as u32;
-        let read1 = mem.read(0x5) as u32;
-        let read2 = mem.read(0x6) as u32;
-        let read3 = mem.read(0x7) as u32;
+        mem_phase(&ctrl_write_word, &mut bus, 0x4, 0xdeadbeef);
+        let read0 = bus.mem().read(0x4) as u32;
+        let read1 = bus.mem().read(0x5) as u32;
+        let read2 = bus.mem().read(0x6) as u32;
+        let read3 = bus.mem().read(0x7) as u32;
         let read32 = (read0 << 24) | (read1 << 16) | (read2 << 8) | (read3 << 0);
         assert_eq!(read32, 0xdeadbeef);
+        assert_eq!(bus.read_word(0x4), 0xdeadbeef);
 
         let ctrl_write_byte = ControlBits {
-            mem_by_byte: 1,
+            mem: MemAccess::Store(Width::Byte),
             ..ctrl_write_word
         };
-        mem_phase(&ctrl_write_byte, &mut mem, 0x4, 0x66);
-        assert_eq!(mem.read(0x4), 0x66);
-        assert_eq!(mem.read(0x5), 0xad);
+        mem_phase(&ctrl_write_byte, &mut bus, 0x4, 0x66);
+        assert_eq!(bus.mem().read(0x4), 0x66);
+        assert_eq!(bus.mem().read(0x5), 0xad);
+        assert_eq!(bus.read_word(0x4), 0x66ad_beef);
+        // the half word store takes the low half of the value
+        let ctrl_write_half = ControlBits {
+            mem: MemAccess::Store(Width::Half),
+            ..ctrl_write_word
+        };
+        mem_phase(&ctrl_write_half, &mut bus, 0x6, 0x1234_5678);
+        assert_eq!(bus.read_word(0x4), 0x66ad_5678);
+    }
+
+    #[test]
+    fn test_mem_phase_parts() {
+        let mut mem = data_mem::Memory::new();
+        for (i, byte) in [0x11, 0x22, 0x33, 0x44].iter().enumerate() {
+            mem.write(*byte, i);
+        }
+        let mut bus = bus::Bus::new(&mut mem);
+        let part = |mem| ControlBits { mem, ..ControlBits::default() };
+        let lwl = part(MemAccess::LoadPart(Part::Left));
+        let lwr = part(MemAccess::LoadPart(Part::Right));
+        assert_eq!(mem_phase(&lwl, &mut bus, 0x1, 0xaabb_ccdd), Some(0x2233_44dd));
+        assert_eq!(mem_phase(&lwr, &mut bus, 0x1, 0xaabb_ccdd), Some(0xaabb_1122));
+        assert_eq!(mem_phase(&lwl, &mut bus, 0x0, 0xaabb_ccdd), Some(0x1122_3344));
+        assert_eq!(mem_phase(&lwr, &mut bus, 0x3, 0xaabb_ccdd), Some(0x1122_3344));
+
+        mem_phase(&part(MemAccess::StorePart(Part::Left)), &mut bus, 0x2, 0xaabb_ccdd);
+        assert_eq!(bus.read_word(0), 0x1122_aabb);
+        mem_phase(&part(MemAccess::StorePart(Part::Right)), &mut bus, 0x1, 0xaabb_ccdd);
+        assert_eq!(bus.read_word(0), 0xccdd_aabb);
+
+        // only the part accesses take unaligned half words and words
+        assert!(misaligned(&part(MemAccess::Load { width: Width::Word, signed: true }), 0x2));
+        assert!(misaligned(&part(MemAccess::Store(Width::Half)), 0x1));
+        assert!(!misaligned(&part(MemAccess::Store(Width::Half)), 0x2));
+        assert!(!misaligned(&part(MemAccess::Load { width: Width::Byte, signed: true }), 0x3));
+        assert!(!misaligned(&lwl, 0x3));
     }
 
     #[test]
@@ -245,29 +363,15 @@ mod tests {
         let mut regfile = reg_file::Registers::new();
 
         let ctrl_write = ControlBits {
-            alu_op: 0,
-            alu_bnegate: 0,
-
-            mem_read: 0,
-            mem_write: 0,
-            mem_to_reg: 0,
-
-            reg_dst: 0,
-            reg_write: 1,
-
-            branch: 0,
-            jump: 0,
-
-            not_res: 0,
-            mem_by_byte: 0,
-            imm_upper: 0,
+            reg_dst: RegDst::Rt,
+            ..ControlBits::default()
         };
         write_back(&mut regfile, 11, &ctrl_write, 45);
         assert_eq!(regfile.load(11), 45);
 
 
         let ctrl_no_write = ControlBits {
-            reg_write: 0,
+            reg_dst: RegDst::None,
             ..ctrl_write
         };
         write_back(&mut regfile, 12, &ctrl_no_write, 45);
@@ -275,4 +379,4 @@ mod tests {
         
 
     }
-}
\ No newline at end of file
+}
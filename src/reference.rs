@@ -0,0 +1,482 @@
+/*
+ * reference.rs
+ *
+ * Author: Travis Banken
+ *
+ * Reference interpreter: runs the instruction set directly, one match arm
+ * per instruction, without control bits, an ALU or the bus. It shares no
+ * code with the datapath so the lockstep harness (lockstep.rs) can check one
+ * against the other.
+ *
+ * It follows MIPS I, plus the MIPS32 release 1 and 2 instructions of
+ * instruction.rs, with the conventions of this processor: no delay slots
+ * (links are pc + 4), branch targets are absolute ((pc & 0xffff_0000) | imm)
+ * like the j targets ((pc & 0xff00_0000) | addr26). Exceptions go to the
+ * general vector 0x8000_0080, which ends the program. Neither coprocessor 0
+ * nor the devices are modelled: cop0 instructions raise a reserved
+ * instruction exception and accesses outside of data memory address errors.
+ */
+#![allow(dead_code)]
+
+use crate::hardware::cp0::ExcCode;
+
+pub const EXCEPTION_VECTOR: u32 = 0x8000_0080;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reference {
+    pub regs: [u32; 32],
+    pub hi: u32,
+    pub lo: u32,
+    pub pc: u32,
+    // data memory
+    pub mem: Vec<u8>,
+    // instruction memory
+    text: Vec<u8>,
+}
+
+impl Reference {
+    pub fn new(text: &[u8], mem: &[u8], pc: u32) -> Reference {
+        Reference {
+            regs: [0; 32],
+            hi: 0,
+            lo: 0,
+            pc,
+            mem: mem.to_vec(),
+            text: text.to_vec(),
+        }
+    }
+
+    /*
+     * Runs until the next instruction would be fetched from outside of
+     * instruction memory, like the processor.
+     */
+    pub fn is_running(&self) -> bool {
+        return (self.pc as usize) < self.text.len();
+    }
+
+    /*
+     * Runs one instruction, or returns the exception it raised (the pc is
+     * then the exception vector).
+     */
+    pub fn step(&mut self) -> Result<(), ExcCode> {
+        let res = self.execute();
+        if res.is_err() {
+            self.pc = EXCEPTION_VECTOR;
+        }
+        return res;
+    }
+
+    // *** PRIVATE FN ***
+
+    fn execute(&mut self) -> Result<(), ExcCode> {
+        let pc = self.pc;
+        let word = self.fetch(pc)?;
+        let op = word >> 26;
+        let rs = ((word >> 21) & 0x1f) as usize;
+        let rt = ((word >> 16) & 0x1f) as usize;
+        let rd = ((word >> 11) & 0x1f) as usize;
+        let shamt = (word >> 6) & 0x1f;
+        let funct = word & 0x3f;
+        let imm = word & 0xffff;
+        let simm = imm as u16 as i16 as u32;
+        let s = self.regs[rs];
+        let t = self.regs[rt];
+        let branch = (pc & 0xffff_0000) | imm;
+        let mut next = pc.wrapping_add(4);
+
+        match op {
+            0x00 => match funct {
+                0x00 => self.set(rd, t << shamt), // sll
+                0x02 if rs == 1 => self.set(rd, t.rotate_right(shamt)), // rotr
+                0x02 => self.set(rd, t >> shamt), // srl
+                0x03 => self.set(rd, ((t as i32) >> shamt) as u32), // sra
+                0x04 => self.set(rd, t << (s & 0x1f)), // sllv
+                0x06 if shamt == 1 => self.set(rd, t.rotate_right(s & 0x1f)), // rotrv
+                0x06 => self.set(rd, t >> (s & 0x1f)), // srlv
+                0x07 => self.set(rd, ((t as i32) >> (s & 0x1f)) as u32), // srav
+                0x08 => next = s, // jr
+                0x09 => { // jalr
+                    self.set(rd, pc.wrapping_add(4));
+                    next = s;
+                },
+                0x0A => if t == 0 {self.set(rd, s)}, // movz
+                0x0B => if t != 0 {self.set(rd, s)}, // movn
+                0x0C => return Err(ExcCode::Sys), // syscall
+                0x0D => return Err(ExcCode::Bp), // break
+                0x0F => (), // sync, accesses are already in order
+                0x10 => self.set(rd, self.hi), // mfhi
+                0x11 => self.hi = s, // mthi
+                0x12 => self.set(rd, self.lo), // mflo
+                0x13 => self.lo = s, // mtlo
+                0x18 => { // mult
+                    let prod = (s as i32 as i64) * (t as i32 as i64);
+                    self.hi = (prod >> 32) as u32;
+                    self.lo = prod as u32;
+                },
+                0x19 => { // multu
+                    let prod = (s as u64) * (t as u64);
+                    self.hi = (prod >> 32) as u32;
+                    self.lo = prod as u32;
+                },
+                0x1A => { // div, hi and lo are left alone when dividing by 0
+                    if t != 0 {
+                        self.lo = (s as i32).wrapping_div(t as i32) as u32;
+                        self.hi = (s as i32).wrapping_rem(t as i32) as u32;
+                    }
+                },
+                0x1B => { // divu
+                    if let (Some(quot), Some(rem)) = (s.checked_div(t), s.checked_rem(t)) {
+                        self.lo = quot;
+                        self.hi = rem;
+                    }
+                },
+                0x20 => { // add
+                    let sum = (s as i32).checked_add(t as i32).ok_or(ExcCode::Ov)?;
+                    self.set(rd, sum as u32);
+                },
+                0x21 => self.set(rd, s.wrapping_add(t)), // addu
+                0x22 => { // sub
+                    let diff = (s as i32).checked_sub(t as i32).ok_or(ExcCode::Ov)?;
+                    self.set(rd, diff as u32);
+                },
+                0x23 => self.set(rd, s.wrapping_sub(t)), // subu
+                0x24 => self.set(rd, s & t), // and
+                0x25 => self.set(rd, s | t), // or
+                0x26 => self.set(rd, s ^ t), // xor
+                0x27 => self.set(rd, !(s | t)), // nor
+                0x2A => self.set(rd, ((s as i32) < (t as i32)) as u32), // slt
+                0x2B => self.set(rd, (s < t) as u32), // sltu
+                0x30..=0x37 => if trap_taken(funct, s, t)? {return Err(ExcCode::Tr)}, // tge, tgeu, tlt, tltu, teq, tne
+                _ => return Err(ExcCode::RI),
+            },
+            // tgei, tgeiu, tlti, tltiu, teqi, tnei
+            0x01 if rt & 0x18 == 0x08 => if trap_taken(rt as u32, s, simm)? {return Err(ExcCode::Tr)},
+            0x01 => { // bltz, bgez, bltzal, bgezal
+                let taken = match rt {
+                    0x00 | 0x10 => (s as i32) < 0,
+                    0x01 | 0x11 => (s as i32) >= 0,
+                    _ => return Err(ExcCode::RI),
+                };
+                if rt & 0x10 != 0 {
+                    self.set(31, pc.wrapping_add(4));
+                }
+                if taken {
+                    next = branch;
+                }
+            },
+            0x02 => next = (pc & 0xff00_0000) | (word & 0x03ff_ffff), // j
+            0x03 => { // jal
+                self.set(31, pc.wrapping_add(4));
+                next = (pc & 0xff00_0000) | (word & 0x03ff_ffff);
+            },
+            0x04 => if s == t {next = branch}, // beq
+            0x05 => if s != t {next = branch}, // bne
+            0x06 => if (s as i32) <= 0 {next = branch}, // blez
+            0x07 => if (s as i32) > 0 {next = branch}, // bgtz
+            0x08 => { // addi
+                let sum = (s as i32).checked_add(simm as i32).ok_or(ExcCode::Ov)?;
+                self.set(rt, sum as u32);
+            },
+            0x09 => self.set(rt, s.wrapping_add(simm)), // addiu
+            0x0A => self.set(rt, ((s as i32) < (simm as i32)) as u32), // slti
+            0x0B => self.set(rt, (s < simm) as u32), // sltiu
+            0x0C => self.set(rt, s & imm), // andi
+            0x0D => self.set(rt, s | imm), // ori
+            0x0E => self.set(rt, s ^ imm), // xori
+            0x0F => self.set(rt, imm << 16), // lui
+            0x1C => match funct {
+                0x00 => self.set_acc(self.acc().wrapping_add((s as i32 as i64 * t as i32 as i64) as u64)), // madd
+                0x01 => self.set_acc(self.acc().wrapping_add(s as u64 * t as u64)), // maddu
+                0x02 => self.set(rd, s.wrapping_mul(t)), // mul, hi and lo are left alone
+                0x04 => self.set_acc(self.acc().wrapping_sub((s as i32 as i64 * t as i32 as i64) as u64)), // msub
+                0x05 => self.set_acc(self.acc().wrapping_sub(s as u64 * t as u64)), // msubu
+                0x20 => self.set(rd, s.leading_zeros()), // clz
+                0x21 => self.set(rd, s.leading_ones()), // clo
+                _ => return Err(ExcCode::RI),
+            },
+            0x1F => match funct {
+                0x00 => { // ext, size rd + 1 from bit shamt
+                    let mask = (1u64 << (rd + 1)) - 1;
+                    self.set(rt, ((s as u64 >> shamt) & mask) as u32);
+                },
+                0x04 => { // ins, bits shamt to rd
+                    if (rd as u32) < shamt {
+                        return Err(ExcCode::RI);
+                    }
+                    let mask = (((1u64 << (rd as u32 + 1 - shamt)) - 1) << shamt) as u32;
+                    self.set(rt, (t & !mask) | ((s << shamt) & mask));
+                },
+                0x20 => match shamt {
+                    0x02 => self.set(rd, ((t & 0x00ff_00ff) << 8) | ((t >> 8) & 0x00ff_00ff)), // wsbh
+                    0x10 => self.set(rd, t as u8 as i8 as u32), // seb
+                    0x18 => self.set(rd, t as u16 as i16 as u32), // seh
+                    _ => return Err(ExcCode::RI),
+                },
+                _ => return Err(ExcCode::RI),
+            },
+            0x20 => { // lb
+                let val = self.load(s.wrapping_add(simm), 1)?;
+                self.set(rt, val as u8 as i8 as u32);
+            },
+            0x21 => { // lh
+                let val = self.load(s.wrapping_add(simm), 2)?;
+                self.set(rt, val as u16 as i16 as u32);
+            },
+            0x23 => { // lw
+                let val = self.load(s.wrapping_add(simm), 4)?;
+                self.set(rt, val);
+            },
+            0x24 => { // lbu
+                let val = self.load(s.wrapping_add(simm), 1)?;
+                self.set(rt, val);
+            },
+            0x25 => { // lhu
+                let val = self.load(s.wrapping_add(simm), 2)?;
+                self.set(rt, val);
+            },
+            0x22 => { // lwl, the bytes from addr to the end of its word into the top of rt
+                let addr = s.wrapping_add(simm);
+                let mut val = t;
+                for i in 0..4 - (addr & 0x3) {
+                    let shift = 24 - 8 * i;
+                    val = (val & !(0xff << shift)) | (self.load(addr + i, 1)? << shift);
+                }
+                self.set(rt, val);
+            },
+            0x26 => { // lwr, the bytes from the start of the word to addr into the bottom of rt
+                let addr = s.wrapping_add(simm);
+                let mut val = t;
+                for i in 0..=(addr & 0x3) {
+                    val = (val & !(0xff << (8 * i))) | (self.load(addr - i, 1)? << (8 * i));
+                }
+                self.set(rt, val);
+            },
+            0x28 => self.store(s.wrapping_add(simm), 1, t)?, // sb
+            0x29 => self.store(s.wrapping_add(simm), 2, t)?, // sh
+            0x2A => { // swl, the top of rt to the bytes from addr to the end of its word
+                let addr = s.wrapping_add(simm);
+                for i in 0..4 - (addr & 0x3) {
+                    self.store(addr + i, 1, t >> (24 - 8 * i))?;
+                }
+            },
+            0x2B => self.store(s.wrapping_add(simm), 4, t)?, // sw
+            0x2E => { // swr, the bottom of rt to the bytes from the start of the word to addr
+                let addr = s.wrapping_add(simm);
+                for i in 0..=(addr & 0x3) {
+                    self.store(addr - i, 1, t >> (8 * i))?;
+                }
+            },
+            _ => return Err(ExcCode::RI),
+        }
+        self.pc = next;
+        return Ok(());
+    }
+
+    fn set(&mut self, reg: usize, val: u32) {
+        // $zero stays 0
+        if reg != 0 {
+            self.regs[reg] = val;
+        }
+    }
+
+    /*
+     * HI and LO as one 64 bit accumulator, for madd and msub.
+     */
+    fn acc(&self) -> u64 {
+        return (self.hi as u64) << 32 | self.lo as u64;
+    }
+
+    fn set_acc(&mut self, acc: u64) {
+        self.hi = (acc >> 32) as u32;
+        self.lo = acc as u32;
+    }
+
+    fn fetch(&self, addr: u32) -> Result<u32, ExcCode> {
+        if addr & 0x3 != 0 || addr as usize + 4 > self.text.len() {
+            return Err(ExcCode::AdEL);
+        }
+        let at = addr as usize;
+        return Ok(u32::from_be_bytes([self.text[at], self.text[at + 1], self.text[at + 2], self.text[at + 3]]));
+    }
+
+    /*
+     * Big endian load of size bytes, zero extended.
+     */
+    fn load(&self, addr: u32, size: u32) -> Result<u32, ExcCode> {
+        if !addr.is_multiple_of(size) || addr as usize + size as usize > self.mem.len() {
+            return Err(ExcCode::AdEL);
+        }
+        let at = addr as usize;
+        return Ok(self.mem[at..at + size as usize].iter().fold(0, |acc, byte| (acc << 8) | *byte as u32));
+    }
+
+    fn store(&mut self, addr: u32, size: u32, val: u32) -> Result<(), ExcCode> {
+        if !addr.is_multiple_of(size) || addr as usize + size as usize > self.mem.len() {
+            return Err(ExcCode::AdES);
+        }
+        for i in 0..size {
+            let shift = 8 * (size - 1 - i);
+            self.mem[(addr + i) as usize] = (val >> shift) as u8;
+        }
+        return Ok(());
+    }
+}
+
+/*
+ * Does the trap with the given condition (the low 3 bits of the funct of
+ * tge..tne, or of the rt of tgei..tnei) go off?
+ */
+fn trap_taken(cond: u32, a: u32, b: u32) -> Result<bool, ExcCode> {
+    return match cond & 0x7 {
+        0 => Ok(a as i32 >= b as i32),
+        1 => Ok(a >= b),
+        2 => Ok((a as i32) < b as i32),
+        3 => Ok(a < b),
+        4 => Ok(a == b),
+        6 => Ok(a != b),
+        _ => Err(ExcCode::RI),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::demos::assembler::instr_macros::*;
+    use crate::demos::assembler::reg_macros::*;
+
+    fn run(program: &[u32]) -> (Reference, Option<ExcCode>) {
+        let text: Vec<u8> = program.iter().flat_map(|word| word.to_be_bytes().to_vec()).collect();
+        let mut reference = Reference::new(&text, &[0; 256], 0);
+        while reference.is_running() {
+            if let Err(exc) = reference.step() {
+                return (reference, Some(exc));
+            }
+        }
+        return (reference, None);
+    }
+
+    #[test]
+    fn test_alu() {
+        let (reference, exc) = run(&[
+            ADDI (T0(), ZERO(), 0xfffe),    // -2
+            ADDIU(T1(), ZERO(), 3),
+            SLT  (T2(), T0(),   T1()),
+            SLTU (T3(), T0(),   T1()),
+            SLTI (T4(), T0(),   0xffff),    // -2 < -1
+            SLTIU(T5(), T1(),   0xffff),    // 3 < 0xffffffff
+            ORI  (T6(), ZERO(), 0xfffe),
+            NOR  (T7(), T0(),   ZERO()),
+            ADD  (ZERO(), T1(), T1()),
+        ]);
+        assert_eq!(exc, None);
+        assert_eq!(reference.regs[T0() as usize], 0xffff_fffe);
+        assert_eq!(reference.regs[T2() as usize], 1);
+        assert_eq!(reference.regs[T3() as usize], 0);
+        assert_eq!(reference.regs[T4() as usize], 1);
+        assert_eq!(reference.regs[T5() as usize], 1);
+        assert_eq!(reference.regs[T6() as usize], 0xfffe);
+        assert_eq!(reference.regs[T7() as usize], 1);
+        assert_eq!(reference.regs[0], 0);
+
+        let (reference, exc) = run(&[
+            LUI (T0(), 0x7fff),
+            ORI (T0(), T0(), 0xffff),
+            ADDU(T1(), T0(), T0()),
+            ADD (T2(), T0(), T0()),
+        ]);
+        assert_eq!(exc, Some(ExcCode::Ov));
+        assert_eq!(reference.regs[T1() as usize], 0xffff_fffe);
+        assert_eq!(reference.regs[T2() as usize], 0);
+        assert_eq!(reference.pc, EXCEPTION_VECTOR);
+    }
+
+    #[test]
+    fn test_memory_and_branches() {
+        let (reference, exc) = run(&[
+            ADDI(T0(), ZERO(), 0x80),
+            SW  (T0(), 0xfffc, T0()),       // 0x7c
+            ADDI(T1(), ZERO(), 0xff),
+            SB  (T1(), 1,      T0()),
+            LB  (T2(), 1,      T0()),
+            LW  (T3(), 0xfffc, T0()),
+            BEQ (T2(), T1(),   0x24),       // not taken, -1 != 0xff
+            BNE (T2(), T1(),   0x24),
+            ADDI(S0(), ZERO(), 1),          // skipped
+            J   (0x2c),
+            ADDI(S0(), ZERO(), 2),          // skipped
+            LW  (T4(), 2,      T0()),
+        ]);
+        assert_eq!(exc, Some(ExcCode::AdEL));
+        assert_eq!(reference.mem[0x7c..0x82], [0, 0, 0, 0x80, 0, 0xff]);
+        assert_eq!(reference.regs[T2() as usize], 0xffff_ffff);
+        assert_eq!(reference.regs[T3() as usize], 0x80);
+        assert_eq!(reference.regs[S0() as usize], 0);
+    }
+
+    #[test]
+    fn test_mips32() {
+        let (reference, exc) = run(&[
+            ADDI (T0(), ZERO(), 0xfffd),    // -3
+            ADDIU(T1(), ZERO(), 0x1234),
+            MUL  (T2(), T0(),   T1()),
+            MULT (T0(), T1()),
+            MADD (T0(), T1()),              // hi:lo = 2 * -3 * 0x1234
+            MSUBU(T1(), T1()),
+            CLZ  (T3(), T1()),
+            CLO  (T4(), T0()),
+            SEB  (T5(), T1()),              // 0x34
+            SEH  (T6(), T0()),
+            WSBH (T7(), T1()),
+            ROTR (S0(), T1(),   4),
+            ROTRV(S1(), T1(),   T0()),      // by 29
+            EXT  (S2(), T1(),   4, 8),
+            INS  (S3(), T0(),   8, 4),      // 0 with bits 8..11 from -3
+            MOVZ (S4(), T1(),   ZERO()),
+            MOVN (S5(), T1(),   ZERO()),
+            SYNC (),
+            TNE  (T0(), T0()),
+            TLTIU(T1(), 0x1000),
+            TEQI (T1(), 0x1234),
+        ]);
+        assert_eq!(exc, Some(ExcCode::Tr));
+        assert_eq!(reference.pc, EXCEPTION_VECTOR);
+        assert_eq!(reference.regs[T2() as usize], (-3i32 * 0x1234) as u32);
+        let acc = (2 * -3i64 * 0x1234) as u64 - 0x1234 * 0x1234;
+        assert_eq!((reference.hi, reference.lo), ((acc >> 32) as u32, acc as u32));
+        assert_eq!(reference.regs[T3() as usize], 19);
+        assert_eq!(reference.regs[T4() as usize], 30);
+        assert_eq!(reference.regs[T5() as usize], 0x34);
+        assert_eq!(reference.regs[T6() as usize], 0xffff_fffd);
+        assert_eq!(reference.regs[T7() as usize], 0x3412);
+        assert_eq!(reference.regs[S0() as usize], 0x4000_0123);
+        assert_eq!(reference.regs[S1() as usize], 0x1234_u32.rotate_right(29));
+        assert_eq!(reference.regs[S2() as usize], 0x23);
+        assert_eq!(reference.regs[S3() as usize], 0xd00);
+        assert_eq!(reference.regs[S4() as usize], 0x1234);
+        assert_eq!(reference.regs[S5() as usize], 0);
+    }
+
+    #[test]
+    fn test_unaligned() {
+        let (reference, exc) = run(&[
+            LUI (T0(), 0x1122),
+            ORI (T0(), T0(), 0x3344),
+            SW  (T0(), 0x40, ZERO()),
+            ADDI(T1(), ZERO(), -1i16 as u16),
+            ADDI(T2(), ZERO(), -1i16 as u16),
+            LWL (T1(), 0x41, ZERO()),       // 0x223344ff
+            LWR (T2(), 0x41, ZERO()),       // 0xffff1122
+            SWL (T0(), 0x46, ZERO()),       // 0x11 0x22 at 0x46
+            SWR (T0(), 0x49, ZERO()),       // 0x33 0x44 at 0x48
+            LWL (T3(), 0x40, ZERO()),       // aligned, the whole word
+            LWR (T3(), 0x43, ZERO()),
+            LH  (T4(), 0x41, ZERO()),
+        ]);
+        assert_eq!(exc, Some(ExcCode::AdEL));
+        assert_eq!(reference.regs[T1() as usize], 0x2233_44ff);
+        assert_eq!(reference.regs[T2() as usize], 0xffff_1122);
+        assert_eq!(reference.regs[T3() as usize], 0x1122_3344);
+        assert_eq!(reference.mem[0x44..0x4c], [0, 0, 0x11, 0x22, 0x33, 0x44, 0, 0]);
+        assert_eq!(reference.regs[T4() as usize], 0);
+    }
+}
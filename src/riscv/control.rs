@@ -0,0 +1,183 @@
+/*
+ * control.rs
+ *
+ * Author: Travis Banken
+ *
+ * Control table of the RV32I instructions and the M, A and Zicsr
+ * extensions, the same control bits the MIPS datapath decodes
+ * (control_bits.rs). Every instruction writes rd, the alu gets rs1 (or the
+ * pc) and rs2 or the immediate of the format. The csr instructions and mret
+ * use cop0, they run on the csrs instead of the alu.
+ */
+#![allow(dead_code)]
+
+use crate::control_bits::{self, AluOp, AluSrc, ControlBits, MemAccess, PcSource, RegDst, Width};
+use crate::riscv::instruction::{self, Instruction, Op};
+
+pub struct Control {
+    pub op: Op,
+    pub ctrl: ControlBits,
+}
+
+/*
+ * Row of the table decoding the instruction, None for an illegal
+ * instruction.
+ */
+pub fn decode(instr: &Instruction) -> Option<&'static Control> {
+    let op = instruction::decode(instr)?.op;
+    return CONTROL_TABLE.iter().find(|row| row.op == op);
+}
+
+// *** PRIVATE FN ***
+
+const NOP: ControlBits = control_bits::NOP;
+
+// rd = rs1 op rs2
+const REG: ControlBits = ControlBits { reg_dst: RegDst::Rd, ..NOP };
+
+// rd = rs1 op imm
+const IMM: ControlBits = ControlBits { alu_src: AluSrc::SignImm, ..REG };
+
+// address rs1 + imm
+const LOAD: ControlBits = ControlBits { mem: MemAccess::Load { width: Width::Word, signed: true }, ..IMM };
+const STORE: ControlBits = ControlBits { mem: MemAccess::Store(Width::Word), reg_dst: RegDst::None, ..IMM };
+
+// compares rs1 with rs2
+const BRANCH: ControlBits = ControlBits { alu_op: AluOp::Sub, pc_src: PcSource::BranchEq, ..NOP };
+
+// reads and writes a csr
+const CSR: ControlBits = ControlBits { reg_dst: RegDst::Rd, cop0: true, ..NOP };
+
+const fn load(width: Width, signed: bool) -> ControlBits {
+    ControlBits { mem: MemAccess::Load { width, signed }, ..LOAD }
+}
+
+pub static CONTROL_TABLE: [Control; 66] = [
+    // rs1 is x0 for the U format
+    Control { op: Op::Lui, ctrl: ControlBits { alu_src: AluSrc::UpperImm, ..REG } },
+    Control { op: Op::Auipc, ctrl: ControlBits { alu_src: AluSrc::UpperImm, alu_pc: true, ..REG } },
+    Control { op: Op::Jal, ctrl: ControlBits { pc_src: PcSource::Jump, link: true, ..REG } },
+    // the alu adds the target, the low bit is cleared
+    Control { op: Op::Jalr, ctrl: ControlBits { pc_src: PcSource::JumpReg, link: true, ..IMM } },
+
+    Control { op: Op::Beq, ctrl: BRANCH },
+    Control { op: Op::Bne, ctrl: ControlBits { pc_src: PcSource::BranchNe, ..BRANCH } },
+    Control { op: Op::Blt, ctrl: ControlBits { alu_op: AluOp::Slt, pc_src: PcSource::BranchLt, ..BRANCH } },
+    Control { op: Op::Bge, ctrl: ControlBits { alu_op: AluOp::Slt, pc_src: PcSource::BranchGe, ..BRANCH } },
+    Control { op: Op::Bltu, ctrl: ControlBits { alu_op: AluOp::Sltu, pc_src: PcSource::BranchLt, ..BRANCH } },
+    Control { op: Op::Bgeu, ctrl: ControlBits { alu_op: AluOp::Sltu, pc_src: PcSource::BranchGe, ..BRANCH } },
+
+    Control { op: Op::Lb, ctrl: load(Width::Byte, true) },
+    Control { op: Op::Lh, ctrl: load(Width::Half, true) },
+    Control { op: Op::Lw, ctrl: LOAD },
+    Control { op: Op::Lbu, ctrl: load(Width::Byte, false) },
+    Control { op: Op::Lhu, ctrl: load(Width::Half, false) },
+    Control { op: Op::Sb, ctrl: ControlBits { mem: MemAccess::Store(Width::Byte), ..STORE } },
+    Control { op: Op::Sh, ctrl: ControlBits { mem: MemAccess::Store(Width::Half), ..STORE } },
+    Control { op: Op::Sw, ctrl: STORE },
+
+    // unlike MIPS every immediate is sign extended
+    Control { op: Op::Addi, ctrl: IMM },
+    Control { op: Op::Slti, ctrl: ControlBits { alu_op: AluOp::Slt, ..IMM } },
+    Control { op: Op::Sltiu, ctrl: ControlBits { alu_op: AluOp::Sltu, ..IMM } },
+    Control { op: Op::Xori, ctrl: ControlBits { alu_op: AluOp::Xor, ..IMM } },
+    Control { op: Op::Ori, ctrl: ControlBits { alu_op: AluOp::Or, ..IMM } },
+    Control { op: Op::Andi, ctrl: ControlBits { alu_op: AluOp::And, ..IMM } },
+    // the shift amount is the low 5 bits of the immediate
+    Control { op: Op::Slli, ctrl: ControlBits { alu_op: AluOp::Sll, ..IMM } },
+    Control { op: Op::Srli, ctrl: ControlBits { alu_op: AluOp::Srl, ..IMM } },
+    Control { op: Op::Srai, ctrl: ControlBits { alu_op: AluOp::Sra, ..IMM } },
+
+    Control { op: Op::Add, ctrl: REG },
+    Control { op: Op::Sub, ctrl: ControlBits { alu_op: AluOp::Sub, ..REG } },
+    Control { op: Op::Sll, ctrl: ControlBits { alu_op: AluOp::Sll, ..REG } },
+    Control { op: Op::Slt, ctrl: ControlBits { alu_op: AluOp::Slt, ..REG } },
+    Control { op: Op::Sltu, ctrl: ControlBits { alu_op: AluOp::Sltu, ..REG } },
+    Control { op: Op::Xor, ctrl: ControlBits { alu_op: AluOp::Xor, ..REG } },
+    Control { op: Op::Srl, ctrl: ControlBits { alu_op: AluOp::Srl, ..REG } },
+    Control { op: Op::Sra, ctrl: ControlBits { alu_op: AluOp::Sra, ..REG } },
+    Control { op: Op::Or, ctrl: ControlBits { alu_op: AluOp::Or, ..REG } },
+    Control { op: Op::And, ctrl: ControlBits { alu_op: AluOp::And, ..REG } },
+
+    // memory is never reordered, ecall and ebreak raise their exception
+    // before anything runs
+    Control { op: Op::Fence, ctrl: NOP },
+    Control { op: Op::Ecall, ctrl: NOP },
+    Control { op: Op::Ebreak, ctrl: NOP },
+
+    Control { op: Op::Mul, ctrl: ControlBits { alu_op: AluOp::Mul, ..REG } },
+    Control { op: Op::Mulh, ctrl: ControlBits { alu_op: AluOp::Mulh, ..REG } },
+    Control { op: Op::Mulhsu, ctrl: ControlBits { alu_op: AluOp::Mulhsu, ..REG } },
+    Control { op: Op::Mulhu, ctrl: ControlBits { alu_op: AluOp::Mulhu, ..REG } },
+    Control { op: Op::Div, ctrl: ControlBits { alu_op: AluOp::Div, ..REG } },
+    Control { op: Op::Divu, ctrl: ControlBits { alu_op: AluOp::Divu, ..REG } },
+    Control { op: Op::Rem, ctrl: ControlBits { alu_op: AluOp::Rem, ..REG } },
+    Control { op: Op::Remu, ctrl: ControlBits { alu_op: AluOp::Remu, ..REG } },
+
+    // the address is rs1 (the immediate is 0). sc.w writes rd with its
+    // result, the amos load rd and then store the new value on their own
+    Control { op: Op::LrW, ctrl: LOAD },
+    Control { op: Op::ScW, ctrl: ControlBits { reg_dst: RegDst::Rd, ..STORE } },
+    Control { op: Op::AmoswapW, ctrl: LOAD },
+    Control { op: Op::AmoaddW, ctrl: LOAD },
+    Control { op: Op::AmoxorW, ctrl: LOAD },
+    Control { op: Op::AmoandW, ctrl: LOAD },
+    Control { op: Op::AmoorW, ctrl: LOAD },
+    Control { op: Op::AmominW, ctrl: LOAD },
+    Control { op: Op::AmomaxW, ctrl: LOAD },
+    Control { op: Op::AmominuW, ctrl: LOAD },
+    Control { op: Op::AmomaxuW, ctrl: LOAD },
+
+    Control { op: Op::Csrrw, ctrl: CSR },
+    Control { op: Op::Csrrs, ctrl: CSR },
+    Control { op: Op::Csrrc, ctrl: CSR },
+    Control { op: Op::Csrrwi, ctrl: CSR },
+    Control { op: Op::Csrrsi, ctrl: CSR },
+    Control { op: Op::Csrrci, ctrl: CSR },
+    // jumps to mepc
+    Control { op: Op::Mret, ctrl: ControlBits { pc_src: PcSource::JumpReg, cop0: true, ..NOP } },
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::riscv::instruction::encode;
+
+    #[test]
+    fn test_table() {
+        // every instruction decodes to its own row
+        for (i, row) in CONTROL_TABLE.iter().enumerate() {
+            let def = instruction::def(row.op);
+            let word = encode(row.op, &vec![0; def.operands.len()]);
+            let control = decode(&Instruction::new(word)).unwrap();
+            assert!(std::ptr::eq(control, &CONTROL_TABLE[i]), "{}", def.mnemonic);
+            assert!(!row.ctrl.trap_overflow, "{}", def.mnemonic);
+            // only the system instructions use the csrs
+            let system = row.op == Op::Mret || matches!(def.format, instruction::Format::I(0x73, _));
+            assert_eq!(row.ctrl.cop0, system, "{}", def.mnemonic);
+            // only rd is written
+            assert_ne!(row.ctrl.reg_dst, RegDst::Rt, "{}", def.mnemonic);
+            if row.ctrl.mem != MemAccess::None {
+                assert_eq!((row.ctrl.alu_op, row.ctrl.alu_src), (AluOp::Add, AluSrc::SignImm), "{}", def.mnemonic);
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode() {
+        let control = |word: u32| decode(&Instruction::new(word)).map(|row| row.ctrl);
+        let bgeu = control(encode(Op::Bgeu, &[5, 6, 8])).unwrap();
+        assert!(bgeu.branch());
+        assert_eq!(bgeu.alu_op, AluOp::Sltu);
+        let lhu = control(encode(Op::Lhu, &[5, 2, 10])).unwrap();
+        assert_eq!(lhu.mem, MemAccess::Load { width: Width::Half, signed: false });
+        assert_eq!(lhu.mem_size(), 2);
+        assert!(control(encode(Op::Jalr, &[1, 0, 5])).unwrap().link);
+        let sc = control(encode(Op::ScW, &[10, 12, 11])).unwrap();
+        assert!(sc.mem_write() && sc.reg_write());
+        assert!(control(encode(Op::Csrrw, &[10, 0x305, 11])).unwrap().cop0);
+        // all zeros and all ones are illegal
+        assert!(control(0).is_none());
+        assert!(control(0xffff_ffff).is_none());
+    }
+}
@@ -0,0 +1,360 @@
+/*
+ * csr.rs
+ *
+ * Author: Travis Banken
+ *
+ * Machine mode control and status registers of the RISC-V frontend and the
+ * traps that go through them, after the privileged spec. There is only
+ * machine mode: mstatus.MPP always reads 3 and ecall raises cause 11.
+ *
+ * Registers:
+ *  mstatus   MIE (bit 3) and MPIE (bit 7) are writable
+ *  misa      RV32IMA, read only
+ *  mie/mip   MSI (bit 3), MTI (bit 7) and MEI (bit 11), mip is read only
+ *            and follows the interrupt lines: the mmio timer is MTI, the
+ *            uart MEI
+ *  mtvec     trap vector, mode 0 direct or 1 vectored (interrupts go to
+ *            base + 4 * cause)
+ *  mscratch, mepc, mcause, mtval
+ *  mcycle/minstret (and their upper halves), cycle/instret read only
+ *  mvendorid, marchid, mimpid, mhartid read 0
+ *
+ * Without a trap vector (mtvec 0, its reset value, where the program
+ * starts) a trap ends the run like it did before traps existed.
+ */
+#![allow(dead_code)]
+
+use crate::hardware::cp0::ExcCode;
+use crate::hardware::intc;
+
+pub const MSTATUS: u16 = 0x300;
+pub const MISA: u16 = 0x301;
+pub const MIE: u16 = 0x304;
+pub const MTVEC: u16 = 0x305;
+pub const MSCRATCH: u16 = 0x340;
+pub const MEPC: u16 = 0x341;
+pub const MCAUSE: u16 = 0x342;
+pub const MTVAL: u16 = 0x343;
+pub const MIP: u16 = 0x344;
+pub const MCYCLE: u16 = 0xb00;
+pub const MINSTRET: u16 = 0xb02;
+pub const MCYCLEH: u16 = 0xb80;
+pub const MINSTRETH: u16 = 0xb82;
+pub const CYCLE: u16 = 0xc00;
+pub const INSTRET: u16 = 0xc02;
+pub const CYCLEH: u16 = 0xc80;
+pub const INSTRETH: u16 = 0xc82;
+pub const MVENDORID: u16 = 0xf11;
+pub const MARCHID: u16 = 0xf12;
+pub const MIMPID: u16 = 0xf13;
+pub const MHARTID: u16 = 0xf14;
+
+// mstatus bits
+pub const STATUS_MIE: u32 = 1 << 3;
+pub const STATUS_MPIE: u32 = 1 << 7;
+pub const STATUS_MPP: u32 = 3 << 11;
+
+// mie/mip bits, the cause of the interrupt is the bit number
+pub const IRQ_MSI: u32 = 1 << 3;
+pub const IRQ_MTI: u32 = 1 << 7;
+pub const IRQ_MEI: u32 = 1 << 11;
+
+// exception causes
+pub const MISALIGNED_FETCH: u32 = 0;
+pub const ILLEGAL_INSTRUCTION: u32 = 2;
+pub const BREAKPOINT: u32 = 3;
+pub const MISALIGNED_LOAD: u32 = 4;
+pub const MISALIGNED_STORE: u32 = 6;
+pub const ECALL_M: u32 = 11;
+// set in mcause for interrupts
+pub const INTERRUPT: u32 = 1 << 31;
+
+// RV32 with the I, M and A extensions
+const MISA_VALUE: u32 = 1 << 30 | 1 << 12 | 1 << 8 | 1;
+
+const NAMES: [(u16, &str); 21] = [
+    (MSTATUS, "mstatus"), (MISA, "misa"), (MIE, "mie"), (MTVEC, "mtvec"),
+    (MSCRATCH, "mscratch"), (MEPC, "mepc"), (MCAUSE, "mcause"), (MTVAL, "mtval"), (MIP, "mip"),
+    (MCYCLE, "mcycle"), (MINSTRET, "minstret"), (MCYCLEH, "mcycleh"), (MINSTRETH, "minstreth"),
+    (CYCLE, "cycle"), (INSTRET, "instret"), (CYCLEH, "cycleh"), (INSTRETH, "instreth"),
+    (MVENDORID, "mvendorid"), (MARCHID, "marchid"), (MIMPID, "mimpid"), (MHARTID, "mhartid"),
+];
+
+/*
+ * A trap: mcause and mtval.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Trap {
+    pub cause: u32,
+    pub tval: u32,
+}
+
+impl Trap {
+    pub fn new(cause: u32, tval: u32) -> Trap {
+        Trap { cause, tval }
+    }
+
+    pub fn interrupt(cause: u32) -> Trap {
+        Trap { cause: INTERRUPT | cause, tval: 0 }
+    }
+
+    pub fn is_interrupt(&self) -> bool {
+        self.cause & INTERRUPT != 0
+    }
+
+    /*
+     * The MIPS code of the trap, what the cycle records and the tools show.
+     */
+    pub fn exc_code(&self) -> ExcCode {
+        if self.is_interrupt() {
+            return ExcCode::Int;
+        }
+        match self.cause {
+            MISALIGNED_FETCH | MISALIGNED_LOAD => ExcCode::AdEL,
+            MISALIGNED_STORE => ExcCode::AdES,
+            BREAKPOINT => ExcCode::Bp,
+            ECALL_M => ExcCode::Sys,
+            _ => ExcCode::RI,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Csrs {
+    pub mstatus: u32,
+    pub mie: u32,
+    pub mip: u32,
+    pub mtvec: u32,
+    pub mscratch: u32,
+    pub mepc: u32,
+    pub mcause: u32,
+    pub mtval: u32,
+    pub mcycle: u64,
+    pub minstret: u64,
+    // address reserved by lr.w, sc.w only stores there
+    pub reservation: Option<u32>,
+}
+
+impl Csrs {
+    pub fn new() -> Csrs {
+        Csrs::default()
+    }
+
+    /*
+     * Value of a register, None if it does not exist.
+     */
+    pub fn read(&self, csr: u16) -> Option<u32> {
+        let val = match csr {
+            MSTATUS => self.mstatus | STATUS_MPP,
+            MISA => MISA_VALUE,
+            MIE => self.mie,
+            MIP => self.mip,
+            MTVEC => self.mtvec,
+            MSCRATCH => self.mscratch,
+            MEPC => self.mepc,
+            MCAUSE => self.mcause,
+            MTVAL => self.mtval,
+            MCYCLE | CYCLE => self.mcycle as u32,
+            MCYCLEH | CYCLEH => (self.mcycle >> 32) as u32,
+            MINSTRET | INSTRET => self.minstret as u32,
+            MINSTRETH | INSTRETH => (self.minstret >> 32) as u32,
+            MVENDORID | MARCHID | MIMPID | MHARTID => 0,
+            _ => return None,
+        };
+        return Some(val);
+    }
+
+    /*
+     * Writes a register, false if it does not exist or is read only (the
+     * upper two bits of the number are 3). Bits that are not writable keep
+     * their value.
+     */
+    pub fn write(&mut self, csr: u16, val: u32) -> bool {
+        if csr >> 10 == 3 || self.read(csr).is_none() {
+            return false;
+        }
+        match csr {
+            MSTATUS => self.mstatus = val & (STATUS_MIE | STATUS_MPIE),
+            MIE => self.mie = val & (IRQ_MSI | IRQ_MTI | IRQ_MEI),
+            // only the direct and vectored modes exist
+            MTVEC => self.mtvec = val & !0x2,
+            MSCRATCH => self.mscratch = val,
+            MEPC => self.mepc = val & !0x3,
+            MCAUSE => self.mcause = val,
+            MTVAL => self.mtval = val,
+            MCYCLE => self.mcycle = (self.mcycle & !0xffff_ffff) | val as u64,
+            MCYCLEH => self.mcycle = (self.mcycle & 0xffff_ffff) | (val as u64) << 32,
+            MINSTRET => self.minstret = (self.minstret & !0xffff_ffff) | val as u64,
+            MINSTRETH => self.minstret = (self.minstret & 0xffff_ffff) | (val as u64) << 32,
+            // misa and mip ignore writes
+            _ => (),
+        }
+        return true;
+    }
+
+    /*
+     * Sets mip from the lines of the interrupt controller.
+     */
+    pub fn set_lines(&mut self, lines: u8) {
+        let raised = |line: usize| lines & (1 << line) != 0;
+        self.mip = if raised(intc::LINE_UART) {IRQ_MEI} else {0} | if raised(intc::LINE_TIMER) {IRQ_MTI} else {0};
+    }
+
+    /*
+     * Cause of the interrupt to take before the next instruction, external
+     * before software before timer.
+     */
+    pub fn pending_interrupt(&self) -> Option<u32> {
+        if self.mstatus & STATUS_MIE == 0 {
+            return None;
+        }
+        let pending = self.mip & self.mie;
+        return [IRQ_MEI, IRQ_MSI, IRQ_MTI].iter()
+            .find(|bit| pending & **bit != 0)
+            .map(|bit| bit.trailing_zeros());
+    }
+
+    /*
+     * Takes a trap of the instruction at pc, returns the address of the
+     * handler.
+     */
+    pub fn trap(&mut self, trap: &Trap, pc: u32) -> u32 {
+        self.mepc = pc;
+        self.mcause = trap.cause;
+        self.mtval = trap.tval;
+        let mie = self.mstatus & STATUS_MIE != 0;
+        self.mstatus = if mie {STATUS_MPIE} else {0};
+        self.reservation = None;
+        let base = self.mtvec & !0x3;
+        if trap.is_interrupt() && self.mtvec & 0x1 == 1 {
+            return base + 4 * (trap.cause & !INTERRUPT);
+        }
+        return base;
+    }
+
+    /*
+     * Returns from a trap with mret, gives the address to return to.
+     */
+    pub fn mret(&mut self) -> u32 {
+        let mpie = self.mstatus & STATUS_MPIE != 0;
+        self.mstatus = STATUS_MPIE | if mpie {STATUS_MIE} else {0};
+        return self.mepc;
+    }
+
+    /*
+     * Every register in a fixed order, and back, for snapshots.
+     */
+    pub fn dump(&self) -> [u32; 13] {
+        let reservation = self.reservation.map(|addr| addr | 1).unwrap_or(0);
+        [self.mstatus, self.mie, self.mip, self.mtvec, self.mscratch, self.mepc, self.mcause, self.mtval,
+         self.mcycle as u32, (self.mcycle >> 32) as u32, self.minstret as u32, (self.minstret >> 32) as u32,
+         reservation]
+    }
+
+    pub fn restore(&mut self, regs: &[u32; 13]) {
+        self.mstatus = regs[0];
+        self.mie = regs[1];
+        self.mip = regs[2];
+        self.mtvec = regs[3];
+        self.mscratch = regs[4];
+        self.mepc = regs[5];
+        self.mcause = regs[6];
+        self.mtval = regs[7];
+        self.mcycle = regs[8] as u64 | (regs[9] as u64) << 32;
+        self.minstret = regs[10] as u64 | (regs[11] as u64) << 32;
+        // reserved addresses are word aligned, bit 0 marks one
+        self.reservation = if regs[12] & 1 == 1 {Some(regs[12] & !1)} else {None};
+    }
+}
+
+/*
+ * Name of a register number, e.g. 0x300 -> "mstatus".
+ */
+pub fn name(csr: u16) -> Option<&'static str> {
+    return NAMES.iter().find(|(num, _)| *num == csr).map(|(_, name)| *name);
+}
+
+/*
+ * Number of a register name.
+ */
+pub fn number(name: &str) -> Option<u16> {
+    return NAMES.iter().find(|(_, n)| *n == name).map(|(num, _)| *num);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_write() {
+        let mut csrs = Csrs::new();
+        assert_eq!(csrs.read(MSTATUS), Some(STATUS_MPP));
+        assert!(csrs.write(MSTATUS, 0xffff_ffff));
+        assert_eq!(csrs.read(MSTATUS), Some(STATUS_MPP | STATUS_MIE | STATUS_MPIE));
+        assert_eq!(csrs.read(MISA), Some(0x4000_1101));
+        assert!(csrs.write(MTVEC, 0x43));
+        assert_eq!(csrs.mtvec, 0x41);
+        assert!(csrs.write(MEPC, 0x13));
+        assert_eq!(csrs.mepc, 0x10);
+
+        assert!(csrs.write(MCYCLEH, 1));
+        assert!(csrs.write(MCYCLE, 2));
+        assert_eq!((csrs.read(CYCLE), csrs.read(CYCLEH)), (Some(2), Some(1)));
+        // read only and missing registers
+        assert!(!csrs.write(CYCLE, 0));
+        assert!(!csrs.write(MHARTID, 0));
+        assert_eq!(csrs.read(0x180), None);
+        assert!(!csrs.write(0x180, 0));
+        assert!(csrs.write(MIP, 0xffff_ffff));
+        assert_eq!(csrs.mip, 0);
+    }
+
+    #[test]
+    fn test_trap() {
+        let mut csrs = Csrs::new();
+        csrs.mtvec = 0x40;
+        csrs.mstatus = STATUS_MIE;
+        csrs.reservation = Some(0x10);
+        let handler = csrs.trap(&Trap::new(MISALIGNED_LOAD, 0x23), 0x8);
+        assert_eq!(handler, 0x40);
+        assert_eq!((csrs.mepc, csrs.mcause, csrs.mtval), (0x8, 4, 0x23));
+        assert_eq!(csrs.mstatus, STATUS_MPIE);
+        assert_eq!(csrs.reservation, None);
+        assert_eq!(csrs.mret(), 0x8);
+        assert_eq!(csrs.mstatus, STATUS_MIE | STATUS_MPIE);
+
+        // vectored interrupts
+        csrs.mtvec = 0x41;
+        assert_eq!(csrs.trap(&Trap::interrupt(7), 0x10), 0x40 + 28);
+        assert_eq!(csrs.mcause, 0x8000_0007);
+        assert_eq!(Trap::interrupt(7).exc_code(), ExcCode::Int);
+        assert_eq!(Trap::new(MISALIGNED_STORE, 0).exc_code(), ExcCode::AdES);
+    }
+
+    #[test]
+    fn test_interrupts() {
+        let mut csrs = Csrs::new();
+        csrs.set_lines(1 << intc::LINE_UART | 1 << intc::LINE_TIMER);
+        assert_eq!(csrs.mip, IRQ_MEI | IRQ_MTI);
+        assert_eq!(csrs.pending_interrupt(), None);
+        csrs.mstatus = STATUS_MIE;
+        csrs.mie = IRQ_MTI;
+        assert_eq!(csrs.pending_interrupt(), Some(7));
+        csrs.mie |= IRQ_MEI;
+        assert_eq!(csrs.pending_interrupt(), Some(11));
+    }
+
+    #[test]
+    fn test_dump() {
+        let mut csrs = Csrs::new();
+        csrs.mtvec = 0x40;
+        csrs.mcycle = 0x1_0000_0002;
+        csrs.minstret = 0x3_0000_0004;
+        csrs.reservation = Some(0x20);
+        let mut restored = Csrs::new();
+        restored.restore(&csrs.dump());
+        assert_eq!(restored, csrs);
+        assert_eq!(name(0x341), Some("mepc"));
+        assert_eq!(number("mhartid"), Some(0xf14));
+    }
+}
@@ -0,0 +1,134 @@
+/*
+ * disasm.rs
+ *
+ * Author: Travis Banken
+ *
+ * Turns RISC-V instruction words back into assembly text, in the syntax of
+ * the GNU assembler with ABI register names. Branch and jump targets are
+ * printed as the absolute address they go to.
+ */
+#![allow(dead_code)]
+
+use crate::riscv::csr;
+use crate::riscv::instruction::{self, operand_value, Instruction, Operand};
+
+// standard calling convention register names
+pub const REG_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2",
+    "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
+    "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+/*
+ * ABI name of a register number, e.g. 10 -> "a0".
+ */
+pub fn reg_name(reg_num: u8) -> &'static str {
+    REG_NAMES[(reg_num & 0x1f) as usize]
+}
+
+/*
+ * Register number of a name with or without a '$', by ABI name ("a0"),
+ * architectural name ("x10") or number ("10").
+ */
+pub fn reg_num(name: &str) -> Option<u8> {
+    let name = name.strip_prefix('$').unwrap_or(name);
+    if let Some(num) = REG_NAMES.iter().position(|n| *n == name) {
+        return Some(num as u8);
+    }
+    if name == "fp" {
+        return Some(8);
+    }
+    match name.strip_prefix('x').unwrap_or(name).parse::<u8>() {
+        Ok(num) if num < 32 => Some(num),
+        _ => None,
+    }
+}
+
+/*
+ * Disassembles the instruction word found at address pc.
+ */
+pub fn disassemble(word: u32, pc: u32) -> String {
+    // addi zero, zero, 0
+    if word == 0x0000_0013 {
+        return "nop".to_string();
+    }
+    let instr = Instruction::new(word);
+    let def = match instruction::decode(&instr) {
+        Some(def) => def,
+        None => return format!(".word 0x{:08x}", word),
+    };
+
+    let mut operands = Vec::new();
+    for operand in def.operands.iter() {
+        let val = operand_value(*operand, def, &instr);
+        let text = match operand {
+            Operand::Rd | Operand::Rs1 | Operand::Rs2 => reg_name(val as u8).to_string(),
+            Operand::Shamt => val.to_string(),
+            Operand::Imm => sign_extend(val, 12).to_string(),
+            Operand::Offset => format!("{}({})", sign_extend(val, 12), reg_name(instr.rs1)),
+            // printed with the offset
+            Operand::Base => continue,
+            Operand::Branch => format!("0x{:08x}", pc.wrapping_add(sign_extend(val, 13) as u32)),
+            Operand::Jump => format!("0x{:08x}", pc.wrapping_add(sign_extend(val, 21) as u32)),
+            Operand::Upper => format!("0x{:x}", val),
+            Operand::Addr => format!("({})", reg_name(val as u8)),
+            Operand::Csr => match csr::name(val as u16) {
+                Some(name) => name.to_string(),
+                None => format!("0x{:x}", val),
+            },
+            Operand::Uimm => val.to_string(),
+        };
+        operands.push(text);
+    }
+    if operands.is_empty() {
+        return def.mnemonic.to_string();
+    }
+    return format!("{} {}", def.mnemonic, operands.join(", "));
+}
+
+// *** PRIVATE FN ***
+
+fn sign_extend(val: u32, bits: u32) -> i32 {
+    return ((val << (32 - bits)) as i32) >> (32 - bits);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::riscv::instruction::{encode, Op};
+
+    #[test]
+    fn test_disassemble() {
+        assert_eq!(disassemble(encode(Op::Addi, &[10, 10, 0xfff]), 0), "addi a0, a0, -1");
+        assert_eq!(disassemble(encode(Op::Add, &[5, 6, 7]), 0), "add t0, t1, t2");
+        assert_eq!(disassemble(encode(Op::Sw, &[1, 0xffc, 2]), 0), "sw ra, -4(sp)");
+        assert_eq!(disassemble(encode(Op::Lbu, &[5, 3, 10]), 0), "lbu t0, 3(a0)");
+        assert_eq!(disassemble(encode(Op::Bne, &[5, 0, 0x1ff8]), 0x20), "bne t0, zero, 0x00000018");
+        assert_eq!(disassemble(encode(Op::Jal, &[1, 0x40]), 0x10), "jal ra, 0x00000050");
+        assert_eq!(disassemble(encode(Op::Jalr, &[0, 0, 1]), 0), "jalr zero, 0(ra)");
+        assert_eq!(disassemble(encode(Op::Lui, &[10, 0xcafe]), 0), "lui a0, 0xcafe");
+        assert_eq!(disassemble(encode(Op::Srai, &[5, 5, 3]), 0), "srai t0, t0, 3");
+        assert_eq!(disassemble(encode(Op::Ecall, &[]), 0), "ecall");
+        assert_eq!(disassemble(encode(Op::Mulhu, &[10, 11, 12]), 0), "mulhu a0, a1, a2");
+        assert_eq!(disassemble(encode(Op::LrW, &[10, 11]), 0), "lr.w a0, (a1)");
+        assert_eq!(disassemble(encode(Op::AmoswapW, &[10, 12, 11]), 0), "amoswap.w a0, a2, (a1)");
+        assert_eq!(disassemble(encode(Op::Csrrw, &[0, 0x305, 5]), 0), "csrrw zero, mtvec, t0");
+        assert_eq!(disassemble(encode(Op::Csrrsi, &[10, 0x7c0, 8]), 0), "csrrsi a0, 0x7c0, 8");
+        assert_eq!(disassemble(encode(Op::Mret, &[]), 0), "mret");
+        assert_eq!(disassemble(0x0000_0013, 0), "nop");
+        assert_eq!(disassemble(0, 0), ".word 0x00000000");
+        assert_eq!(disassemble(0xffff_ffff, 0), ".word 0xffffffff");
+    }
+
+    #[test]
+    fn test_reg_num() {
+        assert_eq!(reg_num("a0"), Some(10));
+        assert_eq!(reg_num("$s11"), Some(27));
+        assert_eq!(reg_num("fp"), Some(8));
+        assert_eq!(reg_num("x31"), Some(31));
+        assert_eq!(reg_num("5"), Some(5));
+        assert_eq!(reg_num("x32"), None);
+        assert_eq!(reg_num("v0"), None);
+    }
+}
@@ -0,0 +1,484 @@
+/*
+ * instruction.rs
+ *
+ * Author: Travis Banken
+ *
+ * Fields of a RISC-V instruction word, and the definition of every RV32I
+ * instruction and of the M, A and Zicsr extensions (plus mret): its
+ * mnemonic, format and operands. The decoder, the encoders, the
+ * disassembler and the control table are built on DEFS, like the MIPS ones
+ * are on instruction::DEFS.
+ *
+ * Immediates are scattered over the word differently in every format:
+ *  R  funct7 rs2 rs1 funct3 rd opcode
+ *  I  imm[11:0] rs1 funct3 rd opcode
+ *  S  imm[11:5] rs2 rs1 funct3 imm[4:0] opcode
+ *  B  imm[12|10:5] rs2 rs1 funct3 imm[4:1|11] opcode
+ *  U  imm[31:12] rd opcode
+ *  J  imm[20|10:1|11|19:12] rd opcode
+ * The atomics are R format with funct5 and the aq/rl bits (ignored, there is
+ * one hart) in funct7, the csr instructions I format with the csr number in
+ * the immediate.
+ */
+#![allow(dead_code)]
+
+use crate::isa::Flow;
+use Operand::*;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Instruction {
+    pub word: u32,
+    pub opcode: u8,
+    pub rd: u8,
+    pub funct3: u8,
+    pub rs1: u8,
+    pub rs2: u8,
+    pub funct7: u8,
+}
+
+impl Instruction {
+    /*
+     * Splits a word into its fields.
+     */
+    pub fn new(word: u32) -> Instruction {
+        Instruction {
+            word,
+            opcode: (word & 0x7f) as u8,
+            rd: ((word >> 7) & 0x1f) as u8,
+            funct3: ((word >> 12) & 0x7) as u8,
+            rs1: ((word >> 15) & 0x1f) as u8,
+            rs2: ((word >> 20) & 0x1f) as u8,
+            funct7: (word >> 25) as u8,
+        }
+    }
+
+    // sign extended immediates of the formats
+
+    pub fn imm_i(&self) -> u32 {
+        ((self.word as i32) >> 20) as u32
+    }
+
+    pub fn imm_s(&self) -> u32 {
+        (((self.word as i32) >> 20) as u32 & !0x1f) | ((self.word >> 7) & 0x1f)
+    }
+
+    pub fn imm_b(&self) -> u32 {
+        let sign = (((self.word as i32) >> 31) as u32) << 12;
+        sign | ((self.word << 4) & 0x800) | ((self.word >> 20) & 0x7e0) | ((self.word >> 7) & 0x1e)
+    }
+
+    pub fn imm_u(&self) -> u32 {
+        self.word & 0xffff_f000
+    }
+
+    pub fn imm_j(&self) -> u32 {
+        let sign = (((self.word as i32) >> 31) as u32) << 20;
+        sign | (self.word & 0x000f_f000) | ((self.word >> 9) & 0x800) | ((self.word >> 20) & 0x7fe)
+    }
+}
+
+/*
+ * Every instruction, in the order of DEFS.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Op {
+    Lui, Auipc, Jal, Jalr,
+    Beq, Bne, Blt, Bge, Bltu, Bgeu,
+    Lb, Lh, Lw, Lbu, Lhu, Sb, Sh, Sw,
+    Addi, Slti, Sltiu, Xori, Ori, Andi, Slli, Srli, Srai,
+    Add, Sub, Sll, Slt, Sltu, Xor, Srl, Sra, Or, And,
+    Fence, Ecall, Ebreak,
+    Mul, Mulh, Mulhsu, Mulhu, Div, Divu, Rem, Remu,
+    LrW, ScW, AmoswapW, AmoaddW, AmoxorW, AmoandW, AmoorW, AmominW, AmomaxW, AmominuW, AmomaxuW,
+    Csrrw, Csrrs, Csrrc, Csrrwi, Csrrsi, Csrrci, Mret,
+}
+
+/*
+ * Format of an instruction with the fields that select it.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    // opcode 0x33, by funct3 and funct7
+    R(u8, u8),
+    // by opcode and funct3
+    I(u8, u8),
+    // shifts by an immediate (opcode 0x13), by funct3 and the upper 7 bits
+    // of the immediate
+    Shift(u8, u8),
+    // stores (opcode 0x23) and branches (opcode 0x63) by funct3
+    S(u8),
+    B(u8),
+    // by opcode
+    U(u8),
+    J(u8),
+    // opcode 0x73 with funct3, rs1 and rd 0, by the immediate
+    System(u16),
+    // atomics (opcode 0x2f, funct3 2) by funct5
+    Amo(u8),
+}
+
+/*
+ * Operand of an instruction in assembly order, with the field it is
+ * encoded in.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+    Rd,
+    Rs1,
+    Rs2,
+    // 12 bit immediate of the I format
+    Imm,
+    // shift amount in rs2
+    Shamt,
+    // 12 bit immediate (I or S format) and rs1, written "offset(base)"
+    Offset,
+    Base,
+    // pc relative targets of the B and J formats
+    Branch,
+    Jump,
+    // upper 20 bits of the U format
+    Upper,
+    // address in rs1 of the atomics, written "(base)"
+    Addr,
+    // csr number in the 12 bit immediate
+    Csr,
+    // 5 bit unsigned immediate of the csr instructions in rs1
+    Uimm,
+}
+
+pub struct Def {
+    pub op: Op,
+    pub mnemonic: &'static str,
+    pub format: Format,
+    pub operands: &'static [Operand],
+}
+
+/*
+ * Definition of an instruction.
+ */
+pub fn def(op: Op) -> &'static Def {
+    return &DEFS[op as usize];
+}
+
+/*
+ * Definition of the instruction with the given mnemonic.
+ */
+pub fn lookup(mnemonic: &str) -> Option<&'static Def> {
+    return DEFS.iter().find(|def| def.mnemonic == mnemonic);
+}
+
+/*
+ * Definition of a decoded instruction, None if no instruction has its
+ * opcode and function fields.
+ */
+pub fn decode(instr: &Instruction) -> Option<&'static Def> {
+    let format = match instr.opcode {
+        0x33 => Format::R(instr.funct3, instr.funct7),
+        0x13 if instr.funct3 == 1 || instr.funct3 == 5 => Format::Shift(instr.funct3, instr.funct7),
+        0x23 => Format::S(instr.funct3),
+        0x63 => Format::B(instr.funct3),
+        0x37 | 0x17 => Format::U(instr.opcode),
+        0x6f => Format::J(instr.opcode),
+        0x73 if instr.funct3 == 0 && instr.rs1 == 0 && instr.rd == 0 => Format::System((instr.word >> 20) as u16),
+        0x2f if instr.funct3 == 2 => Format::Amo(instr.funct7 >> 2),
+        opcode => Format::I(opcode, instr.funct3),
+    };
+    return DEFS.iter().find(|def| def.format == format);
+}
+
+/*
+ * Word of an instruction, with the values of its operands in assembly
+ * order. Values are truncated to their field, Branch and Jump are byte
+ * offsets from the instruction and Upper the value of the upper 20 bits.
+ */
+pub fn encode(op: Op, values: &[u32]) -> u32 {
+    let def = def(op);
+    assert_eq!(values.len(), def.operands.len(), "Error: [{}] takes {} operands", def.mnemonic, def.operands.len());
+    let mut word = match def.format {
+        Format::R(funct3, funct7) => 0x33 | (funct3 as u32) << 12 | (funct7 as u32) << 25,
+        Format::I(opcode, funct3) => opcode as u32 | (funct3 as u32) << 12,
+        Format::Shift(funct3, funct7) => 0x13 | (funct3 as u32) << 12 | (funct7 as u32) << 25,
+        Format::S(funct3) => 0x23 | (funct3 as u32) << 12,
+        Format::B(funct3) => 0x63 | (funct3 as u32) << 12,
+        Format::U(opcode) | Format::J(opcode) => opcode as u32,
+        Format::System(imm) => 0x73 | (imm as u32) << 20,
+        Format::Amo(funct5) => 0x2f | 2 << 12 | (funct5 as u32) << 27,
+    };
+    for (operand, val) in def.operands.iter().zip(values.iter()) {
+        word |= match operand {
+            Rd => (val & 0x1f) << 7,
+            Rs1 | Base | Addr | Uimm => (val & 0x1f) << 15,
+            Rs2 | Shamt => (val & 0x1f) << 20,
+            Imm | Csr => (val & 0xfff) << 20,
+            Offset if matches!(def.format, Format::S(_)) => (val & 0xfe0) << 20 | (val & 0x1f) << 7,
+            Offset => (val & 0xfff) << 20,
+            Branch => {
+                (val & 0x1000) << 19 | (val & 0x7e0) << 20 | (val & 0x1e) << 7 | (val & 0x800) >> 4
+            },
+            Jump => (val & 0x10_0000) << 11 | (val & 0x7fe) << 20 | (val & 0x800) << 9 | (val & 0xf_f000),
+            Upper => (val & 0xf_ffff) << 12,
+        };
+    }
+    return word;
+}
+
+/*
+ * Value of an operand in a decoded instruction, immediates as the bits of
+ * their field (not sign extended).
+ */
+pub fn operand_value(operand: Operand, def: &Def, instr: &Instruction) -> u32 {
+    return match operand {
+        Rd => instr.rd as u32,
+        Rs1 | Base | Addr | Uimm => instr.rs1 as u32,
+        Rs2 | Shamt => instr.rs2 as u32,
+        Imm | Csr => instr.imm_i() & 0xfff,
+        Offset => immediate(def, instr) & 0xfff,
+        Branch => instr.imm_b() & 0x1fff,
+        Jump => instr.imm_j() & 0x1f_ffff,
+        Upper => instr.imm_u() >> 12,
+    };
+}
+
+/*
+ * Sign extended immediate of the format of the instruction, in place for
+ * the alu (the U format has it in the upper 20 bits). 0 for the R format
+ * and the atomics.
+ */
+pub fn immediate(def: &Def, instr: &Instruction) -> u32 {
+    return match def.format {
+        Format::R(..) | Format::Amo(_) => 0,
+        Format::I(..) | Format::Shift(..) | Format::System(_) => instr.imm_i(),
+        Format::S(_) => instr.imm_s(),
+        Format::B(_) => instr.imm_b(),
+        Format::U(_) => instr.imm_u(),
+        Format::J(_) => instr.imm_j(),
+    };
+}
+
+/*
+ * How the instruction word at pc moves the pc. jal and jalr linking ra are
+ * calls, jalr x0 jumping to ra is a return. mret leaves the block for mepc.
+ */
+pub fn flow(word: u32, pc: u32) -> Flow {
+    let instr = Instruction::new(word);
+    let def = match decode(&instr) {
+        Some(def) => def,
+        None => return Flow::default(),
+    };
+    return match def.op {
+        Op::Beq | Op::Bne | Op::Blt | Op::Bge | Op::Bltu | Op::Bgeu => Flow {
+            branch: true,
+            target: Some(pc.wrapping_add(instr.imm_b())),
+            ends_block: true,
+            ..Flow::default()
+        },
+        Op::Jal => Flow {
+            target: Some(pc.wrapping_add(instr.imm_j())),
+            ends_block: true,
+            call: instr.rd == 1,
+            ..Flow::default()
+        },
+        Op::Jalr => Flow {
+            ends_block: true,
+            call: instr.rd == 1,
+            ret: instr.rd == 0 && instr.rs1 == 1,
+            ..Flow::default()
+        },
+        Op::Mret => Flow {
+            ends_block: true,
+            ..Flow::default()
+        },
+        _ => Flow::default(),
+    };
+}
+
+// *** PRIVATE FN ***
+
+const ALU: &[Operand] = &[Rd, Rs1, Rs2];
+const ALU_IMM: &[Operand] = &[Rd, Rs1, Imm];
+const SHIFT: &[Operand] = &[Rd, Rs1, Shamt];
+const LOAD: &[Operand] = &[Rd, Offset, Base];
+const STORE: &[Operand] = &[Rs2, Offset, Base];
+const BRANCH: &[Operand] = &[Rs1, Rs2, Branch];
+const UPPER: &[Operand] = &[Rd, Upper];
+const NONE: &[Operand] = &[];
+const AMO: &[Operand] = &[Rd, Rs2, Addr];
+const CSR: &[Operand] = &[Rd, Csr, Rs1];
+const CSR_IMM: &[Operand] = &[Rd, Csr, Uimm];
+
+const fn r(op: Op, mnemonic: &'static str, funct3: u8, funct7: u8) -> Def {
+    Def { op, mnemonic, format: Format::R(funct3, funct7), operands: ALU }
+}
+
+const fn i(op: Op, mnemonic: &'static str, opcode: u8, funct3: u8, operands: &'static [Operand]) -> Def {
+    Def { op, mnemonic, format: Format::I(opcode, funct3), operands }
+}
+
+const fn amo(op: Op, mnemonic: &'static str, funct5: u8) -> Def {
+    Def { op, mnemonic, format: Format::Amo(funct5), operands: AMO }
+}
+
+pub static DEFS: [Def; 66] = [
+    Def { op: Op::Lui, mnemonic: "lui", format: Format::U(0x37), operands: UPPER },
+    Def { op: Op::Auipc, mnemonic: "auipc", format: Format::U(0x17), operands: UPPER },
+    Def { op: Op::Jal, mnemonic: "jal", format: Format::J(0x6f), operands: &[Rd, Jump] },
+    i(Op::Jalr, "jalr", 0x67, 0, LOAD),
+
+    Def { op: Op::Beq, mnemonic: "beq", format: Format::B(0), operands: BRANCH },
+    Def { op: Op::Bne, mnemonic: "bne", format: Format::B(1), operands: BRANCH },
+    Def { op: Op::Blt, mnemonic: "blt", format: Format::B(4), operands: BRANCH },
+    Def { op: Op::Bge, mnemonic: "bge", format: Format::B(5), operands: BRANCH },
+    Def { op: Op::Bltu, mnemonic: "bltu", format: Format::B(6), operands: BRANCH },
+    Def { op: Op::Bgeu, mnemonic: "bgeu", format: Format::B(7), operands: BRANCH },
+
+    i(Op::Lb, "lb", 0x03, 0, LOAD),
+    i(Op::Lh, "lh", 0x03, 1, LOAD),
+    i(Op::Lw, "lw", 0x03, 2, LOAD),
+    i(Op::Lbu, "lbu", 0x03, 4, LOAD),
+    i(Op::Lhu, "lhu", 0x03, 5, LOAD),
+    Def { op: Op::Sb, mnemonic: "sb", format: Format::S(0), operands: STORE },
+    Def { op: Op::Sh, mnemonic: "sh", format: Format::S(1), operands: STORE },
+    Def { op: Op::Sw, mnemonic: "sw", format: Format::S(2), operands: STORE },
+
+    i(Op::Addi, "addi", 0x13, 0, ALU_IMM),
+    i(Op::Slti, "slti", 0x13, 2, ALU_IMM),
+    i(Op::Sltiu, "sltiu", 0x13, 3, ALU_IMM),
+    i(Op::Xori, "xori", 0x13, 4, ALU_IMM),
+    i(Op::Ori, "ori", 0x13, 6, ALU_IMM),
+    i(Op::Andi, "andi", 0x13, 7, ALU_IMM),
+    Def { op: Op::Slli, mnemonic: "slli", format: Format::Shift(1, 0x00), operands: SHIFT },
+    Def { op: Op::Srli, mnemonic: "srli", format: Format::Shift(5, 0x00), operands: SHIFT },
+    Def { op: Op::Srai, mnemonic: "srai", format: Format::Shift(5, 0x20), operands: SHIFT },
+
+    r(Op::Add, "add", 0, 0x00),
+    r(Op::Sub, "sub", 0, 0x20),
+    r(Op::Sll, "sll", 1, 0x00),
+    r(Op::Slt, "slt", 2, 0x00),
+    r(Op::Sltu, "sltu", 3, 0x00),
+    r(Op::Xor, "xor", 4, 0x00),
+    r(Op::Srl, "srl", 5, 0x00),
+    r(Op::Sra, "sra", 5, 0x20),
+    r(Op::Or, "or", 6, 0x00),
+    r(Op::And, "and", 7, 0x00),
+
+    // the predecessor and successor sets are ignored, memory is never
+    // reordered
+    i(Op::Fence, "fence", 0x0f, 0, NONE),
+    Def { op: Op::Ecall, mnemonic: "ecall", format: Format::System(0), operands: NONE },
+    Def { op: Op::Ebreak, mnemonic: "ebreak", format: Format::System(1), operands: NONE },
+
+    // M
+    r(Op::Mul, "mul", 0, 0x01),
+    r(Op::Mulh, "mulh", 1, 0x01),
+    r(Op::Mulhsu, "mulhsu", 2, 0x01),
+    r(Op::Mulhu, "mulhu", 3, 0x01),
+    r(Op::Div, "div", 4, 0x01),
+    r(Op::Divu, "divu", 5, 0x01),
+    r(Op::Rem, "rem", 6, 0x01),
+    r(Op::Remu, "remu", 7, 0x01),
+
+    // A, words only
+    Def { op: Op::LrW, mnemonic: "lr.w", format: Format::Amo(0x02), operands: &[Rd, Addr] },
+    amo(Op::ScW, "sc.w", 0x03),
+    amo(Op::AmoswapW, "amoswap.w", 0x01),
+    amo(Op::AmoaddW, "amoadd.w", 0x00),
+    amo(Op::AmoxorW, "amoxor.w", 0x04),
+    amo(Op::AmoandW, "amoand.w", 0x0c),
+    amo(Op::AmoorW, "amoor.w", 0x08),
+    amo(Op::AmominW, "amomin.w", 0x10),
+    amo(Op::AmomaxW, "amomax.w", 0x14),
+    amo(Op::AmominuW, "amominu.w", 0x18),
+    amo(Op::AmomaxuW, "amomaxu.w", 0x1c),
+
+    // Zicsr
+    i(Op::Csrrw, "csrrw", 0x73, 1, CSR),
+    i(Op::Csrrs, "csrrs", 0x73, 2, CSR),
+    i(Op::Csrrc, "csrrc", 0x73, 3, CSR),
+    i(Op::Csrrwi, "csrrwi", 0x73, 5, CSR_IMM),
+    i(Op::Csrrsi, "csrrsi", 0x73, 6, CSR_IMM),
+    i(Op::Csrrci, "csrrci", 0x73, 7, CSR_IMM),
+    Def { op: Op::Mret, mnemonic: "mret", format: Format::System(0x302), operands: NONE },
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_defs() {
+        for (i, def) in DEFS.iter().enumerate() {
+            assert_eq!(def.op as usize, i, "{}", def.mnemonic);
+            assert_eq!(lookup(def.mnemonic).map(|d| d.op), Some(def.op));
+            assert_eq!(DEFS.iter().filter(|d| d.format == def.format).count(), 1, "{}", def.mnemonic);
+            assert_eq!(def.operands.contains(&Offset), def.operands.contains(&Base), "{}", def.mnemonic);
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        // every instruction encodes then decodes to itself, with each
+        // operand in its own field
+        for def in DEFS.iter() {
+            let values: Vec<u32> = def.operands.iter().enumerate()
+                .map(|(i, operand)| match operand {
+                    Imm | Offset => 0x800 | i as u32,
+                    Branch => 0x1000 | (i as u32) << 1,
+                    Jump => 0x10_0800 | (i as u32) << 1,
+                    Upper => 0x8_0000 | i as u32,
+                    _ => 1 + i as u32 * 7,
+                })
+                .collect();
+            let word = encode(def.op, &values);
+            let instr = Instruction::new(word);
+            assert_eq!(decode(&instr).map(|d| d.op), Some(def.op), "{}", def.mnemonic);
+            let decoded: Vec<u32> = def.operands.iter().map(|operand| operand_value(*operand, def, &instr)).collect();
+            assert_eq!(decoded, values, "{}", def.mnemonic);
+        }
+    }
+
+    #[test]
+    fn test_encode() {
+        // words from the GNU assembler
+        assert_eq!(encode(Op::Addi, &[10, 0, 5]), 0x00500513);
+        assert_eq!(encode(Op::Add, &[5, 6, 7]), 0x007302b3);
+        assert_eq!(encode(Op::Sub, &[5, 6, 7]), 0x407302b3);
+        assert_eq!(encode(Op::Sw, &[5, 0xffc, 2]), 0xfe512e23);
+        assert_eq!(encode(Op::Lw, &[5, 8, 2]), 0x00812283);
+        assert_eq!(encode(Op::Beq, &[5, 0, 0x1ff8]), 0xfe028ce3);
+        assert_eq!(encode(Op::Jal, &[1, 0x10]), 0x010000ef);
+        assert_eq!(encode(Op::Lui, &[10, 0x12345]), 0x12345537);
+        assert_eq!(encode(Op::Srai, &[5, 5, 3]), 0x4032d293);
+        assert_eq!(encode(Op::Ecall, &[]), 0x00000073);
+        assert_eq!(encode(Op::Ebreak, &[]), 0x00100073);
+        assert_eq!(encode(Op::Mul, &[10, 11, 12]), 0x02c58533);
+        assert_eq!(encode(Op::Remu, &[10, 11, 12]), 0x02c5f533);
+        assert_eq!(encode(Op::LrW, &[10, 11]), 0x1005a52f);
+        assert_eq!(encode(Op::ScW, &[10, 12, 11]), 0x18c5a52f);
+        assert_eq!(encode(Op::AmoaddW, &[10, 12, 11]), 0x00c5a52f);
+        assert_eq!(encode(Op::AmomaxuW, &[10, 12, 11]), 0xe0c5a52f);
+        assert_eq!(encode(Op::Csrrw, &[10, 0x305, 11]), 0x30559573);
+        assert_eq!(encode(Op::Csrrsi, &[0, 0x300, 8]), 0x30046073);
+        assert_eq!(encode(Op::Mret, &[]), 0x30200073);
+    }
+
+    #[test]
+    fn test_immediates() {
+        assert_eq!(Instruction::new(0xfe512e23).imm_s(), (-4i32) as u32);
+        assert_eq!(Instruction::new(0xfe028ce3).imm_b(), (-8i32) as u32);
+        assert_eq!(Instruction::new(0x010000ef).imm_j(), 0x10);
+        assert_eq!(Instruction::new(0xfff00513).imm_i(), 0xffff_ffff);
+        // a jal back by 2048 bytes
+        assert_eq!(Instruction::new(encode(Op::Jal, &[0, 0x1f_f800])).imm_j(), (-2048i32) as u32);
+    }
+
+    #[test]
+    fn test_flow() {
+        let call = flow(encode(Op::Jal, &[1, 0x10]), 0x20);
+        assert_eq!((call.call, call.target, call.ends_block), (true, Some(0x30), true));
+        assert!(flow(encode(Op::Jalr, &[0, 0, 1]), 0).ret);
+        let branch = flow(encode(Op::Bne, &[5, 0, 0x1ffc]), 0x10);
+        assert_eq!((branch.branch, branch.target), (true, Some(0xc)));
+        assert_eq!(flow(encode(Op::Add, &[1, 2, 3]), 0), Flow::default());
+        assert!(flow(encode(Op::Mret, &[]), 0).ends_block);
+    }
+}
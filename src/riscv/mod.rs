@@ -0,0 +1,6 @@
+// mod file for the RISC-V frontend, see isa.rs
+pub mod instruction;
+pub mod control;
+pub mod csr;
+pub mod disasm;
+pub mod single_cycle;
@@ -0,0 +1,737 @@
+/*
+ * single_cycle.rs
+ *
+ * Author: Travis Banken
+ *
+ * Completes a single cycle of a RISC-V instruction on the datapath of the
+ * processor: the register file (x0 hardwired to zero), the alu, the
+ * memories, caches and devices, the watches and the observers are the ones
+ * MIPS uses. Instruction words and data are little endian, words read from
+ * a device are its value as it is.
+ *
+ * Traps go through the machine mode csrs (csr.rs): illegal instructions,
+ * misaligned fetches, loads and stores, ecall, ebreak and the interrupts of
+ * the timer and the uart. The cycle records the MIPS code of the trap. A
+ * program without a trap vector ends when it traps, or with an ecall exit
+ * (a7 = 93) like it would under a proxy kernel.
+ */
+#![allow(dead_code)]
+
+use crate::control_bits::{self, AluSrc, ControlBits, MemAccess, PcSource, Width};
+use crate::cycle::{self, Cycle, RegWrite};
+use crate::hardware::{bus, data_mem, instr_mem};
+use crate::instruction;
+use crate::isa::Isa;
+use crate::phases::execute_alu;
+use crate::riscv::control;
+use crate::riscv::csr::{self, Trap};
+use crate::riscv::instruction::{self as rv, Format, Instruction, Op, Operand};
+use crate::single_cycle::Processor;
+
+// register holding the system call number of an ecall
+const A7: usize = 17;
+// exit system call of the proxy kernel
+const SYS_EXIT: u32 = 93;
+
+// the accesses of the atomics, always words
+const LOAD_WORD: ControlBits = ControlBits {
+    mem: MemAccess::Load { width: Width::Word, signed: true },
+    ..control_bits::NOP
+};
+const STORE_WORD: ControlBits = ControlBits { mem: MemAccess::Store(Width::Word), ..control_bits::NOP };
+
+/*
+ * Runs one instruction or takes the trap it raises, and counts it in mcycle
+ * and minstret.
+ */
+pub fn step(cpu: &mut Processor, instr_mem: &instr_mem::Memory, bus: &mut bus::Bus, cycle: &mut Cycle) {
+    let start = cpu.cycles;
+    let res = execute(cpu, instr_mem, bus, cycle);
+    cpu.csrs.mcycle = cpu.csrs.mcycle.wrapping_add(cpu.cycles - start);
+    match res {
+        Ok(()) => cpu.csrs.minstret = cpu.csrs.minstret.wrapping_add(1),
+        Err(trap) => {
+            cycle.exception = Some(trap.exc_code());
+            take_trap(cpu, &trap, cycle.pc);
+        },
+    }
+}
+
+/*
+ * Runs one instruction, or returns the trap it raises before it changed
+ * anything. Pending interrupts are raised before the instruction starts.
+ */
+pub fn execute(cpu: &mut Processor, instr_mem: &instr_mem::Memory, bus: &mut bus::Bus,
+               cycle: &mut Cycle) -> Result<(), Trap> {
+    cpu.instr_count += 1;
+    cpu.cycles += 1;
+    let pc = cpu.ip;
+
+    // interrupts
+    cpu.intc.set_lines(bus.tick());
+    cpu.csrs.set_lines(cpu.intc.lines());
+    if let Some(cause) = cpu.csrs.pending_interrupt() {
+        return Err(Trap::interrupt(cause));
+    }
+
+    // Fetch instruction
+    if !pc.is_multiple_of(4) {
+        return Err(Trap::new(csr::MISALIGNED_FETCH, pc));
+    }
+    let word = Isa::Rv32i.fetch(instr_mem, pc as usize);
+    if let Some(hier) = cpu.hierarchy.as_mut() {
+        cpu.cycles += (hier.fetch(pc) - hier.fetch_hit_latency()) as u64;
+    }
+    cycle.word = word;
+
+    if cpu.debug {
+        println!("> Instruction Pointer: 0x{:x}", pc);
+        println!("> Instruction: 0x{:08x}", word);
+    }
+
+    // decode instruction
+    let instr = Instruction::new(word);
+    let (def, ctrl) = match (rv::decode(&instr), control::decode(&instr)) {
+        (Some(def), Some(row)) => (def, row.ctrl),
+        _ => return Err(Trap::new(csr::ILLEGAL_INSTRUCTION, word)),
+    };
+    // formats without rs1 or rs2 read x0 on that port
+    let uses = |operand: Operand| def.operands.contains(&operand);
+    let rs1 = if uses(Operand::Rs1) || uses(Operand::Base) || uses(Operand::Addr) {instr.rs1} else {0};
+    let rs2 = if uses(Operand::Rs2) {instr.rs2} else {0};
+    let imm = rv::immediate(def, &instr);
+    cycle.instr = mips_fields(&instr, rs1, rs2, imm);
+    cycle.ctrl = ctrl;
+    cycle.reads = def.operands.iter()
+        .filter_map(|operand| match operand {
+            Operand::Rs1 | Operand::Base | Operand::Addr => Some(rs1),
+            Operand::Rs2 => Some(rs2),
+            _ => None,
+        })
+        .map(|reg| (reg, cpu.regfile.load(reg as usize)))
+        .collect();
+
+    match def.op {
+        // without a trap vector nothing handles the exit call
+        Op::Ecall if cpu.csrs.mtvec == 0 && cpu.regfile.load(A7) == SYS_EXIT => {
+            cpu.ip = instr_mem::Memory::get_size() as u32;
+            return Ok(());
+        },
+        Op::Ecall => return Err(Trap::new(csr::ECALL_M, 0)),
+        Op::Ebreak => return Err(Trap::new(csr::BREAKPOINT, pc)),
+        _ => (),
+    }
+
+    // the csrs run instead of the alu
+    let csr_res = if ctrl.cop0 {
+        system(cpu, def.op, &instr, word)?
+    } else {
+        0
+    };
+
+    // Execute alu
+    let alu_in1 = if ctrl.alu_pc {pc} else {cpu.regfile.load(rs1 as usize)};
+    let alu_in2 = if ctrl.alu_src == AluSrc::Rt {cpu.regfile.load(rs2 as usize)} else {imm};
+    let alu_res = execute_alu(ctrl.alu_op, alu_in1, alu_in2);
+    cycle.alu_in1 = alu_in1;
+    cycle.alu_in2 = alu_in2;
+    cycle.alu_res = alu_res;
+    cycle.alu_zero = if alu_res == 0 {1} else {0};
+
+    // the jump or branch to a misaligned target faults, not the fetch.
+    // mret jumps to mepc, which is always aligned
+    let next_pc = calc_pc(&ctrl, pc, imm, if ctrl.cop0 {csr_res} else {alu_res});
+    if !next_pc.is_multiple_of(4) {
+        return Err(Trap::new(csr::MISALIGNED_FETCH, next_pc));
+    }
+    if ctrl.branch() {
+        cycle.branch = Some(ctrl.branch_taken(alu_res));
+    }
+
+    // mem phase, sc.w gives its result like a load
+    let write_val = cpu.regfile.load(rs2 as usize);
+    let loaded = if let Format::Amo(_) = def.format {
+        atomic(cpu, bus, def.op, alu_res, write_val, pc, cycle)?
+    } else if ctrl.mem != MemAccess::None {
+        mem_phase(cpu, bus, &ctrl, alu_res, write_val, pc, cycle)?
+    } else {
+        0
+    };
+
+    // write back phase
+    if ctrl.reg_write() {
+        let wbval = if ctrl.link {
+            pc.wrapping_add(4)
+        } else if ctrl.mem != MemAccess::None {
+            loaded
+        } else if ctrl.cop0 {
+            csr_res
+        } else {
+            alu_res
+        };
+        // the register file drops writes to x0
+        if instr.rd != 0 {
+            cycle.write = Some(RegWrite {
+                reg: instr.rd,
+                old: cpu.regfile.load(instr.rd as usize),
+                new: wbval,
+            });
+        }
+        cpu.regfile.write(wbval, instr.rd as usize);
+    }
+    cpu.watches.check_regs(pc, &cpu.regfile);
+
+    cpu.ip = next_pc;
+    return Ok(());
+}
+
+/*
+ * Takes the trap of the instruction at pc: the csrs record it and the pc
+ * goes to the trap vector. Without one the pc leaves instruction memory
+ * like at the end of a program.
+ */
+pub fn take_trap(cpu: &mut Processor, trap: &Trap, pc: u32) {
+    if cpu.debug {
+        println!("> Trap: cause 0x{:x} at 0x{:x}", trap.cause, pc);
+    }
+    let handler = cpu.csrs.trap(trap, pc);
+    cpu.ip = if cpu.csrs.mtvec == 0 {instr_mem::Memory::get_size() as u32} else {handler};
+}
+
+// *** PRIVATE FN ***
+
+/*
+ * Runs a csr instruction or mret, returns the old value of the csr (the
+ * address to return to for mret). csrrs and csrrc with x0 or 0 only read.
+ */
+fn system(cpu: &mut Processor, op: Op, instr: &Instruction, word: u32) -> Result<u32, Trap> {
+    if op == Op::Mret {
+        return Ok(cpu.csrs.mret());
+    }
+    let illegal = Trap::new(csr::ILLEGAL_INSTRUCTION, word);
+    let num = (instr.imm_i() & 0xfff) as u16;
+    let old = cpu.csrs.read(num).ok_or(illegal)?;
+    let src = match op {
+        Op::Csrrw | Op::Csrrs | Op::Csrrc => cpu.regfile.load(instr.rs1 as usize),
+        _ => instr.rs1 as u32,
+    };
+    let (new, writes) = match op {
+        Op::Csrrw | Op::Csrrwi => (src, true),
+        Op::Csrrs | Op::Csrrsi => (old | src, instr.rs1 != 0),
+        _ => (old & !src, instr.rs1 != 0),
+    };
+    if writes && !cpu.csrs.write(num, new) {
+        return Err(illegal);
+    }
+    return Ok(old);
+}
+
+/*
+ * Runs lr.w, sc.w or an amo at addr, returns the value for rd: the word
+ * loaded, or 0 when sc.w stored and 1 when it did not. An amo loads the old
+ * word and stores the new one in the same cycle. Any of them ends the
+ * reservation of lr.w.
+ */
+fn atomic(cpu: &mut Processor, bus: &mut bus::Bus, op: Op, addr: u32, val: u32,
+          pc: u32, cycle: &mut Cycle) -> Result<u32, Trap> {
+    if !addr.is_multiple_of(4) {
+        let cause = if op == Op::LrW {csr::MISALIGNED_LOAD} else {csr::MISALIGNED_STORE};
+        return Err(Trap::new(cause, addr));
+    }
+    let reserved = cpu.csrs.reservation.take() == Some(addr);
+    match op {
+        Op::LrW => {
+            let loaded = mem_phase(cpu, bus, &LOAD_WORD, addr, 0, pc, cycle)?;
+            cpu.csrs.reservation = Some(addr);
+            return Ok(loaded);
+        },
+        Op::ScW if reserved => {
+            mem_phase(cpu, bus, &STORE_WORD, addr, val, pc, cycle)?;
+            return Ok(0);
+        },
+        Op::ScW => return Ok(1),
+        _ => (),
+    }
+    let old = mem_phase(cpu, bus, &LOAD_WORD, addr, 0, pc, cycle)?;
+    let new = match op {
+        Op::AmoswapW => val,
+        Op::AmoaddW => old.wrapping_add(val),
+        Op::AmoxorW => old ^ val,
+        Op::AmoandW => old & val,
+        Op::AmoorW => old | val,
+        Op::AmominW => (old as i32).min(val as i32) as u32,
+        Op::AmomaxW => (old as i32).max(val as i32) as u32,
+        Op::AmominuW => old.min(val),
+        Op::AmomaxuW => old.max(val),
+        _ => unreachable!("{:?} is not an amo", op),
+    };
+    mem_phase(cpu, bus, &STORE_WORD, addr, new, pc, cycle)?;
+    return Ok(old);
+}
+
+/*
+ * Address of the next instruction. Branches and jal are relative to the
+ * pc, jalr clears the low bit of the sum.
+ */
+fn calc_pc(ctrl: &ControlBits, pc: u32, imm: u32, alu_res: u32) -> u32 {
+    return match ctrl.pc_src {
+        PcSource::Jump => pc.wrapping_add(imm),
+        PcSource::JumpReg => alu_res & !1,
+        _ if ctrl.branch_taken(alu_res) => pc.wrapping_add(imm),
+        _ => pc.wrapping_add(4),
+    };
+}
+
+/*
+ * Loads or stores at addr, returns the value loaded (extended to 32 bits).
+ * Half words and words must be aligned.
+ */
+fn mem_phase(cpu: &mut Processor, bus: &mut bus::Bus, ctrl: &ControlBits, addr: u32, write_val: u32,
+             pc: u32, cycle: &mut Cycle) -> Result<u32, Trap> {
+    let size = ctrl.mem_size();
+    let is_write = ctrl.mem_write();
+    if !addr.is_multiple_of(size) {
+        let cause = if is_write {csr::MISALIGNED_STORE} else {csr::MISALIGNED_LOAD};
+        return Err(Trap::new(cause, addr));
+    }
+
+    // devices are never cached
+    let cached = !bus.is_device(addr);
+    if let Some(hier) = cpu.hierarchy.as_mut().filter(|_| cached) {
+        let latency = if is_write {hier.store(addr)} else {hier.load(addr)};
+        cpu.cycles += (latency - hier.data_hit_latency()) as u64;
+    }
+
+    let mask = if size == 4 {0xffff_ffff} else {(1 << (8 * size)) - 1};
+    // devices are not read for the old value, reading them has side effects
+    let old_val = if is_write && cached {peek(bus.mem(), addr, size)} else {0};
+    let value = if is_write {
+        store(bus, addr, size, write_val);
+        write_val & mask
+    } else {
+        load(bus, addr, size)
+    };
+    let old_val = if is_write && cached {old_val} else {value};
+    cycle.mem = Some(cycle::MemAccess {
+        addr,
+        paddr: addr,
+        size,
+        is_write,
+        old: old_val,
+        value,
+    });
+    if cpu.watches.watches_mem(addr, size) {
+        cpu.watches.check_mem(pc, addr, size, is_write, old_val, value);
+    }
+
+    return Ok(match ctrl.mem {
+        MemAccess::Load { signed: true, .. } if size < 4 => {
+            let shift = 32 - 8 * size;
+            (((value << shift) as i32) >> shift) as u32
+        },
+        _ => value,
+    });
+}
+
+fn load(bus: &mut bus::Bus, addr: u32, size: u32) -> u32 {
+    if size == 4 {
+        let word = bus.read_word(addr);
+        return if bus.is_device(addr) {word} else {Isa::Rv32i.byte_order(word)};
+    }
+    let mut val = 0;
+    for i in (0..size).rev() {
+        val = (val << 8) | bus.read_byte(addr + i) as u32;
+    }
+    return val;
+}
+
+fn store(bus: &mut bus::Bus, addr: u32, size: u32, val: u32) {
+    if size == 4 {
+        let word = if bus.is_device(addr) {val} else {Isa::Rv32i.byte_order(val)};
+        bus.write_word(word, addr);
+        return;
+    }
+    for i in 0..size {
+        bus.write_byte((val >> (8 * i)) as u8, addr + i);
+    }
+}
+
+/*
+ * Reads a little endian value from memory without going through the bus.
+ */
+fn peek(mem: &data_mem::Memory, addr: u32, size: u32) -> u32 {
+    let mut val = 0;
+    for i in (0..size as usize).rev() {
+        let at = addr as usize + i;
+        let byte = if at < data_mem::Memory::get_size() {mem.read(at)} else {0};
+        val = (val << 8) | byte as u32;
+    }
+    return val;
+}
+
+/*
+ * The fields in the layout the tools label the datapath with: rs1 as rs,
+ * rs2 as rt, funct3 as funct and the immediate in imm16 and addr.
+ */
+fn mips_fields(instr: &Instruction, rs1: u8, rs2: u8, imm: u32) -> instruction::Instruction {
+    instruction::Instruction {
+        opcode: instr.opcode,
+        rs: rs1,
+        rt: rs2,
+        rd: instr.rd,
+        shamt: instr.rs2,
+        funct: instr.funct3,
+        imm16: imm as u16,
+        addr: imm & 0x03ff_ffff,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::cp0::ExcCode;
+    use crate::hardware::{intc, timer};
+    use crate::riscv::instruction::encode;
+    use crate::single_cycle::Options;
+
+    // registers of the standard calling convention
+    const RA: u32 = 1;
+    const SP: u32 = 2;
+    const T0: u32 = 5;
+    const T1: u32 = 6;
+    const T2: u32 = 7;
+    const A0: u32 = 10;
+    const A1: u32 = 11;
+    const A2: u32 = 12;
+    const A7: u32 = 17;
+
+    fn program(words: &[u32]) -> instr_mem::Memory {
+        let mut mem = instr_mem::Memory::new();
+        for (i, word) in words.iter().enumerate() {
+            for (j, byte) in word.to_le_bytes().iter().enumerate() {
+                mem.write(*byte, i * 4 + j);
+            }
+        }
+        return mem;
+    }
+
+    fn run(words: &[u32], data_mem: &mut data_mem::Memory) -> (Processor, Vec<Cycle>) {
+        let mem = program(words);
+        let mut bus = bus::Bus::new(data_mem);
+        let opts = Options {
+            isa: Isa::Rv32i,
+            ..Options::default()
+        };
+        let mut cpu = Processor::new(&opts);
+        let mut cycles = Vec::new();
+        while cpu.is_running() && cycles.len() < 1000 {
+            cycles.push(cpu.step(&mem, &mut bus));
+        }
+        return (cpu, cycles);
+    }
+
+    #[test]
+    fn test_alu() {
+        let mut data_mem = data_mem::Memory::new();
+        let (cpu, _) = run(&[
+            encode(Op::Addi, &[T0, 0, 0xff6]),      // -10
+            encode(Op::Addi, &[T1, 0, 3]),
+            encode(Op::Sub, &[T2, T1, T0]),          // 13
+            encode(Op::Slt, &[A0, T0, T1]),          // 1
+            encode(Op::Sltu, &[A1, T0, T1]),         // 0
+            encode(Op::Srai, &[T0, T0, 1]),          // -5
+            encode(Op::Xori, &[T1, T1, 0xfff]),      // !3
+            encode(Op::Lui, &[SP, 0xdead0]),
+            encode(Op::Auipc, &[RA, 1]),             // 0x1000 + 0x20
+            encode(Op::Addi, &[0, 0, 1]),            // x0 stays 0
+        ], &mut data_mem);
+        assert_eq!(cpu.regfile.load(T2 as usize), 13);
+        assert_eq!(cpu.regfile.load(A0 as usize), 1);
+        assert_eq!(cpu.regfile.load(A1 as usize), 0);
+        assert_eq!(cpu.regfile.load(T0 as usize), (-5i32) as u32);
+        assert_eq!(cpu.regfile.load(T1 as usize), !3);
+        assert_eq!(cpu.regfile.load(SP as usize), 0xdead_0000);
+        assert_eq!(cpu.regfile.load(RA as usize), 0x1020);
+        assert_eq!(cpu.regfile.load(0), 0);
+    }
+
+    #[test]
+    fn test_memory() {
+        let mut data_mem = data_mem::Memory::new();
+        let (cpu, cycles) = run(&[
+            encode(Op::Lui, &[T0, 0xcafec]),
+            encode(Op::Addi, &[T0, T0, 0xabe]),      // 0xcafebabe
+            encode(Op::Sw, &[T0, 0x40, 0]),
+            encode(Op::Lb, &[A0, 0x40, 0]),          // 0xffffffbe
+            encode(Op::Lhu, &[A1, 0x42, 0]),         // 0xcafe
+            encode(Op::Sh, &[T0, 0x48, 0]),
+            encode(Op::Sb, &[T0, 0x4b, 0]),
+            encode(Op::Lw, &[T1, 0x48, 0]),          // 0xbe00babe
+        ], &mut data_mem);
+        assert_eq!(data_mem.read(0x40), 0xbe);
+        assert_eq!(data_mem.read(0x43), 0xca);
+        assert_eq!(cpu.regfile.load(A0 as usize), 0xffff_ffbe);
+        assert_eq!(cpu.regfile.load(A1 as usize), 0xcafe);
+        assert_eq!(cpu.regfile.load(T1 as usize), 0xbe00_babe);
+        let sw = cycles[2].mem.unwrap();
+        assert_eq!((sw.addr, sw.size, sw.is_write, sw.value), (0x40, 4, true, 0xcafe_babe));
+        assert_eq!(cycles[4].mem.unwrap().value, 0xcafe);
+    }
+
+    #[test]
+    fn test_watches() {
+        let mem = program(&[
+            encode(Op::Addi, &[T0, 0, 0x7be]),
+            encode(Op::Sw, &[T0, 0x40, 0]),
+            encode(Op::Lb, &[A0, 0x40, 0]),
+        ]);
+        let opts = Options {
+            isa: Isa::Rv32i,
+            watches: vec![
+                crate::watch::Watch::parse("write:0x40+4,log", Isa::Rv32i).unwrap(),
+                crate::watch::Watch::parse("read:0x40+1,log", Isa::Rv32i).unwrap(),
+            ],
+            ..Options::default()
+        };
+        let mut data_mem = data_mem::Memory::new();
+        let mut bus = bus::Bus::new(&mut data_mem);
+        let mut cpu = Processor::new(&opts);
+        for _ in 0..3 {
+            cpu.step(&mem, &mut bus);
+        }
+
+        // a load reads the same value it leaves, like on the MIPS datapath
+        let log: Vec<String> = cpu.watches.take_log().iter().map(|hit| hit.to_string()).collect();
+        assert_eq!(log, vec![
+            "0x00000004: write 0x00000040 (4 bytes) 0x0 -> 0x7be",
+            "0x00000008: read 0x00000040 (1 bytes) 0xbe -> 0xbe",
+        ]);
+    }
+
+    #[test]
+    fn test_control_flow() {
+        // sum 1..=5 in a loop, through a call
+        let mut data_mem = data_mem::Memory::new();
+        let (cpu, cycles) = run(&[
+            encode(Op::Addi, &[A0, 0, 5]),
+            encode(Op::Jal, &[RA, 0x14]),            // call sum at 0x18
+            encode(Op::Jal, &[0, 0x100]),            // off the end of memory
+            0, 0, 0,
+            // sum: a1 = a0 + (a0 - 1) + ... + 1
+            encode(Op::Add, &[A1, A1, A0]),
+            encode(Op::Addi, &[A0, A0, 0xfff]),
+            encode(Op::Blt, &[0, A0, 0x1ff8]),       // back while 0 < a0
+            encode(Op::Jalr, &[0, 0, RA]),
+        ], &mut data_mem);
+        assert_eq!(cpu.regfile.load(A1 as usize), 15);
+        assert_eq!(cpu.regfile.load(RA as usize), 8);
+        assert_eq!(cpu.ip, 0x108);
+        assert!(cycles.iter().all(|cycle| cycle.retired()));
+        assert_eq!(cycles.iter().filter(|cycle| cycle.branch == Some(true)).count(), 4);
+        // jal writes the return address
+        assert_eq!(cycles[1].write, Some(RegWrite { reg: RA as u8, old: 0, new: 8 }));
+    }
+
+    #[test]
+    fn test_exceptions() {
+        let mut data_mem = data_mem::Memory::new();
+        let exception = |words: &[u32]| {
+            let mut data_mem = data_mem::Memory::new();
+            let (cpu, cycles) = run(words, &mut data_mem);
+            assert!(!cpu.is_running());
+            return cycles.last().unwrap().exception;
+        };
+        assert_eq!(exception(&[encode(Op::Ecall, &[])]), Some(ExcCode::Sys));
+        assert_eq!(exception(&[encode(Op::Ebreak, &[])]), Some(ExcCode::Bp));
+        assert_eq!(exception(&[0]), Some(ExcCode::RI));
+        assert_eq!(exception(&[encode(Op::Lw, &[T0, 2, 0])]), Some(ExcCode::AdEL));
+        assert_eq!(exception(&[encode(Op::Sh, &[T0, 1, 0])]), Some(ExcCode::AdES));
+        assert_eq!(exception(&[encode(Op::Jal, &[0, 6])]), Some(ExcCode::AdEL));
+        // fence does nothing, the zero word after it is illegal
+        let (_, cycles) = run(&[encode(Op::Fence, &[])], &mut data_mem);
+        assert_eq!((cycles[0].next_pc, cycles[0].retired()), (4, true));
+        assert_eq!(cycles[1].exception, Some(ExcCode::RI));
+        // the trap is recorded even without a vector
+        let (cpu, _) = run(&[encode(Op::Lw, &[T0, 2, 0])], &mut data_mem);
+        assert_eq!((cpu.csrs.mcause, cpu.csrs.mtval, cpu.csrs.mepc), (csr::MISALIGNED_LOAD, 2, 0));
+    }
+
+    #[test]
+    fn test_multiply_divide() {
+        let mut data_mem = data_mem::Memory::new();
+        let (cpu, _) = run(&[
+            encode(Op::Addi, &[T0, 0, 0xff9]),      // -7
+            encode(Op::Addi, &[T1, 0, 2]),
+            encode(Op::Mul, &[A0, T0, T1]),          // -14
+            encode(Op::Mulh, &[A1, T0, T1]),         // -1
+            encode(Op::Mulhu, &[A2, T0, T1]),        // 1
+            encode(Op::Div, &[T2, T0, T1]),          // -3
+            encode(Op::Rem, &[SP, T0, T1]),          // -1
+            encode(Op::Divu, &[RA, T0, 0]),          // by zero: all ones
+            encode(Op::Remu, &[A7, T0, 0]),          // by zero: -7
+        ], &mut data_mem);
+        assert_eq!(cpu.regfile.load(A0 as usize), (-14i32) as u32);
+        assert_eq!(cpu.regfile.load(A1 as usize), 0xffff_ffff);
+        assert_eq!(cpu.regfile.load(A2 as usize), 1);
+        assert_eq!(cpu.regfile.load(T2 as usize), (-3i32) as u32);
+        assert_eq!(cpu.regfile.load(SP as usize), 0xffff_ffff);
+        assert_eq!(cpu.regfile.load(RA as usize), 0xffff_ffff);
+        assert_eq!(cpu.regfile.load(A7 as usize), (-7i32) as u32);
+    }
+
+    #[test]
+    fn test_atomics() {
+        let mut data_mem = data_mem::Memory::new();
+        let (cpu, cycles) = run(&[
+            encode(Op::Addi, &[A0, 0, 0x40]),
+            encode(Op::Addi, &[T0, 0, 5]),
+            encode(Op::Sw, &[T0, 0, A0]),
+            encode(Op::Addi, &[T1, 0, 0xffd]),      // -3
+            encode(Op::AmoaddW, &[A1, T1, A0]),      // a1 = 5, mem = 2
+            encode(Op::AmominW, &[A2, T1, A0]),      // a2 = 2, mem = -3
+            encode(Op::AmomaxuW, &[0, T0, A0]),      // mem = -3
+            encode(Op::LrW, &[T2, A0]),              // t2 = -3
+            encode(Op::ScW, &[SP, T0, A0]),          // sp = 0, mem = 5
+            encode(Op::ScW, &[RA, T1, A0]),          // no reservation, ra = 1
+            encode(Op::AmoswapW, &[A7, T1, A0]),     // a7 = 5, mem = -3
+        ], &mut data_mem);
+        assert_eq!(cpu.regfile.load(A1 as usize), 5);
+        assert_eq!(cpu.regfile.load(A2 as usize), 2);
+        assert_eq!(cpu.regfile.load(T2 as usize), (-3i32) as u32);
+        assert_eq!(cpu.regfile.load(SP as usize), 0);
+        assert_eq!(cpu.regfile.load(RA as usize), 1);
+        assert_eq!(cpu.regfile.load(A7 as usize), 5);
+        assert_eq!(data_mem.read(0x40), 0xfd);
+        assert_eq!(cpu.csrs.reservation, None);
+        // the amo records its store
+        let amo = cycles[4].mem.unwrap();
+        assert_eq!((amo.is_write, amo.old, amo.value), (true, 5, 2));
+        assert!(cycles[9].mem.is_none());
+
+        // a misaligned amo is a misaligned store
+        let (cpu, cycles) = run(&[encode(Op::Addi, &[A0, 0, 2]), encode(Op::AmoorW, &[A1, 0, A0])], &mut data_mem);
+        assert_eq!(cycles[1].exception, Some(ExcCode::AdES));
+        assert_eq!((cpu.csrs.mcause, cpu.csrs.mtval, cpu.csrs.minstret), (csr::MISALIGNED_STORE, 2, 1));
+    }
+
+    #[test]
+    fn test_csrs() {
+        let mut data_mem = data_mem::Memory::new();
+        let (cpu, cycles) = run(&[
+            encode(Op::Addi, &[T0, 0, 0x7ff]),
+            encode(Op::Csrrw, &[0, csr::MSCRATCH as u32, T0]),
+            encode(Op::Csrrsi, &[A0, csr::MSCRATCH as u32, 0x10]),   // a0 = 0x7ff
+            encode(Op::Csrrci, &[A1, csr::MSCRATCH as u32, 1]),      // a1 = 0x7ff
+            encode(Op::Csrrs, &[A2, csr::MSCRATCH as u32, 0]),       // a2 = 0x7fe
+            encode(Op::Csrrs, &[T1, csr::MINSTRET as u32, 0]),       // 5 retired before
+            encode(Op::Csrrs, &[T2, csr::MISA as u32, 0]),
+        ], &mut data_mem);
+        assert_eq!(cpu.regfile.load(A0 as usize), 0x7ff);
+        assert_eq!(cpu.regfile.load(A1 as usize), 0x7ff);
+        assert_eq!(cpu.regfile.load(A2 as usize), 0x7fe);
+        assert_eq!(cpu.regfile.load(T1 as usize), 5);
+        assert_eq!(cpu.regfile.load(T2 as usize), 0x4000_1101);
+        // the illegal zero word after the program takes a cycle
+        assert_eq!((cpu.csrs.minstret, cpu.csrs.mcycle), (7, 8));
+        assert!(cycles[1].ctrl.cop0);
+
+        // missing and read only csrs are illegal
+        let (_, cycles) = run(&[encode(Op::Csrrw, &[A0, 0x7c0, 0])], &mut data_mem);
+        assert_eq!(cycles[0].exception, Some(ExcCode::RI));
+        let (cpu, cycles) = run(&[encode(Op::Csrrw, &[A0, csr::MHARTID as u32, 0])], &mut data_mem);
+        assert_eq!(cycles[0].exception, Some(ExcCode::RI));
+        assert_eq!(cpu.csrs.mtval, encode(Op::Csrrw, &[A0, csr::MHARTID as u32, 0]));
+    }
+
+    #[test]
+    fn test_traps() {
+        // the handler skips the ecall and counts the traps in a1
+        let mut data_mem = data_mem::Memory::new();
+        let (cpu, cycles) = run(&[
+            encode(Op::Addi, &[T0, 0, 0x20]),
+            encode(Op::Csrrw, &[0, csr::MTVEC as u32, T0]),
+            encode(Op::Ecall, &[]),
+            encode(Op::Addi, &[A0, 0, 7]),
+            encode(Op::Addi, &[A7, 0, SYS_EXIT]),
+            encode(Op::Ecall, &[]),
+            encode(Op::Csrrw, &[0, csr::MTVEC as u32, 0]),
+            encode(Op::Ecall, &[]),
+            // handler at 0x20
+            encode(Op::Csrrs, &[T1, csr::MEPC as u32, 0]),
+            encode(Op::Addi, &[T1, T1, 4]),
+            encode(Op::Csrrw, &[0, csr::MEPC as u32, T1]),
+            encode(Op::Addi, &[A1, A1, 1]),
+            encode(Op::Csrrs, &[T2, csr::MCAUSE as u32, 0]),
+            encode(Op::Mret, &[]),
+        ], &mut data_mem);
+        assert_eq!(cycles[2].exception, Some(ExcCode::Sys));
+        assert_eq!(cycles[2].next_pc, 0x20);
+        assert_eq!(cycles[8].next_pc, 0x0c);
+        // the exit call traps too while there is a vector
+        assert_eq!(cycles[17].next_pc, 0x18);
+        assert_eq!(cycles.len(), 20);
+        assert_eq!(cpu.regfile.load(A1 as usize), 2);
+        assert_eq!(cpu.regfile.load(T2 as usize), csr::ECALL_M);
+        assert_eq!(cpu.regfile.load(A0 as usize), 7);
+
+        // without a vector the exit call ends the run
+        let (cpu, cycles) = run(&[
+            encode(Op::Addi, &[A0, 0, 3]),
+            encode(Op::Addi, &[A7, 0, SYS_EXIT]),
+            encode(Op::Ecall, &[]),
+            encode(Op::Addi, &[A0, 0, 4]),
+        ], &mut data_mem);
+        assert!(!cpu.is_running());
+        assert_eq!(cycles.len(), 3);
+        assert!(cycles[2].retired());
+        assert_eq!(cpu.regfile.load(A0 as usize), 3);
+    }
+
+    #[test]
+    fn test_timer_interrupt() {
+        const S0: u32 = 8;
+        const S1: u32 = 9;
+        const S2: u32 = 18;
+        let mem = program(&[
+            encode(Op::Lui, &[S2, 0xffff0]),
+            encode(Op::Addi, &[T0, 0, 0x40]),
+            encode(Op::Csrrw, &[0, csr::MTVEC as u32, T0]),
+            encode(Op::Addi, &[T0, 0, csr::IRQ_MTI]),
+            encode(Op::Csrrw, &[0, csr::MIE as u32, T0]),
+            encode(Op::Csrrsi, &[0, csr::MSTATUS as u32, csr::STATUS_MIE]),
+            // one shot timer
+            encode(Op::Addi, &[T0, 0, 3]),
+            encode(Op::Sw, &[T0, 0x14, S2]),         // reload
+            encode(Op::Sw, &[T0, 0x10, S2]),         // enable | irq enable
+            encode(Op::Addi, &[S0, S0, 1]),
+            encode(Op::Addi, &[S0, S0, 1]),
+            encode(Op::Addi, &[S0, S0, 1]),
+            encode(Op::Jal, &[0, 0x100]),
+            0, 0, 0,
+            // handler at 0x40
+            encode(Op::Addi, &[T2, 0, 1]),
+            encode(Op::Sw, &[T2, 0x1c, S2]),         // clear expired
+            encode(Op::Addi, &[S1, S1, 1]),
+            encode(Op::Mret, &[]),
+        ]);
+        let mut data_mem = data_mem::Memory::new();
+        let mut bus = bus::Bus::new(&mut data_mem);
+        bus.attach_irq(timer::TIMER_BASE, Box::new(timer::Timer::new()), intc::LINE_TIMER);
+        let opts = Options {
+            isa: Isa::Rv32i,
+            ..Options::default()
+        };
+        let mut cpu = Processor::new(&opts);
+        let mut cycles = Vec::new();
+        while cpu.is_running() {
+            cycles.push(cpu.step(&mem, &mut bus));
+        }
+        assert_eq!(cpu.regfile.load(S0 as usize), 3);
+        // one shot, only interrupts once
+        assert_eq!(cpu.regfile.load(S1 as usize), 1);
+        assert_eq!(cpu.csrs.mcause, csr::INTERRUPT | 7);
+        assert_eq!(cycles.iter().filter(|cycle| cycle.exception == Some(ExcCode::Int)).count(), 1);
+        // interrupts are enabled again after mret
+        assert_eq!(cpu.csrs.mstatus & csr::STATUS_MIE, csr::STATUS_MIE);
+    }
+}
@@ -10,23 +10,225 @@
 use crate::hardware::*;
 use crate::phases::*;
 use crate::instruction::Instruction;
-use crate::control_bits::ControlBits;
+use crate::control_bits::{self, AluOp, AluSrc, CondWrite, ControlBits, HiLo, PcSource, RegDst};
+use crate::hardware::hierarchy::{Hierarchy, HierarchyConfig};
+use crate::tools::cache_report;
+use crate::tools::trace;
+use crate::tools::vcd;
+use crate::tools::datapath;
+use crate::tools::profile;
+use crate::tools::coverage;
+use crate::tools::symbols::SymbolTable;
+use crate::debugger;
+use crate::gdb;
+use crate::watch;
+use crate::history::HistoryConfig;
+use crate::snapshot::Snapshot;
+use crate::loader;
+use crate::cycle::{Cycle, MemAccess, Observer, RegWrite};
+use crate::isa::Isa;
+use crate::riscv;
 
-pub fn start(instr_mem: &instr_mem::Memory, data_mem: &mut data_mem::Memory, debug: bool) {
-    if debug {
-        println!("Debug Mode: ON");
+// exit codes of a run that did not end normally
+pub const EXIT_ERROR: i32 = 1;
+pub const EXIT_MAX_STEPS: i32 = 124;
+
+/*
+ * Options for a run of the processor.
+ */
+#[derive(Default)]
+pub struct Options {
+    pub debug: bool,
+    // instruction set of the program
+    pub isa: Isa,
+    // memory timing is not modelled unless some level is configured
+    pub hierarchy: HierarchyConfig,
+    // translate addresses through the mmu (R3000 segments + tlb)
+    pub mmu: bool,
+    // address of the first instruction
+    pub entry: u32,
+    // console uart on the bus at uart::UART_BASE
+    pub uart: bool,
+    // count down timer on the bus at timer::TIMER_BASE
+    pub timer: bool,
+    // hand control to the interactive debugger instead of running
+    pub interactive: bool,
+    // serve a gdb remote stub instead of running
+    pub gdb: Option<gdb::Transport>,
+    pub watches: Vec<watch::Watch>,
+    // how much the debugger remembers for reverse execution
+    pub history: HistoryConfig,
+    // record every retired instruction
+    pub trace: trace::TraceConfig,
+    // value change dump of the datapath signals
+    pub vcd: Option<String>,
+    // directory to draw the datapath of every cycle into
+    pub datapath: Option<String>,
+    // instruction mix, hot spots and call graph of the run
+    pub profile: profile::ProfileConfig,
+    // which instructions, blocks and branch directions ran
+    pub coverage: coverage::CoverageConfig,
+    // stop the run after this many instructions
+    pub max_steps: Option<u64>,
+    // start with random values in the registers instead of zeros, from
+    // this seed
+    pub random_regs: Option<u32>,
+    // call the program like main(argc, argv) with these arguments, None
+    // leaves the registers and memory as they are
+    pub args: Option<Vec<String>>,
+    // start from a saved machine state, save the state at the end
+    pub load_state: Option<String>,
+    pub save_state: Option<String>,
+}
+
+/*
+ * State of the processor between two cycles.
+ */
+pub struct Processor {
+    pub isa: Isa,
+    pub regfile: reg_file::Registers,
+    pub ip: u32,
+    // results of the multiply and divide instructions
+    pub hi: u32,
+    pub lo: u32,
+    pub cp0: cp0::Cp0,
+    // machine mode csrs of RISC-V
+    pub csrs: riscv::csr::Csrs,
+    pub mmu: mmu::Mmu,
+    pub intc: intc::InterruptController,
+    pub hierarchy: Option<Hierarchy>,
+    pub watches: watch::Watchpoints,
+    // told about every cycle (tracing, profiling, ...)
+    pub observers: Vec<Box<dyn Observer>>,
+
+    // every instruction takes one cycle plus any cycles stalled on a cache
+    pub instr_count: u64,
+    pub cycles: u64,
+
+    pub debug: bool,
+}
+
+impl Processor {
+    pub fn new(opts: &Options) -> Processor {
+        let hierarchy = if opts.hierarchy.is_enabled() {
+            Some(Hierarchy::new(&opts.hierarchy))
+        } else {
+            None
+        };
+        let regfile = match opts.random_regs {
+            Some(seed) => reg_file::Registers::garbage(seed),
+            None => reg_file::Registers::new(),
+        };
+        let mut watches = watch::Watchpoints::new();
+        for w in opts.watches.iter() {
+            watches.add(*w, &regfile);
+        }
+        Processor {
+            isa: opts.isa,
+            regfile,
+            ip: opts.entry,
+            hi: 0,
+            lo: 0,
+            cp0: cp0::Cp0::new(),
+            csrs: riscv::csr::Csrs::new(),
+            mmu: mmu::Mmu::new(opts.mmu),
+            intc: intc::InterruptController::new(),
+            hierarchy,
+            watches,
+            observers: Vec::new(),
+            instr_count: 0,
+            cycles: 0,
+            debug: opts.debug,
+        }
+    }
+
+    /*
+     * The processor runs until the next instruction would be fetched from
+     * outside of instruction memory.
+     */
+    pub fn is_running(&self) -> bool {
+        match self.mmu.translate(&self.cp0, self.ip, mmu::AccessKind::Fetch) {
+            Ok(addr) => (addr as usize) < instr_mem::Memory::get_size(),
+            // fetching raises an exception, which still needs to be taken
+            Err(_) => true,
+        }
+    }
+
+    /*
+     * Runs one instruction through every phase of the cycle, or takes the
+     * exception it raises. Pending interrupts are taken before the
+     * instruction starts. The observers see what happened afterwards, it is
+     * also returned.
+     */
+    pub fn step(&mut self, instr_mem: &instr_mem::Memory, bus: &mut bus::Bus) -> Cycle {
+        let mut cycle = Cycle {
+            cycle: self.cycles,
+            pc: self.ip,
+            isa: self.isa,
+            ..Cycle::default()
+        };
+        match self.isa {
+            Isa::Mips => {
+                if let Err(exc) = self.execute(instr_mem, bus, &mut cycle) {
+                    cycle.exception = Some(exc.code);
+                    self.take_exception(&exc, cycle.pc);
+                }
+            },
+            Isa::Rv32i => riscv::single_cycle::step(self, instr_mem, bus, &mut cycle),
+        }
+        cycle.next_pc = self.ip;
+
+        if !self.observers.is_empty() {
+            let mut observers = std::mem::take(&mut self.observers);
+            for observer in observers.iter_mut() {
+                observer.cycle(&cycle);
+            }
+            self.observers = observers;
+        }
+        return cycle;
+    }
+
+    /*
+     * Tells the observers the run is over.
+     */
+    pub fn finish(&mut self) {
+        for observer in self.observers.iter_mut() {
+            observer.finish();
+        }
     }
 
-    // let mut instr_mem = instr_mem::Memory::new();
-    let mut regfile = reg_file::Registers::new();
+    // *** PRIVATE FN ***
+
+    fn execute(&mut self, instr_mem: &instr_mem::Memory, bus: &mut bus::Bus,
+               cycle: &mut Cycle) -> Result<(), cp0::Exception> {
+        self.instr_count += 1;
+        self.cycles += 1;
+        self.cp0.tick_random();
+        self.cp0.tick_count();
+        let ip = self.ip;
+
+        // interrupts
+        self.intc.set_lines(bus.tick());
+        self.intc.set_line(intc::LINE_CP0_TIMER, self.cp0.timer_irq());
+        self.intc.update_cause(&mut self.cp0);
+        if self.cp0.interrupt_pending() {
+            return Err(cp0::Exception::new(cp0::ExcCode::Int));
+        }
 
-    let mem_size = instr_mem::Memory::get_size();
-    let mut ip: u32 = 0;
-    while ip < mem_size as u32 {
-        // Fetch instruction
-        let instr_raw = instr_fetch(&instr_mem, ip as usize);
+        // Fetch instruction, from a word aligned pc
+        if ip & 0x3 != 0 {
+            return Err(cp0::Exception::with_addr(cp0::ExcCode::AdEL, ip));
+        }
+        let fetch = self.mmu.map(&self.cp0, ip, mmu::AccessKind::Fetch)?;
+        let fetch_addr = fetch.paddr;
+        let instr_raw = instr_fetch(instr_mem, fetch_addr as usize);
+        if let Some(hier) = self.hierarchy.as_mut() {
+            let latency = if fetch.cached {hier.fetch(fetch_addr)} else {hier.uncached(false)};
+            self.cycles += (latency - hier.fetch_hit_latency()) as u64;
+        }
+        cycle.word = instr_raw;
 
-        if debug {
+        if self.debug {
             println!("> Instruction Pointer: 0x{:x}", ip);
             println!("> Instruction: 0x{:08x}", instr_raw);
         }
@@ -35,395 +237,1022 @@ pub fn start(instr_mem: &instr_mem::Memory, data_mem: &mut data_mem::Memory, deb
         let mut instr_struct = Instruction::default();
         instr_decode(instr_raw, &mut instr_struct);
         
-        let mut ctrl_bits = ControlBits::default();
-        fill_control_bits(&mut ctrl_bits, &instr_struct);
+        cycle.instr = instr_struct;
+        let ctrl_bits = match control_bits::decode(&instr_struct) {
+            Some(row) => row.ctrl,
+            None => return Err(cp0::Exception::new(cp0::ExcCode::RI)),
+        };
+        // ins with the highest bit of the field below the lowest is reserved
+        if ctrl_bits.insert && instr_struct.rd < instr_struct.shamt {
+            return Err(cp0::Exception::new(cp0::ExcCode::RI));
+        }
+        cycle.ctrl = ctrl_bits;
+        cycle.reads = regs_read(&ctrl_bits, &instr_struct).iter()
+            .map(|reg| (*reg, self.regfile.load(*reg as usize)))
+            .collect();
 
+        // coprocessor 0 runs instead of the alu
+        let cop0_res = if ctrl_bits.cop0 {
+            self.execute_cop0(&instr_struct)?
+        } else {
+            0
+        };
 
         // Execute alu
-        let alu_in1 = get_alu_in1(&regfile, &instr_struct);
-        let alu_in2 = get_alu_in2(&regfile, &instr_struct, &ctrl_bits);
+        let alu_in1 = get_alu_in1(&self.regfile, &instr_struct, &ctrl_bits);
+        let alu_in2 = get_alu_in2(&self.regfile, &instr_struct, &ctrl_bits);
         
-        let alu_res = execute_alu(ctrl_bits.alu_op, alu_in1, alu_in2, ctrl_bits.alu_bnegate);
-        let alu_zero = if alu_res == 0 {0} else {1};
+        let alu_res = execute_alu(ctrl_bits.alu_op, alu_in1, alu_in2);
+        let alu_zero = if alu_res == 0 {1} else {0};
 
-        // not the res of alu if ctrl bit is on
-        let alu_res = if ctrl_bits.not_res == 1 {!alu_res} else {alu_res};
-        let alu_zero = if ctrl_bits.not_res == 1 {(!alu_zero) & 0x1} else {alu_zero};
+        // add, addi and sub trap instead of writing an overflowed result
+        if ctrl_bits.trap_overflow && alu_overflow(ctrl_bits.alu_op, alu_in1, alu_in2) {
+            return Err(cp0::Exception::new(cp0::ExcCode::Ov));
+        }
+        if ctrl_bits.trap_taken(alu_res) {
+            return Err(cp0::Exception::new(cp0::ExcCode::Tr));
+        }
+        cycle.alu_in1 = alu_in1;
+        cycle.alu_in2 = alu_in2;
+        cycle.alu_res = alu_res;
+        cycle.alu_zero = alu_zero;
 
         // mem phase
-        let write_val = regfile.load(instr_struct.rt as usize);
-        let wbval = match mem_phase(&ctrl_bits, data_mem, alu_res as usize, write_val) {
-            Some(res) => res,
-            None => 0
+        let write_val = self.regfile.load(instr_struct.rt as usize);
+        let mem_op = ctrl_bits.mem != control_bits::MemAccess::None;
+        let is_write = ctrl_bits.mem_write();
+        if misaligned(&ctrl_bits, alu_res) {
+            let code = if is_write {cp0::ExcCode::AdES} else {cp0::ExcCode::AdEL};
+            return Err(cp0::Exception::with_addr(code, alu_res));
+        }
+        let translation = if mem_op {
+            let kind = if is_write {mmu::AccessKind::Store} else {mmu::AccessKind::Load};
+            self.mmu.map(&self.cp0, alu_res, kind)?
+        } else {
+            mmu::Translation { paddr: alu_res, cached: true }
         };
+        let mem_addr = translation.paddr;
+        let device = bus.is_device(mem_addr);
+        let size = ctrl_bits.mem_size();
+        // lwl, lwr, swl and swr access the aligned word holding the address
+        let (vaddr, paddr) = if ctrl_bits.mem_part() {(alu_res & !0x3, mem_addr & !0x3)} else {(alu_res, mem_addr)};
+        // an access past the end of data memory is an address error, like a
+        // misaligned one
+        if mem_op && !device && paddr as usize + size as usize > data_mem::Memory::get_size() {
+            let code = if is_write {cp0::ExcCode::AdES} else {cp0::ExcCode::AdEL};
+            return Err(cp0::Exception::with_addr(code, alu_res));
+        }
+        // devices are never cached, the others follow the segment or page
+        let cached = translation.cached && !device;
+        if let Some(hier) = self.hierarchy.as_mut().filter(|_| !device) {
+            let latency = if !cached {
+                hier.uncached(is_write)
+            } else if ctrl_bits.mem_read() {
+                hier.load(mem_addr)
+            } else if is_write {
+                hier.store(mem_addr)
+            } else {
+                hier.data_hit_latency()
+            };
+            self.cycles += (latency - hier.data_hit_latency()) as u64;
+        }
+        // devices are not read for the old value, reading them has side effects
+        let old_val = if is_write && !device {peek(bus.mem(), paddr, size)} else {0};
+        let wbval = mem_phase(&ctrl_bits, bus, mem_addr, write_val).unwrap_or_default();
+        if mem_op {
+            // the bytes themselves, not the sign extended load result or the
+            // merged register of a part access
+            let mask = if size == 4 {0xffff_ffff} else {(1 << (8 * size)) - 1};
+            let new_val = if ctrl_bits.mem_part() && !device {
+                peek(bus.mem(), paddr, size)
+            } else if is_write {
+                write_val & mask
+            } else {
+                wbval & mask
+            };
+            let old_val = if is_write && !device {old_val} else {new_val};
+            cycle.mem = Some(MemAccess {
+                addr: vaddr,
+                paddr,
+                size,
+                is_write,
+                old: old_val,
+                value: new_val,
+            });
+            if self.watches.watches_mem(vaddr, size) {
+                self.watches.check_mem(ip, vaddr, size, is_write, old_val, new_val);
+            }
+        }
 
         // write back phase
-        let wbval = if ctrl_bits.mem_to_reg == 1 {wbval} else {alu_res};
-        let reg_num = if ctrl_bits.reg_dst == 1 {instr_struct.rd} else {instr_struct.rt};
-        write_back(&mut regfile, reg_num as usize, &ctrl_bits, wbval);
+        let wbval = if ctrl_bits.link {
+            ip.wrapping_add(4)
+        } else if ctrl_bits.mem_read() {
+            wbval
+        } else if ctrl_bits.cop0 {
+            cop0_res
+        } else if ctrl_bits.hilo_read() {
+            if ctrl_bits.hilo == HiLo::Mfhi {self.hi} else {self.lo}
+        } else if ctrl_bits.cond_write != CondWrite::Always {
+            // movz and movn move rs past the alu
+            alu_in1
+        } else if ctrl_bits.insert {
+            // the field is where the alu inserts all ones
+            alu_res | (write_val & !execute_alu(AluOp::Ins, 0xffff_ffff, alu_in2))
+        } else {
+            alu_res
+        };
+        let reg_num = match ctrl_bits.reg_dst {
+            RegDst::Rd => instr_struct.rd,
+            RegDst::Ra => 31,
+            _ => instr_struct.rt,
+        };
+        let reg_write = ctrl_bits.write_taken(write_val);
+        // the register file drops writes to $zero
+        if reg_write && reg_num != 0 {
+            cycle.write = Some(RegWrite {
+                reg: reg_num,
+                old: self.regfile.load(reg_num as usize),
+                new: wbval,
+            });
+        }
+        if reg_write {
+            write_back(&mut self.regfile, reg_num as usize, &ctrl_bits, wbval);
+        }
+        if ctrl_bits.hilo != HiLo::None {
+            let acc = accumulate(ctrl_bits.hilo, (self.hi as u64) << 32 | self.lo as u64, alu_in1, alu_in2);
+            self.hi = (acc >> 32) as u32;
+            self.lo = acc as u32;
+        }
+        self.watches.check_regs(ip, &self.regfile);
 
         // calculate new ip val
-        let addr = if ctrl_bits.branch == 1 {
-            instr_struct.imm16 as u32
-        } else if ctrl_bits.jump == 1 {
-            instr_struct.addr
-        } else {
-            0 // addr not needed
+        let addr = match ctrl_bits.pc_src {
+            PcSource::Jump => instr_struct.addr,
+            PcSource::JumpReg => alu_in1,
+            PcSource::Next => 0, // addr not needed
+            _ => instr_struct.imm16 as u32,
         };
+        if ctrl_bits.branch() {
+            cycle.branch = Some(ctrl_bits.branch_taken(alu_res));
+        }
 
-        ip = calc_ip(&ctrl_bits, ip, addr, alu_zero);
+        self.ip = calc_ip(&ctrl_bits, ip, addr, alu_res);
+        return Ok(());
     }
-}
 
-fn get_alu_in1(regfile: &reg_file::Registers, instr: &Instruction) -> u32 {
-    let reg_num = instr.rs as usize;
-    return regfile.load(reg_num);
+    /*
+     * Runs a coprocessor 0 instruction, returns the value read by mfc0.
+     */
+    fn execute_cop0(&mut self, instr: &Instruction) -> Result<u32, cp0::Exception> {
+        let status = self.cp0.load(cp0::STATUS);
+        if self.cp0.is_user_mode() && status & cp0::STATUS_CU0 == 0 {
+            return Err(cp0::Exception::new(cp0::ExcCode::CpU));
+        }
+
+        match instr.rs {
+            0x00 => return Ok(self.cp0.load(instr.rd as usize)), // mfc0
+            0x04 => { // mtc0
+                let val = self.regfile.load(instr.rt as usize);
+                self.cp0.write(val, instr.rd as usize);
+            },
+            0x10 => match instr.funct {
+                0x01 => { // tlbr
+                    let entry = self.mmu.tlb.read(self.cp0.index());
+                    self.cp0.set(entry.hi, cp0::ENTRY_HI);
+                    self.cp0.set(entry.lo, cp0::ENTRY_LO);
+                },
+                0x02 => { // tlbwi
+                    let entry = self.tlb_entry();
+                    self.mmu.tlb.write(self.cp0.index(), entry);
+                },
+                0x06 => { // tlbwr
+                    let entry = self.tlb_entry();
+                    self.mmu.tlb.write(self.cp0.random_index(), entry);
+                },
+                0x08 => { // tlbp
+                    let index = match self.mmu.tlb.probe(self.cp0.load(cp0::ENTRY_HI)) {
+                        Some(index) => (index as u32) << 8,
+                        None => cp0::INDEX_P,
+                    };
+                    self.cp0.set(index, cp0::INDEX);
+                },
+                0x10 => self.cp0.restore_from_exception(), // rfe
+                // decode has no row for the others, they are reserved
+                _ => return Err(cp0::Exception::new(cp0::ExcCode::RI)),
+            },
+            _ => return Err(cp0::Exception::new(cp0::ExcCode::RI)),
+        };
+        return Ok(0);
+    }
+
+    fn tlb_entry(&self) -> tlb::TlbEntry {
+        tlb::TlbEntry {
+            hi: self.cp0.load(cp0::ENTRY_HI),
+            lo: self.cp0.load(cp0::ENTRY_LO),
+        }
+    }
+
+    fn take_exception(&mut self, exc: &cp0::Exception, ip: u32) {
+        if self.debug {
+            println!("> Exception: {:?} at 0x{:x}", exc.code, ip);
+        }
+        self.ip = self.cp0.enter_exception(exc, ip);
+    }
 }
 
-fn get_alu_in2(regfile: &reg_file::Registers, instr: &Instruction, ctrl: &ControlBits) -> u32 {
-    if ctrl.reg_dst == 0 {
-        if ctrl.imm_upper == 1 {
-            return (instr.imm16 as u32) << 16;
-        } else {
-            return instr.imm16 as u32;
+/*
+ * Runs the program and returns its exit code: the low byte of $v0 (a0 for
+ * RISC-V) when it ends, EXIT_ERROR when the run could not start or an exception ended it
+ * and EXIT_MAX_STEPS when it ran out of --max-steps.
+ */
+pub fn start(instr_mem: &instr_mem::Memory, data_mem: &mut data_mem::Memory, symbols: &SymbolTable,
+             opts: &Options) -> i32 {
+    if opts.debug {
+        println!("Debug Mode: ON");
+    }
+
+    let mut bus = bus::Bus::new(data_mem);
+    if opts.uart {
+        bus.attach_irq(uart::UART_BASE, Box::new(uart::Uart::stdio()), intc::LINE_UART);
+    }
+    if opts.timer {
+        bus.attach_irq(timer::TIMER_BASE, Box::new(timer::Timer::new()), intc::LINE_TIMER);
+    }
+
+    let mut cpu = Processor::new(opts);
+    if let Some(args) = opts.args.as_ref() {
+        if let Err(msg) = loader::call_main(&mut cpu, bus.mem_mut(), args) {
+            eprintln!("{}", msg);
+            return EXIT_ERROR;
         }
+    }
+    // a saved state replaces the program along with everything else
+    let mut loaded_mem = instr_mem.clone();
+    if let Some(path) = opts.load_state.as_ref() {
+        let res = Snapshot::load(path).and_then(|snap| {
+            snap.restore_instr_mem(&mut loaded_mem);
+            snap.restore(&mut cpu, &mut bus)
+        });
+        if let Err(msg) = res {
+            eprintln!("{}", msg);
+            return EXIT_ERROR;
+        }
+    }
+    let instr_mem = &loaded_mem;
+
+    if opts.trace.path.is_some() {
+        match trace::Tracer::open(&opts.trace) {
+            Ok(tracer) => cpu.observers.push(Box::new(tracer)),
+            Err(msg) => {
+                eprintln!("{}", msg);
+                return EXIT_ERROR;
+            }
+        }
+    }
+    if let Some(path) = opts.vcd.as_ref() {
+        match vcd::VcdWriter::create(path) {
+            Ok(writer) => cpu.observers.push(Box::new(writer)),
+            Err(msg) => {
+                eprintln!("{}", msg);
+                return EXIT_ERROR;
+            }
+        }
+    }
+    if let Some(dir) = opts.datapath.as_ref() {
+        match datapath::DatapathRenderer::create(dir) {
+            Ok(renderer) => cpu.observers.push(Box::new(renderer)),
+            Err(msg) => {
+                eprintln!("{}", msg);
+                return EXIT_ERROR;
+            }
+        }
+    }
+    if opts.profile.enabled() {
+        cpu.observers.push(Box::new(profile::Profiler::new(&opts.profile, symbols)));
+    }
+    if opts.coverage.enabled() {
+        cpu.observers.push(Box::new(coverage::Coverage::new(instr_mem, symbols, &opts.coverage, opts.isa)));
+    }
+    let mut code = None;
+    if let Some(transport) = opts.gdb.as_ref() {
+        gdb::start(&mut cpu, instr_mem, &mut bus, symbols, transport, opts.history);
+    } else if opts.interactive {
+        debugger::start(&mut cpu, instr_mem, &mut bus, symbols, opts.history);
     } else {
-        return regfile.load(instr.rt as usize);
+        let mut steps = 0;
+        while cpu.is_running() {
+            if opts.max_steps == Some(steps) {
+                eprintln!("Stopped after {} instructions (--max-steps)", steps);
+                code = Some(EXIT_MAX_STEPS);
+                break;
+            }
+            let cycle = cpu.step(instr_mem, &mut bus);
+            steps += 1;
+            if let Some(exc) = cycle.exception.filter(|_| !cpu.is_running()) {
+                eprintln!("Exception {:?} at 0x{:08x} ended the program", exc, cycle.pc);
+                code = Some(EXIT_ERROR);
+            }
+            for hit in cpu.watches.take_log() {
+                if hit.action == watch::Action::Log {
+                    println!("Watch {}: {}", hit.watch + 1, hit);
+                }
+            }
+            if let Some(hit) = cpu.watches.take_stop() {
+                println!("Stopped by watch {}: {}", hit.watch + 1, hit);
+                break;
+            }
+        }
+    }
+    cpu.finish();
+    if opts.debug {
+        println!("> Registers:");
+        print!("{}", cpu.regfile.format_with(|reg| cpu.isa.reg_name(reg)));
+    }
+    if let Some(path) = opts.save_state.as_ref() {
+        if let Err(msg) = Snapshot::capture(&cpu, instr_mem, &bus).save(path) {
+            eprintln!("{}", msg);
+        }
+    }
+
+    if let Some(hier) = cpu.hierarchy.as_ref() {
+        cache_report::print_hierarchy(hier);
+        cache_report::print_cycles(cpu.instr_count, cpu.cycles);
     }
+    return code.unwrap_or((cpu.regfile.load(cpu.isa.result_reg()) & 0xff) as i32);
 }
 
-fn calc_ip(ctrl: &ControlBits, ip: u32, addr: u32, alu_zero: u32) -> u32 {
-    if ctrl.branch == 1 && alu_zero == 1 {
-        return (ip & 0xffff_0000) | addr; // addr only 16 bits max
-    } else if ctrl.jump == 1 {
-        return (ip & 0xff00_0000) | addr; // addr 28 bit max 
-    } else {
-        return ip + 4;
+/*
+ * Registers the instruction reads through the register file ports.
+ */
+fn regs_read(ctrl: &ControlBits, instr: &Instruction) -> Vec<u8> {
+    let mut regs = Vec::new();
+    if ctrl.cop0 {
+        // only mtc0 reads a gpr
+        if instr.rs == 0x04 {
+            regs.push(instr.rt);
+        }
+        return regs;
+    }
+    // mfhi and mflo read HI or LO
+    if ctrl.pc_src == PcSource::Jump || ctrl.alu_src == AluSrc::UpperImm || ctrl.hilo_read() {
+        return regs;
+    }
+    // rotr reads only rt
+    if !ctrl.alu_shamt {
+        regs.push(instr.rs);
     }
+    // lwl, lwr and ins merge into rt, mthi and mtlo only move rs
+    let moves_rs = matches!(ctrl.hilo, HiLo::Mthi | HiLo::Mtlo);
+    let reads_rt = (ctrl.alu_src == AluSrc::Rt && ctrl.pc_src != PcSource::JumpReg && !moves_rs) ||
+        ctrl.mem_write() || ctrl.mem_part() || ctrl.insert;
+    if reads_rt {
+        regs.push(instr.rt);
+    }
+    return regs;
 }
 
-fn fill_control_bits(ctrl: &mut ControlBits, instr: &Instruction) {
-    match instr.opcode {
-        0x00 => { // r-format
-            ctrl.reg_dst = 1;
-            ctrl.reg_write = 1;
-            ctrl.branch = 0;
-            ctrl.jump = 0;
-            ctrl.mem_read = 0;
-            ctrl.mem_write = 0;
-            ctrl.mem_to_reg = 0;
-            ctrl.mem_by_byte = 0;
-            ctrl.imm_upper = 0;
-            match instr.funct {
-                // 0x00 => panic!("Error: Unsupported FUNCT [0x00 (sll)"), // sll
-                0x00 => { // sll, NOP for now
-                    ctrl.alu_op = 0;
-                    ctrl.alu_bnegate = 0;
-                    ctrl.not_res = 0;
-                }
-                0x02 => panic!("Error: Unsupported FUNCT [0x02 (srl)"), // srl
-                0x03 => panic!("Error: Unsupported FUNCT [0x03 (sra)"), // sra
-                0x08 => panic!("Error: Unsupported FUNCT [0x08 (jr)]"), // jr
-                0x10 => panic!("Error: Unsupported FUNCT [0x10 (mfhi)"), // mfhi
-                0x11 => panic!("Error: Unsupported FUNCT [0x11 (mthi)"), // mthi
-                0x12 => panic!("Error: Unsupported FUNCT [0x12 (mflo)"), // mflo
-                0x13 => panic!("Error: Unsupported FUNCT [0x13 (mtlo)"), // mtlo
-                0x18 => panic!("Error: Unsupported FUNCT [0x18 (mult)"), // mult
-                0x19 => panic!("Error: Unsupported FUNCT [0x19 (multu)"), // multu
-                0x1A => panic!("Error: Unsupported FUNCT [0x1A (div)]"), // div
-                0x1B => panic!("Error: Unsupported FUNCT [0x1B (divu)]"), // divu
-                0x20 => { // add
-                    ctrl.alu_op = 2;
-                    ctrl.alu_bnegate = 0;
-                    ctrl.not_res = 0;
-                },
-                0x21 => { // addu
-                    ctrl.alu_op = 2;
-                    ctrl.alu_bnegate = 0;
-                    ctrl.not_res = 0;
-                },
-                0x22 => { // sub
-                    ctrl.alu_op = 2;
-                    ctrl.alu_bnegate = 1;
-                    ctrl.not_res = 0;
-                },
-                0x23 => { // subu
-                    ctrl.alu_op = 2;
-                    ctrl.alu_bnegate = 1;
-                    ctrl.not_res = 0;
-                },
-                0x24 => { // and
-                    ctrl.alu_op = 0;
-                    ctrl.alu_bnegate = 0;
-                    ctrl.not_res = 0;
-                },
-                0x25 => { // or
-                    ctrl.alu_op = 1;
-                    ctrl.alu_bnegate = 0;
-                    ctrl.not_res = 0;
-                },
-                0x26 => { // xor
-                    ctrl.alu_op = 4;
-                    ctrl.alu_bnegate = 0;
-                    ctrl.not_res = 0;
-                },
-                0x27 => { // nor
-                    ctrl.alu_op = 1;
-                    ctrl.alu_bnegate = 0;
-                    ctrl.not_res = 1;
-                },
-                0x2A => { // slt
-                    ctrl.alu_op = 3;
-                    ctrl.alu_bnegate = 1;
-                    ctrl.not_res = 0;
-                },
-                0x2B => { // sltu
-                    ctrl.alu_op = 3;
-                    ctrl.alu_bnegate = 1;
-                    ctrl.not_res = 0;
-                },
-                funct => panic!("Error: Unsupported FUNCT [{:X}]", funct),
-            };
-        },
-        0x02 => { // j
-            ctrl.reg_dst = 0;
-            ctrl.reg_write = 0;
-
-            ctrl.branch = 0;
-            ctrl.jump = 1;
-
-            ctrl.mem_read = 0;
-            ctrl.mem_write = 0;
-            ctrl.mem_to_reg = 0;
-            ctrl.mem_by_byte = 0;
-
-            ctrl.alu_op = 0; // TODO: alu op for jump
-            ctrl.alu_bnegate = 0;
-            ctrl.not_res = 0;
-            ctrl.imm_upper = 0;
-        },
-        0x03 => panic!("Error: Unsupported OPCODE [0x03 (jal)]"),
-        0x04 => { // beq
-            ctrl.reg_dst = 1;
-            ctrl.reg_write = 0;
-
-            ctrl.branch = 1;
-            ctrl.jump = 0;
-
-            ctrl.mem_read = 0;
-            ctrl.mem_write = 0;
-            ctrl.mem_to_reg = 0;
-            ctrl.mem_by_byte = 0;
-
-            ctrl.alu_op = 4;
-            ctrl.alu_bnegate = 0;
-            ctrl.not_res = 1;
-            ctrl.imm_upper = 0;
-        },
-        0x05 => { // bne
-            ctrl.reg_dst = 1;
-            ctrl.reg_write = 0;
-
-            ctrl.branch = 1;
-            ctrl.jump = 0;
-
-            ctrl.mem_read = 0;
-            ctrl.mem_write = 0;
-            ctrl.mem_to_reg = 0;
-            ctrl.mem_by_byte = 0;
-
-            ctrl.alu_op = 4;
-            ctrl.alu_bnegate = 0;
-            ctrl.not_res = 0;
-            ctrl.imm_upper = 0;
-        },
-        0x08 => { // addi
-            ctrl.reg_dst = 0;
-            ctrl.reg_write = 1;
-
-            ctrl.branch = 0;
-            ctrl.jump = 0;
-
-            ctrl.mem_read = 0;
-            ctrl.mem_write = 0;
-            ctrl.mem_to_reg = 0;
-            ctrl.mem_by_byte = 0;
-
-            ctrl.alu_op = 2;
-            ctrl.alu_bnegate = 0;
-            ctrl.not_res = 0;
-            ctrl.imm_upper = 0;
-        },
-        0x09 => { // addiu
-            ctrl.reg_dst = 0;
-            ctrl.reg_write = 1;
-
-            ctrl.branch = 0;
-            ctrl.jump = 0;
-
-            ctrl.mem_read = 0;
-            ctrl.mem_write = 0;
-            ctrl.mem_to_reg = 0;
-            ctrl.mem_by_byte = 0;
-
-            ctrl.alu_op = 2;
-            ctrl.alu_bnegate = 0;
-            ctrl.not_res = 0;
-            ctrl.imm_upper = 0;
-        },
-        0x0A => { // slti
-            ctrl.reg_dst = 1;
-            ctrl.reg_write = 1;
-
-            ctrl.branch = 0;
-            ctrl.jump = 0;
-
-            ctrl.mem_read = 0;
-            ctrl.mem_write = 0;
-            ctrl.mem_to_reg = 0;
-            ctrl.mem_by_byte = 0;
-
-            ctrl.alu_op = 3;
-            ctrl.alu_bnegate = 1;
-            ctrl.not_res = 0;
-            ctrl.imm_upper = 0;
-        },
-        0x0B => { // sltiu
-            ctrl.reg_dst = 1;
-            ctrl.reg_write = 1;
-
-            ctrl.branch = 0;
-            ctrl.jump = 0;
-
-            ctrl.mem_read = 0;
-            ctrl.mem_write = 0;
-            ctrl.mem_to_reg = 0;
-            ctrl.mem_by_byte = 0;
-
-            ctrl.alu_op = 3;
-            ctrl.alu_bnegate = 1;
-            ctrl.not_res = 0;
-            ctrl.imm_upper = 0;
-        },
-        0x0C => { // andi
-            ctrl.reg_dst = 0;
-            ctrl.reg_write = 1;
-
-            ctrl.branch = 0;
-            ctrl.jump = 0;
-
-            ctrl.mem_read = 0;
-            ctrl.mem_write = 0;
-            ctrl.mem_to_reg = 0;
-            ctrl.mem_by_byte = 0;
-
-            ctrl.alu_op = 0;
-            ctrl.alu_bnegate = 0;
-            ctrl.not_res = 0;
-            ctrl.imm_upper = 0;
-        },
-        0x0D => { // ori
-            ctrl.reg_dst = 0;
-            ctrl.reg_write = 1;
-
-            ctrl.branch = 0;
-            ctrl.jump = 0;
-
-            ctrl.mem_read = 0;
-            ctrl.mem_write = 0;
-            ctrl.mem_to_reg = 0;
-            ctrl.mem_by_byte = 0;
-
-            ctrl.alu_op = 1;
-            ctrl.alu_bnegate = 0;
-            ctrl.not_res = 0;
-            ctrl.imm_upper = 0;
-        },
-        0x0F => { // lui
-            ctrl.reg_dst = 0;
-            ctrl.reg_write = 1;
-
-            ctrl.branch = 0;
-            ctrl.jump = 0;
-
-            ctrl.mem_read = 0;
-            ctrl.mem_write = 0;
-            ctrl.mem_to_reg = 0;
-            ctrl.mem_by_byte = 0;
-
-            ctrl.alu_op = 1;
-            ctrl.alu_bnegate = 0;
-            ctrl.not_res = 0;
-            ctrl.imm_upper = 1;
-        },
-        0x20 => { // lb
-            ctrl.reg_dst = 0;
-            ctrl.reg_write = 1;
-
-            ctrl.branch = 0;
-            ctrl.jump = 0;
-
-            ctrl.mem_read = 1;
-            ctrl.mem_write = 0;
-            ctrl.mem_to_reg = 1;
-            ctrl.mem_by_byte = 1;
-
-            ctrl.alu_op = 2;
-            ctrl.alu_bnegate = 0;
-            ctrl.not_res = 0;
-            ctrl.imm_upper = 0;
-        },
-        0x23 => { // lw
-            ctrl.reg_dst = 0;
-            ctrl.reg_write = 1;
-
-            ctrl.branch = 0;
-            ctrl.jump = 0;
-
-            ctrl.mem_read = 1;
-            ctrl.mem_write = 0;
-            ctrl.mem_to_reg = 1;
-            ctrl.mem_by_byte = 0;
-
-            ctrl.alu_op = 2;
-            ctrl.alu_bnegate = 0;
-            ctrl.not_res = 0;
-            ctrl.imm_upper = 0;
-        },
-        0x28 => { // sb
-            ctrl.reg_dst = 0;
-            ctrl.reg_write = 0;
-
-            ctrl.branch = 0;
-            ctrl.jump = 0;
-
-            ctrl.mem_read = 0;
-            ctrl.mem_write = 1;
-            ctrl.mem_to_reg = 0;
-            ctrl.mem_by_byte = 1;
-
-            ctrl.alu_op = 2;
-            ctrl.alu_bnegate = 0;
-            ctrl.not_res = 0;
-            ctrl.imm_upper = 0;
-        },
-        0x2B => { // sw
-            ctrl.reg_dst = 0;
-            ctrl.reg_write = 0;
-
-            ctrl.branch = 0;
-            ctrl.jump = 0;
-
-            ctrl.mem_read = 0;
-            ctrl.mem_write = 1;
-            ctrl.mem_to_reg = 0;
-            ctrl.mem_by_byte = 0;
-
-            ctrl.alu_op = 2;
-            ctrl.alu_bnegate = 0;
-            ctrl.not_res = 0;
-            ctrl.imm_upper = 0;
-        },
-        opcode => panic!("Error: Unsupported OPCODE [{:X}]", opcode)
+/*
+ * Reads a big endian value from memory without going through the bus.
+ */
+fn peek(mem: &data_mem::Memory, addr: u32, size: u32) -> u32 {
+    let mut val = 0;
+    for i in 0..size as usize {
+        let at = addr as usize + i;
+        let byte = if at < data_mem::Memory::get_size() {mem.read(at)} else {0};
+        val = (val << 8) | byte as u32;
+    }
+    return val;
+}
+
+fn get_alu_in1(regfile: &reg_file::Registers, instr: &Instruction, ctrl: &ControlBits) -> u32 {
+    if ctrl.alu_shamt {
+        return instr.shamt as u32;
+    }
+    let reg_num = instr.rs as usize;
+    return regfile.load(reg_num);
+}
+
+fn get_alu_in2(regfile: &reg_file::Registers, instr: &Instruction, ctrl: &ControlBits) -> u32 {
+    return match ctrl.alu_src {
+        AluSrc::Rt => regfile.load(instr.rt as usize),
+        AluSrc::SignImm => instr.imm16 as i16 as u32,
+        AluSrc::ZeroImm => instr.imm16 as u32,
+        AluSrc::UpperImm => (instr.imm16 as u32) << 16,
+        AluSrc::Field => (instr.rd as u32) << 5 | instr.shamt as u32,
     };
 }
+
+fn calc_ip(ctrl: &ControlBits, ip: u32, addr: u32, alu_res: u32) -> u32 {
+    return match ctrl.pc_src {
+        _ if ctrl.branch_taken(alu_res) => (ip & 0xffff_0000) | addr, // addr only 16 bits max
+        PcSource::Jump => (ip & 0xff00_0000) | addr, // addr 28 bit max
+        PcSource::JumpReg => addr,
+        _ => ip + 4,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::demos::assembler::reg_macros::*;
+    use crate::demos::assembler::instr_macros::*;
+    use crate::demos::assembler::mem_loader::MemLoader;
+
+    fn run(mem: instr_mem::Memory, data_mem: &mut data_mem::Memory, opts: &Options) -> Processor {
+        let mut bus = bus::Bus::new(data_mem);
+        let mut cpu = Processor::new(opts);
+        while cpu.is_running() {
+            cpu.step(&mem, &mut bus);
+        }
+        return cpu;
+    }
+
+    #[test]
+    fn test_tlb_refill() {
+        let mut loader = MemLoader::new(instr_mem::Memory::new());
+
+        // refill handler at 0x8000_0000, maps the page to physical page 0
+        loader.load_instr( ADDI(K1(), ZERO(), 0x600) ); // V | D
+        loader.load_instr( MTC0(K1(), cp0::ENTRY_LO as u8) );
+        loader.load_instr( TLBWR() );
+        loader.load_instr( MFC0(K0(), cp0::EPC as u8) );
+        loader.load_instr( RFE() );
+        loader.load_instr( JR  (K0()) );
+
+        // boot code at 0x8000_00c0
+        loader.set_ip(0xc0);
+        loader.load_instr( LUI (T0(),         0xcafe) );
+        loader.load_instr( ORI (T0(), T0(),   0xbabe) );
+        loader.load_instr( LUI (S0(),         0x0040) );
+        loader.load_instr( ORI (S0(), S0(),   0x0042) );
+        loader.load_instr( SWL (T0(), 0     , S0()  ) );
+        loader.load_instr( SWR (T0(), 3     , S0()  ) );
+        loader.load_instr( LWL (T1(), 0     , S0()  ) );
+        loader.load_instr( LWR (T1(), 3     , S0()  ) );
+
+        let opts = Options {
+            mmu: true,
+            entry: 0x8000_00c0,
+            ..Options::default()
+        };
+        let mut data_mem = data_mem::Memory::new();
+        let cpu = run(loader.return_mem(), &mut data_mem, &opts);
+
+        assert_eq!(cpu.regfile.load(T1() as usize), 0xcafebabe);
+        assert_eq!(data_mem.read(0x42), 0xca);
+        assert_eq!(data_mem.read(0x45), 0xbe);
+        assert_eq!((cpu.cp0.load(cp0::CAUSE) >> 2) & 0x1f, cp0::ExcCode::TlbS as u32);
+        assert_eq!(cpu.cp0.load(cp0::BAD_VADDR), 0x0040_0042);
+        assert!(cpu.mmu.tlb.probe(0x0040_0000).is_some());
+    }
+
+    #[test]
+    fn test_tlbp_tlbr() {
+        let mut loader = MemLoader::new(instr_mem::Memory::new());
+        loader.load_instr( LUI (T0(),         0x0050) );
+        loader.load_instr( ADDI(T1(), ZERO(), 0x200 ) ); // V
+        loader.load_instr( ADDI(T2(), ZERO(), 0x500 ) ); // index 5
+        loader.load_instr( MTC0(T0(), cp0::ENTRY_HI as u8) );
+        loader.load_instr( MTC0(T1(), cp0::ENTRY_LO as u8) );
+        loader.load_instr( MTC0(T2(), cp0::INDEX as u8) );
+        loader.load_instr( TLBWI() );
+        loader.load_instr( MTC0(ZERO(), cp0::INDEX as u8) );
+        loader.load_instr( MTC0(ZERO(), cp0::ENTRY_LO as u8) );
+        loader.load_instr( TLBP() );
+        loader.load_instr( TLBR() );
+        loader.load_instr( MFC0(S0(), cp0::INDEX as u8) );
+        loader.load_instr( MFC0(S1(), cp0::ENTRY_LO as u8) );
+
+        let opts = Options {
+            mmu: true,
+            entry: 0x8000_0000,
+            ..Options::default()
+        };
+        let mut data_mem = data_mem::Memory::new();
+        let cpu = run(loader.return_mem(), &mut data_mem, &opts);
+        assert_eq!(cpu.regfile.load(S0() as usize), 0x500);
+        assert_eq!(cpu.regfile.load(S1() as usize), 0x200);
+    }
+
+    #[test]
+    fn test_user_mode_address_error() {
+        let mut loader = MemLoader::new(instr_mem::Memory::new());
+        // general exception handler at 0x8000_0080 stops the run
+        loader.set_ip(0x80);
+        loader.load_instr( MFC0(S0(), cp0::CAUSE as u8) );
+        loader.load_instr( LUI (T0(),         0x8000) );
+        loader.load_instr( ORI (T0(), T0(),   0x0100) );
+        loader.load_instr( JR  (T0()) );
+
+        let opts = Options {
+            mmu: true,
+            entry: 0x8000_0000,
+            ..Options::default()
+        };
+        let mut cpu = Processor::new(&opts);
+        // user mode, kseg0 is out of reach
+        cpu.cp0.write(cp0::STATUS_KUC, cp0::STATUS);
+        let mem = loader.return_mem();
+        let mut data_mem = data_mem::Memory::new();
+        let mut bus = bus::Bus::new(&mut data_mem);
+        while cpu.is_running() {
+            cpu.step(&mem, &mut bus);
+        }
+        assert_eq!((cpu.regfile.load(S0() as usize) >> 2) & 0x1f, cp0::ExcCode::AdEL as u32);
+        assert_eq!(cpu.cp0.load(cp0::EPC), 0x8000_0000);
+    }
+
+    #[test]
+    fn test_misaligned_fetch() {
+        let mut loader = MemLoader::new(instr_mem::Memory::new());
+        loader.load_instr( ADDI(T0(), ZERO(), 0x12) );
+        loader.load_instr( JR  (T0()) );
+        let mem = loader.return_mem();
+
+        let mut data_mem = data_mem::Memory::new();
+        let mut bus = bus::Bus::new(&mut data_mem);
+        let mut cpu = Processor::new(&Options::default());
+        cpu.step(&mem, &mut bus);
+        cpu.step(&mem, &mut bus);
+        assert_eq!(cpu.ip, 0x12);
+        let cycle = cpu.step(&mem, &mut bus);
+        assert_eq!(cycle.exception, Some(cp0::ExcCode::AdEL));
+        assert_eq!(cpu.cp0.load(cp0::BAD_VADDR), 0x12);
+        assert_eq!(cpu.cp0.load(cp0::EPC), 0x12);
+        assert_eq!(cpu.ip, 0x8000_0080);
+    }
+
+    #[test]
+    fn test_kseg1_uncached() {
+        let mut loader = MemLoader::new(instr_mem::Memory::new());
+        loader.load_instr( LUI (T0(),         0xa000) );
+        loader.load_instr( LW  (T1(), 0x40  , T0()  ) ); // kseg1
+        loader.load_instr( LUI (T0(),         0x8000) );
+        loader.load_instr( LW  (T1(), 0x80  , T0()  ) ); // kseg0
+        let mem = loader.return_mem();
+
+        let opts = Options {
+            mmu: true,
+            entry: 0x8000_0000,
+            hierarchy: HierarchyConfig {
+                l1d: Some(cache::CacheConfig::default()),
+                ..HierarchyConfig::default()
+            },
+            ..Options::default()
+        };
+        let mut cpu = Processor::new(&opts);
+        let mut data_mem = data_mem::Memory::new();
+        let mut bus = bus::Bus::new(&mut data_mem);
+        for _ in 0..4 {
+            cpu.step(&mem, &mut bus);
+        }
+        let l1d = cpu.hierarchy.as_ref().unwrap().l1d.as_ref().unwrap();
+        assert!(!l1d.contains(0x40));
+        assert!(l1d.contains(0x80));
+    }
+
+    #[test]
+    fn test_kseg1_part_access() {
+        let mut loader = MemLoader::new(instr_mem::Memory::new());
+        loader.load_instr( LUI (T0(),         0xa000) );
+        loader.load_instr( LUI (T1(),         0x1122) );
+        loader.load_instr( ORI (T1(), T1(),   0x3344) );
+        loader.load_instr( SWL (T1(), 0x42  , T0()  ) ); // kseg1
+        loader.load_instr( LWR (T2(), 0x43  , T0()  ) );
+        let mem = loader.return_mem();
+
+        let mut cpu = Processor::new(&Options { mmu: true, entry: 0x8000_0000, ..Options::default() });
+        let mut data_mem = data_mem::Memory::new();
+        let mut bus = bus::Bus::new(&mut data_mem);
+        let mut cycles = Vec::new();
+        for _ in 0..5 {
+            cycles.push(cpu.step(&mem, &mut bus));
+        }
+        // uncached part accesses still show the aligned word in memory
+        let swl = cycles[3].mem.unwrap();
+        assert_eq!((swl.addr, swl.paddr, swl.old, swl.value), (0xa000_0040, 0x40, 0, 0x1122));
+        let lwr = cycles[4].mem.unwrap();
+        assert_eq!((lwr.addr, lwr.value), (0xa000_0040, 0x1122));
+    }
+
+    #[test]
+    fn test_uart() {
+        let mut loader = MemLoader::new(instr_mem::Memory::new());
+        loader.load_instr( LUI (S0(),         0xffff) );
+        loader.load_instr( LW  (T0(), 0x0   , S0()  ) ); // receiver control
+        loader.load_instr( LB  (T1(), 0x7   , S0()  ) ); // receiver data
+        loader.load_instr( LW  (T2(), 0x0   , S0()  ) );
+        loader.load_instr( SW  (T1(), 0xc   , S0()  ) ); // echo it back
+        let mem = loader.return_mem();
+
+        let mut uart = uart::Uart::new(None, Box::new(std::io::sink()));
+        uart.push_input(b"A");
+        let mut data_mem = data_mem::Memory::new();
+        let mut bus = bus::Bus::new(&mut data_mem);
+        bus.attach(uart::UART_BASE, Box::new(uart));
+
+        let mut cpu = Processor::new(&Options::default());
+        while cpu.is_running() {
+            cpu.step(&mem, &mut bus);
+        }
+        assert_eq!(cpu.regfile.load(T0() as usize), 1);
+        assert_eq!(cpu.regfile.load(T1() as usize), 'A' as u32);
+        assert_eq!(cpu.regfile.load(T2() as usize), 0);
+        // nothing leaked into memory
+        assert_eq!(data_mem.read(0xc), 0);
+    }
+
+    #[test]
+    fn test_count_compare_interrupt() {
+        let mut loader = MemLoader::new(instr_mem::Memory::new());
+
+        // general exception handler at 0x8000_0080
+        loader.set_ip(0x80);
+        loader.load_instr( MTC0(ZERO(), cp0::COMPARE as u8) ); // ack
+        loader.load_instr( ADDI(S1(), S1(),   1) );
+        loader.load_instr( MFC0(K0(), cp0::EPC as u8) );
+        loader.load_instr( RFE() );
+        loader.load_instr( JR  (K0()) );
+
+        // boot code at 0x8000_00c0
+        loader.set_ip(0xc0);
+        loader.load_instr( ADDI(T0(), ZERO(), 6) );
+        loader.load_instr( MTC0(T0(), cp0::COMPARE as u8) );
+        loader.load_instr( ORI (T1(), ZERO(), 0x8001) ); // IM7 | IEc
+        loader.load_instr( MTC0(T1(), cp0::STATUS as u8) );
+        loader.load_instr( ADDI(S0(), S0(),   1) );
+        loader.load_instr( ADDI(S0(), S0(),   1) ); // interrupted here
+        loader.load_instr( ADDI(S0(), S0(),   1) );
+
+        let opts = Options {
+            mmu: true,
+            entry: 0x8000_00c0,
+            ..Options::default()
+        };
+        let mut data_mem = data_mem::Memory::new();
+        let cpu = run(loader.return_mem(), &mut data_mem, &opts);
+
+        assert_eq!(cpu.regfile.load(S0() as usize), 3);
+        assert_eq!(cpu.regfile.load(S1() as usize), 1);
+        assert_eq!(cpu.cp0.load(cp0::EPC), 0x8000_00d4);
+        assert_eq!((cpu.cp0.load(cp0::CAUSE) >> 2) & 0x1f, cp0::ExcCode::Int as u32);
+        assert_eq!(cpu.cp0.load(cp0::CAUSE) & cp0::CAUSE_IP, 0);
+    }
+
+    #[test]
+    fn test_timer_interrupt() {
+        let mut loader = MemLoader::new(instr_mem::Memory::new());
+
+        // general exception handler at 0x8000_0080
+        loader.set_ip(0x80);
+        loader.load_instr( ADDI(T2(), ZERO(), 1) );
+        loader.load_instr( SW  (T2(), 0x1c  , S2()  ) ); // clear expired
+        loader.load_instr( ADDI(S1(), S1(),   1) );
+        loader.load_instr( MFC0(K0(), cp0::EPC as u8) );
+        loader.load_instr( RFE() );
+        loader.load_instr( JR  (K0()) );
+
+        // boot code at 0x8000_00c0, maps the device page then starts a one
+        // shot timer
+        loader.set_ip(0xc0);
+        loader.load_instr( LUI (S2(),         0xffff) );
+        loader.load_instr( MTC0(S2(), cp0::ENTRY_HI as u8) );
+        loader.load_instr( ORI (K1(), S2(),   0x700) ); // D | V | G
+        loader.load_instr( MTC0(K1(), cp0::ENTRY_LO as u8) );
+        loader.load_instr( TLBWI() );
+        loader.load_instr( ADDI(T0(), ZERO(), 3) );
+        loader.load_instr( SW  (T0(), 0x14  , S2()  ) ); // reload
+        loader.load_instr( SW  (T0(), 0x10  , S2()  ) ); // enable | irq enable
+        loader.load_instr( ADDI(T1(), ZERO(), 0x0801) ); // IM3 | IEc
+        loader.load_instr( MTC0(T1(), cp0::STATUS as u8) );
+        loader.load_instr( ADDI(S0(), S0(),   1) );
+        loader.load_instr( ADDI(S0(), S0(),   1) );
+        loader.load_instr( ADDI(S0(), S0(),   1) );
+        let mem = loader.return_mem();
+
+        let mut data_mem = data_mem::Memory::new();
+        let mut bus = bus::Bus::new(&mut data_mem);
+        bus.attach_irq(timer::TIMER_BASE, Box::new(timer::Timer::new()), intc::LINE_TIMER);
+
+        let opts = Options {
+            mmu: true,
+            entry: 0x8000_00c0,
+            ..Options::default()
+        };
+        let mut cpu = Processor::new(&opts);
+        while cpu.is_running() {
+            cpu.step(&mem, &mut bus);
+        }
+        assert_eq!(cpu.regfile.load(S0() as usize), 3);
+        // one shot, only interrupts once
+        assert_eq!(cpu.regfile.load(S1() as usize), 1);
+        assert_eq!(cpu.cp0.load(cp0::EPC), 0x8000_00e8);
+    }
+
+    #[test]
+    fn test_watches() {
+        let mut loader = MemLoader::new(instr_mem::Memory::new());
+        loader.load_instr( ORI (T0(), ZERO(), 0xbabe) );
+        loader.load_instr( ADDI(S0(), ZERO(), 0x40)   );
+        loader.load_instr( SW  (T0(), 0     , S0()  ) );
+        loader.load_instr( LB  (T1(), 3     , S0()  ) );
+        loader.load_instr( SB  (T1(), 0     , S0()  ) );
+        let mem = loader.return_mem();
+
+        let opts = Options {
+            watches: vec![
+                watch::Watch::parse("write:0x40+4,log", Isa::Mips).unwrap(),
+                watch::Watch::parse("read:0x43+1,log", Isa::Mips).unwrap(),
+                watch::Watch::parse("reg:$t1,log", Isa::Mips).unwrap(),
+            ],
+            ..Options::default()
+        };
+        let mut data_mem = data_mem::Memory::new();
+        let mut cpu = run(mem, &mut data_mem, &opts);
+
+        let log: Vec<String> = cpu.watches.take_log().iter().map(|hit| hit.to_string()).collect();
+        assert_eq!(log, vec![
+            "0x00000008: write 0x00000040 (4 bytes) 0x0 -> 0xbabe",
+            "0x0000000c: read 0x00000043 (1 bytes) 0xbe -> 0xbe",
+            "0x0000000c: $t1 0x0 -> 0xffffffbe",
+            "0x00000010: write 0x00000040 (1 bytes) 0x0 -> 0xbe",
+        ]);
+        assert_eq!(cpu.watches.take_stop(), None);
+    }
+
+    #[test]
+    fn test_set_less_than() {
+        let mut loader = MemLoader::new(instr_mem::Memory::new());
+        loader.load_instr( ADDI (T0(), ZERO(), 0xffff) ); // -1
+        loader.load_instr( ADDI (T1(), ZERO(), 1) );
+        loader.load_instr( SLT  (T2(), T0(),   T1()) );
+        loader.load_instr( SLTU (T3(), T0(),   T1()) );
+        loader.load_instr( SLTI (T4(), T0(),   0) );
+        loader.load_instr( SLTIU(T5(), T1(),   0xffff) );
+        loader.load_instr( DIV  (T0(), T1()) ); // reserved
+
+        let mut data_mem = data_mem::Memory::new();
+        let mut bus = bus::Bus::new(&mut data_mem);
+        let mem = loader.return_mem();
+        let mut cpu = Processor::new(&Options::default());
+        for _ in 0..6 {
+            cpu.step(&mem, &mut bus);
+        }
+        assert_eq!(cpu.regfile.load(T2() as usize), 1);
+        assert_eq!(cpu.regfile.load(T3() as usize), 0);
+        // slti and sltiu write rt, not rd
+        assert_eq!(cpu.regfile.load(T4() as usize), 1);
+        assert_eq!(cpu.regfile.load(T5() as usize), 1);
+        assert_eq!(cpu.regfile.load(ZERO() as usize), 0);
+
+        let cycle = cpu.step(&mem, &mut bus);
+        assert_eq!(cycle.exception, Some(cp0::ExcCode::RI));
+        assert_eq!(cpu.ip, 0x8000_0080);
+    }
+
+    #[test]
+    fn test_shifts() {
+        let mut loader = MemLoader::new(instr_mem::Memory::new());
+        loader.load_instr( ADDI(T0(), ZERO(), 0xfff0) ); // -16
+        loader.load_instr( ADDI(T1(), ZERO(), 0x24) ); // shifts by 4
+        loader.load_instr( SLL (T2(), T0(),   4) );
+        loader.load_instr( SRL (T3(), T0(),   28) );
+        loader.load_instr( SRA (T4(), T0(),   2) );
+        loader.load_instr( SLLV(T5(), T0(),   T1()) );
+        loader.load_instr( SRLV(T6(), T0(),   T1()) );
+        loader.load_instr( SRAV(T7(), T0(),   T1()) );
+        loader.load_instr( 0 ); // nop
+
+        let mut data_mem = data_mem::Memory::new();
+        let mut bus = bus::Bus::new(&mut data_mem);
+        let mem = loader.return_mem();
+        let mut cpu = Processor::new(&Options::default());
+        for _ in 0..9 {
+            assert_eq!(cpu.step(&mem, &mut bus).exception, None);
+        }
+        assert_eq!(cpu.regfile.load(T2() as usize), 0xffff_ff00);
+        assert_eq!(cpu.regfile.load(T3() as usize), 0xf);
+        assert_eq!(cpu.regfile.load(T4() as usize), 0xffff_fffc);
+        assert_eq!(cpu.regfile.load(T5() as usize), 0xffff_ff00);
+        assert_eq!(cpu.regfile.load(T6() as usize), 0x0fff_ffff);
+        assert_eq!(cpu.regfile.load(T7() as usize), 0xffff_ffff);
+        assert_eq!(cpu.regfile.load(ZERO() as usize), 0);
+        assert_eq!(cpu.ip, 9 * 4);
+    }
+
+    #[test]
+    fn test_hilo() {
+        let mut loader = MemLoader::new(instr_mem::Memory::new());
+        loader.load_instr( ADDI (T0(), ZERO(), 0xfffd) ); // -3
+        loader.load_instr( ORI  (T1(), ZERO(), 0x1280) );
+        loader.load_instr( MULT (T0(), T1()) );
+        loader.load_instr( ADDI (S0(), ZERO(), 2) );
+        loader.load_instr( MADD (S0(), T1()) ); // -0x1280
+        loader.load_instr( MFLO (T2()) );
+        loader.load_instr( MFHI (T3()) );
+        loader.load_instr( MULTU(T0(), T1()) );
+        loader.load_instr( MFHI (T4()) );
+        loader.load_instr( MTHI (T1()) );
+        loader.load_instr( MTLO (ZERO()) );
+        loader.load_instr( MFHI (T5()) );
+        loader.load_instr( MFLO (T6()) );
+
+        let mut data_mem = data_mem::Memory::new();
+        let mut bus = bus::Bus::new(&mut data_mem);
+        let mem = loader.return_mem();
+        let mut cpu = Processor::new(&Options::default());
+        let mut cycles = Vec::new();
+        for _ in 0..13 {
+            cycles.push(cpu.step(&mem, &mut bus));
+        }
+        assert_eq!(cpu.regfile.load(T2() as usize), 0xffff_ed80);
+        assert_eq!(cpu.regfile.load(T3() as usize), 0xffff_ffff);
+        assert_eq!(cpu.regfile.load(T4() as usize), 0x127f);
+        assert_eq!(cpu.regfile.load(T5() as usize), 0x1280);
+        assert_eq!(cpu.regfile.load(T6() as usize), 0);
+        assert_eq!((cpu.hi, cpu.lo), (0x1280, 0));
+        // mfhi reads no register, mthi only rs
+        assert!(cycles[6].reads.is_empty());
+        assert_eq!(cycles[9].reads, vec![(T1(), 0x1280)]);
+    }
+
+    #[test]
+    fn test_mips32() {
+        let mut loader = MemLoader::new(instr_mem::Memory::new());
+        loader.load_instr( ADDI(T0(), ZERO(), 0xfffd) ); // -3
+        loader.load_instr( ORI (T1(), ZERO(), 0x1280) );
+        loader.load_instr( MUL (T2(), T0(),   T1()) );
+        loader.load_instr( CLZ (T3(), T1()) );
+        loader.load_instr( CLO (T4(), T0()) );
+        loader.load_instr( SYNC() );
+        loader.load_instr( SEB (T5(), T1()) );
+        loader.load_instr( SEH (T6(), T0()) );
+        loader.load_instr( WSBH(T7(), T1()) );
+        // hi/lo = -3 * 0x1280 + 2 * 0x1280, then - 0x1280 * 2 unsigned
+        loader.load_instr( MADD (T0(), T1()) );
+        loader.load_instr( ADDI (S0(), ZERO(), 2) );
+        loader.load_instr( MADD (S0(), T1()) );
+        loader.load_instr( MSUBU(T1(), S0()) );
+        loader.load_instr( MOVZ (S1(), T1(),   ZERO()) ); // written
+        loader.load_instr( MOVN (S2(), T1(),   ZERO()) ); // not written
+        loader.load_instr( EXT  (S3(), T1(),   4,      8) );
+        loader.load_instr( INS  (T0(), T1(),   8,      8) );
+        loader.load_instr( ROTR (S4(), T1(),   8) );
+        loader.load_instr( ROTRV(S5(), T1(),   S0()) );
+        loader.load_instr( TNE  (T1(), T1()) ); // not taken
+        loader.load_instr( TGEI (T0(), 0) );
+        loader.load_instr( TLTIU(T1(), 0xffff) ); // taken, the immediate is sign extended
+
+        let mut data_mem = data_mem::Memory::new();
+        let mut bus = bus::Bus::new(&mut data_mem);
+        let mem = loader.return_mem();
+        let mut cpu = Processor::new(&Options::default());
+        for _ in 0..20 {
+            cpu.step(&mem, &mut bus);
+        }
+        assert_eq!(cpu.regfile.load(T2() as usize), (-3i32 * 0x1280) as u32);
+        assert_eq!(cpu.regfile.load(T3() as usize), 19);
+        assert_eq!(cpu.regfile.load(T4() as usize), 30);
+        assert_eq!(cpu.regfile.load(T5() as usize), 0xffff_ff80);
+        assert_eq!(cpu.regfile.load(T6() as usize), 0xffff_fffd);
+        assert_eq!(cpu.regfile.load(T7() as usize), 0x8012);
+        assert_eq!(((cpu.hi as u64) << 32 | cpu.lo as u64) as i64, -3 * 0x1280);
+        assert_eq!(cpu.regfile.load(S1() as usize), 0x1280);
+        assert_eq!(cpu.regfile.load(S2() as usize), 0);
+        assert_eq!(cpu.regfile.load(S3() as usize), 0x28);
+        assert_eq!(cpu.regfile.load(T0() as usize), 0xffff_80fd);
+        assert_eq!(cpu.regfile.load(S4() as usize), 0x8000_0012);
+        assert_eq!(cpu.regfile.load(S5() as usize), 0x0000_04a0);
+
+        // tgei does not trap on a negative $t0, tltiu 0x1280 < 0xffffffff does
+        let cycle = cpu.step(&mem, &mut bus);
+        assert_eq!(cycle.exception, None);
+        let cycle = cpu.step(&mem, &mut bus);
+        assert_eq!(cycle.exception, Some(cp0::ExcCode::Tr));
+        assert_eq!(cpu.cp0.load(cp0::EPC), 0x54);
+    }
+
+    #[test]
+    fn test_unaligned() {
+        let mut loader = MemLoader::new(instr_mem::Memory::new());
+        loader.load_instr( LUI (T0(),         0x1122) );
+        loader.load_instr( ORI (T0(), T0(),   0x3344) );
+        loader.load_instr( SWL (T0(), 0x42,   ZERO()) ); // 0x11 0x22 at 0x42
+        loader.load_instr( SWR (T0(), 0x45,   ZERO()) ); // 0x33 0x44 at 0x44
+        loader.load_instr( LWL (T1(), 0x42,   ZERO()) ); // the word at 0x42, in two parts
+        loader.load_instr( LWR (T1(), 0x45,   ZERO()) );
+        loader.load_instr( LW  (T2(), 0x42,   ZERO()) );
+
+        let mut data_mem = data_mem::Memory::new();
+        let mut bus = bus::Bus::new(&mut data_mem);
+        let mem = loader.return_mem();
+        let mut cpu = Processor::new(&Options::default());
+        let mut cycles = Vec::new();
+        for _ in 0..7 {
+            cycles.push(cpu.step(&mem, &mut bus));
+        }
+        assert_eq!(cpu.regfile.load(T1() as usize), 0x1122_3344);
+        assert_eq!(bus.read_word(0x40), 0x0000_1122);
+        assert_eq!(bus.read_word(0x44), 0x3344_0000);
+        // the part accesses show the aligned word
+        let swl = cycles[2].mem.unwrap();
+        assert_eq!((swl.addr, swl.size, swl.old, swl.value), (0x40, 4, 0, 0x1122));
+
+        // a misaligned lw is an address error
+        assert_eq!(cycles[6].exception, Some(cp0::ExcCode::AdEL));
+        assert_eq!(cpu.cp0.load(cp0::BAD_VADDR), 0x42);
+        assert_eq!(cpu.regfile.load(T2() as usize), 0);
+        assert_eq!(cpu.ip, 0x8000_0080);
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        // data memory is 256 bytes, an access past it is an address error
+        let accesses = [
+            (LW (T1(), 0xfc,  ZERO()), None),
+            (LW (T1(), 0x100, ZERO()), Some(cp0::ExcCode::AdEL)),
+            (SB (T1(), 0x100, ZERO()), Some(cp0::ExcCode::AdES)),
+            (LWR(T1(), 0x103, ZERO()), Some(cp0::ExcCode::AdEL)), // the aligned word at 0x100
+        ];
+        for (word, exception) in accesses.iter() {
+            let mut loader = MemLoader::new(instr_mem::Memory::new());
+            loader.load_instr(*word);
+            let mem = loader.return_mem();
+            let mut data_mem = data_mem::Memory::new();
+            let mut bus = bus::Bus::new(&mut data_mem);
+            let mut cpu = Processor::new(&Options::default());
+            let cycle = cpu.step(&mem, &mut bus);
+            assert_eq!(cycle.exception, *exception);
+            if exception.is_some() {
+                assert_eq!(cpu.cp0.load(cp0::BAD_VADDR), *word & 0xffff);
+                assert!(cycle.mem.is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn test_zero_register() {
+        let mut loader = MemLoader::new(instr_mem::Memory::new());
+        loader.load_instr( ADDI(ZERO(), ZERO(), 5) );
+        loader.load_instr( ADD (T0(),   ZERO(), ZERO()) );
+        let mut data_mem = data_mem::Memory::new();
+        let mut bus = bus::Bus::new(&mut data_mem);
+        let mem = loader.return_mem();
+        let opts = Options {
+            random_regs: Some(3),
+            ..Options::default()
+        };
+        let mut cpu = Processor::new(&opts);
+        assert_ne!(cpu.regfile.load(T0() as usize), 0);
+        let cycle = cpu.step(&mem, &mut bus);
+        assert_eq!(cycle.write, None);
+        cpu.step(&mem, &mut bus);
+        assert_eq!(cpu.regfile.load(ZERO() as usize), 0);
+        assert_eq!(cpu.regfile.load(T0() as usize), 0);
+    }
+
+    #[test]
+    fn test_observers() {
+        struct Recorder(std::rc::Rc<std::cell::RefCell<Vec<Cycle>>>);
+        impl Observer for Recorder {
+            fn cycle(&mut self, cycle: &Cycle) {
+                self.0.borrow_mut().push(cycle.clone());
+            }
+        }
+
+        let mut loader = MemLoader::new(instr_mem::Memory::new());
+        loader.load_instr( ORI (T0(), ZERO(), 0xbabe) );
+        loader.load_instr( SB  (T0(), 0x40  , ZERO()) );
+        loader.load_instr( LW  (T1(), 0x40  , ZERO()) );
+        loader.load_instr( BEQ (T0(), T1(),   0x0) );
+        loader.load_instr( J   (0x0) );
+        let mem = loader.return_mem();
+
+        let cycles = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let mut data_mem = data_mem::Memory::new();
+        let mut bus = bus::Bus::new(&mut data_mem);
+        let mut cpu = Processor::new(&Options::default());
+        cpu.observers.push(Box::new(Recorder(cycles.clone())));
+        for _ in 0..5 {
+            cpu.step(&mem, &mut bus);
+        }
+
+        let cycles = cycles.borrow();
+        assert_eq!(cycles.len(), 5);
+        assert_eq!(cycles[0].reads, vec![(ZERO(), 0)]);
+        assert_eq!(cycles[0].write, Some(RegWrite { reg: T0(), old: 0, new: 0xbabe }));
+        assert_eq!(cycles[1].reads, vec![(ZERO(), 0), (T0(), 0xbabe)]);
+        assert_eq!(cycles[1].mem, Some(MemAccess {
+            addr: 0x40, paddr: 0x40, size: 1, is_write: true, old: 0, value: 0xbe
+        }));
+        assert_eq!(cycles[2].mem.unwrap().value, 0xbe00_0000);
+        assert_eq!(cycles[2].write.unwrap().new, 0xbe00_0000);
+        assert_eq!(cycles[3].branch, Some(false));
+        assert_eq!(cycles[3].class(), crate::cycle::Class::Branch);
+        assert_eq!(cycles[4].branch, None);
+        assert_eq!(cycles[4].next_pc, 0x0);
+        assert_eq!(cycles[4].cycle, 4);
+        assert!(cycles.iter().all(|c| c.retired()));
+    }
+}
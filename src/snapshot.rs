@@ -0,0 +1,385 @@
+/*
+ * snapshot.rs
+ *
+ * Author: Travis Banken
+ *
+ * Complete machine state saved to and loaded from a file: pc, registers,
+ * HI/LO, CP0, the RISC-V csrs, the tlb, the interrupt lines, both memories,
+ * the devices on the bus and the instruction and cycle counters. Cache contents are not saved,
+ * the caches keep whatever they held.
+ *
+ * File format (big endian):
+ *  "RVPS", u16 version
+ *  sections until the end of the file, each a 4 byte tag, u32 length and
+ *  the payload:
+ *   "CPU "  u32 pc, hi, lo, u64 instructions, u64 cycles, 32 x u32 registers
+ *   "CP0 "  32 x u32 registers, u8 timer interrupt pending
+ *   "CSR "  13 x u32 csrs in the order of csr::Csrs::dump (reset values
+ *           when missing)
+ *   "MMU "  u8 enabled, 64 x (u32 entry hi, u32 entry lo)
+ *   "INTC"  u8 raised lines
+ *   "IMEM"  instruction memory
+ *   "DMEM"  data memory
+ *   "DEV "  u32 base, u8 name length, name, device state (one per device)
+ * Sections a reader does not know are skipped.
+ */
+#![allow(dead_code)]
+
+use crate::hardware::*;
+use crate::hardware::tlb::{TlbEntry, TLB_SIZE};
+use crate::riscv::csr;
+use crate::single_cycle::Processor;
+use std::fs;
+
+pub const MAGIC: &[u8; 4] = b"RVPS";
+pub const VERSION: u16 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub pc: u32,
+    pub hi: u32,
+    pub lo: u32,
+    pub instr_count: u64,
+    pub cycles: u64,
+    pub regs: [u32; 32],
+    pub cp0: [u32; 32],
+    pub cp0_timer_irq: bool,
+    pub csrs: [u32; 13],
+    pub mmu_enabled: bool,
+    pub tlb: Vec<TlbEntry>,
+    pub intc: u8,
+    pub instr_mem: Vec<u8>,
+    pub data_mem: Vec<u8>,
+    // (base, name, state)
+    pub devices: Vec<(u32, String, Vec<u8>)>,
+}
+
+impl Snapshot {
+    pub fn capture(cpu: &Processor, instr_mem: &instr_mem::Memory, bus: &bus::Bus) -> Snapshot {
+        Snapshot {
+            pc: cpu.ip,
+            hi: cpu.hi,
+            lo: cpu.lo,
+            instr_count: cpu.instr_count,
+            cycles: cpu.cycles,
+            regs: cpu.regfile.dump(),
+            cp0: cpu.cp0.dump(),
+            cp0_timer_irq: cpu.cp0.timer_irq(),
+            csrs: cpu.csrs.dump(),
+            mmu_enabled: cpu.mmu.enabled,
+            tlb: (0..TLB_SIZE).map(|i| cpu.mmu.tlb.read(i)).collect(),
+            intc: cpu.intc.lines(),
+            instr_mem: instr_mem.bytes().to_vec(),
+            data_mem: bus.mem().bytes().to_vec(),
+            devices: bus.save_devices(),
+        }
+    }
+
+    /*
+     * Puts the machine back in the saved state, instruction memory aside
+     * (see restore_instr_mem). The bus needs the same devices attached.
+     */
+    pub fn restore(&self, cpu: &mut Processor, bus: &mut bus::Bus) -> Result<(), String> {
+        bus.restore_devices(&self.devices)?;
+        cpu.ip = self.pc;
+        cpu.hi = self.hi;
+        cpu.lo = self.lo;
+        cpu.instr_count = self.instr_count;
+        cpu.cycles = self.cycles;
+        cpu.regfile.restore(&self.regs);
+        cpu.cp0.restore(&self.cp0, self.cp0_timer_irq);
+        cpu.csrs.restore(&self.csrs);
+        cpu.mmu.enabled = self.mmu_enabled;
+        for (i, entry) in self.tlb.iter().enumerate() {
+            cpu.mmu.tlb.write(i, *entry);
+        }
+        cpu.intc.set_lines(self.intc);
+        bus.mem_mut().load_bytes(&self.data_mem);
+        cpu.watches.sync_regs(&cpu.regfile);
+        return Ok(());
+    }
+
+    pub fn restore_instr_mem(&self, instr_mem: &mut instr_mem::Memory) {
+        instr_mem.load_bytes(&self.instr_mem);
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&VERSION.to_be_bytes());
+
+        let mut cpu = Vec::new();
+        for val in [self.pc, self.hi, self.lo].iter() {
+            cpu.extend_from_slice(&val.to_be_bytes());
+        }
+        cpu.extend_from_slice(&self.instr_count.to_be_bytes());
+        cpu.extend_from_slice(&self.cycles.to_be_bytes());
+        push_words(&mut cpu, &self.regs);
+        section(&mut out, b"CPU ", &cpu);
+
+        let mut cp0 = Vec::new();
+        push_words(&mut cp0, &self.cp0);
+        cp0.push(self.cp0_timer_irq as u8);
+        section(&mut out, b"CP0 ", &cp0);
+        let mut csrs = Vec::new();
+        push_words(&mut csrs, &self.csrs);
+        section(&mut out, b"CSR ", &csrs);
+
+        let mut mmu = vec![self.mmu_enabled as u8];
+        for entry in self.tlb.iter() {
+            mmu.extend_from_slice(&entry.hi.to_be_bytes());
+            mmu.extend_from_slice(&entry.lo.to_be_bytes());
+        }
+        section(&mut out, b"MMU ", &mmu);
+        section(&mut out, b"INTC", &[self.intc]);
+        section(&mut out, b"IMEM", &self.instr_mem);
+        section(&mut out, b"DMEM", &self.data_mem);
+
+        for (base, name, state) in self.devices.iter() {
+            let mut dev = base.to_be_bytes().to_vec();
+            dev.push(name.len() as u8);
+            dev.extend_from_slice(name.as_bytes());
+            dev.extend_from_slice(state);
+            section(&mut out, b"DEV ", &dev);
+        }
+        return out;
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Snapshot, String> {
+        if bytes.len() < 6 || &bytes[..4] != MAGIC {
+            return Err("Snapshot-Error: not a snapshot file".to_string());
+        }
+        let version = u16::from_be_bytes([bytes[4], bytes[5]]);
+        if version > VERSION {
+            return Err(format!("Snapshot-Error: version {} is newer than this emulator ({})", version, VERSION));
+        }
+
+        let mut snap = Snapshot {
+            pc: 0,
+            hi: 0,
+            lo: 0,
+            instr_count: 0,
+            cycles: 0,
+            regs: [0; 32],
+            cp0: [0; 32],
+            cp0_timer_irq: false,
+            csrs: csr::Csrs::new().dump(),
+            mmu_enabled: false,
+            tlb: vec![TlbEntry::default(); TLB_SIZE],
+            intc: 0,
+            instr_mem: Vec::new(),
+            data_mem: Vec::new(),
+            devices: Vec::new(),
+        };
+        let mut seen_cpu = false;
+        let mut at = 6;
+        while at < bytes.len() {
+            if at + 8 > bytes.len() {
+                return Err("Snapshot-Error: truncated section header".to_string());
+            }
+            let tag = &bytes[at..at + 4];
+            let len = read_u32(bytes, at + 4) as usize;
+            let body = match bytes.get(at + 8..at + 8 + len) {
+                Some(body) => body,
+                None => return Err(format!("Snapshot-Error: truncated section [{}]",
+                                           String::from_utf8_lossy(tag))),
+            };
+            at += 8 + len;
+
+            let bad = || format!("Snapshot-Error: bad section [{}]", String::from_utf8_lossy(tag));
+            match tag {
+                b"CPU " => {
+                    if body.len() != 12 + 16 + 32 * 4 {
+                        return Err(bad());
+                    }
+                    snap.pc = read_u32(body, 0);
+                    snap.hi = read_u32(body, 4);
+                    snap.lo = read_u32(body, 8);
+                    snap.instr_count = read_u64(body, 12);
+                    snap.cycles = read_u64(body, 20);
+                    snap.regs = read_words(body, 28);
+                    seen_cpu = true;
+                },
+                b"CP0 " => {
+                    if body.len() != 32 * 4 + 1 {
+                        return Err(bad());
+                    }
+                    snap.cp0 = read_words(body, 0);
+                    snap.cp0_timer_irq = body[128] != 0;
+                },
+                b"CSR " => {
+                    if body.len() != 13 * 4 {
+                        return Err(bad());
+                    }
+                    for (i, word) in snap.csrs.iter_mut().enumerate() {
+                        *word = read_u32(body, 4 * i);
+                    }
+                },
+                b"MMU " => {
+                    if body.len() != 1 + 8 * TLB_SIZE {
+                        return Err(bad());
+                    }
+                    snap.mmu_enabled = body[0] != 0;
+                    snap.tlb = (0..TLB_SIZE)
+                        .map(|i| TlbEntry { hi: read_u32(body, 1 + 8 * i), lo: read_u32(body, 5 + 8 * i) })
+                        .collect();
+                },
+                b"INTC" => snap.intc = *body.first().ok_or_else(bad)?,
+                b"IMEM" => snap.instr_mem = body.to_vec(),
+                b"DMEM" => snap.data_mem = body.to_vec(),
+                b"DEV " => {
+                    if body.len() < 5 || body.len() < 5 + body[4] as usize {
+                        return Err(bad());
+                    }
+                    let name_end = 5 + body[4] as usize;
+                    let name = String::from_utf8_lossy(&body[5..name_end]).to_string();
+                    snap.devices.push((read_u32(body, 0), name, body[name_end..].to_vec()));
+                },
+                _ => {},
+            }
+        }
+
+        if !seen_cpu {
+            return Err("Snapshot-Error: no CPU section".to_string());
+        }
+        if snap.instr_mem.len() != instr_mem::Memory::get_size() {
+            return Err(format!("Snapshot-Error: instruction memory is {} bytes, expected {}",
+                               snap.instr_mem.len(), instr_mem::Memory::get_size()));
+        }
+        if snap.data_mem.len() != data_mem::Memory::get_size() {
+            return Err(format!("Snapshot-Error: data memory is {} bytes, expected {}",
+                               snap.data_mem.len(), data_mem::Memory::get_size()));
+        }
+        return Ok(snap);
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        return fs::write(path, self.encode())
+            .map_err(|e| format!("Snapshot-Error: cannot write [{}]: {}", path, e));
+    }
+
+    pub fn load(path: &str) -> Result<Snapshot, String> {
+        let bytes = fs::read(path).map_err(|e| format!("Snapshot-Error: cannot read [{}]: {}", path, e))?;
+        return Snapshot::decode(&bytes);
+    }
+}
+
+// *** PRIVATE FN ***
+
+fn section(out: &mut Vec<u8>, tag: &[u8; 4], body: &[u8]) {
+    out.extend_from_slice(tag);
+    out.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    out.extend_from_slice(body);
+}
+
+fn push_words(out: &mut Vec<u8>, words: &[u32]) {
+    for word in words.iter() {
+        out.extend_from_slice(&word.to_be_bytes());
+    }
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    return u32::from_be_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]]);
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> u64 {
+    return ((read_u32(bytes, at) as u64) << 32) | read_u32(bytes, at + 4) as u64;
+}
+
+fn read_words(bytes: &[u8], at: usize) -> [u32; 32] {
+    let mut words = [0; 32];
+    for (i, word) in words.iter_mut().enumerate() {
+        *word = read_u32(bytes, at + 4 * i);
+    }
+    return words;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::demos::assembler::instr_macros::*;
+    use crate::demos::assembler::mem_loader::MemLoader;
+    use crate::demos::assembler::reg_macros::*;
+    use crate::single_cycle::Options;
+
+    fn program() -> instr_mem::Memory {
+        let mut loader = MemLoader::new(instr_mem::Memory::new());
+        loader.load_instr( LUI (S0(),         0xffff) );
+        loader.load_instr( ADDI(T0(), ZERO(), 0x20) );
+        loader.load_instr( SW  (T0(), 0x14  , S0()) ); // timer reload
+        loader.load_instr( ADDI(T0(), ZERO(), 0x1) );
+        loader.load_instr( SW  (T0(), 0x10  , S0()) ); // enable
+        loader.load_instr( ADDI(T1(), ZERO(), 0x42) );
+        loader.load_instr( SB  (T1(), 0x7   , ZERO()) );
+        loader.load_instr( ADDI(T1(), T1(),   1) );
+        loader.load_instr( SB  (T1(), 0x8   , ZERO()) );
+        return loader.return_mem();
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mem = program();
+        let mut data_mem = data_mem::Memory::new();
+        let mut bus = bus::Bus::new(&mut data_mem);
+        bus.attach(timer::TIMER_BASE, Box::new(timer::Timer::new()));
+        let mut cpu = Processor::new(&Options::default());
+        cpu.mmu.tlb.write(3, TlbEntry { hi: 0x0040_0000, lo: 0x0000_0200 });
+        cpu.csrs.mtvec = 0x40;
+        cpu.csrs.reservation = Some(0x80);
+        for _ in 0..7 {
+            cpu.step(&mem, &mut bus);
+        }
+        let snap = Snapshot::capture(&cpu, &mem, &bus);
+        assert_eq!(Snapshot::decode(&snap.encode()), Ok(snap.clone()));
+
+        // finish the run, then go back to the snapshot in a new machine
+        while cpu.is_running() {
+            cpu.step(&mem, &mut bus);
+        }
+        let end = Snapshot::capture(&cpu, &mem, &bus);
+
+        let mut mem2 = instr_mem::Memory::new();
+        let mut data_mem2 = data_mem::Memory::new();
+        let mut bus2 = bus::Bus::new(&mut data_mem2);
+        bus2.attach(timer::TIMER_BASE, Box::new(timer::Timer::new()));
+        let mut cpu2 = Processor::new(&Options::default());
+        let snap = Snapshot::decode(&snap.encode()).unwrap();
+        snap.restore_instr_mem(&mut mem2);
+        snap.restore(&mut cpu2, &mut bus2).unwrap();
+        assert_eq!(cpu2.ip, 0x1c);
+        assert_eq!(bus2.mem().read(0x7), 0x42);
+        assert_eq!(cpu2.mmu.tlb.read(3), TlbEntry { hi: 0x0040_0000, lo: 0x0000_0200 });
+        assert_eq!((cpu2.csrs.mtvec, cpu2.csrs.reservation), (0x40, Some(0x80)));
+        while cpu2.is_running() {
+            cpu2.step(&mem2, &mut bus2);
+        }
+        assert_eq!(Snapshot::capture(&cpu2, &mem2, &bus2), end);
+    }
+
+    #[test]
+    fn test_bad_files() {
+        let mem = program();
+        let mut data_mem = data_mem::Memory::new();
+        let bus = bus::Bus::new(&mut data_mem);
+        let cpu = Processor::new(&Options::default());
+        let bytes = Snapshot::capture(&cpu, &mem, &bus).encode();
+
+        assert!(Snapshot::decode(b"RVPT\x00\x01").is_err());
+        let mut newer = bytes.clone();
+        newer[5] = 2;
+        assert!(Snapshot::decode(&newer).unwrap_err().contains("version 2"));
+        assert!(Snapshot::decode(&bytes[..bytes.len() - 1]).is_err());
+
+        // unknown sections are skipped
+        let mut extra = bytes.clone();
+        section(&mut extra, b"XTRA", &[1, 2, 3]);
+        assert!(Snapshot::decode(&extra).is_ok());
+
+        // devices have to be there to be restored
+        let mut with_timer = Snapshot::decode(&bytes).unwrap();
+        with_timer.devices.push((timer::TIMER_BASE, "timer".to_string(), vec![0; 16]));
+        let mut data_mem = data_mem::Memory::new();
+        let mut bus = bus::Bus::new(&mut data_mem);
+        let mut cpu = Processor::new(&Options::default());
+        assert!(with_timer.restore(&mut cpu, &mut bus).is_err());
+    }
+}
@@ -0,0 +1,573 @@
+/*
+ * asm.rs
+ *
+ * Author: Travis Banken
+ *
+ * Two pass assembler for MIPS source text, the counterpart of disasm.rs. It
+ * knows every instruction of instruction::DEFS plus the usual pseudo
+ * instructions (nop, move, li, la, b, beqz, bnez) and the directives .text,
+ * .data, .word, .half, .byte, .ascii, .asciiz, .space and .align (.globl,
+ * .ent, .end and .set are accepted and ignored).
+ *
+ * Branch targets are absolute like everywhere else in this processor, so
+ * "beq $t0, $zero, done" encodes the address of done. Immediates are 16 bit,
+ * negative values are written in two's complement. Text and data both start
+ * at address 0 of their own memory.
+ */
+#![allow(dead_code)]
+
+use crate::hardware::reg_file::reg_num;
+use crate::instruction::{self, encode, Def, Op, Operand};
+use crate::tools::symbols::SymbolTable;
+use std::collections::HashMap;
+
+/*
+ * Output of the assembler.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct Assembly {
+    pub text: Vec<u8>,
+    pub data: Vec<u8>,
+    // labels of the text section and the source line of every instruction
+    pub symbols: SymbolTable,
+    // labels of the data section
+    pub data_labels: Vec<(String, u32)>,
+}
+
+impl Assembly {
+    /*
+     * Address execution starts at: _start, main or the first instruction.
+     */
+    pub fn entry(&self) -> u32 {
+        return self.symbols.lookup("_start")
+            .or_else(|| self.symbols.lookup("main"))
+            .unwrap_or(0);
+    }
+}
+
+/*
+ * Assembles source text, file is only used in errors and line info.
+ */
+pub fn assemble(source: &str, file: &str) -> Result<Assembly, String> {
+    let mut asm = Assembler {
+        file: file.to_string(),
+        line: 0,
+        pass: 1,
+        labels: HashMap::new(),
+        in_data: false,
+        out: Assembly::default(),
+    };
+    for pass in 1..=2 {
+        asm.pass = pass;
+        asm.in_data = false;
+        asm.out = Assembly::default();
+        for (i, line) in source.lines().enumerate() {
+            asm.line = i as u32 + 1;
+            asm.statement(line).map_err(|msg| format!("Asm-Error: {}:{}: {}", file, asm.line, msg))?;
+        }
+    }
+    return Ok(asm.out);
+}
+
+// *** PRIVATE FN ***
+
+struct Assembler {
+    file: String,
+    line: u32,
+    pass: u32,
+    // every label with its address, from the first pass
+    labels: HashMap<String, u32>,
+    in_data: bool,
+    out: Assembly,
+}
+
+impl Assembler {
+    fn statement(&mut self, line: &str) -> Result<(), String> {
+        let mut rest = strip_comment(line).trim();
+
+        // labels
+        while let Some(colon) = rest.find(':') {
+            let name = &rest[..colon];
+            if !is_ident(name) {
+                break;
+            }
+            self.label(name)?;
+            rest = rest[colon + 1..].trim();
+        }
+        if rest.is_empty() {
+            return Ok(());
+        }
+
+        let (op, args) = match rest.find(char::is_whitespace) {
+            Some(at) => (&rest[..at], rest[at..].trim()),
+            None => (rest, ""),
+        };
+        let args = split_operands(args);
+        if let Some(directive) = op.strip_prefix('.') {
+            return self.directive(directive, &args);
+        }
+        if self.in_data {
+            return Err(format!("instruction [{}] in the .data section", op));
+        }
+        let words = self.instruction(&op.to_lowercase(), &args)?;
+        for word in words {
+            let addr = self.out.text.len() as u32;
+            self.out.symbols.add_line(addr, &self.file, self.line);
+            self.out.text.extend_from_slice(&word.to_be_bytes());
+        }
+        return Ok(());
+    }
+
+    fn label(&mut self, name: &str) -> Result<(), String> {
+        let addr = if self.in_data {self.out.data.len()} else {self.out.text.len()} as u32;
+        if self.pass == 1 && self.labels.insert(name.to_string(), addr).is_some() {
+            return Err(format!("label [{}] defined twice", name));
+        }
+        if self.in_data {
+            self.out.data_labels.push((name.to_string(), addr));
+        } else {
+            self.out.symbols.add(name, addr);
+        }
+        return Ok(());
+    }
+
+    fn directive(&mut self, name: &str, args: &[String]) -> Result<(), String> {
+        match name {
+            "text" => self.in_data = false,
+            "data" => self.in_data = true,
+            "globl" | "global" | "ent" | "end" | "set" => (),
+            "word" | "half" | "byte" => {
+                let size = match name {"word" => 4, "half" => 2, _ => 1};
+                for arg in args {
+                    let val = self.value(arg)?;
+                    let bytes = val.to_be_bytes();
+                    let bytes = &bytes[4 - size..];
+                    self.emit(bytes)?;
+                }
+            },
+            "ascii" | "asciiz" => {
+                for arg in args {
+                    let mut bytes = parse_string(arg)?;
+                    if name == "asciiz" {
+                        bytes.push(0);
+                    }
+                    self.emit(&bytes)?;
+                }
+            },
+            "space" => {
+                let len = self.number(one(args, ".space")?)?;
+                self.emit(&vec![0; len as usize])?;
+            },
+            "align" => {
+                let align = 1 << self.number(one(args, ".align")?)?.min(8);
+                let len = if self.in_data {self.out.data.len()} else {self.out.text.len()};
+                let pad = (align - len % align) % align;
+                self.emit(&vec![0; pad])?;
+            },
+            _ => return Err(format!("unknown directive [.{}]", name)),
+        }
+        return Ok(());
+    }
+
+    fn emit(&mut self, bytes: &[u8]) -> Result<(), String> {
+        if self.in_data {
+            self.out.data.extend_from_slice(bytes);
+        } else if !bytes.len().is_multiple_of(4) || !self.out.text.len().is_multiple_of(4) {
+            return Err("data in the .text section must be whole words".to_string());
+        } else {
+            for word in bytes.chunks(4) {
+                let addr = self.out.text.len() as u32;
+                self.out.symbols.add_line(addr, &self.file, self.line);
+                self.out.text.extend_from_slice(word);
+            }
+        }
+        return Ok(());
+    }
+
+    /*
+     * Words of an instruction, pseudo instructions can take more than one.
+     */
+    fn instruction(&self, op: &str, args: &[String]) -> Result<Vec<u32>, String> {
+        let word = match op {
+            // pseudo instructions
+            "nop" => {
+                self.count(op, args, 0)?;
+                0
+            },
+            "move" => {
+                self.count(op, args, 2)?;
+                encode(Op::Addu, &[reg(&args[0])? as u32, reg(&args[1])? as u32, 0])
+            },
+            "b" => {
+                self.count(op, args, 1)?;
+                encode(Op::Beq, &[0, 0, self.branch_target(&args[0])? as u32])
+            },
+            "beqz" | "bnez" => {
+                self.count(op, args, 2)?;
+                let target = self.branch_target(&args[1])?;
+                encode(if op == "beqz" {Op::Beq} else {Op::Bne}, &[reg(&args[0])? as u32, 0, target as u32])
+            },
+            "li" | "la" => {
+                self.count(op, args, 2)?;
+                let rt = reg(&args[0])? as u32;
+                let val = self.value(&args[1])?;
+                // li of a number that fits in 16 bits is a single ori, la
+                // and labels always take lui + ori so both passes agree
+                if op == "li" && parse_num(&args[1]).is_some() && val <= 0xffff {
+                    encode(Op::Ori, &[rt, 0, val])
+                } else {
+                    return Ok(vec![
+                        encode(Op::Lui, &[rt, val >> 16]),
+                        encode(Op::Ori, &[rt, rt, val & 0xffff]),
+                    ]);
+                }
+            },
+            // jalr $rs links to $ra
+            "jalr" if args.len() == 1 => encode(Op::Jalr, &[31, reg(&args[0])? as u32]),
+            _ => match instruction::lookup(op) {
+                Some(def) => self.encode(def, args)?,
+                None => return Err(format!("unknown instruction [{}]", op)),
+            },
+        };
+        return Ok(vec![word]);
+    }
+
+    /*
+     * Word of an instruction from its definition, offset($base) is one
+     * argument for two operands.
+     */
+    fn encode(&self, def: &Def, args: &[String]) -> Result<u32, String> {
+        let count = def.operands.iter().filter(|operand| **operand != Operand::Base).count();
+        self.count(def.mnemonic, args, count)?;
+        let mut values = Vec::new();
+        let mut args = args.iter();
+        for operand in def.operands.iter() {
+            if *operand == Operand::Base {
+                continue;
+            }
+            let arg = args.next().unwrap();
+            match operand {
+                Operand::Rd | Operand::Rs | Operand::Rt => values.push(reg(arg)? as u32),
+                Operand::Shamt => {
+                    let shamt = self.number(arg)?;
+                    if shamt > 31 {
+                        return Err(format!("shift amount [{}] is more than 31", arg));
+                    }
+                    values.push(shamt);
+                },
+                Operand::Imm => values.push(self.imm(arg)? as u32),
+                Operand::Offset => {
+                    let (offset, base) = self.mem_operand(arg)?;
+                    values.push(offset as u32);
+                    values.push(base as u32);
+                },
+                Operand::Branch => values.push(self.branch_target(arg)? as u32),
+                Operand::Target => {
+                    let target = self.value(arg)?;
+                    if target > 0x03ff_ffff {
+                        return Err(format!("jump target [{}] does not fit in 26 bits", arg));
+                    }
+                    values.push(target);
+                },
+                Operand::Cop0Reg => values.push(cop0_reg(arg)? as u32),
+                Operand::ExtSize | Operand::InsSize => {
+                    // the position is the shamt operand before it
+                    let pos = *values.last().unwrap();
+                    let size = self.number(arg)?;
+                    if size == 0 || pos + size > 32 {
+                        return Err(format!("bit field of size [{}] at bit {} does not fit in 32 bits", arg, pos));
+                    }
+                    values.push(size);
+                },
+                Operand::Base => (),
+            }
+        }
+        return Ok(encode(def.op, &values));
+    }
+
+    fn count(&self, op: &str, args: &[String], expected: usize) -> Result<(), String> {
+        if args.len() != expected {
+            return Err(format!("[{}] takes {} operands, got {}", op, expected, args.len()));
+        }
+        return Ok(());
+    }
+
+    /*
+     * Number or label, labels are 0 in the first pass.
+     */
+    fn value(&self, arg: &str) -> Result<u32, String> {
+        if let Some(val) = parse_num(arg) {
+            return Ok(val as u32);
+        }
+        if let Some(addr) = self.labels.get(arg) {
+            return Ok(*addr);
+        }
+        if self.pass == 1 && is_ident(arg) {
+            return Ok(0);
+        }
+        return Err(format!("unknown label or bad number [{}]", arg));
+    }
+
+    fn number(&self, arg: &str) -> Result<u32, String> {
+        match parse_num(arg) {
+            Some(val) if val >= 0 => Ok(val as u32),
+            _ => Err(format!("expected a number, got [{}]", arg)),
+        }
+    }
+
+    fn imm(&self, arg: &str) -> Result<u16, String> {
+        let val = match parse_num(arg) {
+            Some(val) => val,
+            None => self.value(arg)? as i64,
+        };
+        if !(-0x8000..=0xffff).contains(&val) {
+            return Err(format!("immediate [{}] does not fit in 16 bits", arg));
+        }
+        return Ok(val as u16);
+    }
+
+    fn branch_target(&self, arg: &str) -> Result<u16, String> {
+        let target = self.value(arg)?;
+        if target > 0xffff {
+            return Err(format!("branch target [{}] does not fit in 16 bits", arg));
+        }
+        return Ok(target as u16);
+    }
+
+    /*
+     * "offset($reg)", "($reg)" or "label($reg)".
+     */
+    fn mem_operand(&self, arg: &str) -> Result<(u16, u8), String> {
+        let open = arg.find('(').ok_or(format!("expected offset($reg), got [{}]", arg))?;
+        let inner = arg[open + 1..].strip_suffix(')').ok_or(format!("expected offset($reg), got [{}]", arg))?;
+        let offset = arg[..open].trim();
+        let offset = if offset.is_empty() {0} else {self.imm(offset)?};
+        return Ok((offset, reg(inner.trim())?));
+    }
+}
+
+fn reg(arg: &str) -> Result<u8, String> {
+    if !arg.starts_with('$') {
+        return Err(format!("expected a register, got [{}]", arg));
+    }
+    return reg_num(arg).ok_or(format!("unknown register [{}]", arg));
+}
+
+/*
+ * Cop0 registers are numbers, with or without the '$'.
+ */
+fn cop0_reg(arg: &str) -> Result<u8, String> {
+    match arg.strip_prefix('$').unwrap_or(arg).parse::<u8>() {
+        Ok(num) if num < 32 => Ok(num),
+        _ => Err(format!("expected a cop0 register number, got [{}]", arg)),
+    }
+}
+
+fn one<'a>(args: &'a [String], name: &str) -> Result<&'a str, String> {
+    if args.len() != 1 {
+        return Err(format!("[{}] takes 1 operand, got {}", name, args.len()));
+    }
+    return Ok(&args[0]);
+}
+
+/*
+ * Decimal, 0x hex, negative or 'c' character numbers.
+ */
+fn parse_num(arg: &str) -> Option<i64> {
+    let (neg, digits) = match arg.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, arg),
+    };
+    let val = if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        i64::from_str_radix(&hex.replace('_', ""), 16).ok()?
+    } else if digits.len() >= 3 && digits.starts_with('\'') && digits.ends_with('\'') {
+        let bytes = parse_string(&format!("\"{}\"", &digits[1..digits.len() - 1])).ok()?;
+        if bytes.len() != 1 {
+            return None;
+        }
+        bytes[0] as i64
+    } else {
+        digits.parse::<i64>().ok()?
+    };
+    if val > 0xffff_ffff {
+        return None;
+    }
+    return Some(if neg {-val} else {val});
+}
+
+/*
+ * Contents of a "quoted" string with \n, \t, \0, \\ and \" escapes.
+ */
+fn parse_string(arg: &str) -> Result<Vec<u8>, String> {
+    let inner = arg.strip_prefix('"').and_then(|s| s.strip_suffix('"'))
+        .ok_or(format!("expected a quoted string, got [{}]", arg))?;
+    let mut bytes = Vec::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('n') => bytes.push(b'\n'),
+            Some('t') => bytes.push(b'\t'),
+            Some('r') => bytes.push(b'\r'),
+            Some('0') => bytes.push(0),
+            Some('\\') => bytes.push(b'\\'),
+            Some('"') => bytes.push(b'"'),
+            Some('\'') => bytes.push(b'\''),
+            _ => return Err(format!("bad escape in [{}]", arg)),
+        }
+    }
+    return Ok(bytes);
+}
+
+fn is_ident(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '.' => (),
+        _ => return false,
+    }
+    return chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$');
+}
+
+/*
+ * Line without its '#' comment, a '#' inside quotes is kept.
+ */
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut prev = ' ';
+    for (i, c) in line.char_indices() {
+        if (c == '"' || c == '\'') && prev != '\\' {
+            quoted = !quoted;
+        } else if c == '#' && !quoted {
+            return &line[..i];
+        }
+        prev = c;
+    }
+    return line;
+}
+
+/*
+ * Operands split on the commas outside of quotes.
+ */
+fn split_operands(args: &str) -> Vec<String> {
+    let mut ops = Vec::new();
+    let mut cur = String::new();
+    let mut quoted = false;
+    let mut prev = ' ';
+    for c in args.chars() {
+        if (c == '"' || c == '\'') && prev != '\\' {
+            quoted = !quoted;
+        }
+        if c == ',' && !quoted {
+            ops.push(cur.trim().to_string());
+            cur.clear();
+        } else {
+            cur.push(c);
+        }
+        prev = c;
+    }
+    if !cur.trim().is_empty() || !ops.is_empty() {
+        ops.push(cur.trim().to_string());
+    }
+    return ops;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::demos::assembler::instr_macros::*;
+    use crate::demos::assembler::reg_macros::*;
+    use crate::instruction::DEFS;
+    use crate::tools::disasm::disassemble;
+
+    fn words(asm: &Assembly) -> Vec<u32> {
+        asm.text.chunks(4).map(|w| u32::from_be_bytes([w[0], w[1], w[2], w[3]])).collect()
+    }
+
+    #[test]
+    fn test_assemble() {
+        let src = "
+            .data
+    msg:    .asciiz \"hi#\\n\"   # comment
+    nums:   .word 1, msg, -1
+            .text
+    main:   addi $t0, $zero, 0x10
+            li   $t1, 0x12345678
+            la   $a0, nums
+    loop:   sb   $t0, 0x40($t0)
+            lw   $t2, ($a0)
+            beq  $t0, $zero, done
+            bne  $t0, $t1, loop
+            j    loop
+    done:   mtc0 $t0, $12
+            move $v0, $t0
+            jr   $ra
+        ";
+        let asm = assemble(src, "test.s").unwrap();
+        assert_eq!(asm.data, vec![b'h', b'i', b'#', b'\n', 0, 0, 0, 0, 1, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(asm.data_labels, vec![("msg".to_string(), 0), ("nums".to_string(), 5)]);
+        assert_eq!(words(&asm), vec![
+            ADDI(T0(), ZERO(), 0x10),
+            LUI(T1(), 0x1234),
+            ORI(T1(), T1(), 0x5678),
+            LUI(A0(), 0),
+            ORI(A0(), A0(), 5),
+            SB(T0(), 0x40, T0()),
+            LW(T2(), 0, A0()),
+            BEQ(T0(), ZERO(), 0x28),
+            BNE(T0(), T1(), 0x14),
+            J(0x14),
+            MTC0(T0(), 12),
+            ADDU(V0(), T0(), ZERO()),
+            JR(RA()),
+        ]);
+        assert_eq!(asm.symbols.lookup("loop"), Some(0x14));
+        assert_eq!(asm.symbols.lookup("msg"), None);
+        assert_eq!(asm.symbols.line_at(0x8), Some(("test.s", 7)));
+        assert_eq!(asm.entry(), 0);
+    }
+
+    #[test]
+    fn test_disassembly_round_trip() {
+        let src = "add $t1, $t2, $t3\nsll $t0, $t1, 4\njalr $ra, $t0\nmult $t0, $t1\nmfhi $v0\n\
+                   sltiu $t0, $t1, 0xffff\nlhu $t0, 0x8($sp)\njal 0x40\ntlbwi\nsyscall\n\
+                   mul $v0, $a0, $a1\nins $t0, $t1, 4, 28\nrotrv $t0, $t1, $t2\nseh $t0, $t1\n";
+        let asm = assemble(src, "rt.s").unwrap();
+        let text: Vec<String> = words(&asm).iter().enumerate()
+            .map(|(i, word)| disassemble(*word, i as u32 * 4))
+            .collect();
+        let again = assemble(&text.join("\n"), "rt.s").unwrap();
+        assert_eq!(again.text, asm.text);
+        assert_eq!(text[0], "add $t1, $t2, $t3");
+    }
+
+    #[test]
+    fn test_every_instruction() {
+        // every definition disassembles to text that assembles back to it
+        for def in DEFS.iter() {
+            let values: Vec<u32> = (0..def.operands.len() as u32).map(|i| 3 + i * 5).collect();
+            let word = encode(def.op, &values);
+            let text = disassemble(word, 0);
+            assert!(text.starts_with(def.mnemonic), "{}", text);
+            let asm = assemble(&text, "every.s").unwrap_or_else(|e| panic!("{}: {}", text, e));
+            assert_eq!(words(&asm), vec![word], "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = |src: &str| assemble(src, "bad.s").unwrap_err();
+        assert_eq!(err("nop\nfoo $t0"), "Asm-Error: bad.s:2: unknown instruction [foo]");
+        assert_eq!(err("add $t0, $t1"), "Asm-Error: bad.s:1: [add] takes 3 operands, got 2");
+        assert_eq!(err("addi $t0, $t1, 0x10000"), "Asm-Error: bad.s:1: immediate [0x10000] does not fit in 16 bits");
+        assert_eq!(err("j nowhere"), "Asm-Error: bad.s:1: unknown label or bad number [nowhere]");
+        assert_eq!(err("a:\na:"), "Asm-Error: bad.s:2: label [a] defined twice");
+        assert_eq!(err("add $t0, $t1, $t99"), "Asm-Error: bad.s:1: unknown register [$t99]");
+        assert_eq!(err("ext $t0, $t1, 30, 4"),
+                   "Asm-Error: bad.s:1: bit field of size [4] at bit 30 does not fit in 32 bits");
+        assert_eq!(err(".data\nadd $t0, $t1, $t2"), "Asm-Error: bad.s:2: instruction [add] in the .data section");
+    }
+}
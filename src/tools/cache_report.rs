@@ -0,0 +1,77 @@
+/*
+ * cache_report.rs
+ * 
+ * Author: Travis Banken
+ * 
+ * Print the statistics gathered by the cache model
+ */
+#![allow(dead_code)]
+
+use crate::hardware::cache::Cache;
+use crate::hardware::hierarchy::Hierarchy;
+
+/*
+ * Print every level of the hierarchy followed by the average memory access
+ * times of the L1s.
+ */
+pub fn print_hierarchy(hier: &Hierarchy) {
+    if let Some(cache) = hier.l1i.as_ref() {
+        print_report("L1 Instruction Cache", cache);
+    }
+    if let Some(cache) = hier.l1d.as_ref() {
+        print_report("L1 Data Cache", cache);
+    }
+    if let Some(cache) = hier.l2.as_ref() {
+        print_report("L2 Unified Cache", cache);
+    }
+
+    let config = hier.memory_config();
+    let stats = hier.memory_stats();
+    println!("======================================");
+    println!("             Main Memory              ");
+    println!("======================================");
+    println!("Latency:     {} cycles, {} bytes/cycle", config.latency, config.bandwidth);
+    println!("Accesses:    {} ({} reads, {} writes)", stats.reads + stats.writes, stats.reads, stats.writes);
+    println!("Bytes:       {}", stats.bytes);
+    println!("Cycles:      {}", stats.cycles);
+    println!("======================================\n");
+
+    if hier.l2.is_some() {
+        println!("Inclusion:    {:?}", hier.inclusion());
+    }
+    if let Some(cache) = hier.l1i.as_ref() {
+        println!("AMAT (instr): {:.2} cycles (measured {:.2})", hier.amat(cache), hier.measured_amat(cache));
+    }
+    if let Some(cache) = hier.l1d.as_ref() {
+        println!("AMAT (data):  {:.2} cycles (measured {:.2})", hier.amat(cache), hier.measured_amat(cache));
+    }
+}
+
+pub fn print_report(name: &str, cache: &Cache) {
+    let config = cache.config();
+    let stats = cache.stats();
+
+    println!("======================================");
+    println!("{:^38}", name);
+    println!("======================================");
+    println!("Geometry:    {} bytes, {} byte blocks, {} way, {} sets",
+             config.size, config.block_size, config.assoc, config.num_sets());
+    println!("Policy:      {:?}, {:?}, write allocate: {}",
+             config.replacement, config.write_policy, config.write_allocate);
+    println!("Accesses:    {} ({} reads, {} writes)", stats.accesses(), stats.reads, stats.writes);
+    println!("Hits:        {}", stats.hits);
+    println!("Misses:      {} ({:.2}%)", stats.misses(), stats.miss_rate() * 100.0);
+    println!("  compulsory {}", stats.compulsory);
+    println!("  capacity   {}", stats.capacity);
+    println!("  conflict   {}", stats.conflict);
+    println!("Evictions:   {} ({} write backs)", stats.evictions, stats.writebacks);
+    println!("Cycles:      {}", stats.cycles);
+    println!("======================================\n");
+}
+
+pub fn print_cycles(instructions: u64, cycles: u64) {
+    let cpi = if instructions == 0 {0.0} else {cycles as f64 / instructions as f64};
+    println!("Instructions: {}", instructions);
+    println!("Cycles:       {}", cycles);
+    println!("CPI:          {:.2}", cpi);
+}
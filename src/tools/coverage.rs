@@ -0,0 +1,378 @@
+/*
+ * coverage.rs
+ *
+ * Author: Travis Banken
+ *
+ * Code coverage of a program: how often every instruction ran, which basic
+ * blocks were entered and which directions of every branch were seen. The
+ * report is an annotated disassembly (gcov style, ##### for instructions
+ * that never ran) and/or an LCOV tracefile for genhtml and the CI coverage
+ * services. LCOV lines are the source lines of the assembler when the
+ * program has them, otherwise line n is the nth instruction of the program.
+ *
+ * The program is every instruction with a source line, or without lines
+ * every word from 0 up to the last non-zero word of instruction memory
+ * (loader::instructions).
+ */
+#![allow(dead_code)]
+
+use crate::cycle::{Cycle, Observer};
+use crate::hardware::instr_mem;
+use crate::isa::Isa;
+use crate::loader;
+use crate::tools::symbols::SymbolTable;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+
+// source file of the LCOV records when the program has no lines
+pub const NO_SOURCE: &str = "program.s";
+
+/*
+ * Where the annotated listing and the LCOV file go, "-" for stdout.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct CoverageConfig {
+    pub listing: Option<String>,
+    pub lcov: Option<String>,
+}
+
+impl CoverageConfig {
+    pub fn enabled(&self) -> bool {
+        self.listing.is_some() || self.lcov.is_some()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Summary {
+    pub instructions: usize,
+    pub instructions_hit: usize,
+    pub blocks: usize,
+    pub blocks_hit: usize,
+    // two per branch, taken and not taken
+    pub directions: usize,
+    pub directions_hit: usize,
+}
+
+pub struct Coverage {
+    // address -> word of every instruction of the program
+    program: BTreeMap<u32, u32>,
+    // first instruction of every basic block
+    leaders: BTreeSet<u32>,
+    hits: BTreeMap<u32, u64>,
+    // pc -> (taken, not taken)
+    branches: BTreeMap<u32, (u64, u64)>,
+    symbols: SymbolTable,
+    config: CoverageConfig,
+    isa: Isa,
+}
+
+impl Coverage {
+    pub fn new(mem: &instr_mem::Memory, symbols: &SymbolTable, config: &CoverageConfig, isa: Isa) -> Coverage {
+        let program = loader::instructions(mem, symbols, isa);
+
+        let mut leaders = BTreeSet::new();
+        if let Some(first) = program.keys().next() {
+            leaders.insert(*first);
+        }
+        for (addr, _) in symbols.iter() {
+            leaders.insert(addr);
+        }
+        for (pc, word) in program.iter() {
+            let flow = isa.flow(*word, *pc);
+            if let Some(target) = flow.target {
+                leaders.insert(target);
+            }
+            if flow.ends_block {
+                leaders.insert(pc + 4);
+            }
+        }
+        leaders.retain(|addr| program.contains_key(addr));
+
+        Coverage {
+            program,
+            leaders,
+            hits: BTreeMap::new(),
+            branches: BTreeMap::new(),
+            symbols: symbols.clone(),
+            config: config.clone(),
+            isa,
+        }
+    }
+
+    /*
+     * Times the instruction at pc ran.
+     */
+    pub fn hits(&self, pc: u32) -> u64 {
+        return *self.hits.get(&pc).unwrap_or(&0);
+    }
+
+    /*
+     * (start, end) of every basic block, end is the address after its last
+     * instruction.
+     */
+    pub fn blocks(&self) -> Vec<(u32, u32)> {
+        let mut blocks = Vec::new();
+        let leaders: Vec<u32> = self.leaders.iter().cloned().collect();
+        for (i, start) in leaders.iter().enumerate() {
+            let next = leaders.get(i + 1).cloned().unwrap_or(u32::MAX);
+            let last = self.program.range(*start..next).next_back().map(|(addr, _)| *addr).unwrap();
+            blocks.push((*start, last + 4));
+        }
+        return blocks;
+    }
+
+    pub fn summary(&self) -> Summary {
+        let mut sum = Summary {
+            instructions: self.program.len(),
+            instructions_hit: self.program.keys().filter(|pc| self.hits(**pc) > 0).count(),
+            blocks: self.leaders.len(),
+            blocks_hit: self.leaders.iter().filter(|pc| self.hits(**pc) > 0).count(),
+            directions: 0,
+            directions_hit: 0,
+        };
+        for pc in self.program.keys() {
+            if self.is_branch(*pc) {
+                let (taken, not_taken) = *self.branches.get(pc).unwrap_or(&(0, 0));
+                sum.directions += 2;
+                sum.directions_hit += (taken > 0) as usize + (not_taken > 0) as usize;
+            }
+        }
+        return sum;
+    }
+
+    /*
+     * Disassembly of the program with the times each instruction ran, a blank
+     * line before every basic block.
+     */
+    pub fn listing(&self) -> String {
+        let sum = self.summary();
+        let mut text = format!("Coverage: {}/{} instructions ({:.2}%), {}/{} blocks ({:.2}%), \
+                                {}/{} branch directions ({:.2}%)\n",
+                               sum.instructions_hit, sum.instructions,
+                               percent(sum.instructions_hit, sum.instructions),
+                               sum.blocks_hit, sum.blocks, percent(sum.blocks_hit, sum.blocks),
+                               sum.directions_hit, sum.directions, percent(sum.directions_hit, sum.directions));
+        for (pc, word) in self.program.iter() {
+            if self.leaders.contains(pc) {
+                text.push('\n');
+                if let Some(name) = self.symbols.name_at(*pc) {
+                    text.push_str(&format!("{}:\n", name));
+                }
+            }
+            let hits = self.hits(*pc);
+            let count = if hits == 0 {"#####".to_string()} else {hits.to_string()};
+            let mut line = format!("{:>10}  0x{:08x}: 0x{:08x}  {:<28}", count, pc, word,
+                                   self.isa.disassemble(*word, *pc));
+            if self.is_branch(*pc) && hits > 0 {
+                let (taken, not_taken) = *self.branches.get(pc).unwrap_or(&(0, 0));
+                line.push_str(&match (taken, not_taken) {
+                    (_, 0) => format!(" always taken ({})", taken),
+                    (0, _) => format!(" never taken ({})", not_taken),
+                    _ => format!(" taken {}, not taken {}", taken, not_taken),
+                });
+            }
+            text.push_str(line.trim_end());
+            text.push('\n');
+        }
+        return text;
+    }
+
+    /*
+     * LCOV tracefile, one record per source file.
+     */
+    pub fn lcov(&self) -> String {
+        // file -> line -> instructions on the line
+        let mut files: BTreeMap<&str, BTreeMap<u32, Vec<u32>>> = BTreeMap::new();
+        for (i, pc) in self.program.keys().enumerate() {
+            let (file, line) = self.symbols.line_at(*pc).unwrap_or((NO_SOURCE, i as u32 + 1));
+            files.entry(file).or_default().entry(line).or_default().push(*pc);
+        }
+
+        let mut text = String::new();
+        for (file, lines) in files.iter() {
+            text.push_str("TN:\n");
+            text.push_str(&format!("SF:{}\n", file));
+
+            let mut funcs = Vec::new();
+            for (addr, name) in self.symbols.iter() {
+                if let Some((line, _)) = lines.iter().find(|(_, pcs)| pcs.contains(&addr)) {
+                    funcs.push((name, *line, self.hits(addr)));
+                }
+            }
+            for (name, line, _) in funcs.iter() {
+                text.push_str(&format!("FN:{},{}\n", line, name));
+            }
+            for (name, _, hits) in funcs.iter() {
+                text.push_str(&format!("FNDA:{},{}\n", hits, name));
+            }
+            text.push_str(&format!("FNF:{}\n", funcs.len()));
+            text.push_str(&format!("FNH:{}\n", funcs.iter().filter(|(_, _, hits)| *hits > 0).count()));
+
+            let mut found = 0;
+            let mut hit = 0;
+            for (line, pcs) in lines.iter() {
+                for pc in pcs.iter().filter(|pc| self.is_branch(**pc)) {
+                    let (taken, not_taken) = *self.branches.get(pc).unwrap_or(&(0, 0));
+                    for (branch, count) in [taken, not_taken].iter().enumerate() {
+                        let count = if self.hits(*pc) == 0 {"-".to_string()} else {count.to_string()};
+                        text.push_str(&format!("BRDA:{},{},{},{}\n", line, pc, branch, count));
+                    }
+                    found += 2;
+                    hit += (taken > 0) as usize + (not_taken > 0) as usize;
+                }
+            }
+            text.push_str(&format!("BRF:{}\n", found));
+            text.push_str(&format!("BRH:{}\n", hit));
+
+            for (line, pcs) in lines.iter() {
+                let hits = pcs.iter().map(|pc| self.hits(*pc)).max().unwrap_or(0);
+                text.push_str(&format!("DA:{},{}\n", line, hits));
+            }
+            text.push_str(&format!("LF:{}\n", lines.len()));
+            text.push_str(&format!("LH:{}\n", lines.values()
+                                   .filter(|pcs| pcs.iter().any(|pc| self.hits(*pc) > 0))
+                                   .count()));
+            text.push_str("end_of_record\n");
+        }
+        return text;
+    }
+
+    // *** PRIVATE FN ***
+
+    fn is_branch(&self, pc: u32) -> bool {
+        return self.isa.flow(self.program[&pc], pc).branch;
+    }
+}
+
+impl Observer for Coverage {
+    fn cycle(&mut self, cycle: &Cycle) {
+        if !cycle.retired() || !self.program.contains_key(&cycle.pc) {
+            return;
+        }
+        *self.hits.entry(cycle.pc).or_insert(0) += 1;
+        if let Some(taken) = cycle.branch {
+            let dirs = self.branches.entry(cycle.pc).or_insert((0, 0));
+            if taken {
+                dirs.0 += 1;
+            } else {
+                dirs.1 += 1;
+            }
+        }
+    }
+
+    fn finish(&mut self) {
+        if let Some(path) = self.config.listing.as_ref() {
+            if let Err(msg) = write_out(path, &self.listing()) {
+                eprintln!("{}", msg);
+            }
+        }
+        if let Some(path) = self.config.lcov.as_ref() {
+            if let Err(msg) = write_out(path, &self.lcov()) {
+                eprintln!("{}", msg);
+            }
+        }
+    }
+}
+
+// *** PRIVATE FN ***
+
+fn percent(count: usize, total: usize) -> f64 {
+    if total == 0 {
+        return 100.0;
+    }
+    return count as f64 * 100.0 / total as f64;
+}
+
+fn write_out(path: &str, text: &str) -> Result<(), String> {
+    if path == "-" {
+        print!("{}", text);
+        return Ok(());
+    }
+    match fs::write(path, text) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(format!("Coverage-Error: Failed to write [{}]: {}", path, e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::demos::assembler::instr_macros::*;
+    use crate::demos::assembler::mem_loader::MemLoader;
+    use crate::demos::assembler::reg_macros::*;
+    use crate::hardware::{bus, data_mem};
+    use crate::single_cycle::{Options, Processor};
+
+    /*
+     * Counts t0 up to 2, the beq after the loop is always taken and the addi
+     * it skips never runs.
+     */
+    fn program() -> (instr_mem::Memory, SymbolTable) {
+        let mut loader = MemLoader::new(instr_mem::Memory::new());
+        loader.label("main");
+        loader.load_instr( ADDI(T1(), ZERO(), 2) );
+        let lp = loader.label("loop");
+        loader.load_instr( ADDI(T0(), T0()  , 1) );
+        loader.load_instr( BNE (T0(), T1()  , lp as u16) );
+        loader.load_instr( BEQ (T0(), T1()  , 0x100) ); // off the end of memory
+        loader.load_instr( ADDI(T2(), ZERO(), 1) );
+        let symbols = loader.symbols().clone();
+        return (loader.return_mem(), symbols);
+    }
+
+    fn run(mem: &instr_mem::Memory, cov: &mut Coverage) {
+        let mut data_mem = data_mem::Memory::new();
+        let mut bus = bus::Bus::new(&mut data_mem);
+        let mut cpu = Processor::new(&Options::default());
+        while cpu.is_running() {
+            let cycle = cpu.step(mem, &mut bus);
+            cov.cycle(&cycle);
+        }
+    }
+
+    #[test]
+    fn test_coverage() {
+        let (mem, symbols) = program();
+        let mut cov = Coverage::new(&mem, &symbols, &CoverageConfig::default(), Isa::Mips);
+        assert_eq!(cov.blocks(), vec![(0x0, 0x4), (0x4, 0xc), (0xc, 0x10), (0x10, 0x14)]);
+        run(&mem, &mut cov);
+
+        assert_eq!(cov.hits(0x4), 2);
+        assert_eq!(cov.hits(0x10), 0);
+        assert_eq!(cov.summary(), Summary {
+            instructions: 5, instructions_hit: 4, blocks: 4, blocks_hit: 3, directions: 4, directions_hit: 3,
+        });
+
+        let listing = cov.listing();
+        assert!(listing.starts_with("Coverage: 4/5 instructions (80.00%), 3/4 blocks (75.00%), \
+                                     3/4 branch directions (75.00%)\n"));
+        assert!(listing.contains("\nloop:\n         2  0x00000004: "));
+        assert!(listing.contains("bne $t0, $t1, 0x00000004     taken 1, not taken 1\n"));
+        assert!(listing.contains("beq $t0, $t1, 0x00000100     always taken (1)\n"));
+        assert!(listing.ends_with("     #####  0x00000010: 0x200a0001  addi $t2, $zero, 0x1\n"));
+    }
+
+    #[test]
+    fn test_lcov() {
+        let (mem, symbols) = program();
+        let mut cov = Coverage::new(&mem, &symbols, &CoverageConfig::default(), Isa::Mips);
+        run(&mem, &mut cov);
+        let first = symbols.line_at(0x0).unwrap().1;
+
+        let lcov = cov.lcov();
+        assert!(lcov.starts_with(&format!("TN:\nSF:{}\nFN:{},main\nFN:{},loop\nFNDA:1,main\nFNDA:2,loop\n",
+                                          file!(), first, first + 2)));
+        assert!(lcov.contains(&format!("BRDA:{},12,0,1\nBRDA:{},12,1,0\nBRF:4\nBRH:3\n", first + 4, first + 4)));
+        assert!(lcov.contains(&format!("DA:{},1\nDA:{},2\n", first, first + 2)));
+        assert!(lcov.ends_with(&format!("DA:{},0\nLF:5\nLH:4\nend_of_record\n", first + 5)));
+
+        // without lines every instruction is a line of its own
+        let mut mem = instr_mem::Memory::new();
+        for (i, byte) in ADDI(T1(), ZERO(), 2).to_be_bytes().iter().enumerate() {
+            mem.write(*byte, i);
+        }
+        let cov = Coverage::new(&mem, &SymbolTable::new(), &CoverageConfig::default(), Isa::Mips);
+        assert_eq!(cov.lcov(), format!("TN:\nSF:{}\nFNF:0\nFNH:0\nBRF:0\nBRH:0\nDA:1,0\nLF:1\nLH:0\nend_of_record\n",
+                                       NO_SOURCE));
+    }
+}
@@ -0,0 +1,251 @@
+/*
+ * datapath.rs
+ *
+ * Author: Travis Banken
+ *
+ * Draws the single cycle datapath as a Graphviz graph, one per cycle, with
+ * the paths and control lines used by the instruction highlighted. Every
+ * frame has the same nodes and edges so graphviz lays them out the same way
+ * and the frames can be flipped through as an animation.
+ */
+#![allow(dead_code)]
+
+use crate::control_bits::{AluSrc, PcSource, RegDst};
+use crate::cycle::{Cycle, Observer};
+use std::fs;
+use std::path::PathBuf;
+
+const ACTIVE_DATA: &str = "blue";
+const ACTIVE_CTRL: &str = "red";
+const IDLE: &str = "gray75";
+
+/*
+ * (id, label, shape) of the units of the datapath.
+ */
+const UNITS: [(&str, &str, &str); 14] = [
+    ("pc", "PC", "box"),
+    ("add4", "+4", "circle"),
+    ("imem", "Instruction\nMemory", "box"),
+    ("control", "Control", "ellipse"),
+    ("regdst", "RegDst\nMux", "invtrapezium"),
+    ("regfile", "Registers", "box"),
+    ("extend", "Extend\n(imm16, lui << 16)", "box"),
+    ("alusrc", "ALUSrc\nMux", "invtrapezium"),
+    ("alu", "ALU", "invhouse"),
+    ("dmem", "Data\nMemory", "box"),
+    ("cop0", "CP0", "box"),
+    ("memtoreg", "MemToReg\nMux", "invtrapezium"),
+    ("target", "Branch/Jump\nTarget", "box"),
+    ("pcsrc", "PCSrc\nMux", "invtrapezium"),
+];
+
+/*
+ * Observer writing a frame per cycle into a directory.
+ */
+pub struct DatapathRenderer {
+    dir: PathBuf,
+    failed: bool,
+}
+
+impl DatapathRenderer {
+    /*
+     * Renderer writing cycle_<n>.dot files into dir, created if missing.
+     */
+    pub fn create(dir: &str) -> Result<DatapathRenderer, String> {
+        if let Err(e) = fs::create_dir_all(dir) {
+            return Err(format!("Datapath-Error: Failed to create [{}]: {}", dir, e));
+        }
+        return Ok(DatapathRenderer {
+            dir: PathBuf::from(dir),
+            failed: false,
+        });
+    }
+}
+
+impl Observer for DatapathRenderer {
+    fn cycle(&mut self, cycle: &Cycle) {
+        if self.failed {
+            return;
+        }
+        let path = self.dir.join(format!("cycle_{:06}.dot", cycle.cycle));
+        if let Err(e) = fs::write(&path, render(cycle)) {
+            eprintln!("Datapath-Error: Failed to write [{}]: {}", path.display(), e);
+            self.failed = true;
+        }
+    }
+}
+
+/*
+ * Graphviz DOT of the datapath during a cycle.
+ */
+pub fn render(cycle: &Cycle) -> String {
+    let ctrl = &cycle.ctrl;
+    let retired = cycle.retired();
+    let reads_rs = cycle.reads.iter().any(|(reg, _)| *reg == cycle.instr.rs) && !ctrl.cop0;
+    let imm_operand = retired && ctrl.alu_src != AluSrc::Rt;
+    let reg_write = retired && ctrl.reg_write();
+    let mem_op = cycle.mem.is_some();
+    let mem_write = cycle.mem.map(|m| m.is_write).unwrap_or(false);
+    let taken = cycle.branch == Some(true);
+    let redirect = taken || ctrl.jump();
+    let wb_val = cycle.write.map(|w| w.new).unwrap_or(0);
+
+    let mut dot = String::new();
+    dot.push_str("digraph datapath {\n");
+    dot.push_str("    rankdir=LR;\n");
+    dot.push_str("    node [fontname=\"Helvetica\", fontsize=10];\n");
+    dot.push_str("    edge [fontname=\"Helvetica\", fontsize=9];\n");
+    let title = match cycle.exception {
+        Some(code) => format!("cycle {}  0x{:08x}: {}  (exception {:?})", cycle.cycle, cycle.pc,
+                              cycle.isa.disassemble(cycle.word, cycle.pc), code),
+        None => format!("cycle {}  0x{:08x}: {}", cycle.cycle, cycle.pc, cycle.isa.disassemble(cycle.word, cycle.pc)),
+    };
+    dot.push_str(&format!("    labelloc=t;\n    label=\"{}\";\n", escape(&title)));
+    for (id, label, shape) in UNITS.iter() {
+        dot.push_str(&format!("    {} [label=\"{}\", shape={}];\n", id, escape(label), shape));
+    }
+
+    // data paths
+    let rd = |reg: u8| format!("${}", cycle.isa.reg_name(reg));
+    let rs_label = format!("rs {}", rd(cycle.instr.rs));
+    let rt_label = format!("rt {}", rd(cycle.instr.rt));
+    let rd_label = format!("rd {}", rd(cycle.instr.rd));
+    let mut data = |from: &str, to: &str, label: &str, active: bool| {
+        let (color, width) = if active {(ACTIVE_DATA, 2)} else {(IDLE, 1)};
+        dot.push_str(&format!("    {} -> {} [label=\"{}\", color={}, fontcolor={}, penwidth={}];\n",
+                              from, to, escape(label), color, color, width));
+    };
+    data("pc", "imem", &format!("0x{:08x}", cycle.pc), true);
+    data("pc", "add4", "", true);
+    data("imem", "control", "opcode, funct", true);
+    data("imem", "regfile", &rs_label, reads_rs);
+    data("imem", "regfile", &rt_label, cycle.reads.len() > 1 || (ctrl.cop0 && !cycle.reads.is_empty()));
+    data("imem", "regdst", &rt_label, reg_write && ctrl.reg_dst == RegDst::Rt);
+    data("imem", "regdst", &rd_label, reg_write && ctrl.reg_dst == RegDst::Rd);
+    data("imem", "extend", "imm16", imm_operand || ctrl.branch());
+    data("imem", "target", "addr26", ctrl.pc_src == PcSource::Jump);
+    data("regdst", "regfile", "write reg", reg_write);
+    data("regfile", "alu", &format!("0x{:08x}", cycle.alu_in1), reads_rs && ctrl.pc_src != PcSource::JumpReg);
+    data("regfile", "alusrc", "read data 2",
+         retired && ctrl.alu_src == AluSrc::Rt && cycle.reads.len() > 1 && !ctrl.cop0);
+    data("regfile", "dmem", "write data", mem_write);
+    data("regfile", "cop0", "mtc0", ctrl.cop0 && !cycle.reads.is_empty());
+    data("regfile", "pcsrc", "jr target", ctrl.pc_src == PcSource::JumpReg);
+    data("extend", "alusrc", "imm", imm_operand);
+    data("extend", "target", "branch target", ctrl.branch());
+    data("alusrc", "alu", &format!("0x{:08x}", cycle.alu_in2), retired && !ctrl.jump() && !ctrl.cop0);
+    data("alu", "dmem", "address", mem_op);
+    data("alu", "memtoreg", &format!("0x{:08x}", cycle.alu_res),
+         reg_write && !ctrl.mem_read() && !ctrl.cop0 && !ctrl.link && !ctrl.hilo_read());
+    data("alu", "pcsrc", "zero", ctrl.branch());
+    data("dmem", "memtoreg", "read data", reg_write && ctrl.mem_read());
+    data("cop0", "memtoreg", "mfc0", reg_write && ctrl.cop0);
+    data("memtoreg", "regfile", &format!("write data 0x{:08x}", wb_val), reg_write);
+    data("add4", "memtoreg", "link", reg_write && ctrl.link);
+    data("add4", "pcsrc", "pc + 4", !redirect);
+    data("target", "pcsrc", "", taken || ctrl.pc_src == PcSource::Jump);
+    data("pcsrc", "pc", &format!("0x{:08x}", cycle.next_pc), true);
+
+    // control lines
+    let lines: [(&str, &str, String, bool); 11] = [
+        ("regdst", "reg_dst", format!("{:?}", ctrl.reg_dst), ctrl.reg_write()),
+        ("regfile", "reg_write", (ctrl.reg_write() as u8).to_string(), ctrl.reg_write()),
+        ("alusrc", "alu_src", format!("{:?}", ctrl.alu_src), ctrl.alu_src != AluSrc::Rt),
+        ("alu", "alu_op", format!("{:?}", ctrl.alu_op), !ctrl.jump() && !ctrl.cop0),
+        ("alu", "trap_overflow", (ctrl.trap_overflow as u8).to_string(), ctrl.trap_overflow),
+        ("dmem", "mem_read", (ctrl.mem_read() as u8).to_string(), ctrl.mem_read()),
+        ("dmem", "mem_write", (ctrl.mem_write() as u8).to_string(), ctrl.mem_write()),
+        ("dmem", "mem_size", ctrl.mem_size().to_string(), ctrl.mem_size() != 0),
+        ("memtoreg", "mem_to_reg", (ctrl.mem_read() as u8).to_string(), ctrl.mem_read()),
+        ("cop0", "cop0", (ctrl.cop0 as u8).to_string(), ctrl.cop0),
+        ("pcsrc", "pc_src", format!("{:?}", ctrl.pc_src), ctrl.pc_src != PcSource::Next),
+    ];
+    for (to, name, val, active) in lines.iter() {
+        let (color, width) = if *active && retired {(ACTIVE_CTRL, 2)} else {(IDLE, 1)};
+        dot.push_str(&format!("    control -> {} [label=\"{}={}\", style=dashed, color={}, fontcolor={}, \
+                               penwidth={}];\n", to, name, val, color, color, width));
+    }
+    dot.push_str("}\n");
+    return dot;
+}
+
+// *** PRIVATE FN ***
+
+fn escape(s: &str) -> String {
+    return s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control_bits::{self, AluOp, ControlBits, Width};
+    use crate::cycle::{MemAccess, RegWrite};
+    use crate::demos::assembler::instr_macros::*;
+    use crate::demos::assembler::reg_macros::*;
+    use crate::phases::instr_decode;
+
+    fn edge<'a>(dot: &'a str, from_to: &str, label: &str) -> &'a str {
+        let prefix = format!("    {} [label=\"{}\"", from_to, label);
+        return dot.lines().find(|l| l.starts_with(&prefix)).unwrap();
+    }
+
+    #[test]
+    fn test_load() {
+        let word = LW(T1(), 0x40, ZERO());
+        let mut cycle = Cycle {
+            cycle: 2,
+            pc: 0x8,
+            word,
+            reads: vec![(ZERO(), 0)],
+            alu_in2: 0x40,
+            alu_res: 0x40,
+            write: Some(RegWrite { reg: T1(), old: 0, new: 0xbabe }),
+            mem: Some(MemAccess { addr: 0x40, paddr: 0x40, size: 4, is_write: false, old: 0xbabe, value: 0xbabe }),
+            next_pc: 0xc,
+            ..Cycle::default()
+        };
+        instr_decode(word, &mut cycle.instr);
+        cycle.ctrl = ControlBits {
+            alu_src: AluSrc::SignImm,
+            reg_dst: RegDst::Rt,
+            mem: control_bits::MemAccess::Load { width: Width::Word, signed: true },
+            ..ControlBits::default()
+        };
+
+        let dot = render(&cycle);
+        assert!(dot.contains("label=\"cycle 2  0x00000008: lw $t1, 0x40($zero)\";"));
+        assert!(edge(&dot, "alu -> dmem", "address").contains(ACTIVE_DATA));
+        assert!(edge(&dot, "dmem -> memtoreg", "read data").contains(ACTIVE_DATA));
+        assert!(edge(&dot, "alu -> memtoreg", "0x00000040").contains(IDLE));
+        assert!(edge(&dot, "imem -> regdst", "rt $t1").contains(ACTIVE_DATA));
+        assert!(edge(&dot, "memtoreg -> regfile", "write data 0x0000babe").contains(ACTIVE_DATA));
+        assert!(edge(&dot, "add4 -> pcsrc", "pc + 4").contains(ACTIVE_DATA));
+        assert!(edge(&dot, "control -> dmem", "mem_read=1").contains(ACTIVE_CTRL));
+        assert!(edge(&dot, "control -> dmem", "mem_write=0").contains(IDLE));
+        assert!(edge(&dot, "control -> alusrc", "alu_src=SignImm").contains(ACTIVE_CTRL));
+    }
+
+    #[test]
+    fn test_branch() {
+        let word = BEQ(T0(), T1(), 0x20);
+        let mut cycle = Cycle {
+            pc: 0x4,
+            word,
+            reads: vec![(T0(), 1), (T1(), 1)],
+            branch: Some(true),
+            next_pc: 0x20,
+            ..Cycle::default()
+        };
+        instr_decode(word, &mut cycle.instr);
+        cycle.ctrl.alu_op = AluOp::Sub;
+        cycle.ctrl.pc_src = PcSource::BranchEq;
+
+        let dot = render(&cycle);
+        assert!(edge(&dot, "target -> pcsrc", "").contains(ACTIVE_DATA));
+        assert!(edge(&dot, "add4 -> pcsrc", "pc + 4").contains(IDLE));
+        assert!(edge(&dot, "control -> pcsrc", "pc_src=BranchEq").contains(ACTIVE_CTRL));
+        assert!(edge(&dot, "imem -> regdst", "rd $zero").contains(IDLE));
+        // every frame has the same edges
+        assert_eq!(dot.matches(" -> ").count(), render(&Cycle::default()).matches(" -> ").count());
+    }
+}
@@ -0,0 +1,83 @@
+/*
+ * disasm.rs
+ *
+ * Author: Travis Banken
+ *
+ * Turns instruction words back into assembly text. Branch and jump targets
+ * are printed as the absolute address this processor jumps to.
+ */
+#![allow(dead_code)]
+
+use crate::hardware::reg_file::reg_name;
+use crate::instruction::{self, operand_value, Instruction, Operand};
+use crate::phases::instr_decode;
+
+/*
+ * Disassembles the instruction word found at address pc.
+ */
+pub fn disassemble(word: u32, pc: u32) -> String {
+    if word == 0 {
+        return "nop".to_string();
+    }
+    let mut instr = Instruction::default();
+    instr_decode(word, &mut instr);
+    let def = match instruction::decode(&instr) {
+        Some(def) => def,
+        None => return format!(".word 0x{:08x}", word),
+    };
+
+    let mut operands = Vec::new();
+    for operand in def.operands.iter() {
+        let val = operand_value(*operand, &instr);
+        let text = match operand {
+            Operand::Rd | Operand::Rs | Operand::Rt => format!("${}", reg_name(val as u8)),
+            Operand::Shamt => val.to_string(),
+            Operand::Imm => format!("0x{:x}", val),
+            Operand::Offset => format!("0x{:x}(${})", val, reg_name(instr.rs)),
+            // printed with the offset
+            Operand::Base => continue,
+            Operand::Branch => format!("0x{:08x}", (pc & 0xffff_0000) | val),
+            Operand::Target => format!("0x{:08x}", (pc & 0xff00_0000) | val),
+            Operand::Cop0Reg => format!("${}", val),
+            Operand::ExtSize | Operand::InsSize => val.to_string(),
+        };
+        operands.push(text);
+    }
+    if operands.is_empty() {
+        return def.mnemonic.to_string();
+    }
+    return format!("{} {}", def.mnemonic, operands.join(", "));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::demos::assembler::reg_macros::*;
+    use crate::demos::assembler::instr_macros::*;
+
+    #[test]
+    fn test_disassemble() {
+        assert_eq!(disassemble(ADD(T0(), S0(), S1()), 0), "add $t0, $s0, $s1");
+        assert_eq!(disassemble(ADDI(T0(), ZERO(), 0xbabe), 0), "addi $t0, $zero, 0xbabe");
+        assert_eq!(disassemble(LUI(T1(), 0xcafe), 0), "lui $t1, 0xcafe");
+        assert_eq!(disassemble(SW(T0(), 4, S0()), 0), "sw $t0, 0x4($s0)");
+        assert_eq!(disassemble(BEQ(T0(), ZERO(), 0x1c), 0x8000_0004), "beq $t0, $zero, 0x8000001c");
+        assert_eq!(disassemble(J(0xc), 0x10), "j 0x0000000c");
+        assert_eq!(disassemble(JR(RA()), 0), "jr $ra");
+        assert_eq!(disassemble(MFC0(K0(), 14), 0), "mfc0 $k0, $14");
+        assert_eq!(disassemble(RFE(), 0), "rfe");
+        assert_eq!(disassemble(0, 0), "nop");
+        assert_eq!(disassemble(SLL(T0(), T1(), 4), 0), "sll $t0, $t1, 4");
+        assert_eq!(disassemble(LBU(T0(), 0xfffc, SP()), 0), "lbu $t0, 0xfffc($sp)");
+        assert_eq!(disassemble(MUL(V0(), A0(), A1()), 0), "mul $v0, $a0, $a1");
+        assert_eq!(disassemble(CLZ(V0(), A0()), 0), "clz $v0, $a0");
+        assert_eq!(disassemble(ROTR(V0(), A0(), 8), 0), "rotr $v0, $a0, 8");
+        assert_eq!(disassemble(SRL(V0(), A0(), 8), 0), "srl $v0, $a0, 8");
+        assert_eq!(disassemble(EXT(V0(), A0(), 8, 4), 0), "ext $v0, $a0, 8, 4");
+        assert_eq!(disassemble(INS(V0(), A0(), 8, 4), 0), "ins $v0, $a0, 8, 4");
+        assert_eq!(disassemble(TEQI(A0(), 0xffff), 0), "teqi $a0, 0xffff");
+        assert_eq!(disassemble(SYNC(), 0), "sync");
+        assert_eq!(disassemble(0xffff_ffff, 0), ".word 0xffffffff");
+        assert_eq!(disassemble(0x4220_0000, 0), ".word 0x42200000");
+    }
+}
@@ -0,0 +1,322 @@
+/*
+ * elf.rs
+ *
+ * Author: Travis Banken
+ *
+ * Reads and writes 32 bit ELF executables, big endian MIPS and little endian
+ * RISC-V ones. Only what the loader needs is kept: the machine, the entry point, the PT_LOAD segments and the named
+ * symbols of .symtab. Executable segments go to instruction memory, the
+ * others to data memory, both at their physical address.
+ */
+#![allow(dead_code)]
+
+pub const EM_MIPS: u16 = 8;
+pub const EM_RISCV: u16 = 243;
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHF_WRITE: u32 = 1;
+const SHF_ALLOC: u32 = 2;
+const SHF_EXECINSTR: u32 = 4;
+const STT_SECTION: u8 = 3;
+const STT_FILE: u8 = 4;
+const STB_GLOBAL: u8 = 1;
+
+const EHDR_SIZE: usize = 52;
+const PHDR_SIZE: usize = 32;
+const SHDR_SIZE: usize = 40;
+const SYM_SIZE: usize = 16;
+
+// (name, type, flags, addr, contents, link, entsize) of a section header
+type Section<'a> = (u32, u32, u32, u32, &'a [u8], u32, u32);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub addr: u32,
+    pub bytes: Vec<u8>,
+    pub exec: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: u32,
+    // defined in an executable section
+    pub exec: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Elf {
+    // EM_MIPS or EM_RISCV, which also gives the byte order
+    pub machine: u16,
+    pub entry: u32,
+    pub segments: Vec<Segment>,
+    pub symbols: Vec<Symbol>,
+}
+
+impl Elf {
+    pub fn parse(file: &[u8]) -> Result<Elf, String> {
+        if file.len() < EHDR_SIZE || file[0..4] != [0x7f, b'E', b'L', b'F'] {
+            return Err("Elf-Error: Not an ELF file".to_string());
+        }
+        let little = file[5] == 1;
+        let r16 = |bytes: &[u8], offset: usize| read16(bytes, offset, little);
+        let r32 = |bytes: &[u8], offset: usize| read32(bytes, offset, little);
+        let machine = r16(file, 18)?;
+        if file[4] != 1 || (little, machine) != (false, EM_MIPS) && (little, machine) != (true, EM_RISCV) {
+            return Err("Elf-Error: Only 32 bit big endian MIPS and little endian RISC-V files are supported"
+                       .to_string());
+        }
+        let mut elf = Elf {
+            machine,
+            entry: r32(file, 24)?,
+            ..Elf::default()
+        };
+
+        let phoff = r32(file, 28)? as usize;
+        let phentsize = r16(file, 42)? as usize;
+        for i in 0..r16(file, 44)? as usize {
+            let ph = phoff + i * phentsize;
+            if r32(file, ph)? != PT_LOAD {
+                continue;
+            }
+            let offset = r32(file, ph + 4)? as usize;
+            let filesz = r32(file, ph + 16)? as usize;
+            let memsz = r32(file, ph + 20)? as usize;
+            let mut bytes = slice(file, offset, filesz)?.to_vec();
+            // .bss and friends are zero filled
+            bytes.resize(memsz.max(filesz), 0);
+            elf.segments.push(Segment {
+                addr: r32(file, ph + 12)?,
+                bytes,
+                exec: r32(file, ph + 24)? & PF_X != 0,
+            });
+        }
+
+        let shoff = r32(file, 32)? as usize;
+        let shentsize = r16(file, 46)? as usize;
+        let shnum = r16(file, 48)? as usize;
+        let section = |i: usize| -> Result<usize, String> {
+            if i >= shnum {
+                return Err(format!("Elf-Error: No section {}", i));
+            }
+            return Ok(shoff + i * shentsize);
+        };
+        for i in 0..shnum {
+            let sh = section(i)?;
+            if r32(file, sh + 4)? != SHT_SYMTAB {
+                continue;
+            }
+            let strtab = section(r32(file, sh + 24)? as usize)?;
+            let strings = slice(file, r32(file, strtab + 16)? as usize, r32(file, strtab + 20)? as usize)?;
+            let syms = slice(file, r32(file, sh + 16)? as usize, r32(file, sh + 20)? as usize)?;
+            for sym in syms.chunks_exact(SYM_SIZE) {
+                let name = c_string(strings, r32(sym, 0)? as usize);
+                let kind = sym[12] & 0xf;
+                let shndx = r16(sym, 14)? as usize;
+                if name.is_empty() || kind == STT_SECTION || kind == STT_FILE || shndx == 0 || shndx >= shnum {
+                    continue;
+                }
+                let flags = r32(file, section(shndx)? + 8)?;
+                elf.symbols.push(Symbol {
+                    name,
+                    addr: r32(sym, 4)?,
+                    exec: flags & SHF_EXECINSTR != 0,
+                });
+            }
+        }
+        return Ok(elf);
+    }
+
+    /*
+     * Executable with a .text section for the executable segment, a .data
+     * section for the other one and the symbols in .symtab.
+     */
+    pub fn write(&self) -> Vec<u8> {
+        let little = self.machine == EM_RISCV;
+        let text = self.segments.iter().find(|seg| seg.exec);
+        let data = self.segments.iter().find(|seg| !seg.exec);
+        let loads: Vec<&Segment> = text.iter().chain(data.iter()).cloned().collect();
+
+        // section names
+        let mut shstrtab = vec![0];
+        let mut name = |s: &str| -> u32 {
+            let at = shstrtab.len() as u32;
+            shstrtab.extend_from_slice(s.as_bytes());
+            shstrtab.push(0);
+            return at;
+        };
+        let text_name = name(".text");
+        let data_name = name(".data");
+        let symtab_name = name(".symtab");
+        let strtab_name = name(".strtab");
+        let shstrtab_name = name(".shstrtab");
+
+        // section indexes: null, .text, .data, .symtab, .strtab, .shstrtab
+        let mut sections: Vec<Section> = Vec::new();
+        let mut strtab = vec![0];
+        let mut symtab = vec![0; SYM_SIZE];
+        for sym in self.symbols.iter() {
+            let shndx: u16 = if sym.exec {1} else {2};
+            push32(&mut symtab, strtab.len() as u32, little);
+            push32(&mut symtab, sym.addr, little);
+            push32(&mut symtab, 0, little);
+            symtab.push(STB_GLOBAL << 4);
+            symtab.push(0);
+            push16(&mut symtab, shndx, little);
+            strtab.extend_from_slice(sym.name.as_bytes());
+            strtab.push(0);
+        }
+        let empty: &[u8] = &[];
+        let text_bytes = text.map(|seg| seg.bytes.as_slice()).unwrap_or(empty);
+        let data_bytes = data.map(|seg| seg.bytes.as_slice()).unwrap_or(empty);
+        sections.push((text_name, SHT_PROGBITS, SHF_ALLOC | SHF_EXECINSTR, text.map(|s| s.addr).unwrap_or(0),
+                       text_bytes, 0, 0));
+        sections.push((data_name, SHT_PROGBITS, SHF_ALLOC | SHF_WRITE, data.map(|s| s.addr).unwrap_or(0),
+                       data_bytes, 0, 0));
+        sections.push((symtab_name, SHT_SYMTAB, 0, 0, &symtab, 4, SYM_SIZE as u32));
+        sections.push((strtab_name, SHT_STRTAB, 0, 0, &strtab, 0, 0));
+        sections.push((shstrtab_name, SHT_STRTAB, 0, 0, &shstrtab, 0, 0));
+
+        let phoff = EHDR_SIZE;
+        let mut offset = phoff + loads.len() * PHDR_SIZE;
+        let mut offsets = Vec::new();
+        for section in sections.iter() {
+            offsets.push(offset);
+            offset += section.4.len();
+        }
+        let shoff = (offset + 3) & !3;
+
+        let mut out = Vec::new();
+        let data = if little {1} else {2};
+        out.extend_from_slice(&[0x7f, b'E', b'L', b'F', 1, data, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        push16(&mut out, 2, little); // executable
+        push16(&mut out, self.machine, little);
+        push32(&mut out, 1, little);
+        push32(&mut out, self.entry, little);
+        push32(&mut out, phoff as u32, little);
+        push32(&mut out, shoff as u32, little);
+        push32(&mut out, 0, little);
+        for half in [EHDR_SIZE, PHDR_SIZE, loads.len(), SHDR_SIZE, sections.len() + 1, sections.len()].iter() {
+            push16(&mut out, *half as u16, little);
+        }
+
+        for seg in loads.iter() {
+            let index = if seg.exec {0} else {1};
+            let flags = if seg.exec {PF_R | PF_X} else {PF_R | PF_W};
+            for word in [PT_LOAD, offsets[index] as u32, seg.addr, seg.addr, seg.bytes.len() as u32,
+                         seg.bytes.len() as u32, flags, 4].iter() {
+                push32(&mut out, *word, little);
+            }
+        }
+        for section in sections.iter() {
+            out.extend_from_slice(section.4);
+        }
+        out.resize(shoff, 0);
+
+        out.extend_from_slice(&[0; SHDR_SIZE]);
+        for (i, (name, kind, flags, addr, contents, link, entsize)) in sections.iter().enumerate() {
+            // symbol table info is the index of the first global symbol
+            let info = if *kind == SHT_SYMTAB {1} else {0};
+            for word in [*name, *kind, *flags, *addr, offsets[i] as u32, contents.len() as u32, *link, info, 4,
+                         *entsize].iter() {
+                push32(&mut out, *word, little);
+            }
+        }
+        return out;
+    }
+}
+
+// *** PRIVATE FN ***
+
+fn slice(file: &[u8], offset: usize, len: usize) -> Result<&[u8], String> {
+    match file.get(offset..offset.saturating_add(len)) {
+        Some(bytes) => Ok(bytes),
+        None => Err(format!("Elf-Error: Truncated file, {} bytes at 0x{:x} are missing", len, offset)),
+    }
+}
+
+fn read16(file: &[u8], offset: usize, little: bool) -> Result<u16, String> {
+    let b = slice(file, offset, 2)?;
+    return Ok(if little {u16::from_le_bytes([b[0], b[1]])} else {u16::from_be_bytes([b[0], b[1]])});
+}
+
+fn read32(file: &[u8], offset: usize, little: bool) -> Result<u32, String> {
+    let b = slice(file, offset, 4)?;
+    let bytes = [b[0], b[1], b[2], b[3]];
+    return Ok(if little {u32::from_le_bytes(bytes)} else {u32::from_be_bytes(bytes)});
+}
+
+fn push16(out: &mut Vec<u8>, val: u16, little: bool) {
+    out.extend_from_slice(&if little {val.to_le_bytes()} else {val.to_be_bytes()});
+}
+
+fn push32(out: &mut Vec<u8>, val: u32, little: bool) {
+    out.extend_from_slice(&if little {val.to_le_bytes()} else {val.to_be_bytes()});
+}
+
+fn c_string(strings: &[u8], offset: usize) -> String {
+    let bytes = strings.get(offset..).unwrap_or(&[]);
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    return String::from_utf8_lossy(&bytes[..end]).to_string();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program() -> Elf {
+        Elf {
+            machine: EM_MIPS,
+            entry: 0x8,
+            segments: vec![
+                Segment { addr: 0, bytes: vec![0x20, 0x08, 0x00, 0x01, 0, 0, 0, 0, 0x03, 0xe0, 0x00, 0x08], exec: true },
+                Segment { addr: 0x10, bytes: b"hello\0".to_vec(), exec: false },
+            ],
+            symbols: vec![
+                Symbol { name: "main".to_string(), addr: 0x8, exec: true },
+                Symbol { name: "msg".to_string(), addr: 0x10, exec: false },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let elf = program();
+        let file = elf.write();
+        assert_eq!(&file[0..6], &[0x7f, b'E', b'L', b'F', 1, 2]);
+        assert_eq!(Elf::parse(&file), Ok(elf));
+
+        let text_only = Elf {
+            segments: vec![program().segments[0].clone()],
+            symbols: Vec::new(),
+            ..program()
+        };
+        assert_eq!(Elf::parse(&text_only.write()), Ok(text_only));
+
+        let riscv = Elf {
+            machine: EM_RISCV,
+            ..program()
+        };
+        let file = riscv.write();
+        assert_eq!(&file[0..6], &[0x7f, b'E', b'L', b'F', 1, 1]);
+        assert_eq!(&file[18..20], &[243, 0]);
+        assert_eq!(Elf::parse(&file), Ok(riscv));
+    }
+
+    #[test]
+    fn test_bad_files() {
+        assert_eq!(Elf::parse(b"not an elf"), Err("Elf-Error: Not an ELF file".to_string()));
+        let mut file = program().write();
+        file[5] = 1;
+        assert_eq!(Elf::parse(&file),
+                   Err("Elf-Error: Only 32 bit big endian MIPS and little endian RISC-V files are supported"
+                       .to_string()));
+        let file = program().write();
+        assert!(Elf::parse(&file[..60]).unwrap_err().starts_with("Elf-Error: Truncated file"));
+    }
+}
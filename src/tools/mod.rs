@@ -1,2 +1,13 @@
 pub mod dump_data_mem;
-pub mod dump_instr_mem;
\ No newline at end of file
+pub mod dump_instr_mem;
+pub mod cache_report;
+pub mod symbols;
+pub mod disasm;
+pub mod asm;
+pub mod elf;
+pub mod trace;
+pub mod vcd;
+pub mod datapath;
+pub mod profile;
+pub mod coverage;
+pub mod test_runner;
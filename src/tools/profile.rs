@@ -0,0 +1,486 @@
+/*
+ * profile.rs
+ *
+ * Author: Travis Banken
+ *
+ * Execution profiler: counts the instructions retired per opcode/funct, per
+ * class and per pc, the direction of every branch and the addresses of loads
+ * and stores. Calls are tracked from jal/jalr to the jr $ra that returns
+ * (jalr zero, 0(ra) on RISC-V), which gives a flat profile per function, a
+ * call graph and folded stacks ("main;fn;leaf 12" per line) for
+ * flamegraph.pl, inferno or speedscope.
+ */
+#![allow(dead_code)]
+
+use crate::cycle::{Class, Cycle, Observer};
+use crate::isa::Isa;
+use crate::tools::symbols::SymbolTable;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fs;
+
+// bytes per bar of the load/store address histograms
+pub const BUCKET: u32 = 16;
+// rows of the hot spot table
+pub const HOT_SPOTS: usize = 20;
+// width of the longest histogram bar
+const BAR: u64 = 40;
+
+/*
+ * Where the report and the folded stacks go, "-" for stdout.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct ProfileConfig {
+    pub report: Option<String>,
+    pub folded: Option<String>,
+}
+
+impl ProfileConfig {
+    pub fn enabled(&self) -> bool {
+        self.report.is_some() || self.folded.is_some()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BranchStats {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FuncStats {
+    // instructions retired in the function itself
+    pub own: u64,
+    // including the functions it called
+    pub total: u64,
+    pub calls: u64,
+}
+
+/*
+ * Counters collected during a run. Functions are identified by their entry
+ * address, the first instruction of the run is the entry of the root.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    pub instructions: u64,
+    // cycles that took an exception, not counted anywhere else
+    pub exceptions: u64,
+    // (opcode, funct) -> (first word seen, count), funct is 0 except for
+    // r-format and cop0 instructions, funct3 (and bit 30) on RISC-V
+    pub opcodes: BTreeMap<(u8, u8), (u32, u64)>,
+    pub classes: BTreeMap<Class, u64>,
+    // pc -> (word, count)
+    pub pcs: BTreeMap<u32, (u32, u64)>,
+    pub branches: BTreeMap<u32, BranchStats>,
+    // start of a BUCKET sized range -> accesses
+    pub loads: BTreeMap<u32, u64>,
+    pub stores: BTreeMap<u32, u64>,
+    pub functions: BTreeMap<u32, FuncStats>,
+    // (caller, callee) -> calls
+    pub calls: BTreeMap<(u32, u32), u64>,
+    // call stack, root first -> instructions retired with it on top
+    pub stacks: BTreeMap<Vec<u32>, u64>,
+    stack: Vec<u32>,
+    // of the cycles seen
+    isa: Isa,
+}
+
+impl Profile {
+    pub fn new() -> Profile {
+        Profile::default()
+    }
+
+    /*
+     * Counts one cycle.
+     */
+    pub fn record(&mut self, cycle: &Cycle) {
+        if !cycle.retired() {
+            self.exceptions += 1;
+            return;
+        }
+        if self.stack.is_empty() {
+            self.stack.push(cycle.pc);
+        }
+        self.instructions += 1;
+        self.isa = cycle.isa;
+
+        self.opcodes.entry(opcode_key(cycle)).or_insert((cycle.word, 0)).1 += 1;
+        *self.classes.entry(cycle.class()).or_insert(0) += 1;
+        self.pcs.entry(cycle.pc).or_insert((cycle.word, 0)).1 += 1;
+
+        if let Some(taken) = cycle.branch {
+            let stats = self.branches.entry(cycle.pc).or_default();
+            if taken {
+                stats.taken += 1;
+            } else {
+                stats.not_taken += 1;
+            }
+        }
+        if let Some(mem) = cycle.mem {
+            let bucket = mem.addr - mem.addr % BUCKET;
+            let hist = if mem.is_write {&mut self.stores} else {&mut self.loads};
+            *hist.entry(bucket).or_insert(0) += 1;
+        }
+
+        // the instruction counts for the function it is in, a call is part of
+        // the caller and a return of the function returning
+        let current = *self.stack.last().unwrap();
+        self.functions.entry(current).or_default().own += 1;
+        for (i, func) in self.stack.iter().enumerate() {
+            // recursive functions are only counted once per instruction
+            if !self.stack[..i].contains(func) {
+                self.functions.entry(*func).or_default().total += 1;
+            }
+        }
+        *self.stacks.entry(self.stack.clone()).or_insert(0) += 1;
+
+        let flow = cycle.isa.flow(cycle.word, cycle.pc);
+        if flow.call {
+            let callee = cycle.next_pc;
+            *self.calls.entry((current, callee)).or_insert(0) += 1;
+            self.functions.entry(callee).or_default().calls += 1;
+            self.stack.push(callee);
+        } else if flow.ret && self.stack.len() > 1 {
+            self.stack.pop();
+        }
+    }
+
+    /*
+     * Text report, every table sorted by count.
+     */
+    pub fn report(&self, symbols: &SymbolTable) -> String {
+        let total = self.instructions;
+        let mut text = String::new();
+        text.push_str(&format!("Profile: {} instructions retired, {} exceptions\n", total, self.exceptions));
+
+        text.push_str("\nInstruction mix:\n");
+        let mut classes: Vec<(&Class, &u64)> = self.classes.iter().collect();
+        classes.sort_by_key(|(_, count)| Reverse(**count));
+        for (class, count) in classes {
+            text.push_str(&format!("  {:<8} {:>10} {:>6.2}%\n", class.name(), count, percent(*count, total)));
+        }
+
+        text.push_str("\nOpcodes:\n");
+        let mut opcodes: Vec<_> = self.opcodes.iter().collect();
+        opcodes.sort_by_key(|(_, (_, count))| Reverse(*count));
+        for ((opcode, sub), (word, count)) in opcodes {
+            text.push_str(&format!("  {:<8} 0x{:02x}/0x{:02x} {:>10} {:>6.2}%\n", mnemonic(self.isa, *word), opcode, sub,
+                                   count, percent(*count, total)));
+        }
+
+        text.push_str("\nHot spots:\n");
+        let mut pcs: Vec<(&u32, &(u32, u64))> = self.pcs.iter().collect();
+        pcs.sort_by(|a, b| (b.1).1.cmp(&(a.1).1).then(a.0.cmp(b.0)));
+        for (pc, (word, count)) in pcs.iter().take(HOT_SPOTS) {
+            text.push_str(&format!("  0x{:08x} {:<16} {:>10} {:>6.2}%  {}\n", pc, location(symbols, **pc), count,
+                                   percent(*count, total), self.isa.disassemble(*word, **pc)));
+        }
+
+        if !self.branches.is_empty() {
+            text.push_str("\nBranches:\n");
+            text.push_str(&format!("  {:<10} {:<16} {:>10} {:>10} {:>7}\n", "pc", "", "taken", "not taken",
+                                   "taken%"));
+            let mut branches: Vec<(&u32, &BranchStats)> = self.branches.iter().collect();
+            branches.sort_by_key(|(_, stats)| Reverse(stats.taken + stats.not_taken));
+            for (pc, stats) in branches {
+                text.push_str(&format!("  0x{:08x} {:<16} {:>10} {:>10} {:>6.2}%\n", pc, location(symbols, *pc),
+                                       stats.taken, stats.not_taken,
+                                       percent(stats.taken, stats.taken + stats.not_taken)));
+            }
+        }
+
+        for (name, hist) in [("Loads", &self.loads), ("Stores", &self.stores)].iter() {
+            if hist.is_empty() {
+                continue;
+            }
+            text.push_str(&format!("\n{} by address:\n", name));
+            let max = *hist.values().max().unwrap();
+            for (start, count) in hist.iter() {
+                let bar = (count * BAR).div_ceil(max);
+                text.push_str(&format!("  0x{:08x}-0x{:08x} {:>10} {}\n", start, start + BUCKET - 1, count,
+                                       "#".repeat(bar as usize)));
+            }
+        }
+
+        text.push_str("\nFlat profile:\n");
+        text.push_str(&format!("  {:>7} {:>10} {:>7} {:>10} {:>8}  {}\n", "self%", "self", "total%", "total",
+                               "calls", "function"));
+        let mut functions: Vec<(&u32, &FuncStats)> = self.functions.iter().collect();
+        functions.sort_by(|a, b| b.1.own.cmp(&a.1.own).then(a.0.cmp(b.0)));
+        for (func, stats) in functions {
+            text.push_str(&format!("  {:>6.2}% {:>10} {:>6.2}% {:>10} {:>8}  {}\n", percent(stats.own, total),
+                                   stats.own, percent(stats.total, total), stats.total, stats.calls,
+                                   function(symbols, *func)));
+        }
+
+        if !self.calls.is_empty() {
+            text.push_str("\nCall graph:\n");
+            let mut callers: Vec<u32> = self.calls.keys().map(|(caller, _)| *caller).collect();
+            callers.dedup();
+            for caller in callers {
+                text.push_str(&format!("  {}\n", function(symbols, caller)));
+                let mut callees: Vec<(u32, u64)> = self.calls.iter()
+                    .filter(|((from, _), _)| *from == caller)
+                    .map(|((_, to), count)| (*to, *count))
+                    .collect();
+                callees.sort_by_key(|(_, count)| Reverse(*count));
+                for (callee, count) in callees {
+                    let total = self.functions.get(&callee).map(|f| f.total).unwrap_or(0);
+                    text.push_str(&format!("    -> {:<24} {:>8} calls {:>10} instructions\n",
+                                           function(symbols, callee), count, total));
+                }
+            }
+        }
+        return text;
+    }
+
+    /*
+     * One "root;caller;callee count" line per call stack.
+     */
+    pub fn folded(&self, symbols: &SymbolTable) -> String {
+        let mut text = String::new();
+        for (stack, count) in self.stacks.iter() {
+            let names: Vec<String> = stack.iter().map(|func| function(symbols, *func)).collect();
+            text.push_str(&format!("{} {}\n", names.join(";"), count));
+        }
+        return text;
+    }
+}
+
+/*
+ * Observer collecting a profile, writes the report and the folded stacks
+ * when the run finishes.
+ */
+pub struct Profiler {
+    pub profile: Profile,
+    symbols: SymbolTable,
+    config: ProfileConfig,
+}
+
+impl Profiler {
+    pub fn new(config: &ProfileConfig, symbols: &SymbolTable) -> Profiler {
+        Profiler {
+            profile: Profile::new(),
+            symbols: symbols.clone(),
+            config: config.clone(),
+        }
+    }
+}
+
+impl Observer for Profiler {
+    fn cycle(&mut self, cycle: &Cycle) {
+        self.profile.record(cycle);
+    }
+
+    fn finish(&mut self) {
+        if let Some(path) = self.config.report.as_ref() {
+            if let Err(msg) = write_out(path, &self.profile.report(&self.symbols)) {
+                eprintln!("{}", msg);
+            }
+        }
+        if let Some(path) = self.config.folded.as_ref() {
+            if let Err(msg) = write_out(path, &self.profile.folded(&self.symbols)) {
+                eprintln!("{}", msg);
+            }
+        }
+    }
+}
+
+// *** PRIVATE FN ***
+
+fn write_out(path: &str, text: &str) -> Result<(), String> {
+    if path == "-" {
+        print!("{}", text);
+        return Ok(());
+    }
+    match fs::write(path, text) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(format!("Profile-Error: Failed to write [{}]: {}", path, e)),
+    }
+}
+
+fn percent(count: u64, total: u64) -> f64 {
+    if total == 0 {
+        return 0.0;
+    }
+    return count as f64 * 100.0 / total as f64;
+}
+
+/*
+ * (opcode, funct) counted for an instruction.
+ */
+fn opcode_key(cycle: &Cycle) -> (u8, u8) {
+    let instr = &cycle.instr;
+    let sub = match (cycle.isa, instr.opcode) {
+        (Isa::Mips, 0x00) | (Isa::Mips, 0x1c) => instr.funct,
+        // regimm by rt, seb/seh/wsbh by shamt past the special3 functs
+        (Isa::Mips, 0x01) => instr.rt,
+        (Isa::Mips, 0x1f) if instr.funct == 0x20 => 0x20 | instr.shamt,
+        (Isa::Mips, 0x1f) => instr.funct,
+        // mfc0/mtc0 by rs, the co instructions (tlb ops, rfe) by funct
+        (Isa::Mips, 0x10) if instr.rs & 0x10 == 0 => instr.rs,
+        (Isa::Mips, 0x10) => instr.funct,
+        (Isa::Mips, _) => 0,
+        // add/sub and srl/sra differ in bit 30, the M instructions in bit 25,
+        // ecall/ebreak/mret in the low bits of the immediate
+        (Isa::Rv32i, 0x33) => instr.funct | ((cycle.word >> 25) & 0x20) as u8 | ((cycle.word >> 21) & 0x10) as u8,
+        (Isa::Rv32i, 0x13) if instr.funct == 5 => instr.funct | ((cycle.word >> 25) & 0x20) as u8,
+        (Isa::Rv32i, 0x73) if instr.funct == 0 => ((cycle.word >> 20) & 0xff) as u8,
+        // the atomics by funct5
+        (Isa::Rv32i, 0x2f) => (cycle.word >> 27) as u8,
+        (Isa::Rv32i, 0x37) | (Isa::Rv32i, 0x17) | (Isa::Rv32i, 0x6f) => 0,
+        (Isa::Rv32i, _) => instr.funct,
+    };
+    return (instr.opcode, sub);
+}
+
+fn mnemonic(isa: Isa, word: u32) -> String {
+    let text = isa.disassemble(word, 0);
+    return text.split_whitespace().next().unwrap_or("").to_string();
+}
+
+/*
+ * Label (with offset) of a pc, empty without labels.
+ */
+fn location(symbols: &SymbolTable, pc: u32) -> String {
+    return symbols.describe(pc).unwrap_or_default();
+}
+
+/*
+ * Name of the function entered at addr.
+ */
+fn function(symbols: &SymbolTable, addr: u32) -> String {
+    match symbols.name_at(addr) {
+        Some(name) => name.to_string(),
+        None => format!("0x{:08x}", addr),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cycle::MemAccess;
+    use crate::demos::assembler::instr_macros::*;
+    use crate::demos::assembler::mem_loader::MemLoader;
+    use crate::demos::assembler::reg_macros::*;
+    use crate::hardware::{bus, data_mem, instr_mem};
+    use crate::phases::instr_decode;
+    use crate::single_cycle::{Options, Processor};
+
+    fn cycle(pc: u32, word: u32, next_pc: u32) -> Cycle {
+        let mut c = Cycle {
+            pc,
+            word,
+            next_pc,
+            ..Cycle::default()
+        };
+        instr_decode(word, &mut c.instr);
+        return c;
+    }
+
+    #[test]
+    fn test_mix() {
+        let mut loader = MemLoader::new(instr_mem::Memory::new());
+        loader.label("main");
+        loader.load_instr( ADDI(T1(), ZERO(), 3) );
+        let lp = loader.label("loop");
+        loader.load_instr( BEQ (T0(), T1()  , 0x100) ); // off the end of memory
+        loader.load_instr( SB  (T0(), 0x40  , T0()) );
+        loader.load_instr( ADDI(T0(), T0()  , 1) );
+        loader.load_instr( J   (lp) );
+        let symbols = loader.symbols().clone();
+        let mem = loader.return_mem();
+
+        let mut data_mem = data_mem::Memory::new();
+        let mut bus = bus::Bus::new(&mut data_mem);
+        let mut cpu = Processor::new(&Options::default());
+        let mut profile = Profile::new();
+        while cpu.is_running() {
+            let cycle = cpu.step(&mem, &mut bus);
+            profile.record(&cycle);
+        }
+
+        // addi, 3 times round the loop and the beq leaving it
+        assert_eq!(profile.instructions, 1 + 3 * 4 + 1);
+        assert_eq!(profile.classes[&Class::Store], 3);
+        assert_eq!(profile.opcodes[&(0x08, 0)].1, 4);
+        assert_eq!(profile.pcs[&0x4].1, 4);
+        assert_eq!(profile.branches[&0x4], BranchStats { taken: 1, not_taken: 3 });
+        assert_eq!(profile.stores.iter().collect::<Vec<_>>(), vec![(&0x40, &3)]);
+        assert_eq!(profile.functions[&0].own, 14);
+
+        let report = profile.report(&symbols);
+        assert!(report.starts_with("Profile: 14 instructions retired, 0 exceptions\n"));
+        assert!(report.contains("\nOpcodes:\n  beq      0x04/0x00          4  28.57%\n"));
+        assert!(report.contains("  0x00000004 loop                      4  28.57%  beq $t0, $t1, 0x00000100\n"));
+        assert!(report.contains("  0x00000004 loop                      1          3  25.00%\n"));
+        assert!(report.contains("  0x00000040-0x0000004f          3 ####"));
+        assert_eq!(profile.folded(&symbols), "main 14\n");
+    }
+
+    #[test]
+    fn test_calls() {
+        let mut symbols = SymbolTable::new();
+        symbols.add("main", 0x0);
+        symbols.add("leaf", 0x40);
+        let mut profile = Profile::new();
+        // main calls leaf twice, leaf runs two instructions and returns
+        for call in 0..2 {
+            let pc = call * 8;
+            profile.record(&cycle(pc, 0x0c00_0040, 0x40)); // jal
+            profile.record(&cycle(0x40, ADDI(V0(), ZERO(), 1), 0x44));
+            profile.record(&cycle(0x44, JR(RA()), pc + 4));
+            profile.record(&cycle(pc + 4, ADD(T0(), T0(), V0()), pc + 8));
+        }
+        let mut exc = cycle(0x10, 0, 0x80);
+        exc.exception = Some(crate::hardware::cp0::ExcCode::Sys);
+        profile.record(&exc);
+        profile.record(&cycle(0x10, SW(T0(), 0x40, ZERO()), 0x14));
+        let mut store = cycle(0x14, SW(T0(), 0x44, ZERO()), 0x18);
+        store.mem = Some(MemAccess { addr: 0x44, paddr: 0x44, size: 4, is_write: true, old: 0, value: 2 });
+        profile.record(&store);
+
+        assert_eq!(profile.exceptions, 1);
+        assert_eq!(profile.calls[&(0x0, 0x40)], 2);
+        assert_eq!(profile.functions[&0x40], FuncStats { own: 4, total: 4, calls: 2 });
+        assert_eq!(profile.functions[&0x0], FuncStats { own: 6, total: 10, calls: 0 });
+        assert_eq!(profile.folded(&symbols), "main 6\nmain;leaf 4\n");
+
+        let report = profile.report(&symbols);
+        assert!(report.contains("   60.00%          6 100.00%         10        0  main\n"));
+        assert!(report.contains("Call graph:\n  main\n    -> leaf                            2 calls          4 instructions\n"));
+    }
+
+    #[test]
+    fn test_datapath_calls() {
+        let mut loader = MemLoader::new(instr_mem::Memory::new());
+        loader.label("main");
+        loader.load_instr( JAL (0x40) );
+        loader.load_instr( ADDI(T1(), ZERO(), 0x40) );
+        loader.load_instr( JALR(RA(), T1()) );
+        loader.load_instr( J   (0x100) ); // off the end of memory
+        loader.set_ip(0x40);
+        loader.label("leaf");
+        loader.load_instr( ADDI(V0(), V0(), 1) );
+        loader.load_instr( JR  (RA()) );
+        let symbols = loader.symbols().clone();
+        let mem = loader.return_mem();
+
+        let mut data_mem = data_mem::Memory::new();
+        let mut bus = bus::Bus::new(&mut data_mem);
+        let mut cpu = Processor::new(&Options::default());
+        let mut profile = Profile::new();
+        while cpu.is_running() {
+            let cycle = cpu.step(&mem, &mut bus);
+            profile.record(&cycle);
+        }
+
+        // jal links $ra and jalr rd, both return to the next instruction
+        assert_eq!(cpu.regfile.load(V0() as usize), 2);
+        assert_eq!(cpu.regfile.load(RA() as usize), 0xc);
+        assert_eq!(profile.calls[&(0x0, 0x40)], 2);
+        assert_eq!(profile.functions[&0x40], FuncStats { own: 4, total: 4, calls: 2 });
+        assert_eq!(profile.functions[&0x0], FuncStats { own: 4, total: 8, calls: 0 });
+        assert_eq!(profile.folded(&symbols), "main 4\nmain;leaf 4\n");
+    }
+}
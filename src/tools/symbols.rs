@@ -0,0 +1,138 @@
+/*
+ * symbols.rs
+ *
+ * Author: Travis Banken
+ *
+ * Table of labels (name -> address) for programs, used by the debugger and
+ * the reports to show names instead of raw addresses. It also holds the
+ * source line of every instruction when the assembler knows it.
+ */
+#![allow(dead_code)]
+
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    // kept sorted by address
+    symbols: Vec<(u32, String)>,
+    // address -> (file, line)
+    lines: BTreeMap<u32, (String, u32)>,
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable {
+            symbols: Vec::new(),
+            lines: BTreeMap::new(),
+        }
+    }
+
+    /*
+     * Adds a label, a label that already exists is moved to the new address.
+     */
+    pub fn add(&mut self, name: &str, addr: u32) {
+        self.symbols.retain(|(_, other)| other != name);
+        let pos = self.symbols.iter().position(|(a, _)| *a > addr).unwrap_or(self.symbols.len());
+        self.symbols.insert(pos, (addr, name.to_string()));
+    }
+
+    /*
+     * Address of the label.
+     */
+    pub fn lookup(&self, name: &str) -> Option<u32> {
+        self.symbols.iter().find(|(_, other)| other == name).map(|(addr, _)| *addr)
+    }
+
+    /*
+     * Label at exactly the given address.
+     */
+    pub fn name_at(&self, addr: u32) -> Option<&str> {
+        self.symbols.iter().find(|(a, _)| *a == addr).map(|(_, name)| name.as_str())
+    }
+
+    /*
+     * Closest label at or before the address and the offset from it.
+     */
+    pub fn nearest(&self, addr: u32) -> Option<(&str, u32)> {
+        self.symbols.iter()
+            .rev()
+            .find(|(a, _)| *a <= addr)
+            .map(|(a, name)| (name.as_str(), addr - a))
+    }
+
+    /*
+     * Formats an address as "label" or "label+0x8" when a label is known.
+     */
+    pub fn describe(&self, addr: u32) -> Option<String> {
+        match self.nearest(addr) {
+            Some((name, 0)) => Some(name.to_string()),
+            Some((name, off)) => Some(format!("{}+0x{:x}", name, off)),
+            None => None,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &str)> {
+        self.symbols.iter().map(|(addr, name)| (*addr, name.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    /*
+     * Records the source line the instruction at addr came from.
+     */
+    pub fn add_line(&mut self, addr: u32, file: &str, line: u32) {
+        self.lines.insert(addr, (file.to_string(), line));
+    }
+
+    /*
+     * Source file and line of the instruction at addr.
+     */
+    pub fn line_at(&self, addr: u32) -> Option<(&str, u32)> {
+        self.lines.get(&addr).map(|(file, line)| (file.as_str(), *line))
+    }
+
+    /*
+     * (address, file, line) of every instruction with a line, by address.
+     */
+    pub fn lines(&self) -> impl Iterator<Item = (u32, &str, u32)> {
+        self.lines.iter().map(|(addr, (file, line))| (*addr, file.as_str(), *line))
+    }
+
+    pub fn has_lines(&self) -> bool {
+        !self.lines.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        let mut syms = SymbolTable::new();
+        syms.add("loop", 0x10);
+        syms.add("main", 0x0);
+        syms.add("end", 0x20);
+
+        assert_eq!(syms.lookup("loop"), Some(0x10));
+        assert_eq!(syms.lookup("nope"), None);
+        assert_eq!(syms.name_at(0x0), Some("main"));
+        assert_eq!(syms.nearest(0x1c), Some(("loop", 0xc)));
+        assert_eq!(syms.describe(0x14), Some("loop+0x4".to_string()));
+        assert_eq!(syms.describe(0x20), Some("end".to_string()));
+
+        syms.add("loop", 0x30);
+        assert_eq!(syms.nearest(0x1c), Some(("main", 0x1c)));
+        let addrs: Vec<u32> = syms.iter().map(|(addr, _)| addr).collect();
+        assert_eq!(addrs, vec![0x0, 0x20, 0x30]);
+
+        assert!(!syms.has_lines());
+        syms.add_line(0x4, "prog.s", 12);
+        syms.add_line(0x0, "prog.s", 10);
+        assert_eq!(syms.line_at(0x4), Some(("prog.s", 12)));
+        assert_eq!(syms.line_at(0x8), None);
+        assert_eq!(syms.lines().next(), Some((0x0, "prog.s", 10)));
+    }
+}
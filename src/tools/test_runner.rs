@@ -0,0 +1,595 @@
+/*
+ * test_runner.rs
+ *
+ * Author: Travis Banken
+ *
+ * Runs assembly programs that carry their expected results in comments and
+ * reports which of them passed as TAP or JUnit XML:
+ *
+ *   # expect: $t0 == 0xcafebabe       register (also $hi and $lo) after the run
+ *   # expect-mem: 0x42 word 0xcafebabe data memory, byte, half or word
+ *   # expect-stdout: hello            one line the program prints on the uart
+ *   # expect-exit: 3                  exit code (low byte of $v0)
+ *
+ * Programs are called like "rvp run" calls them, with a console uart that
+ * is captured instead of printed, and must end within the step limit.
+ */
+#![allow(dead_code)]
+
+use crate::hardware::{bus, data_mem, intc, reg_file, uart};
+use crate::loader::{self, Program};
+use crate::single_cycle::{Options, Processor};
+use crate::tools::asm;
+use std::cell::RefCell;
+use std::fs;
+use std::io::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::rc::Rc;
+use std::time::Instant;
+
+pub const DEFAULT_MAX_STEPS: u64 = 1_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Report {
+    Tap,
+    Junit,
+}
+
+impl Report {
+    pub fn parse(name: &str) -> Result<Report, String> {
+        match name {
+            "tap" => Ok(Report::Tap),
+            "junit" => Ok(Report::Junit),
+            _ => Err(format!("Invalid report format [{}], use tap or junit", name)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expect {
+    // register name without the '$', "hi" and "lo" included
+    Reg { name: String, value: u32 },
+    Mem { addr: u32, size: u32, value: u32 },
+    Stdout(String),
+    Exit(i32),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaseResult {
+    pub path: String,
+    // failed expectations, each with what was found instead
+    pub failures: Vec<String>,
+    // the case could not run at all: bad annotation, program did not load
+    pub error: Option<String>,
+    pub steps: u64,
+    pub seconds: f64,
+}
+
+impl CaseResult {
+    pub fn passed(&self) -> bool {
+        return self.error.is_none() && self.failures.is_empty();
+    }
+
+    pub fn name(&self) -> String {
+        return Path::new(&self.path).file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_else(|| self.path.clone());
+    }
+}
+
+/*
+ * The assembly files among the paths, directories are searched recursively
+ * for .s and .asm files with at least one expectation.
+ */
+pub fn discover(paths: &[String]) -> Result<Vec<String>, String> {
+    let mut found = Vec::new();
+    for path in paths.iter() {
+        let meta = match fs::metadata(path) {
+            Ok(meta) => meta,
+            Err(e) => return Err(format!("Test-Error: Failed to read [{}]: {}", path, e)),
+        };
+        if meta.is_dir() {
+            search(Path::new(path), &mut found)?;
+        } else {
+            found.push(path.clone());
+        }
+    }
+    return Ok(found);
+}
+
+/*
+ * Expectations in the comments of a source file.
+ */
+pub fn parse_expectations(source: &str, file: &str) -> Result<Vec<Expect>, String> {
+    let mut expects = Vec::new();
+    for (i, line) in source.lines().enumerate() {
+        let comment = match annotation(line) {
+            Some(comment) => comment,
+            None => continue,
+        };
+        let expect = parse_expect(comment).map_err(|msg| format!("Test-Error: {}:{}: {}", file, i + 1, msg))?;
+        expects.push(expect);
+    }
+    return Ok(expects);
+}
+
+pub fn run_file(path: &str, max_steps: u64) -> CaseResult {
+    let start = Instant::now();
+    let mut result = match fs::read_to_string(path) {
+        Ok(source) => run_source(&source, path, max_steps),
+        Err(e) => CaseResult {
+            path: path.to_string(),
+            failures: Vec::new(),
+            error: Some(format!("Test-Error: Failed to read [{}]: {}", path, e)),
+            steps: 0,
+            seconds: 0.0,
+        },
+    };
+    result.seconds = start.elapsed().as_secs_f64();
+    return result;
+}
+
+/*
+ * Assembles and runs the source, then checks its expectations.
+ */
+pub fn run_source(source: &str, path: &str, max_steps: u64) -> CaseResult {
+    let mut result = CaseResult {
+        path: path.to_string(),
+        failures: Vec::new(),
+        error: None,
+        steps: 0,
+        seconds: 0.0,
+    };
+    let setup = parse_expectations(source, path).and_then(|expects| {
+        if expects.is_empty() {
+            return Err(format!("Test-Error: {}: No expectations", path));
+        }
+        let program = Program::from_assembly(&asm::assemble(source, path)?)?;
+        return Ok((expects, program));
+    });
+    let (expects, program) = match setup {
+        Ok(setup) => setup,
+        Err(msg) => {
+            result.error = Some(msg);
+            return result;
+        }
+    };
+    // the datapath panics on instructions it does not implement, which
+    // fails this case rather than the whole run
+    let run = panic::catch_unwind(AssertUnwindSafe(|| execute(&program, path, max_steps)));
+    let outcome = match run {
+        Ok(Ok(outcome)) => outcome,
+        Ok(Err(msg)) => {
+            result.error = Some(msg);
+            return result;
+        },
+        Err(payload) => {
+            let msg = payload.downcast_ref::<String>().cloned()
+                .or_else(|| payload.downcast_ref::<&str>().map(|msg| msg.to_string()))
+                .unwrap_or_else(|| "unknown panic".to_string());
+            result.error = Some(format!("Test-Error: {}: The processor crashed: {}", path, msg));
+            return result;
+        },
+    };
+    result.steps = outcome.steps;
+    if let Some(stop) = outcome.stop.as_ref() {
+        result.failures.push(stop.clone());
+    }
+    result.failures.extend(check(&expects, &outcome));
+    return result;
+}
+
+/*
+ * Test Anything Protocol, the failures as diagnostic lines.
+ */
+pub fn tap(results: &[CaseResult]) -> String {
+    let mut out = String::new();
+    out.push_str("TAP version 13\n");
+    out.push_str(&format!("1..{}\n", results.len()));
+    for (i, result) in results.iter().enumerate() {
+        let status = if result.passed() {"ok"} else {"not ok"};
+        out.push_str(&format!("{} {} - {}\n", status, i + 1, result.path));
+        for line in result.error.iter().chain(result.failures.iter()).flat_map(|msg| msg.lines()) {
+            out.push_str(&format!("#   {}\n", line));
+        }
+    }
+    return out;
+}
+
+/*
+ * JUnit XML with one test suite, a failed expectation is a <failure> and a
+ * case that could not run an <error>.
+ */
+pub fn junit(results: &[CaseResult]) -> String {
+    let failures = results.iter().filter(|r| r.error.is_none() && !r.failures.is_empty()).count();
+    let errors = results.iter().filter(|r| r.error.is_some()).count();
+    let seconds: f64 = results.iter().map(|r| r.seconds).sum();
+    let counts = format!("tests=\"{}\" failures=\"{}\" errors=\"{}\" time=\"{:.3}\"",
+                         results.len(), failures, errors, seconds);
+
+    let mut out = String::new();
+    out.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    out.push_str(&format!("<testsuites {}>\n", counts));
+    out.push_str(&format!("  <testsuite name=\"rvp\" {}>\n", counts));
+    for result in results.iter() {
+        out.push_str(&format!("    <testcase name=\"{}\" classname=\"{}\" file=\"{}\" time=\"{:.3}\"",
+                              xml_escape(&result.name()), xml_escape(&result.path), xml_escape(&result.path),
+                              result.seconds));
+        if let Some(msg) = result.error.as_ref() {
+            out.push_str(&format!(">\n      <error message=\"{}\"/>\n    </testcase>\n", xml_escape(msg)));
+        } else if !result.failures.is_empty() {
+            let message = format!("{} of the expectations failed", result.failures.len());
+            out.push_str(&format!(">\n      <failure message=\"{}\">{}</failure>\n    </testcase>\n",
+                                  message, xml_escape(&result.failures.join("\n"))));
+        } else {
+            out.push_str("/>\n");
+        }
+    }
+    out.push_str("  </testsuite>\n");
+    out.push_str("</testsuites>\n");
+    return out;
+}
+
+// *** PRIVATE FN ***
+
+// what is left after a run
+struct Outcome {
+    regs: [u32; 32],
+    hi: u32,
+    lo: u32,
+    code: i32,
+    mem: data_mem::Memory,
+    stdout: String,
+    steps: u64,
+    // why the program did not end normally
+    stop: Option<String>,
+}
+
+// uart output kept for the stdout expectations
+#[derive(Clone, Default)]
+struct Capture(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        return Ok(buf.len());
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return Ok(());
+    }
+}
+
+fn execute(program: &Program, path: &str, max_steps: u64) -> Result<Outcome, String> {
+    let mut mem = program.data_mem.clone();
+    let capture = Capture::default();
+    let opts = Options {
+        entry: program.entry,
+        ..Options::default()
+    };
+    let mut cpu = Processor::new(&opts);
+    let mut steps = 0;
+    let mut stop = None;
+    {
+        let mut bus = bus::Bus::new(&mut mem);
+        bus.attach_irq(uart::UART_BASE, Box::new(uart::Uart::new(None, Box::new(capture.clone()))),
+                       intc::LINE_UART);
+        loader::call_main(&mut cpu, bus.mem_mut(), &[path.to_string()])?;
+        while cpu.is_running() {
+            if steps == max_steps {
+                stop = Some(format!("did not end within {} instructions", max_steps));
+                break;
+            }
+            let cycle = cpu.step(&program.instr_mem, &mut bus);
+            steps += 1;
+            if let Some(exc) = cycle.exception.filter(|_| !cpu.is_running()) {
+                stop = Some(format!("exception {:?} at 0x{:08x} ended the program", exc, cycle.pc));
+            }
+        }
+    }
+    let stdout = String::from_utf8_lossy(&capture.0.borrow()).to_string();
+    return Ok(Outcome {
+        regs: cpu.regfile.dump(),
+        hi: cpu.hi,
+        lo: cpu.lo,
+        code: (cpu.regfile.load(2) & 0xff) as i32,
+        mem,
+        stdout,
+        steps,
+        stop,
+    });
+}
+
+fn check(expects: &[Expect], outcome: &Outcome) -> Vec<String> {
+    let mut failures = Vec::new();
+    let mut stdout = String::new();
+    let mut has_stdout = false;
+    for expect in expects.iter() {
+        match expect {
+            Expect::Reg { name, value } => {
+                let got = match name.as_str() {
+                    "hi" => outcome.hi,
+                    "lo" => outcome.lo,
+                    _ => outcome.regs[reg_file::reg_num(name).unwrap_or(0) as usize],
+                };
+                if got != *value {
+                    failures.push(format!("${}: expected 0x{:08x}, got 0x{:08x}", name, value, got));
+                }
+            },
+            Expect::Mem { addr, size, value } => {
+                let got = (0..*size).fold(0, |acc, i| {
+                    (acc << 8) | outcome.mem.read((addr + i) as usize) as u32
+                });
+                if got != *value {
+                    let width = *size as usize * 2;
+                    failures.push(format!("mem 0x{:02x} {}: expected 0x{:0w$x}, got 0x{:0w$x}",
+                                          addr, size_name(*size), value, got, w = width));
+                }
+            },
+            Expect::Stdout(line) => {
+                has_stdout = true;
+                stdout.push_str(line);
+                stdout.push('\n');
+            },
+            Expect::Exit(code) => {
+                if outcome.code != *code {
+                    failures.push(format!("exit code: expected {}, got {}", code, outcome.code));
+                }
+            },
+        }
+    }
+    if has_stdout && outcome.stdout != stdout {
+        failures.push(format!("stdout differs (-expected +actual):\n{}", diff(&stdout, &outcome.stdout)));
+    }
+    return failures;
+}
+
+/*
+ * Line by line diff, good enough for the few lines a program prints.
+ */
+fn diff(expected: &str, actual: &str) -> String {
+    let expected: Vec<&str> = expected.split_terminator('\n').collect();
+    let actual: Vec<&str> = actual.split_terminator('\n').collect();
+    let mut lines = Vec::new();
+    for i in 0..expected.len().max(actual.len()) {
+        match (expected.get(i), actual.get(i)) {
+            (Some(e), Some(a)) if e == a => lines.push(format!("  {}", e)),
+            (e, a) => {
+                if let Some(e) = e {
+                    lines.push(format!("- {}", e));
+                }
+                if let Some(a) = a {
+                    lines.push(format!("+ {}", a));
+                }
+            },
+        }
+    }
+    return lines.join("\n");
+}
+
+/*
+ * The text after the '#' of a comment with an expectation.
+ */
+fn annotation(line: &str) -> Option<&str> {
+    let comment = line[line.find('#')? + 1..].trim_start();
+    if comment.starts_with("expect:") || comment.starts_with("expect-") {
+        return Some(comment);
+    }
+    return None;
+}
+
+fn parse_expect(comment: &str) -> Result<Expect, String> {
+    let (kind, rest) = match comment.find(':') {
+        Some(at) => (&comment[..at], &comment[at + 1..]),
+        None => return Err(format!("Missing ':' in [{}]", comment)),
+    };
+    match kind {
+        "expect" => {
+            let parts: Vec<&str> = rest.split("==").map(|part| part.trim()).collect();
+            if parts.len() != 2 {
+                return Err(format!("Expected [$reg == value], got [{}]", rest.trim()));
+            }
+            let name = parts[0].strip_prefix('$').unwrap_or(parts[0]);
+            let name = match (name, reg_file::reg_num(name)) {
+                ("hi", _) | ("lo", _) => name.to_string(),
+                (_, Some(num)) => reg_file::reg_name(num).to_string(),
+                (_, None) => return Err(format!("Unknown register [{}]", parts[0])),
+            };
+            return Ok(Expect::Reg { name, value: parse_value(parts[1])? });
+        },
+        "expect-mem" => {
+            let parts: Vec<&str> = rest.split_whitespace().collect();
+            if parts.len() != 3 {
+                return Err(format!("Expected [addr byte|half|word value], got [{}]", rest.trim()));
+            }
+            let size = match parts[1] {
+                "byte" => 1,
+                "half" => 2,
+                "word" => 4,
+                _ => return Err(format!("Invalid size [{}], use byte, half or word", parts[1])),
+            };
+            let addr = parse_value(parts[0])?;
+            if addr as usize + size as usize > data_mem::Memory::get_size() {
+                return Err(format!("Address [{}] is outside of data memory", parts[0]));
+            }
+            let value = parse_value(parts[2])?;
+            // unsigned or negative, like the immediates of the assembler
+            let bits = size * 8;
+            let signed = value as i32;
+            let fits = bits == 32 || value >> bits == 0 || (signed < 0 && signed >= -(1 << (bits - 1)));
+            if !fits {
+                return Err(format!("Value [{}] does not fit in a {}", parts[2], parts[1]));
+            }
+            let mask = if size == 4 {0xffff_ffff} else {(1 << (size * 8)) - 1};
+            return Ok(Expect::Mem { addr, size, value: value & mask });
+        },
+        "expect-stdout" => {
+            return Ok(Expect::Stdout(rest.strip_prefix(' ').unwrap_or(rest).trim_end().to_string()));
+        },
+        "expect-exit" => {
+            match rest.trim().parse::<i32>() {
+                Ok(code) if (0..256).contains(&code) => Ok(Expect::Exit(code)),
+                _ => Err(format!("Invalid exit code [{}]", rest.trim())),
+            }
+        },
+        _ => Err(format!("Unknown annotation [{}], use expect, expect-mem, expect-stdout or expect-exit", kind)),
+    }
+}
+
+/*
+ * Hex (0x), decimal or negative decimal number as a 32 bit value.
+ */
+fn parse_value(text: &str) -> Result<u32, String> {
+    let res = if let Some(hex) = text.strip_prefix("0x") {
+        u32::from_str_radix(&hex.replace('_', ""), 16).ok()
+    } else if text.starts_with('-') {
+        text.parse::<i32>().ok().map(|val| val as u32)
+    } else {
+        text.parse::<u32>().ok()
+    };
+    match res {
+        Some(val) => Ok(val),
+        None => Err(format!("Invalid number [{}]", text)),
+    }
+}
+
+fn size_name(size: u32) -> &'static str {
+    match size {
+        1 => "byte",
+        2 => "half",
+        _ => "word",
+    }
+}
+
+fn search(dir: &Path, found: &mut Vec<String>) -> Result<(), String> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => return Err(format!("Test-Error: Failed to read [{}]: {}", dir.display(), e)),
+    };
+    let mut paths: Vec<_> = entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect();
+    paths.sort();
+    for path in paths {
+        if path.is_dir() {
+            search(&path, found)?;
+            continue;
+        }
+        let ext = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+        if ext != "s" && ext != "asm" {
+            continue;
+        }
+        let source = fs::read_to_string(&path).unwrap_or_default();
+        if source.lines().any(|line| annotation(line).is_some()) {
+            found.push(path.to_string_lossy().to_string());
+        }
+    }
+    return Ok(());
+}
+
+fn xml_escape(text: &str) -> String {
+    let mut out = String::new();
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            _ => out.push(c),
+        }
+    }
+    return out;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAM: &str = "\
+# expect: $v0 == 3
+# expect: $t1 == -1
+# expect-mem: 0x10 half 0xffff
+# expect-exit: 3
+# expect-stdout: hi
+main:
+        addi $v0, $zero, 3
+        addi $t0, $zero, 0
+        nor  $t1, $t0, $t0
+        sw   $t1, 0x10($zero)
+        lui  $t2, 0xffff
+        addi $t3, $zero, 0x68
+        sw   $t3, 0xc($t2)
+        addi $t3, $zero, 0x69
+        sw   $t3, 0xc($t2)
+        addi $t3, $zero, 0x0a
+        sw   $t3, 0xc($t2)
+        jr   $ra
+";
+
+    #[test]
+    fn test_pass() {
+        let result = run_source(PROGRAM, "ok.s", 100);
+        assert_eq!(result.failures, Vec::<String>::new());
+        assert_eq!(result.error, None);
+        assert!(result.passed());
+        assert_eq!(result.steps, 12);
+
+        let tap = tap(std::slice::from_ref(&result));
+        assert_eq!(tap, "TAP version 13\n1..1\nok 1 - ok.s\n");
+        assert!(junit(&[result]).contains("<testcase name=\"ok\" classname=\"ok.s\" file=\"ok.s\""));
+    }
+
+    #[test]
+    fn test_fail() {
+        let source = PROGRAM.replace("$v0 == 3", "$v0 == 4").replace("half 0xffff", "byte 0x7f")
+            .replace("expect-stdout: hi", "expect-stdout: ho");
+        let result = run_source(&source, "bad.s", 100);
+        assert_eq!(result.failures, vec![
+            "$v0: expected 0x00000004, got 0x00000003".to_string(),
+            "mem 0x10 byte: expected 0x7f, got 0xff".to_string(),
+            "stdout differs (-expected +actual):\n- ho\n+ hi".to_string(),
+        ]);
+        let tap = tap(std::slice::from_ref(&result));
+        assert!(tap.contains("not ok 1 - bad.s\n#   $v0: expected 0x00000004, got 0x00000003\n"));
+        assert!(tap.contains("#   - ho\n#   + hi\n"));
+        assert!(junit(&[result]).contains("<failure message=\"3 of the expectations failed\">"));
+
+        let result = run_source("# expect: $t0 == 1\nloop: j loop\n", "loop.s", 50);
+        assert_eq!(result.failures, vec![
+            "did not end within 50 instructions".to_string(),
+            "$t0: expected 0x00000001, got 0x00000000".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |source: &str| run_source(source, "e.s", 10).error.expect(source);
+        assert_eq!(error("# expect: $q9 == 1\n"), "Test-Error: e.s:1: Unknown register [$q9]");
+        assert_eq!(error("nop\n# expect-mem: 0x10 long 1\n"),
+                   "Test-Error: e.s:2: Invalid size [long], use byte, half or word");
+        assert_eq!(error("# expect-mem: 0x10 byte 0x100\n"), "Test-Error: e.s:1: Value [0x100] does not fit in a byte");
+        assert_eq!(error("# expect-exit: 300\n"), "Test-Error: e.s:1: Invalid exit code [300]");
+        assert_eq!(error("# expect-regs: 1\n"),
+                   "Test-Error: e.s:1: Unknown annotation [expect-regs], use expect, expect-mem, expect-stdout or expect-exit");
+        assert_eq!(error("nop\n"), "Test-Error: e.s: No expectations");
+        assert!(error("# expect: $t0 == 0\n bogus\n").starts_with("Asm-Error: e.s:2:"));
+        let junit = junit(&[run_source("nop\n", "a&b.s", 10)]);
+        assert!(junit.contains("errors=\"1\""));
+        assert!(junit.contains("<error message=\"Test-Error: a&amp;b.s: No expectations\"/>"));
+    }
+
+    #[test]
+    fn test_programs() {
+        let dir = format!("{}/programs", env!("CARGO_MANIFEST_DIR"));
+        let paths = discover(&[dir]).unwrap();
+        let names: Vec<String> = paths.iter()
+            .map(|path| Path::new(path).file_name().unwrap().to_string_lossy().to_string())
+            .collect();
+        assert!(names.contains(&"demo1.s".to_string()));
+        assert!(names.contains(&"demo2.s".to_string()));
+        for path in paths.iter() {
+            let result = run_file(path, DEFAULT_MAX_STEPS);
+            assert!(result.passed(), "{}", tap(&[result]));
+        }
+    }
+}
@@ -0,0 +1,455 @@
+/*
+ * trace.rs
+ *
+ * Author: Travis Banken
+ *
+ * Records one line per retired instruction: the registers it read and wrote,
+ * the memory it touched and which way branches went. The trace can be written
+ * as text, JSON Lines or a compact binary format, and limited to a pc range or
+ * to some instruction classes.
+ *
+ * Binary format (big endian): the magic "RVPT" and a version byte, then per
+ * instruction
+ *     u64 cycle, u32 pc, u32 word, u8 flags, u8 number of reads,
+ *     (u8 reg, u32 value) per read,
+ *     u8 reg, u32 old, u32 new                      if flags & FLAG_WRITE
+ *     u32 addr, u32 paddr, u8 size, u32 old, u32 value  if flags & FLAG_MEM
+ */
+#![allow(dead_code)]
+
+use crate::cycle::{Class, Cycle, Observer};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+pub const MAGIC: &[u8; 4] = b"RVPT";
+pub const VERSION: u8 = 1;
+
+pub const FLAG_WRITE: u8 = 1 << 0;
+pub const FLAG_MEM: u8 = 1 << 1;
+pub const FLAG_MEM_WRITE: u8 = 1 << 2;
+pub const FLAG_BRANCH: u8 = 1 << 3;
+pub const FLAG_TAKEN: u8 = 1 << 4;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Format {
+    #[default]
+    Text,
+    Jsonl,
+    Binary,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Result<Format, String> {
+        match name {
+            "text" => Ok(Format::Text),
+            "jsonl" | "json" => Ok(Format::Jsonl),
+            "binary" | "bin" => Ok(Format::Binary),
+            _ => Err(format!("Trace-Error: Invalid format [{}], use text, jsonl or binary", name)),
+        }
+    }
+}
+
+/*
+ * Which instructions make it into the trace. An empty filter lets
+ * everything through.
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Filter {
+    // inclusive range of pcs
+    pub pc: Option<(u32, u32)>,
+    pub classes: Vec<Class>,
+}
+
+impl Filter {
+    /*
+     * Parse a filter such as "pc:0x10-0x40,class:load|store".
+     */
+    pub fn parse(spec: &str) -> Result<Filter, String> {
+        let mut filter = Filter::default();
+        for field in spec.split(',').filter(|f| !f.is_empty()) {
+            let (key, val) = match field.find(':') {
+                Some(i) => (&field[..i], &field[i+1..]),
+                None => return Err(format!("Trace-Error: Expected key:value in filter, found [{}]", field)),
+            };
+            match key {
+                "pc" => {
+                    let (lo, hi) = match val.find('-') {
+                        Some(i) => (parse_num(&val[..i])?, parse_num(&val[i+1..])?),
+                        None => {
+                            let pc = parse_num(val)?;
+                            (pc, pc)
+                        }
+                    };
+                    if lo > hi {
+                        return Err(format!("Trace-Error: Empty pc range [{}]", val));
+                    }
+                    filter.pc = Some((lo, hi));
+                },
+                "class" => {
+                    for name in val.split('|') {
+                        filter.classes.push(Class::parse(name).map_err(|e| format!("Trace-Error: {}", e))?);
+                    }
+                },
+                _ => return Err(format!("Trace-Error: Unknown filter key [{}], use pc or class", key)),
+            }
+        }
+        return Ok(filter);
+    }
+
+    pub fn matches(&self, cycle: &Cycle) -> bool {
+        if let Some((lo, hi)) = self.pc {
+            if cycle.pc < lo || cycle.pc > hi {
+                return false;
+            }
+        }
+        return self.classes.is_empty() || self.classes.contains(&cycle.class());
+    }
+}
+
+/*
+ * Where and how to trace a run, no trace without a path.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct TraceConfig {
+    // file to write, "-" for stdout
+    pub path: Option<String>,
+    pub format: Format,
+    pub filter: Filter,
+}
+
+/*
+ * Observer writing the trace.
+ */
+pub struct Tracer {
+    out: Box<dyn Write>,
+    format: Format,
+    filter: Filter,
+    // stop writing after the first error instead of reporting every record
+    failed: bool,
+}
+
+impl Tracer {
+    pub fn new(out: Box<dyn Write>, format: Format, filter: Filter) -> Tracer {
+        let mut tracer = Tracer {
+            out,
+            format,
+            filter,
+            failed: false,
+        };
+        if format == Format::Binary {
+            let mut header = MAGIC.to_vec();
+            header.push(VERSION);
+            tracer.write(&header);
+        }
+        return tracer;
+    }
+
+    /*
+     * Tracer for a config, creating the trace file.
+     */
+    pub fn open(config: &TraceConfig) -> Result<Tracer, String> {
+        let out: Box<dyn Write> = match config.path.as_deref() {
+            None | Some("-") => Box::new(io::stdout()),
+            Some(path) => match File::create(path) {
+                Ok(file) => Box::new(BufWriter::new(file)),
+                Err(e) => return Err(format!("Trace-Error: Failed to create [{}]: {}", path, e)),
+            },
+        };
+        return Ok(Tracer::new(out, config.format, config.filter.clone()));
+    }
+
+    // *** PRIVATE FN ***
+
+    fn write(&mut self, bytes: &[u8]) {
+        if self.failed {
+            return;
+        }
+        if let Err(e) = self.out.write_all(bytes) {
+            eprintln!("Trace-Error: Failed to write trace: {}", e);
+            self.failed = true;
+        }
+    }
+}
+
+impl Observer for Tracer {
+    fn cycle(&mut self, cycle: &Cycle) {
+        if !cycle.retired() || !self.filter.matches(cycle) {
+            return;
+        }
+        let record = match self.format {
+            Format::Text => format!("{}\n", text(cycle)).into_bytes(),
+            Format::Jsonl => format!("{}\n", json(cycle)).into_bytes(),
+            Format::Binary => binary(cycle),
+        };
+        self.write(&record);
+    }
+
+    fn finish(&mut self) {
+        if !self.failed {
+            if let Err(e) = self.out.flush() {
+                eprintln!("Trace-Error: Failed to write trace: {}", e);
+            }
+        }
+    }
+}
+
+/*
+ * One line of the text trace, e.g.
+ * "     3 0x00000008: 0xac080042  sw $t0, 0x42($zero)  $zero=0x00000000 $t0=0x0000babe [0x00000042] 0x00000000 -> 0x0000babe"
+ */
+pub fn text(cycle: &Cycle) -> String {
+    let mut line = format!("{:>6} 0x{:08x}: 0x{:08x}  {:<28}", cycle.cycle, cycle.pc, cycle.word,
+                           cycle.isa.disassemble(cycle.word, cycle.pc));
+    for (reg, val) in cycle.reads.iter() {
+        line.push_str(&format!(" ${}=0x{:08x}", cycle.isa.reg_name(*reg), val));
+    }
+    if let Some(write) = cycle.write {
+        line.push_str(&format!(" ${} 0x{:08x} -> 0x{:08x}", cycle.isa.reg_name(write.reg), write.old, write.new));
+    }
+    if let Some(mem) = cycle.mem {
+        if mem.is_write {
+            line.push_str(&format!(" [0x{:08x}] 0x{:0w$x} -> 0x{:0w$x}", mem.addr, mem.old, mem.value,
+                                   w = mem.size as usize * 2));
+        } else {
+            line.push_str(&format!(" [0x{:08x}] = 0x{:0w$x}", mem.addr, mem.value, w = mem.size as usize * 2));
+        }
+    }
+    match cycle.branch {
+        Some(true) => line.push_str(" taken"),
+        Some(false) => line.push_str(" not taken"),
+        None => (),
+    }
+    return line.trim_end().to_string();
+}
+
+/*
+ * One JSON object of the JSON Lines trace.
+ */
+pub fn json(cycle: &Cycle) -> String {
+    let reads: Vec<String> = cycle.reads.iter()
+        .map(|(reg, val)| format!("{{\"reg\":{},\"name\":\"{}\",\"value\":{}}}", reg, cycle.isa.reg_name(*reg), val))
+        .collect();
+    let write = match cycle.write {
+        Some(w) => format!("{{\"reg\":{},\"name\":\"{}\",\"old\":{},\"new\":{}}}",
+                           w.reg, cycle.isa.reg_name(w.reg), w.old, w.new),
+        None => "null".to_string(),
+    };
+    let mem = match cycle.mem {
+        Some(m) => format!("{{\"addr\":{},\"paddr\":{},\"size\":{},\"write\":{},\"old\":{},\"value\":{}}}",
+                           m.addr, m.paddr, m.size, m.is_write, m.old, m.value),
+        None => "null".to_string(),
+    };
+    let branch = match cycle.branch {
+        Some(taken) => taken.to_string(),
+        None => "null".to_string(),
+    };
+    return format!("{{\"cycle\":{},\"pc\":{},\"word\":{},\"asm\":\"{}\",\"class\":\"{}\",\"reads\":[{}],\
+                    \"write\":{},\"mem\":{},\"branch\":{}}}",
+                   cycle.cycle, cycle.pc, cycle.word, escape(&cycle.isa.disassemble(cycle.word, cycle.pc)),
+                   cycle.class().name(), reads.join(","), write, mem, branch);
+}
+
+/*
+ * One record of the binary trace, see the top of the file for the layout.
+ */
+pub fn binary(cycle: &Cycle) -> Vec<u8> {
+    let mut flags = 0;
+    if cycle.write.is_some() {
+        flags |= FLAG_WRITE;
+    }
+    if let Some(mem) = cycle.mem {
+        flags |= FLAG_MEM;
+        if mem.is_write {
+            flags |= FLAG_MEM_WRITE;
+        }
+    }
+    if let Some(taken) = cycle.branch {
+        flags |= FLAG_BRANCH;
+        if taken {
+            flags |= FLAG_TAKEN;
+        }
+    }
+
+    let mut rec = Vec::with_capacity(32);
+    rec.extend_from_slice(&cycle.cycle.to_be_bytes());
+    rec.extend_from_slice(&cycle.pc.to_be_bytes());
+    rec.extend_from_slice(&cycle.word.to_be_bytes());
+    rec.push(flags);
+    rec.push(cycle.reads.len() as u8);
+    for (reg, val) in cycle.reads.iter() {
+        rec.push(*reg);
+        rec.extend_from_slice(&val.to_be_bytes());
+    }
+    if let Some(write) = cycle.write {
+        rec.push(write.reg);
+        rec.extend_from_slice(&write.old.to_be_bytes());
+        rec.extend_from_slice(&write.new.to_be_bytes());
+    }
+    if let Some(mem) = cycle.mem {
+        rec.extend_from_slice(&mem.addr.to_be_bytes());
+        rec.extend_from_slice(&mem.paddr.to_be_bytes());
+        rec.push(mem.size as u8);
+        rec.extend_from_slice(&mem.old.to_be_bytes());
+        rec.extend_from_slice(&mem.value.to_be_bytes());
+    }
+    return rec;
+}
+
+// *** PRIVATE FN ***
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    return out;
+}
+
+fn parse_num(s: &str) -> Result<u32, String> {
+    let res = match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse::<u32>(),
+    };
+    return res.map_err(|_| format!("Trace-Error: Invalid address [{}]", s));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control_bits;
+    use crate::cycle::{MemAccess, RegWrite};
+    use crate::demos::assembler::instr_macros::*;
+    use crate::demos::assembler::reg_macros::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn store_cycle() -> Cycle {
+        let mut cycle = Cycle {
+            cycle: 3,
+            pc: 0x8,
+            word: SW(T0(), 0x42, ZERO()),
+            reads: vec![(ZERO(), 0), (T0(), 0xbabe)],
+            mem: Some(MemAccess {
+                addr: 0x42,
+                paddr: 0x42,
+                size: 4,
+                is_write: true,
+                old: 0,
+                value: 0xbabe,
+            }),
+            next_pc: 0xc,
+            ..Cycle::default()
+        };
+        cycle.ctrl.mem = control_bits::MemAccess::Store(control_bits::Width::Word);
+        return cycle;
+    }
+
+    fn addi_cycle() -> Cycle {
+        return Cycle {
+            cycle: 0,
+            pc: 0x0,
+            word: ADDI(T0(), ZERO(), 0xbabe),
+            reads: vec![(ZERO(), 0)],
+            write: Some(RegWrite { reg: T0(), old: 0, new: 0xbabe }),
+            next_pc: 0x4,
+            ..Cycle::default()
+        };
+    }
+
+    #[test]
+    fn test_filter_parse() {
+        let filter = Filter::parse("pc:0x10-0x40,class:load|store").unwrap();
+        assert_eq!(filter.pc, Some((0x10, 0x40)));
+        assert_eq!(filter.classes, vec![Class::Load, Class::Store]);
+        assert_eq!(Filter::parse("").unwrap(), Filter::default());
+        assert_eq!(Filter::parse("pc:8").unwrap().pc, Some((8, 8)));
+        assert!(Filter::parse("pc:0x40-0x10").is_err());
+        assert!(Filter::parse("class:float").is_err());
+        assert!(Filter::parse("size:4").is_err());
+        assert!(Filter::parse("pc").is_err());
+    }
+
+    #[test]
+    fn test_filter_matches() {
+        let filter = Filter::parse("class:store").unwrap();
+        assert!(filter.matches(&store_cycle()));
+        assert!(!filter.matches(&addi_cycle()));
+        let filter = Filter::parse("pc:0x4-0x8").unwrap();
+        assert!(filter.matches(&store_cycle()));
+        assert!(!filter.matches(&addi_cycle()));
+    }
+
+    #[test]
+    fn test_text() {
+        assert_eq!(text(&addi_cycle()),
+                   "     0 0x00000000: 0x2008babe  addi $t0, $zero, 0xbabe      $zero=0x00000000 $t0 0x00000000 -> 0x0000babe");
+        let line = text(&store_cycle());
+        assert!(line.contains("sw $t0, 0x42($zero)"));
+        assert!(line.ends_with("[0x00000042] 0x00000000 -> 0x0000babe"));
+    }
+
+    #[test]
+    fn test_json() {
+        assert_eq!(json(&store_cycle()),
+                   "{\"cycle\":3,\"pc\":8,\"word\":2886205506,\"asm\":\"sw $t0, 0x42($zero)\",\"class\":\"store\",\
+                    \"reads\":[{\"reg\":0,\"name\":\"zero\",\"value\":0},{\"reg\":8,\"name\":\"t0\",\"value\":47806}],\
+                    \"write\":null,\"mem\":{\"addr\":66,\"paddr\":66,\"size\":4,\"write\":true,\"old\":0,\"value\":47806},\
+                    \"branch\":null}");
+    }
+
+    #[test]
+    fn test_binary() {
+        let rec = binary(&addi_cycle());
+        // cycle, pc, word, flags, reads, one read, the write
+        assert_eq!(rec.len(), 8 + 4 + 4 + 1 + 1 + 5 + 9);
+        assert_eq!(&rec[12..16], &ADDI(T0(), ZERO(), 0xbabe).to_be_bytes());
+        assert_eq!(rec[16], FLAG_WRITE);
+        assert_eq!(rec[17], 1);
+        assert_eq!(rec[23], T0());
+        assert_eq!(&rec[28..32], &0xbabe_u32.to_be_bytes());
+
+        let rec = binary(&store_cycle());
+        assert_eq!(rec[16], FLAG_MEM | FLAG_MEM_WRITE);
+        assert_eq!(rec.len(), 8 + 4 + 4 + 1 + 1 + 10 + 17);
+    }
+
+    #[test]
+    fn test_tracer() {
+        let buf = Rc::new(RefCell::new(Vec::new()));
+        let mut tracer = Tracer::new(Box::new(Shared(buf.clone())), Format::Jsonl,
+                                     Filter::parse("class:store").unwrap());
+        tracer.cycle(&addi_cycle());
+        tracer.cycle(&store_cycle());
+        let mut faulted = store_cycle();
+        faulted.exception = Some(crate::hardware::cp0::ExcCode::AdES);
+        tracer.cycle(&faulted);
+        tracer.finish();
+        let out = String::from_utf8(buf.borrow().clone()).unwrap();
+        assert_eq!(out.lines().count(), 1);
+        assert!(out.starts_with("{\"cycle\":3,"));
+
+        let buf = Rc::new(RefCell::new(Vec::new()));
+        let mut tracer = Tracer::new(Box::new(Shared(buf.clone())), Format::Binary, Filter::default());
+        tracer.cycle(&addi_cycle());
+        assert_eq!(&buf.borrow()[..5], b"RVPT\x01");
+        assert_eq!(buf.borrow().len(), 5 + binary(&addi_cycle()).len());
+    }
+}
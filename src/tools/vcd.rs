@@ -0,0 +1,340 @@
+/*
+ * vcd.rs
+ *
+ * Author: Travis Banken
+ *
+ * Writes the datapath signals of every cycle as a Value Change Dump, to be
+ * viewed in GTKWave next to the waveforms of a Verilog model of the same
+ * datapath. Each instruction takes one clock period, stalls on a cache
+ * stretch it by the cycles stalled.
+ */
+#![allow(dead_code)]
+
+use crate::control_bits::MemAccess;
+use crate::cycle::{Cycle, Observer};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+// ns per clock cycle, the clock rises at the start of the cycle
+pub const PERIOD: u64 = 10;
+
+/*
+ * (scope, name, width in bits) of every signal in the dump, the values
+ * come out of sample() in the same order. The enum control signals carry
+ * the position of their variant in the declaration.
+ */
+pub const SIGNALS: [(&str, &str, u32); 35] = [
+    ("datapath", "clk", 1),
+    ("datapath", "pc", 32),
+    ("datapath", "instr", 32),
+    ("datapath", "next_pc", 32),
+    ("datapath", "exception", 1),
+    ("datapath", "exc_code", 5),
+
+    ("control", "alu_op", 5),
+    ("control", "alu_src", 3),
+    ("control", "alu_pc", 1),
+    ("control", "alu_shamt", 1),
+    ("control", "trap_overflow", 1),
+    ("control", "trap", 3),
+    ("control", "hilo", 4),
+    ("control", "mem_read", 1),
+    ("control", "mem_write", 1),
+    ("control", "mem_size", 3),
+    ("control", "mem_signed", 1),
+    ("control", "reg_dst", 2),
+    ("control", "cond_write", 2),
+    ("control", "insert", 1),
+    ("control", "pc_src", 3),
+    ("control", "link", 1),
+    ("control", "cop0", 1),
+
+    ("alu", "in1", 32),
+    ("alu", "in2", 32),
+    ("alu", "result", 32),
+    ("alu", "zero", 1),
+
+    ("dmem", "addr", 32),
+    ("dmem", "read_en", 1),
+    ("dmem", "write_en", 1),
+    ("dmem", "write_data", 32),
+    ("dmem", "read_data", 32),
+
+    ("regfile", "write_en", 1),
+    ("regfile", "write_reg", 5),
+    ("regfile", "write_data", 32),
+];
+
+const CLK: usize = 0;
+
+/*
+ * Observer writing the dump.
+ */
+pub struct VcdWriter {
+    out: Box<dyn Write>,
+    // values last written, None before the first cycle
+    last: Option<Vec<u32>>,
+    // end of the last cycle written
+    time: u64,
+    failed: bool,
+}
+
+impl VcdWriter {
+    pub fn new(out: Box<dyn Write>) -> VcdWriter {
+        let mut vcd = VcdWriter {
+            out,
+            last: None,
+            time: 0,
+            failed: false,
+        };
+        let header = header();
+        vcd.write(&header);
+        return vcd;
+    }
+
+    pub fn create(path: &str) -> Result<VcdWriter, String> {
+        match File::create(path) {
+            Ok(file) => Ok(VcdWriter::new(Box::new(BufWriter::new(file)))),
+            Err(e) => Err(format!("Vcd-Error: Failed to create [{}]: {}", path, e)),
+        }
+    }
+
+    // *** PRIVATE FN ***
+
+    fn write(&mut self, text: &str) {
+        if self.failed {
+            return;
+        }
+        if let Err(e) = self.out.write_all(text.as_bytes()) {
+            eprintln!("Vcd-Error: Failed to write waveform: {}", e);
+            self.failed = true;
+        }
+    }
+}
+
+impl Observer for VcdWriter {
+    fn cycle(&mut self, cycle: &Cycle) {
+        let values = sample(cycle);
+        let start = cycle.cycle * PERIOD;
+        let mut text = String::new();
+
+        // rising edge with the values of the cycle
+        text.push_str(&format!("#{}\n", start));
+        match self.last.as_ref() {
+            None => {
+                text.push_str("$dumpvars\n");
+                for (i, val) in values.iter().enumerate() {
+                    text.push_str(&change(i, *val));
+                }
+                text.push_str("$end\n");
+            },
+            Some(last) => {
+                for (i, val) in values.iter().enumerate() {
+                    if last[i] != *val {
+                        text.push_str(&change(i, *val));
+                    }
+                }
+            }
+        }
+
+        // falling edge half way through the first clock of the cycle
+        text.push_str(&format!("#{}\n", start + PERIOD / 2));
+        text.push_str(&change(CLK, 0));
+        self.write(&text);
+
+        let mut last = values;
+        last[CLK] = 0;
+        self.last = Some(last);
+        self.time = start + PERIOD;
+    }
+
+    fn finish(&mut self) {
+        if self.last.is_some() {
+            let end = format!("#{}\n", self.time);
+            self.write(&end);
+        }
+        if !self.failed {
+            if let Err(e) = self.out.flush() {
+                eprintln!("Vcd-Error: Failed to write waveform: {}", e);
+            }
+        }
+    }
+}
+
+/*
+ * Values of SIGNALS during a cycle.
+ */
+pub fn sample(cycle: &Cycle) -> Vec<u32> {
+    let ctrl = &cycle.ctrl;
+    let mem = cycle.mem;
+    let write = cycle.write;
+    return vec![
+        1,
+        cycle.pc,
+        cycle.word,
+        cycle.next_pc,
+        cycle.exception.is_some() as u32,
+        cycle.exception.map(|code| code as u32).unwrap_or(0),
+
+        ctrl.alu_op as u32,
+        ctrl.alu_src as u32,
+        ctrl.alu_pc as u32,
+        ctrl.alu_shamt as u32,
+        ctrl.trap_overflow as u32,
+        ctrl.trap as u32,
+        ctrl.hilo as u32,
+        ctrl.mem_read() as u32,
+        ctrl.mem_write() as u32,
+        ctrl.mem_size(),
+        matches!(ctrl.mem, MemAccess::Load { signed: true, .. }) as u32,
+        ctrl.reg_dst as u32,
+        ctrl.cond_write as u32,
+        ctrl.insert as u32,
+        ctrl.pc_src as u32,
+        ctrl.link as u32,
+        ctrl.cop0 as u32,
+
+        cycle.alu_in1,
+        cycle.alu_in2,
+        cycle.alu_res,
+        cycle.alu_zero,
+
+        mem.map(|m| m.paddr).unwrap_or(0),
+        mem.map(|m| !m.is_write).unwrap_or(false) as u32,
+        mem.map(|m| m.is_write).unwrap_or(false) as u32,
+        mem.filter(|m| m.is_write).map(|m| m.value).unwrap_or(0),
+        mem.filter(|m| !m.is_write).map(|m| m.value).unwrap_or(0),
+
+        write.is_some() as u32,
+        write.map(|w| w.reg as u32).unwrap_or(0),
+        write.map(|w| w.new).unwrap_or(0),
+    ];
+}
+
+/*
+ * Declarations of every signal, up to $enddefinitions.
+ */
+pub fn header() -> String {
+    let mut text = String::new();
+    text.push_str("$version rvp single cycle datapath $end\n");
+    text.push_str("$timescale 1ns $end\n");
+    text.push_str("$scope module datapath $end\n");
+    let mut scope = "datapath";
+    for (i, (sig_scope, name, width)) in SIGNALS.iter().enumerate() {
+        if *sig_scope != scope {
+            if scope != "datapath" {
+                text.push_str("$upscope $end\n");
+            }
+            text.push_str(&format!("$scope module {} $end\n", sig_scope));
+            scope = sig_scope;
+        }
+        if *width == 1 {
+            text.push_str(&format!("$var wire 1 {} {} $end\n", id(i), name));
+        } else {
+            text.push_str(&format!("$var wire {} {} {} [{}:0] $end\n", width, id(i), name, width - 1));
+        }
+    }
+    if scope != "datapath" {
+        text.push_str("$upscope $end\n");
+    }
+    text.push_str("$upscope $end\n");
+    text.push_str("$enddefinitions $end\n");
+    return text;
+}
+
+// *** PRIVATE FN ***
+
+/*
+ * Short identifier of a signal, printable ascii from '%' (some viewers
+ * stumble over ids starting with '#' or '$').
+ */
+fn id(signal: usize) -> String {
+    let mut n = signal;
+    let mut id = String::new();
+    loop {
+        id.push((b'%' + (n % 90) as u8) as char);
+        n /= 90;
+        if n == 0 {
+            return id;
+        }
+    }
+}
+
+fn change(signal: usize, val: u32) -> String {
+    if SIGNALS[signal].2 == 1 {
+        return format!("{}{}\n", val & 0x1, id(signal));
+    }
+    return format!("b{:b} {}\n", val, id(signal));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control_bits::{AluSrc, RegDst};
+    use crate::cycle::RegWrite;
+    use crate::demos::assembler::instr_macros::*;
+    use crate::demos::assembler::reg_macros::*;
+    use std::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn addi_cycle(cycle: u64, val: u16) -> Cycle {
+        let mut c = Cycle {
+            cycle,
+            pc: cycle as u32 * 4,
+            word: ADDI(T0(), ZERO(), val),
+            alu_in2: val as u32,
+            alu_res: val as u32,
+            write: Some(RegWrite { reg: T0(), old: 0, new: val as u32 }),
+            next_pc: cycle as u32 * 4 + 4,
+            ..Cycle::default()
+        };
+        c.ctrl.alu_src = AluSrc::SignImm;
+        c.ctrl.reg_dst = RegDst::Rt;
+        return c;
+    }
+
+    #[test]
+    fn test_header() {
+        let header = header();
+        assert!(header.starts_with("$version"));
+        assert!(header.contains("$scope module control $end\n$var wire 5 + alu_op [4:0] $end\n"));
+        assert!(header.contains("$var wire 1 % clk $end\n"));
+        assert_eq!(header.matches("$scope").count(), header.matches("$upscope").count());
+        assert!(header.ends_with("$upscope $end\n$enddefinitions $end\n"));
+        assert_eq!(SIGNALS.len(), sample(&Cycle::default()).len());
+    }
+
+    #[test]
+    fn test_changes() {
+        let buf = Rc::new(RefCell::new(Vec::new()));
+        let mut vcd = VcdWriter::new(Box::new(Shared(buf.clone())));
+        vcd.cycle(&addi_cycle(0, 0xbabe));
+        vcd.cycle(&addi_cycle(1, 0xbabe));
+        vcd.finish();
+        let out = String::from_utf8(buf.borrow().clone()).unwrap();
+        let body = &out[out.find("$enddefinitions $end\n").unwrap() + 21..];
+
+        let mut lines = body.lines();
+        assert_eq!(lines.next(), Some("#0"));
+        assert_eq!(lines.next(), Some("$dumpvars"));
+        assert_eq!(lines.next(), Some("1%"));
+        assert_eq!(lines.next(), Some("b0 &"));
+        // pc, next_pc and the clock change in the second cycle, nothing else
+        let second = &body[body.find("#10\n").unwrap()..];
+        assert_eq!(second, "#10\n1%\nb100 &\nb1000 (\n#15\n0%\n#20\n");
+    }
+}
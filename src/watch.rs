@@ -0,0 +1,454 @@
+/*
+ * watch.rs
+ *
+ * Author: Travis Banken
+ *
+ * Watchpoints. Memory watches fire on loads and/or stores that touch a range
+ * of (virtual) data addresses, register watches fire whenever the register
+ * changes value. A watch either stops the processor or only adds an entry to
+ * the log, both record the pc and the old and new value.
+ *
+ * Spec (--watch=<spec>):
+ *  write:<addr>[+<len>]   stores to [addr, addr + len), len defaults to 4
+ *  read:<addr>[+<len>]    loads
+ *  access:<addr>[+<len>]  loads and stores
+ *  reg:<reg>              register changed ($t0, t0 or 8), by the register
+ *                         names of the isa
+ * followed by ",log" to log instead of stopping.
+ */
+#![allow(dead_code)]
+
+use crate::hardware::reg_file;
+use crate::isa::Isa;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Stop,
+    Log,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    Mem { addr: u32, len: u32, kind: WatchKind },
+    Reg(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Watch {
+    pub target: Target,
+    pub action: Action,
+    // names the registers
+    pub isa: Isa,
+}
+
+/*
+ * A watch that fired.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hit {
+    // index of the watch that fired
+    pub watch: usize,
+    pub pc: u32,
+    pub access: Access,
+    pub old: u32,
+    pub new: u32,
+    pub action: Action,
+    pub isa: Isa,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    // size is 1 or 4 bytes
+    Mem { addr: u32, size: u32, is_write: bool },
+    Reg(u8),
+}
+
+impl Watch {
+    pub fn parse(spec: &str, isa: Isa) -> Result<Watch, String> {
+        let (spec, action) = match spec.strip_suffix(",log") {
+            Some(spec) => (spec, Action::Log),
+            None => (spec.strip_suffix(",stop").unwrap_or(spec), Action::Stop),
+        };
+        let (kind, loc) = spec.split_once(':')
+            .ok_or(format!("Watch-Error: expected <kind>:<location> not [{}]", spec))?;
+
+        if kind == "reg" {
+            let reg = isa.reg_num(loc).ok_or(format!("Watch-Error: unknown register [{}]", loc))?;
+            return Ok(Watch { target: Target::Reg(reg), action, isa });
+        }
+        let kind = match kind {
+            "read" => WatchKind::Read,
+            "write" => WatchKind::Write,
+            "access" => WatchKind::Access,
+            _ => return Err(format!("Watch-Error: unknown kind [{}], use read, write, access or reg", kind)),
+        };
+        let (addr, len) = match loc.split_once('+') {
+            Some((addr, len)) => (parse_num(addr)?, parse_num(len)?),
+            None => (parse_num(loc)?, 4),
+        };
+        if len == 0 {
+            return Err("Watch-Error: length must be at least 1".to_string());
+        }
+        return Ok(Watch { target: Target::Mem { addr, len, kind }, action, isa });
+    }
+}
+
+impl fmt::Display for Watch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.target {
+            Target::Mem { addr, len, kind } => {
+                let kind = match kind {
+                    WatchKind::Read => "read",
+                    WatchKind::Write => "write",
+                    WatchKind::Access => "access",
+                };
+                write!(f, "{} 0x{:08x}+{}", kind, addr, len)?;
+            },
+            Target::Reg(reg) => write!(f, "reg ${}", self.isa.reg_name(reg))?,
+        }
+        if self.action == Action::Log {
+            write!(f, " (log)")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Hit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.access {
+            Access::Mem { addr, size, is_write } => {
+                let what = if is_write {"write"} else {"read"};
+                write!(f, "0x{:08x}: {} 0x{:08x} ({} bytes) 0x{:x} -> 0x{:x}",
+                       self.pc, what, addr, size, self.old, self.new)
+            },
+            Access::Reg(reg) => {
+                write!(f, "0x{:08x}: ${} 0x{:x} -> 0x{:x}", self.pc, self.isa.reg_name(reg), self.old, self.new)
+            },
+        }
+    }
+}
+
+struct Entry {
+    watch: Watch,
+    // register value last seen
+    last: u32,
+}
+
+pub struct Watchpoints {
+    // removed watches leave a None so the numbers stay the same
+    entries: Vec<Option<Entry>>,
+    log: Vec<Hit>,
+    stop: Option<Hit>,
+}
+
+impl Watchpoints {
+    pub fn new() -> Watchpoints {
+        Watchpoints {
+            entries: Vec::new(),
+            log: Vec::new(),
+            stop: None,
+        }
+    }
+
+    /*
+     * Adds a watch, returns its number. Register watches start from the
+     * current value of the register.
+     */
+    pub fn add(&mut self, watch: Watch, regfile: &reg_file::Registers) -> usize {
+        let last = match watch.target {
+            Target::Reg(reg) => regfile.load(reg as usize),
+            Target::Mem { .. } => 0,
+        };
+        self.entries.push(Some(Entry { watch, last }));
+        return self.entries.len() - 1;
+    }
+
+    /*
+     * Removes a watch by number, returns false if there was none.
+     */
+    pub fn remove(&mut self, num: usize) -> bool {
+        match self.entries.get_mut(num) {
+            Some(entry) if entry.is_some() => {
+                *entry = None;
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /*
+     * Removes a memory watch by its range and kind (gdb's z2-z4).
+     */
+    pub fn remove_mem(&mut self, addr: u32, len: u32, kind: WatchKind) -> bool {
+        let target = Target::Mem { addr, len, kind };
+        let num = self.iter().find(|(_, watch)| watch.target == target).map(|(num, _)| num);
+        match num {
+            Some(num) => self.remove(num),
+            None => false,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.iter().all(|entry| entry.is_none())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (usize, Watch)> + '_ {
+        self.entries.iter()
+            .enumerate()
+            .filter_map(|(num, entry)| entry.as_ref().map(|e| (num, e.watch)))
+    }
+
+    /*
+     * Is any memory watch looking at [addr, addr + size)?
+     */
+    pub fn watches_mem(&self, addr: u32, size: u32) -> bool {
+        self.iter().any(|(_, watch)| match watch.target {
+            Target::Mem { addr: start, len, .. } => overlaps(start, len, addr, size),
+            Target::Reg(_) => false,
+        })
+    }
+
+    /*
+     * Called by the memory phase for every load and store. old is the value
+     * in memory before a store (equal to new for loads).
+     */
+    pub fn check_mem(&mut self, pc: u32, addr: u32, size: u32, is_write: bool, old: u32, new: u32) {
+        let mut hits = Vec::new();
+        for (num, watch) in self.iter() {
+            let (start, len, kind) = match watch.target {
+                Target::Mem { addr, len, kind } => (addr, len, kind),
+                Target::Reg(_) => continue,
+            };
+            let fires = match kind {
+                WatchKind::Read => !is_write,
+                WatchKind::Write => is_write,
+                WatchKind::Access => true,
+            };
+            if fires && overlaps(start, len, addr, size) {
+                hits.push(Hit {
+                    watch: num,
+                    pc,
+                    access: Access::Mem { addr, size, is_write },
+                    old,
+                    new,
+                    action: watch.action,
+                    isa: watch.isa,
+                });
+            }
+        }
+        for hit in hits {
+            self.record(hit);
+        }
+    }
+
+    /*
+     * Called after every instruction, fires the register watches whose
+     * register changed.
+     */
+    pub fn check_regs(&mut self, pc: u32, regfile: &reg_file::Registers) {
+        let mut hits = Vec::new();
+        for (num, entry) in self.entries.iter_mut().enumerate() {
+            let entry = match entry {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let reg = match entry.watch.target {
+                Target::Reg(reg) => reg,
+                Target::Mem { .. } => continue,
+            };
+            let new = regfile.load(reg as usize);
+            if new != entry.last {
+                hits.push(Hit {
+                    watch: num,
+                    pc,
+                    access: Access::Reg(reg),
+                    old: entry.last,
+                    new,
+                    action: entry.watch.action,
+                    isa: entry.watch.isa,
+                });
+                entry.last = new;
+            }
+        }
+        for hit in hits {
+            self.record(hit);
+        }
+    }
+
+    /*
+     * Called for a register write being undone by reverse execution, the
+     * register goes back from new to old.
+     */
+    pub fn check_undo_reg(&mut self, pc: u32, reg: u8, old: u32, new: u32) {
+        let mut hits = Vec::new();
+        for (num, entry) in self.entries.iter_mut().enumerate() {
+            let entry = match entry {
+                Some(entry) => entry,
+                None => continue,
+            };
+            if entry.watch.target != Target::Reg(reg) {
+                continue;
+            }
+            if old != new {
+                hits.push(Hit {
+                    watch: num,
+                    pc,
+                    access: Access::Reg(reg),
+                    old,
+                    new,
+                    action: entry.watch.action,
+                    isa: entry.watch.isa,
+                });
+            }
+            entry.last = old;
+        }
+        for hit in hits {
+            self.record(hit);
+        }
+    }
+
+    /*
+     * Register watches start over from the current register values, after
+     * the registers were changed behind their back.
+     */
+    pub fn sync_regs(&mut self, regfile: &reg_file::Registers) {
+        for entry in self.entries.iter_mut().flatten() {
+            if let Target::Reg(reg) = entry.watch.target {
+                entry.last = regfile.load(reg as usize);
+            }
+        }
+    }
+
+    /*
+     * Hits of every watch, stopping ones included.
+     */
+    pub fn log(&self) -> &[Hit] {
+        &self.log
+    }
+
+    /*
+     * Returns the log and starts a new one.
+     */
+    pub fn take_log(&mut self) -> Vec<Hit> {
+        std::mem::take(&mut self.log)
+    }
+
+    /*
+     * Returns the hit that asked to stop the processor, if any, and clears
+     * it.
+     */
+    pub fn take_stop(&mut self) -> Option<Hit> {
+        self.stop.take()
+    }
+
+    // *** PRIVATE FN ***
+
+    fn record(&mut self, hit: Hit) {
+        if hit.action == Action::Stop && self.stop.is_none() {
+            self.stop = Some(hit);
+        }
+        self.log.push(hit);
+    }
+}
+
+impl Default for Watchpoints {
+    fn default() -> Watchpoints {
+        Watchpoints::new()
+    }
+}
+
+fn overlaps(start: u32, len: u32, addr: u32, size: u32) -> bool {
+    let (start, addr) = (start as u64, addr as u64);
+    return addr < start + len as u64 && start < addr + size as u64;
+}
+
+fn parse_num(num: &str) -> Result<u32, String> {
+    let res = match num.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(&hex.replace('_', ""), 16),
+        None => num.parse::<u32>(),
+    };
+    return res.map_err(|_| format!("Watch-Error: invalid number [{}]", num));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Watch::parse("write:0x40", Isa::Mips), Ok(Watch {
+            target: Target::Mem { addr: 0x40, len: 4, kind: WatchKind::Write },
+            action: Action::Stop,
+            isa: Isa::Mips,
+        }));
+        assert_eq!(Watch::parse("access:16+2,log", Isa::Mips), Ok(Watch {
+            target: Target::Mem { addr: 16, len: 2, kind: WatchKind::Access },
+            action: Action::Log,
+            isa: Isa::Mips,
+        }));
+        assert_eq!(Watch::parse("reg:$t0", Isa::Mips).unwrap().target, Target::Reg(8));
+        assert_eq!(Watch::parse("reg:t0", Isa::Rv32i).unwrap().to_string(), "reg $t0");
+        assert_eq!(Watch::parse("reg:t0", Isa::Rv32i).unwrap().target, Target::Reg(5));
+        assert!(Watch::parse("exec:0x40", Isa::Mips).is_err());
+        assert!(Watch::parse("write:0x40+0", Isa::Mips).is_err());
+        assert!(Watch::parse("reg:$q1", Isa::Mips).is_err());
+        assert_eq!(Watch::parse("read:0x40+8,log", Isa::Mips).unwrap().to_string(), "read 0x00000040+8 (log)");
+    }
+
+    #[test]
+    fn test_mem() {
+        let regs = reg_file::Registers::new();
+        let mut watches = Watchpoints::new();
+        watches.add(Watch::parse("write:0x40", Isa::Mips).unwrap(), &regs);
+        watches.add(Watch::parse("read:0x44+1,log", Isa::Mips).unwrap(), &regs);
+        assert!(watches.watches_mem(0x43, 1));
+        assert!(!watches.watches_mem(0x45, 1));
+
+        // load of a write watch, nothing
+        watches.check_mem(0x0, 0x40, 4, false, 7, 7);
+        assert!(watches.log().is_empty());
+
+        watches.check_mem(0x8, 0x44, 4, false, 9, 9);
+        assert_eq!(watches.take_stop(), None);
+        watches.check_mem(0xc, 0x42, 1, true, 0, 0x42);
+        let hit = watches.take_stop().unwrap();
+        assert_eq!(hit.pc, 0xc);
+        assert_eq!(hit.to_string(), "0x0000000c: write 0x00000042 (1 bytes) 0x0 -> 0x42");
+        assert_eq!(watches.take_log().len(), 2);
+
+        assert!(watches.remove_mem(0x40, 4, WatchKind::Write));
+        assert!(!watches.remove(0));
+        watches.check_mem(0x10, 0x40, 4, true, 0, 1);
+        assert!(watches.log().is_empty());
+    }
+
+    #[test]
+    fn test_reg() {
+        let mut regs = reg_file::Registers::new();
+        regs.write(5, 8);
+        let mut watches = Watchpoints::new();
+        let num = watches.add(Watch::parse("reg:t0,log", Isa::Mips).unwrap(), &regs);
+
+        watches.check_regs(0x0, &regs);
+        assert!(watches.log().is_empty());
+        regs.write(6, 8);
+        watches.check_regs(0x4, &regs);
+        assert_eq!(watches.log()[0].to_string(), "0x00000004: $t0 0x5 -> 0x6");
+        assert_eq!(watches.take_stop(), None);
+
+        assert!(watches.remove(num));
+        assert!(watches.is_empty());
+    }
+}